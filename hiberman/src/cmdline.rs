@@ -0,0 +1,98 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Guards against resuming with a different kernel command line than the one
+//! the system hibernated with.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::warn;
+use openssl::sha::sha256;
+
+use crate::hiberutil::HibernateError;
+
+/// Name of the file on hibermeta with the hash of the kernel command line at
+/// hibernate time.
+pub const CMDLINE_HASH_FILE_NAME: &str = "cmdline_hash";
+
+const PROC_CMDLINE_PATH: &str = "/proc/cmdline";
+
+fn hash_cmdline(cmdline: &str) -> String {
+    hex::encode(sha256(cmdline.trim_end().as_bytes()))
+}
+
+/// Read the current kernel command line.
+pub fn read_cmdline() -> Result<String> {
+    fs::read_to_string(PROC_CMDLINE_PATH).context(format!("Failed to read {}", PROC_CMDLINE_PATH))
+}
+
+/// Record the hash of the kernel command line `cmdline` in the given
+/// hibermeta directory.
+pub fn save_cmdline_hash(meta: &Path, cmdline: &str) -> Result<()> {
+    let path = meta.join(CMDLINE_HASH_FILE_NAME);
+    fs::write(&path, hash_cmdline(cmdline)).context(format!("Failed to write {}", path.display()))
+}
+
+/// Fail with HibernateError::CmdlineMismatch if the current kernel command
+/// line differs from the one recorded in the given hibermeta directory at
+/// hibernate time. With `ignore_mismatch` a difference is only logged. Images
+/// without a recorded hash are accepted.
+pub fn check_cmdline_hash(meta: &Path, ignore_mismatch: bool) -> Result<()> {
+    verify_cmdline_hash(meta, &read_cmdline()?, ignore_mismatch)
+}
+
+fn verify_cmdline_hash(meta: &Path, cmdline: &str, ignore_mismatch: bool) -> Result<()> {
+    let path = meta.join(CMDLINE_HASH_FILE_NAME);
+    if !path.exists() {
+        warn!("No kernel command line hash recorded at hibernate time");
+        return Ok(());
+    }
+
+    let recorded =
+        fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+    if recorded.trim_end() == hash_cmdline(cmdline) {
+        return Ok(());
+    }
+
+    if ignore_mismatch {
+        warn!("Kernel command line changed since hibernate, resuming anyway");
+        return Ok(());
+    }
+
+    Err(HibernateError::CmdlineMismatch()).context("Refusing to resume")
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_cmdline_mismatch() {
+        let temp_dir = tempdir().unwrap();
+        let meta = temp_dir.path();
+
+        let cmdline = "cros_secure console= root=PARTUUID=1234 dm_verity.error_behavior=3\n";
+        let changed = "cros_secure console= root=PARTUUID=5678 dm_verity.error_behavior=3\n";
+
+        // Nothing was recorded.
+        assert!(verify_cmdline_hash(meta, cmdline, false).is_ok());
+
+        save_cmdline_hash(meta, cmdline).unwrap();
+        assert!(verify_cmdline_hash(meta, cmdline, false).is_ok());
+
+        let err = verify_cmdline_hash(meta, changed, false).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::CmdlineMismatch())
+        ));
+
+        // The override accepts the changed command line.
+        assert!(verify_cmdline_hash(meta, changed, true).is_ok());
+    }
+}
@@ -8,12 +8,14 @@ use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 
 use anyhow::Context;
 use anyhow::Result;
+use log::warn;
 
 use crate::hiberutil::path_to_stateful_block;
 use crate::hiberutil::HibernateError;
@@ -32,15 +34,26 @@ use crate::mmapbuf::MmapBuffer;
 /// Table (GPT) header. This space is ideal because its location is fixed, it's
 /// not manipulated in normal circumstances, and the GPT header format is
 /// unlikely to change and start using this space.
+///
+/// The GPT keeps a backup of its header in the last sector of the disk, which
+/// has the same unused space. With the cookie_backup feature a second copy of
+/// the cookie is written there, and read back if the primary copy can't be
+/// read at all.
 struct HibernateCookie {
     blockdev: File,
     buffer: MmapBuffer,
+    backup: bool,
 }
 
 /// Define the size of the region we update.
 const COOKIE_READ_SIZE: usize = 0x400;
 const COOKIE_WRITE_SIZE: usize = 0x400;
 
+/// Define the size of the region at the end of the disk holding the backup
+/// copy. It is read as a whole page so that its offset is aligned for disks
+/// with 4kB blocks too, which O_DIRECT requires.
+const BACKUP_COOKIE_REGION_SIZE: usize = 0x1000;
+
 /// Define the magic value the GPT stamps down, which we will use to verify
 /// we're writing to an area that we expect. If somehow the world shifted out
 /// from under us, this could prevent us from silently corrupting data.
@@ -53,6 +66,12 @@ const GPT_MAGIC: u64 = 0x5452415020494645; // 'EFI PART'
 /// cookie will be written.
 const COOKIE_MAGIC_OFFSET: usize = 0x3E0;
 
+/// The backup GPT header occupies the last sector of the backup region, the
+/// cookie goes at the same offset within that sector as for the primary copy.
+const BACKUP_GPT_MAGIC_OFFSET: usize = BACKUP_COOKIE_REGION_SIZE - 0x200;
+const BACKUP_COOKIE_MAGIC_OFFSET: usize =
+    BACKUP_GPT_MAGIC_OFFSET + (COOKIE_MAGIC_OFFSET - GPT_MAGIC_OFFSET);
+
 /// Define the magic token values we write to indicate a valid hibernate
 /// partition. This is both big (as in bigger than a single bit), and points the
 /// finger at an obvious culprit, in the case this does end up unintentionally
@@ -91,6 +110,11 @@ const COOKIE_EMERGENCY_REBOOT_VALUE: &[u8] = b"EmergencyReboot!";
 /// Define the size of the magic token, in bytes.
 const COOKIE_SIZE: usize = 16;
 
+/// The token is followed by the CRC32 of the token, so that a torn write can
+/// be told apart from a valid cookie. Cookies written before the CRC was
+/// added have zeroes there and are still accepted.
+const COOKIE_CRC_SIZE: usize = 4;
+
 #[derive(Eq, PartialEq)]
 pub enum HibernateCookieValue {
     Uninitialized,
@@ -99,6 +123,154 @@ pub enum HibernateCookieValue {
     ResumeInProgress,
     ResumeAborting,
     EmergencyReboot,
+    /// A known token with a CRC that doesn't match, e.g. after a torn write.
+    Invalid,
+}
+
+/// The two copies of the cookie kept on disk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CookieCopy {
+    Primary,
+    Backup,
+}
+
+impl CookieCopy {
+    /// Returns the size of the region holding the copy, and the offsets of
+    /// the GPT magic and the cookie within that region.
+    fn layout(&self) -> (usize, usize, usize) {
+        match self {
+            CookieCopy::Primary => (COOKIE_READ_SIZE, GPT_MAGIC_OFFSET, COOKIE_MAGIC_OFFSET),
+            CookieCopy::Backup => (
+                BACKUP_COOKIE_REGION_SIZE,
+                BACKUP_GPT_MAGIC_OFFSET,
+                BACKUP_COOKIE_MAGIC_OFFSET,
+            ),
+        }
+    }
+}
+
+/// Convert a cookie value to the token stored on disk.
+fn cookie_token(value: &HibernateCookieValue) -> &'static [u8] {
+    match value {
+        HibernateCookieValue::Uninitialized => COOKIE_NO_RESUME_VALUE,
+        HibernateCookieValue::NoResume => COOKIE_NO_RESUME_VALUE,
+        HibernateCookieValue::ResumeReady => COOKIE_RESUME_READY_VALUE,
+        HibernateCookieValue::ResumeInProgress => COOKIE_RESUME_IN_PROGRESS_VALUE,
+        HibernateCookieValue::ResumeAborting => COOKIE_RESUME_ABORTING_VALUE,
+        HibernateCookieValue::EmergencyReboot => COOKIE_EMERGENCY_REBOOT_VALUE,
+        HibernateCookieValue::Invalid => COOKIE_NO_RESUME_VALUE,
+    }
+}
+
+/// Compute the CRC32 (IEEE 802.3) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+/// Store the token of `value` followed by its CRC at `magic_start` in the
+/// region.
+fn put_cookie(region: &mut [u8], magic_start: usize, value: &HibernateCookieValue) {
+    let token = cookie_token(value);
+    let crc_start = magic_start + COOKIE_SIZE;
+    region[magic_start..crc_start].copy_from_slice(token);
+    region[crc_start..crc_start + COOKIE_CRC_SIZE].copy_from_slice(&crc32(token).to_le_bytes());
+}
+
+/// Parse one copy of the cookie from the region read from disk.
+fn parse_cookie(region: &[u8], copy: CookieCopy) -> Result<HibernateCookieValue> {
+    let (_, gpt_sig_offset, magic_start) = copy.layout();
+
+    // Verify there's a GPT header magic where there should be one.
+    // This would catch cases like writing to the wrong place or the
+    // GPT layout/location changing. This might need enlightenment for a
+    // disk with 4kB blocks, this check will let us know that too.
+    let gpt_sig_offset_end = gpt_sig_offset + 8;
+    let mut gpt_sig = [0u8; 8];
+    gpt_sig.copy_from_slice(&region[gpt_sig_offset..gpt_sig_offset_end]);
+    let gpt_sig = u64::from_le_bytes(gpt_sig);
+    if gpt_sig != GPT_MAGIC {
+        return Err(HibernateError::CookieError(format!(
+            "GPT magic not found in {:?} copy: {:x?}",
+            copy, gpt_sig
+        )))
+        .context("Failed to verify GPT magic");
+    }
+
+    let magic_end = magic_start + COOKIE_SIZE;
+    let value = &region[magic_start..magic_end];
+    let mut crc = [0u8; COOKIE_CRC_SIZE];
+    crc.copy_from_slice(&region[magic_end..magic_end + COOKIE_CRC_SIZE]);
+    let crc = u32::from_le_bytes(crc);
+    let known = [
+        COOKIE_NO_RESUME_VALUE,
+        COOKIE_RESUME_READY_VALUE,
+        COOKIE_RESUME_IN_PROGRESS_VALUE,
+        COOKIE_RESUME_ABORTING_VALUE,
+        COOKIE_EMERGENCY_REBOOT_VALUE,
+    ];
+    if known.contains(&value) && crc != 0 && crc != crc32(value) {
+        warn!("Hibernate cookie CRC mismatch in {:?} copy", copy);
+        return Ok(HibernateCookieValue::Invalid);
+    }
+
+    if value == COOKIE_NO_RESUME_VALUE {
+        Ok(HibernateCookieValue::NoResume)
+    } else if value == COOKIE_RESUME_READY_VALUE {
+        Ok(HibernateCookieValue::ResumeReady)
+    } else if value == COOKIE_RESUME_IN_PROGRESS_VALUE {
+        Ok(HibernateCookieValue::ResumeInProgress)
+    } else if value == COOKIE_RESUME_ABORTING_VALUE {
+        Ok(HibernateCookieValue::ResumeAborting)
+    } else if value == COOKIE_EMERGENCY_REBOOT_VALUE {
+        Ok(HibernateCookieValue::EmergencyReboot)
+    } else {
+        Ok(HibernateCookieValue::Uninitialized)
+    }
+}
+
+/// Pick the cookie value from the primary and backup copies. The primary copy
+/// wins, and the backup is only used if the primary can't be read at all. A
+/// primary with a bad CRC was torn while being written, most likely over an
+/// older value that the backup still holds, e.g. ResumeReady while moving on
+/// to ResumeInProgress. Such a primary means NoResume, falling back to the
+/// backup would resume the image again and again.
+fn select_cookie(
+    primary: Result<HibernateCookieValue>,
+    backup: impl FnOnce() -> Result<HibernateCookieValue>,
+) -> Result<HibernateCookieValue> {
+    match primary {
+        Ok(HibernateCookieValue::Invalid) => {
+            warn!("Primary hibernate cookie is torn, not resuming");
+            return Ok(HibernateCookieValue::NoResume);
+        }
+        Err(_) => {}
+        primary => return primary,
+    }
+
+    match backup() {
+        Ok(HibernateCookieValue::Uninitialized) | Ok(HibernateCookieValue::Invalid) | Err(_) => {
+            primary
+        }
+        Ok(backup) => {
+            warn!(
+                "Primary hibernate cookie is unusable, using backup: {}",
+                cookie_description(&backup)
+            );
+            Ok(backup)
+        }
+    }
 }
 
 impl HibernateCookie {
@@ -112,56 +284,24 @@ impl HibernateCookie {
             .open(path)
             .context("Failed to open hibernate cookie")?;
 
-        let buffer = MmapBuffer::new(COOKIE_READ_SIZE)?;
-        Ok(HibernateCookie { blockdev, buffer })
+        let buffer = MmapBuffer::new(BACKUP_COOKIE_REGION_SIZE)?;
+        Ok(HibernateCookie {
+            blockdev,
+            buffer,
+            backup: cfg!(feature = "cookie_backup"),
+        })
     }
 
     /// Read the contents of the disk to determine if the cookie is set or not.
     /// On success, returns a boolean that is true if the hibernate cookie is
     /// set (indicating the on-disk file systems should not be altered).
     pub fn read(&mut self) -> Result<HibernateCookieValue> {
-        self.blockdev
-            .rewind()
-            .context("Failed to seek in hibernate cookie")?;
-        let buffer_slice = self.buffer.u8_slice_mut();
-        self.blockdev
-            .read_exact(&mut buffer_slice[..COOKIE_READ_SIZE])
-            .context("Failed to read hibernate cookie")?;
-
-        // Verify there's a GPT header magic where there should be one.
-        // This would catch cases like writing to the wrong place or the
-        // GPT layout/location changing. This might need enlightenment for a
-        // disk with 4kB blocks, this check will let us know that too.
-        let gpt_sig_offset = GPT_MAGIC_OFFSET;
-        let gpt_sig_offset_end = gpt_sig_offset + 8;
-        let mut gpt_sig = [0u8; 8];
-        let buffer_slice = self.buffer.u8_slice();
-        gpt_sig.copy_from_slice(&buffer_slice[gpt_sig_offset..gpt_sig_offset_end]);
-        let gpt_sig = u64::from_le_bytes(gpt_sig);
-        if gpt_sig != GPT_MAGIC {
-            return Err(HibernateError::CookieError(format!(
-                "GPT magic not found: {:x?}",
-                gpt_sig
-            )))
-            .context("Failed to verify GPT magic");
+        let primary = self.read_copy(CookieCopy::Primary);
+        if !self.backup {
+            return primary;
         }
 
-        let magic_start = COOKIE_MAGIC_OFFSET;
-        let magic_end = magic_start + COOKIE_SIZE;
-        let value = &buffer_slice[magic_start..magic_end];
-        if value == COOKIE_NO_RESUME_VALUE {
-            Ok(HibernateCookieValue::NoResume)
-        } else if value == COOKIE_RESUME_READY_VALUE {
-            Ok(HibernateCookieValue::ResumeReady)
-        } else if value == COOKIE_RESUME_IN_PROGRESS_VALUE {
-            Ok(HibernateCookieValue::ResumeInProgress)
-        } else if value == COOKIE_RESUME_ABORTING_VALUE {
-            Ok(HibernateCookieValue::ResumeAborting)
-        } else if value == COOKIE_EMERGENCY_REBOOT_VALUE {
-            Ok(HibernateCookieValue::EmergencyReboot)
-        } else {
-            Ok(HibernateCookieValue::Uninitialized)
-        }
+        select_cookie(primary, || self.read_copy(CookieCopy::Backup))
     }
 
     /// Write the hibernate cookie to disk via a fresh read modify write
@@ -170,28 +310,72 @@ impl HibernateCookie {
     /// altered), or poison value (false, indicating no impending hibernate
     /// resume, file systems can be mounted RW).
     pub fn write(&mut self, value: HibernateCookieValue) -> Result<()> {
-        let existing = self.read()?;
+        self.write_copy(CookieCopy::Primary, &value)?;
+
+        // A backup that wasn't updated could bring back a stale value later,
+        // so failing to write it fails the whole write.
+        if self.backup {
+            self.write_copy(CookieCopy::Backup, &value)
+                .context("Failed to write backup hibernate cookie")?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the offset of the region holding a copy of the cookie.
+    fn region_offset(&mut self, copy: CookieCopy) -> Result<u64> {
+        match copy {
+            CookieCopy::Primary => Ok(0),
+            CookieCopy::Backup => {
+                let disk_size = self
+                    .blockdev
+                    .seek(SeekFrom::End(0))
+                    .context("Failed to get the size of the disk")?;
+                disk_size
+                    .checked_sub(BACKUP_COOKIE_REGION_SIZE as u64)
+                    .context("Disk too small for a backup hibernate cookie")
+            }
+        }
+    }
+
+    /// Read the region holding a copy of the cookie into the buffer.
+    fn read_region(&mut self, copy: CookieCopy) -> Result<()> {
+        let (size, _, _) = copy.layout();
+        let offset = self.region_offset(copy)?;
         self.blockdev
-            .rewind()
-            .context("Failed to seek hibernate cookie")?;
-        if value == existing {
+            .seek(SeekFrom::Start(offset))
+            .context("Failed to seek in hibernate cookie")?;
+        let buffer_slice = self.buffer.u8_slice_mut();
+        self.blockdev
+            .read_exact(&mut buffer_slice[..size])
+            .context("Failed to read hibernate cookie")
+    }
+
+    fn read_copy(&mut self, copy: CookieCopy) -> Result<HibernateCookieValue> {
+        self.read_region(copy)?;
+        let (size, _, _) = copy.layout();
+        parse_cookie(&self.buffer.u8_slice()[..size], copy)
+    }
+
+    fn write_copy(&mut self, copy: CookieCopy, value: &HibernateCookieValue) -> Result<()> {
+        let existing = self.read_copy(copy)?;
+        if *value == existing {
             return Ok(());
         }
 
-        let magic_start = COOKIE_MAGIC_OFFSET;
-        let magic_end = magic_start + COOKIE_SIZE;
-        let cookie = match value {
-            HibernateCookieValue::Uninitialized => COOKIE_NO_RESUME_VALUE,
-            HibernateCookieValue::NoResume => COOKIE_NO_RESUME_VALUE,
-            HibernateCookieValue::ResumeReady => COOKIE_RESUME_READY_VALUE,
-            HibernateCookieValue::ResumeInProgress => COOKIE_RESUME_IN_PROGRESS_VALUE,
-            HibernateCookieValue::ResumeAborting => COOKIE_RESUME_ABORTING_VALUE,
-            HibernateCookieValue::EmergencyReboot => COOKIE_EMERGENCY_REBOOT_VALUE,
+        let offset = self.region_offset(copy)?;
+        let (size, _, magic_start) = copy.layout();
+        let buffer_slice = self.buffer.u8_slice_mut();
+        put_cookie(buffer_slice, magic_start, value);
+        // The primary copy only ever writes the sectors it read.
+        let end = match copy {
+            CookieCopy::Primary => COOKIE_WRITE_SIZE,
+            CookieCopy::Backup => size,
         };
 
-        let buffer_slice = self.buffer.u8_slice_mut();
-        buffer_slice[magic_start..magic_end].copy_from_slice(cookie);
-        let end = COOKIE_WRITE_SIZE;
+        self.blockdev
+            .seek(SeekFrom::Start(offset))
+            .context("Failed to seek hibernate cookie")?;
         self.blockdev
             .write_all(&buffer_slice[..end])
             .context("Failed to write hibernate cookie")?;
@@ -235,6 +419,7 @@ pub fn cookie_description(value: &HibernateCookieValue) -> &'static str {
         HibernateCookieValue::ResumeInProgress => "Resume in Progress",
         HibernateCookieValue::ResumeAborting => "Resume Aborting",
         HibernateCookieValue::EmergencyReboot => "Emergency Reboot",
+        HibernateCookieValue::Invalid => "Invalid",
     }
 }
 
@@ -245,3 +430,107 @@ fn open_hibernate_cookie<P: AsRef<Path>>(path_ref: Option<P>) -> Result<Hibernat
         HibernateCookie::new(path_to_stateful_block()?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_region(copy: CookieCopy, value: Option<&HibernateCookieValue>) -> Vec<u8> {
+        let (size, gpt_sig_offset, magic_start) = copy.layout();
+        let mut region = vec![0u8; size];
+        region[gpt_sig_offset..gpt_sig_offset + 8].copy_from_slice(&GPT_MAGIC.to_le_bytes());
+        if let Some(value) = value {
+            put_cookie(&mut region, magic_start, value);
+        }
+
+        region
+    }
+
+    #[test]
+    fn test_cookie_falls_back_to_backup() {
+        let backup = make_region(CookieCopy::Backup, Some(&HibernateCookieValue::ResumeReady));
+        let read_backup = || parse_cookie(&backup, CookieCopy::Backup);
+
+        // An intact primary copy wins.
+        let primary = make_region(CookieCopy::Primary, Some(&HibernateCookieValue::NoResume));
+        let value = select_cookie(parse_cookie(&primary, CookieCopy::Primary), read_backup);
+        assert!(value.unwrap() == HibernateCookieValue::NoResume);
+
+        // A primary copy that was torn moving on from ResumeReady never
+        // falls back to the backup, which still holds ResumeReady.
+        let intact = make_region(
+            CookieCopy::Primary,
+            Some(&HibernateCookieValue::ResumeInProgress),
+        );
+        let mut torn = make_region(
+            CookieCopy::Primary,
+            Some(&HibernateCookieValue::ResumeReady),
+        );
+        torn[COOKIE_MAGIC_OFFSET..COOKIE_MAGIC_OFFSET + COOKIE_SIZE]
+            .copy_from_slice(&intact[COOKIE_MAGIC_OFFSET..COOKIE_MAGIC_OFFSET + COOKIE_SIZE]);
+        let value = select_cookie(parse_cookie(&torn, CookieCopy::Primary), read_backup);
+        assert!(value.unwrap() == HibernateCookieValue::NoResume);
+
+        // Neither does a primary copy with an unrecognized token.
+        let mut primary = make_region(
+            CookieCopy::Primary,
+            Some(&HibernateCookieValue::ResumeReady),
+        );
+        primary[COOKIE_MAGIC_OFFSET] ^= 0xff;
+        let value = select_cookie(parse_cookie(&primary, CookieCopy::Primary), read_backup);
+        assert!(value.unwrap() == HibernateCookieValue::Uninitialized);
+
+        // A corrupted GPT header in the primary copy.
+        let mut primary = make_region(
+            CookieCopy::Primary,
+            Some(&HibernateCookieValue::ResumeReady),
+        );
+        primary[GPT_MAGIC_OFFSET] ^= 0xff;
+        let value = select_cookie(parse_cookie(&primary, CookieCopy::Primary), read_backup);
+        assert!(value.unwrap() == HibernateCookieValue::ResumeReady);
+
+        // Without a usable backup the primary result is returned.
+        let value = select_cookie(parse_cookie(&primary, CookieCopy::Primary), || {
+            parse_cookie(&make_region(CookieCopy::Backup, None), CookieCopy::Backup)
+        });
+        assert!(value.is_err());
+    }
+
+    #[test]
+    fn test_torn_cookie_write() {
+        let intact = make_region(
+            CookieCopy::Primary,
+            Some(&HibernateCookieValue::ResumeReady),
+        );
+        let value = parse_cookie(&intact, CookieCopy::Primary).unwrap();
+        assert!(value == HibernateCookieValue::ResumeReady);
+
+        // The token of ResumeReady made it to disk over NoResume, but only
+        // half of its CRC did.
+        let mut torn = make_region(CookieCopy::Primary, Some(&HibernateCookieValue::NoResume));
+        let crc_start = COOKIE_MAGIC_OFFSET + COOKIE_SIZE;
+        torn[COOKIE_MAGIC_OFFSET..crc_start + 2]
+            .copy_from_slice(&intact[COOKIE_MAGIC_OFFSET..crc_start + 2]);
+        let value = parse_cookie(&torn, CookieCopy::Primary).unwrap();
+        assert!(value == HibernateCookieValue::Invalid);
+
+        // Only the beginning of the token made it to disk.
+        let mut torn = make_region(CookieCopy::Primary, Some(&HibernateCookieValue::NoResume));
+        torn[COOKIE_MAGIC_OFFSET..COOKIE_MAGIC_OFFSET + 8]
+            .copy_from_slice(&intact[COOKIE_MAGIC_OFFSET..COOKIE_MAGIC_OFFSET + 8]);
+        let value = parse_cookie(&torn, CookieCopy::Primary).unwrap();
+        assert!(value == HibernateCookieValue::Uninitialized);
+
+        // Cookies from before the CRC was added are still understood.
+        let mut legacy = intact.clone();
+        legacy[crc_start..crc_start + COOKIE_CRC_SIZE].fill(0);
+        let value = parse_cookie(&legacy, CookieCopy::Primary).unwrap();
+        assert!(value == HibernateCookieValue::ResumeReady);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+}
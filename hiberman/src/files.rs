@@ -5,14 +5,30 @@
 //! High level support for creating and opening the files used by hibernate.
 
 use std::fs::create_dir;
+use std::fs::metadata;
 use std::fs::remove_file;
 use std::fs::OpenOptions;
 use std::path::Path;
+use std::path::PathBuf;
 
 use anyhow::Context;
 use anyhow::Result;
+use log::info;
 use log::warn;
 
+use crate::cmdline::CMDLINE_HASH_FILE_NAME;
+use crate::hiberlog::LogFile;
+use crate::hiberlog::RESUME_LOG_FILE_NAME;
+use crate::hiberlog::SUSPEND_LOG_FILE_NAME;
+use crate::hiberutil::HibernateError;
+use crate::hiberutil::HibernateStage;
+use crate::hiberutil::TimestampFile;
+use crate::image_info::IMAGE_INFO_FILE_NAME;
+use crate::metrics::METRICS_FILE_PATH;
+use crate::resume_failure::RESUME_FAILURE_FILE_NAME;
+use crate::vboot::VBOOT_STATE_FILE_NAME;
+use crate::volume::VOLUME_MANAGER;
+
 /// Define the directory where hibernate state files are kept.
 pub const HIBERMETA_DIR: &str = "/mnt/hibermeta";
 /// Define the ramfs location where ephemeral files are stored that should not
@@ -22,6 +38,18 @@ pub const TMPFS_DIR: &str = "/run/hibernate/";
 /// Services outside of hiberman use this file, so don't change this name
 /// carelessly.
 const RESUME_IN_PROGRESS_FILE: &str = "resume_in_progress";
+/// Free space in the thinpool (in percent) below which the data of a
+/// hibernate image that can't be resumed any more is deleted, unless
+/// overridden in HibernateOptions.
+pub const DEFAULT_LOW_DISK_THRESHOLD_PERCENT: u64 = 10;
+/// The files on hibermeta that describe the last hibernate image.
+const IMAGE_FILE_NAMES: [&str; 5] = [
+    SUSPEND_LOG_FILE_NAME,
+    RESUME_LOG_FILE_NAME,
+    IMAGE_INFO_FILE_NAME,
+    CMDLINE_HASH_FILE_NAME,
+    VBOOT_STATE_FILE_NAME,
+];
 
 /// Add the resuming file token that other services can check to quickly see if
 /// a resume is in progress.
@@ -58,3 +86,236 @@ pub fn remove_resume_in_progress_file() {
         }
     }
 }
+
+/// Returns the free space in bytes in the thinpool of the stateful partition,
+/// which hibernate images are written to.
+pub fn stateful_free_bytes() -> Result<u64> {
+    VOLUME_MANAGER.read().unwrap().get_free_thinpool_space()
+}
+
+/// Returns whether the stateful partition has enough free space for writing
+/// a hibernate image of `image_size` bytes.
+pub fn has_room_for_image(image_size: u64) -> Result<bool> {
+    has_room_for_image_with(stateful_free_bytes, image_size)
+}
+
+/// Like has_room_for_image(), getting the free space from `free_bytes`.
+pub(crate) fn has_room_for_image_with<F>(free_bytes: F, image_size: u64) -> Result<bool>
+where
+    F: FnOnce() -> Result<u64>,
+{
+    let free_bytes = free_bytes().context("Failed to get the free space on stateful")?;
+    if free_bytes < image_size {
+        warn!(
+            "Not enough space ({} MB) in the thinpool for writing the hibernate image",
+            free_bytes / (1024 * 1024)
+        );
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Fail with HibernateError::LowDiskThresholdOutOfRange if the low disk
+/// threshold is not a percentage.
+pub fn check_low_disk_threshold(threshold_percent: u64) -> Result<()> {
+    if threshold_percent > 100 {
+        return Err(HibernateError::LowDiskThresholdOutOfRange(threshold_percent).into());
+    }
+
+    Ok(())
+}
+
+/// Delete the files describing the last hibernate image from the hibermeta
+/// directory `meta` if the free space in the thinpool is below
+/// `threshold_percent`. Must only be called once the image can't be resumed
+/// any more. Returns whether the disk was low on space.
+pub fn delete_image_files_if_disk_full(
+    meta: &Path,
+    free_percent: f64,
+    threshold_percent: u64,
+) -> bool {
+    if free_percent >= threshold_percent as f64 {
+        return false;
+    }
+
+    info!(
+        "Only {:.1}% of the thinpool is free, deleting hibernate data",
+        free_percent
+    );
+    for name in IMAGE_FILE_NAMES {
+        let path = meta.join(name);
+        if path.exists() {
+            if let Err(e) = remove_file(&path) {
+                warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    true
+}
+
+/// The kinds of files hibernate keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HiberFileKind {
+    SuspendLog,
+    ResumeLog,
+    Metrics,
+    ResumeTimestamp,
+    ImageInfo,
+    CmdlineHash,
+    VbootState,
+    LastResumeFailure,
+    ResumeInProgress,
+}
+
+/// Describes the current state of a file used by hibernate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HiberFileInfo {
+    pub path: PathBuf,
+    pub kind: HiberFileKind,
+    pub exists: bool,
+    /// Size of the file in bytes, 0 if it doesn't exist.
+    pub size: u64,
+}
+
+/// Enumerate all the files used by hibernate along with their existence and
+/// current size. Files on hibermeta are only visible while it is mounted.
+pub fn list_hibernate_files() -> Vec<HiberFileInfo> {
+    list_hibernate_files_in(Path::new("/"))
+}
+
+fn list_hibernate_files_in(root: &Path) -> Vec<HiberFileInfo> {
+    let files = [
+        (
+            HiberFileKind::SuspendLog,
+            LogFile::get_path(HibernateStage::Suspend),
+        ),
+        (
+            HiberFileKind::ResumeLog,
+            LogFile::get_path(HibernateStage::Resume),
+        ),
+        (HiberFileKind::Metrics, METRICS_FILE_PATH.clone()),
+        (
+            HiberFileKind::ResumeTimestamp,
+            TimestampFile::full_path("resume_start.ts"),
+        ),
+        (
+            HiberFileKind::ImageInfo,
+            Path::new(HIBERMETA_DIR).join(IMAGE_INFO_FILE_NAME),
+        ),
+        (
+            HiberFileKind::CmdlineHash,
+            Path::new(HIBERMETA_DIR).join(CMDLINE_HASH_FILE_NAME),
+        ),
+        (
+            HiberFileKind::VbootState,
+            Path::new(HIBERMETA_DIR).join(VBOOT_STATE_FILE_NAME),
+        ),
+        (
+            HiberFileKind::LastResumeFailure,
+            Path::new(HIBERMETA_DIR).join(RESUME_FAILURE_FILE_NAME),
+        ),
+        (
+            HiberFileKind::ResumeInProgress,
+            Path::new(TMPFS_DIR).join(RESUME_IN_PROGRESS_FILE),
+        ),
+    ];
+
+    files
+        .into_iter()
+        .map(|(kind, path)| {
+            let path = root.join(path.strip_prefix("/").unwrap_or(&path));
+            let (exists, size) = match metadata(&path) {
+                Ok(m) => (true, m.len()),
+                Err(_) => (false, 0),
+            };
+
+            HiberFileInfo {
+                path,
+                kind,
+                exists,
+                size,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_list_hibernate_files() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path();
+        let hibermeta = root.join(HIBERMETA_DIR.trim_start_matches('/'));
+        fs::create_dir_all(&hibermeta).unwrap();
+        fs::write(hibermeta.join("suspend_log"), [0u8; 100]).unwrap();
+        fs::write(hibermeta.join("metrics"), [0u8; 42]).unwrap();
+
+        let files = list_hibernate_files_in(root);
+        assert_eq!(files.len(), 9);
+
+        for file in files {
+            assert!(file.path.starts_with(root));
+            let expected = match file.kind {
+                HiberFileKind::SuspendLog => (true, 100),
+                HiberFileKind::Metrics => (true, 42),
+                _ => (false, 0),
+            };
+            assert_eq!((file.exists, file.size), expected, "{:?}", file.kind);
+        }
+    }
+
+    #[test]
+    fn test_delete_image_files_if_disk_full() {
+        let temp_dir = tempdir().unwrap();
+        let meta = temp_dir.path();
+        for name in IMAGE_FILE_NAMES.iter().chain(&["metrics"]) {
+            fs::write(meta.join(name), "data").unwrap();
+        }
+
+        // Enough space left.
+        assert!(!delete_image_files_if_disk_full(meta, 25.0, 10));
+        assert!(!delete_image_files_if_disk_full(meta, 10.0, 10));
+        assert!(!delete_image_files_if_disk_full(meta, 5.5, 0));
+        for name in IMAGE_FILE_NAMES {
+            assert!(meta.join(name).exists(), "{}", name);
+        }
+
+        assert!(delete_image_files_if_disk_full(meta, 25.0, 30));
+        for name in IMAGE_FILE_NAMES {
+            assert!(!meta.join(name).exists(), "{}", name);
+        }
+        // The metrics are not about the image and are kept.
+        assert!(meta.join("metrics").exists());
+    }
+
+    #[test]
+    fn test_has_room_for_image() {
+        const MB: u64 = 1024 * 1024;
+        assert!(has_room_for_image_with(|| Ok(100 * MB), 100 * MB).unwrap());
+        assert!(has_room_for_image_with(|| Ok(100 * MB), 10 * MB).unwrap());
+        assert!(!has_room_for_image_with(|| Ok(100 * MB), 100 * MB + 1).unwrap());
+        assert!(!has_room_for_image_with(|| Ok(0), 1).unwrap());
+        assert!(has_room_for_image_with(|| Err(anyhow::anyhow!("lvs failed")), 1).is_err());
+    }
+
+    #[test]
+    fn test_check_low_disk_threshold() {
+        for threshold in [0, DEFAULT_LOW_DISK_THRESHOLD_PERCENT, 100] {
+            check_low_disk_threshold(threshold).unwrap();
+        }
+
+        let err = check_low_disk_threshold(101).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::LowDiskThresholdOutOfRange(101))
+        ));
+    }
+}
@@ -45,9 +45,9 @@ const KMSG_PATH: &str = "/dev/kmsg";
 const LOG_PREFIX: &str = "hiberman";
 
 /// Define the name of the resume log file.
-const RESUME_LOG_FILE_NAME: &str = "resume_log";
+pub const RESUME_LOG_FILE_NAME: &str = "resume_log";
 /// Define the name of the suspend log file.
-const SUSPEND_LOG_FILE_NAME: &str = "suspend_log";
+pub const SUSPEND_LOG_FILE_NAME: &str = "suspend_log";
 
 static STATE: OnceCell<Mutex<Hiberlog>> = OnceCell::new();
 
@@ -8,33 +8,57 @@ pub mod cookie;
 pub mod hiberlog;
 pub mod metrics;
 
+mod cmdline;
 mod cryptohome;
 mod device_mapper;
 mod files;
 mod hiberutil;
+mod image_info;
 mod ioctl;
 mod lvm;
 mod mmapbuf;
 mod powerd;
+mod profile;
+mod progress;
 mod resume;
 mod resume_dbus;
+mod resume_failure;
 mod resume_init;
 mod snapdev;
 mod snapwatch;
 mod suspend;
+mod trace;
 mod update_engine;
+mod vboot;
 mod volume;
 
 use crate::resume_dbus::send_abort;
 
+pub use files::has_room_for_image;
+pub use files::list_hibernate_files;
+pub use files::stateful_free_bytes;
+pub use files::HiberFileInfo;
+pub use files::HiberFileKind;
 pub use hiberutil::AbortResumeOptions;
 pub use hiberutil::HibernateOptions;
+pub use hiberutil::PowerDownMode;
 pub use hiberutil::ResumeInitOptions;
 pub use hiberutil::ResumeOptions;
+pub use image_info::dump_image_info;
+pub use image_info::estimate_resume_duration;
+pub use image_info::IMAGE_INFO_VERSION;
+pub use profile::ProfileReport;
+pub use progress::ProgressCallback;
+pub use resume_failure::ResumeFailure;
 
 use crate::snapdev::SnapshotDevice;
 use crate::snapdev::SnapshotMode;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
 use anyhow::Result;
+use log::warn;
 use resume::ResumeConductor;
 use resume_init::ResumeInitConductor;
 use suspend::SuspendConductor;
@@ -49,7 +73,51 @@ pub fn abort_resume(options: AbortResumeOptions) -> Result<()> {
 /// after the system has successfully hibernated and resumed.
 pub fn hibernate(options: HibernateOptions) -> Result<()> {
     let mut conductor = SuspendConductor::new()?;
-    conductor.hibernate(options)
+    conductor.hibernate(options, None)
+}
+
+/// Hibernate the system like hibernate(), calling `progress` periodically
+/// with the number of bytes of the image written so far and the total size
+/// of the image while the image is written.
+pub fn hibernate_with_progress(
+    options: HibernateOptions,
+    progress: ProgressCallback,
+) -> Result<()> {
+    let mut conductor = SuspendConductor::new()?;
+    conductor.hibernate(options, Some(progress))
+}
+
+/// Hibernate the system like hibernate(), but give up with
+/// HibernateError::Cancelled if `cancel` gets set before the snapshot is
+/// taken. Setting `cancel` later has no effect.
+pub fn hibernate_cancellable(options: HibernateOptions, cancel: Arc<AtomicBool>) -> Result<()> {
+    let mut conductor = SuspendConductor::new()?;
+    conductor.set_cancel_token(cancel);
+    conductor.hibernate(options, None)
+}
+
+/// Perform a profiling hibernate run. This snapshots the system and estimates
+/// the compressed image size and write duration, but does not power off. The
+/// write throughput is measured with a scratch file created at
+/// `scratch_path`, which must not exist yet.
+pub fn profile_hibernate(scratch_path: &Path) -> Result<ProfileReport> {
+    let mut conductor = SuspendConductor::new()?;
+    conductor.profile_run(scratch_path)
+}
+
+/// Convert the image info file at `from_path` to the format of
+/// `target_version` and write it to `to_path`, for analyzing images captured
+/// with an older hiberman. Downgrades are refused.
+pub fn migrate_image_info(from_path: &Path, to_path: &Path, target_version: u32) -> Result<()> {
+    image_info::ImageInfo::migrate(from_path, to_path, target_version)
+}
+
+/// Describe the hibernate image in the given hibermeta directory and whether
+/// it would be resumed, from the hibernate cookie on the stateful block
+/// device. Nothing is modified.
+pub fn inspect_image(meta: &Path) -> Result<String> {
+    let cookie = cookie::get_hibernate_cookie::<&Path>(None)?;
+    image_info::summarize_image(meta, &cookie)
 }
 
 /// Prepare the system for resume. This is run very early in boot (from
@@ -72,6 +140,19 @@ pub fn resume(options: ResumeOptions) -> Result<()> {
     conductor.resume(options)
 }
 
+/// Get the reason the last resume attempt failed, None if no failure was
+/// recorded or the record can't be read. The record is on hibermeta and only
+/// visible while it is mounted.
+pub fn last_resume_failure() -> Option<ResumeFailure> {
+    match ResumeFailure::load(Path::new(files::HIBERMETA_DIR)) {
+        Ok(failure) => failure,
+        Err(e) => {
+            warn!("Failed to read the last resume failure: {:?}", e);
+            None
+        }
+    }
+}
+
 /// Tear down the hiberimage DM device. This includes tearing down the
 /// underlying logical volume, as well as the integrity DM devices and
 /// logical volume.
@@ -13,6 +13,8 @@ use std::io::prelude::*;
 use std::io::BufReader;
 use std::mem::MaybeUninit;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::exit;
 use std::process::Command;
@@ -33,13 +35,26 @@ use thiserror::Error as ThisError;
 
 use crate::cookie::set_hibernate_cookie;
 use crate::cookie::HibernateCookieValue;
+use crate::files::DEFAULT_LOW_DISK_THRESHOLD_PERCENT;
 use crate::files::HIBERMETA_DIR;
 use crate::hiberlog::redirect_log;
 use crate::hiberlog::HiberlogOut;
 use crate::metrics::METRICS_LOGGER;
 use crate::mmapbuf::MmapBuffer;
+use crate::trace::TRACER;
 
 const KEYCTL_PATH: &str = "/bin/keyctl";
+const SWAPPINESS_PATH: &str = "/proc/sys/vm/swappiness";
+
+/// Swappiness used while hibernating unless overridden in HibernateOptions.
+/// A high value makes preallocating the memory for the snapshot push as
+/// much as possible to swap.
+pub const DEFAULT_SUSPEND_SWAPPINESS: i32 = 100;
+/// How long to wait for userspace to freeze while hibernating unless
+/// overridden in HibernateOptions.
+pub const DEFAULT_FREEZE_TIMEOUT: Duration = Duration::from_secs(20);
+/// Highest swappiness the kernel accepts.
+const MAX_SWAPPINESS: i32 = 200;
 
 /// Define the hibernate stages.
 pub enum HibernateStage {
@@ -104,21 +119,115 @@ pub enum HibernateError {
     #[error("Merge timeout error")]
     MergeTimeoutError(),
     /// Update engine busy error
-    #[error("Update engine busy")]
-    UpdateEngineBusyError(),
+    #[error("Update engine busy: {0}")]
+    UpdateEngineBusyError(String),
     /// Key retrieve error
     #[error("Unable to retrieve crypto key")]
     KeyRetrievalError(),
     /// Syscall stat error
     #[error("Snapshot stat error: {0}")]
     SnapshotStatDeviceError(nix::Error),
+    /// The kernel command line changed since hibernate.
+    #[error("Kernel command line changed since hibernate")]
+    CmdlineMismatch(),
+    /// The verified boot state changed since hibernate.
+    #[error("Verified boot state changed since hibernate: {0}")]
+    VbootStateMismatch(String),
+    /// The version of an image info file is not supported.
+    #[error("Unsupported image info version: {0}")]
+    ImageInfoVersionError(String),
+    /// The swappiness is outside of the range the kernel accepts.
+    #[error("Swappiness {0} is out of range")]
+    SwappinessOutOfRange(i32),
+    /// Userspace didn't freeze in time.
+    #[error("Userspace did not freeze within {0:?}")]
+    FreezeTimeout(Duration),
+    /// The low disk threshold is not a percentage.
+    #[error("Low disk threshold {0}% is out of range")]
+    LowDiskThresholdOutOfRange(u64),
+    /// A command hiberman runs didn't finish in time.
+    #[error("Command timed out: {0}")]
+    CommandTimeoutError(String),
+    /// Hibernate was cancelled before the snapshot was taken.
+    #[error("Hibernate cancelled {0}")]
+    Cancelled(String),
+    /// The hibernate image is too old to be resumed safely.
+    #[error("Hibernate image is too old: {0}")]
+    ImageTooOld(String),
+    /// The recorded size of the hibernate image doesn't fit this system.
+    #[error("Implausible hibernate image size: {0}")]
+    ImageSizeMismatch(String),
+    /// A path given as a block device isn't one.
+    #[error("Invalid block device: {0}")]
+    InvalidBlockDeviceError(String),
+    /// The operator-supplied image annotation is invalid.
+    #[error("Invalid image annotation: {0}")]
+    InvalidAnnotationError(String),
 }
 
 /// Options taken from the command line affecting hibernate.
-#[derive(Default)]
 pub struct HibernateOptions {
     pub dry_run: bool,
     pub reboot: bool,
+    /// If set, write a Chrome trace of the hibernate phases to this file.
+    pub trace_path: Option<PathBuf>,
+    /// Keep hibernating with metrics disabled if the metrics file can't be
+    /// written, instead of aborting.
+    pub metrics_optional: bool,
+    /// Log the duration of each suspend phase as a separate metric.
+    pub phase_metrics: bool,
+    /// Free-form label stored in the image info to identify the image.
+    pub annotation: Option<String>,
+    /// Command run once after a successful resume, before the hibernate
+    /// cookie is cleared.
+    pub post_resume_hook: Option<PathBuf>,
+    /// Swappiness while hibernating, DEFAULT_SUSPEND_SWAPPINESS if not set.
+    pub swappiness: Option<i32>,
+    /// Free space in the thinpool (in percent) below which the hibernate
+    /// data is deleted after a resume or a failed hibernate.
+    pub low_disk_threshold_percent: u64,
+    /// How long to wait for userspace to freeze before giving up on
+    /// hibernating.
+    pub freeze_timeout: Duration,
+    /// Go through the checks and preparations for hibernate, but stop before
+    /// freezing userspace or taking the snapshot.
+    pub validate_only: bool,
+    /// How long to wait for the update engine to go idle before giving up on
+    /// hibernating. By default hibernate fails right away if it is busy.
+    pub update_engine_wait: Duration,
+    /// How to power down once the image is written, unless rebooting.
+    pub power_down_mode: PowerDownMode,
+}
+
+/// How the system is powered down after the hibernate image was written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PowerDownMode {
+    /// Power off right away with reboot(RB_POWER_OFF).
+    #[default]
+    PowerOff,
+    /// Hand off to the kernel to enter the platform hibernate state (e.g.
+    /// ACPI S4) through the snapshot device.
+    Platform,
+}
+
+impl Default for HibernateOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            reboot: false,
+            trace_path: None,
+            metrics_optional: true,
+            phase_metrics: false,
+            annotation: None,
+            post_resume_hook: None,
+            swappiness: None,
+            low_disk_threshold_percent: DEFAULT_LOW_DISK_THRESHOLD_PERCENT,
+            freeze_timeout: DEFAULT_FREEZE_TIMEOUT,
+            validate_only: false,
+            update_engine_wait: Duration::ZERO,
+            power_down_mode: PowerDownMode::default(),
+        }
+    }
 }
 
 /// Options taken from the command line affecting resume-init.
@@ -131,6 +240,20 @@ pub struct ResumeInitOptions {
 #[derive(Default)]
 pub struct ResumeOptions {
     pub dry_run: bool,
+    /// Load and verify the hibernate image, but discard it instead of
+    /// jumping into it. The hibernated session is lost either way.
+    pub verify_only: bool,
+    /// Resume even if the kernel command line changed since hibernate.
+    pub ignore_cmdline_mismatch: bool,
+    /// Block device to resume from instead of the stateful block device, e.g.
+    /// for recovery tooling. The hibernate cookie is read from it and the
+    /// hibernate volumes are taken from the volume group on its first
+    /// partition.
+    pub block_device: Option<PathBuf>,
+    /// Refuse to resume images that were written longer ago than this.
+    pub max_image_age: Option<Duration>,
+    /// Hint the kernel to read the hibernate image ahead before loading it.
+    pub prewarm_image: bool,
 }
 
 /// Options taken from the command line affecting abort-resume.
@@ -227,12 +350,29 @@ fn get_available_swap_mb() -> Result<u32> {
         .context("Failed to find available swap information")
 }
 
+/// How much memory preallocation freed up for the hibernate snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PreallocatedMemory {
+    /// Bytes of memory needed for the snapshot, which were allocated.
+    pub target: u64,
+    /// Bytes of memory free after releasing the allocation again.
+    pub free: u64,
+}
+
+impl PreallocatedMemory {
+    /// Returns false if less memory than needed for the snapshot is free,
+    /// i.e. hibernate proceeds under memory pressure.
+    pub fn is_complete(&self) -> bool {
+        self.free >= self.target
+    }
+}
+
 // Preallocate memory that will be needed for the hibernate snapshot.
 // Currently the kernel is not always able to reclaim memory effectively
 // when allocating the memory needed for the hibernate snapshot. By
 // preallocating this memory, we force memory to be swapped into zram and
 // ensure that we have the free memory needed for the snapshot.
-pub fn prealloc_mem() -> Result<()> {
+pub fn prealloc_mem() -> Result<PreallocatedMemory> {
     let available_mb = get_available_memory_mb();
     let available_swap = get_available_swap_mb()?;
     let total_avail = available_mb + available_swap;
@@ -317,7 +457,28 @@ pub fn prealloc_mem() -> Result<()> {
         "System has {} MB of free memory, {} MB of free swap after freeing giant allocation",
         available_mb_final, available_swap_final
     );
-    Ok(())
+
+    let preallocated = PreallocatedMemory {
+        target: hiber_size as u64,
+        free: available_mb_final as u64 * 1024 * 1024,
+    };
+    info!(
+        "Preallocated {} MB for hibernate, {} MB free afterwards",
+        hiber_mb, available_mb_final
+    );
+    {
+        let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
+
+        metrics_logger.log_metric(
+            "Platform.Hibernate.MemoryAvailableAfterPrealloc",
+            available_mb_final as isize,
+            0,
+            32768,
+            50,
+        );
+    }
+
+    Ok(preallocated)
 }
 
 /// Look through /proc/mounts to find the block device supporting the
@@ -345,15 +506,19 @@ pub fn get_device_mounted_at_dir(mount_path: &str) -> Result<String> {
 
 /// Return the path to partition one (stateful) on the root block device.
 pub fn stateful_block_partition_one() -> Result<String> {
-    let rootdev = path_to_stateful_block()?;
-    let last = rootdev.chars().last();
+    Ok(block_partition_one(&path_to_stateful_block()?))
+}
+
+/// Return the path to partition one on the given block device.
+pub fn block_partition_one(blockdev: &str) -> String {
+    let last = blockdev.chars().last();
     if let Some(last) = last {
         if last.is_numeric() {
-            return Ok(format!("{}p1", rootdev));
+            return format!("{}p1", blockdev);
         }
     }
 
-    Ok(format!("{}1", rootdev))
+    format!("{}1", blockdev)
 }
 
 /// Determine the path to the block device containing the stateful partition.
@@ -364,6 +529,31 @@ pub fn path_to_stateful_block() -> Result<String> {
     Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
+/// Fail with HibernateError::InvalidBlockDeviceError if `path` doesn't exist
+/// or isn't a block device.
+pub fn check_block_device(path: &Path) -> Result<()> {
+    let file_type = match fs::metadata(path) {
+        Ok(metadata) => metadata.file_type(),
+        Err(e) => {
+            return Err(HibernateError::InvalidBlockDeviceError(format!(
+                "{}: {}",
+                path.display(),
+                e
+            ))
+            .into())
+        }
+    };
+    if !file_type.is_block_device() {
+        return Err(HibernateError::InvalidBlockDeviceError(format!(
+            "{} is not a block device",
+            path.display()
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
 /// Determines if the stateful-rw snapshot is active, indicating a resume boot.
 pub fn is_snapshot_active() -> bool {
     fs::metadata("/dev/mapper/stateful-rw").is_ok()
@@ -411,6 +601,7 @@ pub fn log_duration(action: &str, duration: Duration) {
         duration.as_secs(),
         duration.subsec_millis()
     );
+    TRACER.lock().unwrap().record_phase(action, duration);
 }
 
 /// Log a duration with an I/O rate at level info in the form:
@@ -425,6 +616,58 @@ pub fn log_io_duration(action: &str, io_bytes: u64, duration: Duration) {
         io_bytes,
         rate
     );
+    TRACER.lock().unwrap().record_phase(action, duration);
+}
+
+/// Fail with HibernateError::SwappinessOutOfRange if the kernel doesn't
+/// accept the swappiness.
+pub fn check_swappiness(value: i32) -> Result<()> {
+    if !(0..=MAX_SWAPPINESS).contains(&value) {
+        return Err(HibernateError::SwappinessOutOfRange(value).into());
+    }
+
+    Ok(())
+}
+
+/// Changes the swappiness of the system, and restores the previous value when
+/// dropped.
+pub struct Swappiness {
+    path: PathBuf,
+    previous: i32,
+}
+
+impl Swappiness {
+    /// Set the swappiness of the system to `value`.
+    pub fn set_swappiness(value: i32) -> Result<Self> {
+        Self::set_swappiness_at(Path::new(SWAPPINESS_PATH), value)
+    }
+
+    fn set_swappiness_at(path: &Path, value: i32) -> Result<Self> {
+        check_swappiness(value)?;
+
+        let previous = fs::read_to_string(path)
+            .context(format!("Failed to read {}", path.display()))?
+            .trim()
+            .parse()
+            .context(format!("Failed to parse {}", path.display()))?;
+        fs::write(path, value.to_string())
+            .context(format!("Failed to write {}", path.display()))?;
+        debug!("Set swappiness to {}, was {}", value, previous);
+
+        Ok(Swappiness {
+            path: path.to_path_buf(),
+            previous,
+        })
+    }
+}
+
+impl Drop for Swappiness {
+    fn drop(&mut self) {
+        debug!("Restoring swappiness to {}", self.previous);
+        if let Err(e) = fs::write(&self.path, self.previous.to_string()) {
+            warn!("Failed to restore swappiness to {}: {}", self.previous, e);
+        }
+    }
 }
 
 /// Wait for a std::process::Command, and convert the exit status into a Result
@@ -672,7 +915,62 @@ impl TimestampFile {
         Ok(Duration::from_millis(millis))
     }
 
-    fn full_path(name: &str) -> PathBuf {
+    /// Get the path of the timestamp file with the given name.
+    pub fn full_path(name: &str) -> PathBuf {
         PathBuf::from(format!("/{HIBERMETA_DIR}/{name}"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_swappiness() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let path = dir.join("swappiness");
+        fs::write(&path, "60\n").unwrap();
+
+        for value in [-1, 201] {
+            let err = Swappiness::set_swappiness_at(&path, value).err().unwrap();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(HibernateError::SwappinessOutOfRange(_))
+            ));
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "60\n");
+
+        {
+            let _swappiness = Swappiness::set_swappiness_at(&path, 200).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), "200");
+        }
+        // The previous value is restored when the swappiness is dropped.
+        assert_eq!(fs::read_to_string(&path).unwrap(), "60");
+    }
+
+    #[test]
+    fn test_block_partition_one() {
+        assert_eq!(block_partition_one("/dev/sda"), "/dev/sda1");
+        assert_eq!(block_partition_one("/dev/nvme0n1"), "/dev/nvme0n1p1");
+        assert_eq!(block_partition_one("/dev/mmcblk0"), "/dev/mmcblk0p1");
+    }
+
+    #[test]
+    fn test_check_block_device() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let file = dir.join("not_a_device");
+        fs::write(&file, "").unwrap();
+
+        for path in [file, dir.join("missing")] {
+            let err = check_block_device(&path).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(HibernateError::InvalidBlockDeviceError(_))
+            ));
+        }
+    }
+}
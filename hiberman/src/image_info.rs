@@ -0,0 +1,619 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Records information about the last hibernate image and estimates how long
+//! it takes to resume from it.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::info;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::cmdline::CMDLINE_HASH_FILE_NAME;
+use crate::cookie::cookie_description;
+use crate::cookie::HibernateCookieValue;
+use crate::hiberutil::get_page_size;
+use crate::hiberutil::HibernateError;
+use crate::vboot::VBOOT_STATE_FILE_NAME;
+
+/// Name of the image info file on hibermeta.
+pub const IMAGE_INFO_FILE_NAME: &str = "image_info";
+
+/// Version of the image info format written by this hiberman.
+///
+/// Version 1 files have no version field. Version 2 added `version` and
+/// `write_duration_ms`. Version 3 added `annotation`. Version 4 added
+/// `suspend_timestamp`.
+pub const IMAGE_INFO_VERSION: u32 = 4;
+
+/// Maximum length (in bytes) of an image annotation.
+pub const MAX_ANNOTATION_LEN: usize = 256;
+
+const MB: u64 = 1024 * 1024;
+
+/// The kind of storage the hibernate image is written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageKind {
+    Emmc,
+    Nvme,
+    Other,
+}
+
+impl StorageKind {
+    /// Determine the kind of storage from the path of its block device.
+    pub fn from_block_device(path: &str) -> Self {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if name.starts_with("nvme") {
+            StorageKind::Nvme
+        } else if name.starts_with("mmcblk") {
+            StorageKind::Emmc
+        } else {
+            StorageKind::Other
+        }
+    }
+
+    /// Conservative throughput estimate (in bytes per second) for the kind
+    /// of storage, used when no throughput was measured.
+    fn default_throughput(&self) -> u64 {
+        match self {
+            StorageKind::Emmc => 150 * MB,
+            StorageKind::Nvme => 1000 * MB,
+            StorageKind::Other => 300 * MB,
+        }
+    }
+}
+
+/// Information about the hibernate image, recorded by the suspend path.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageInfo {
+    /// Version of the format, 1 for files that predate versioning.
+    #[serde(default = "image_info_version_1")]
+    pub version: u32,
+    /// Size of the hibernate image in bytes.
+    pub image_size: u64,
+    /// Throughput (in bytes per second) measured while writing the image, 0
+    /// if unknown.
+    pub write_throughput: u64,
+    pub storage_kind: StorageKind,
+    /// Time (in milliseconds) it took to write the image, 0 if unknown.
+    /// Added in version 2.
+    #[serde(default)]
+    pub write_duration_ms: u64,
+    /// Free-form label supplied by the operator to identify the image, e.g.
+    /// the name of the test that captured it. Added in version 3.
+    #[serde(default)]
+    pub annotation: Option<String>,
+    /// When the image was written, in seconds since the Unix epoch. Added in
+    /// version 4.
+    #[serde(default)]
+    pub suspend_timestamp: Option<u64>,
+}
+
+impl fmt::Display for ImageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "image_size: {}", self.image_size)?;
+        writeln!(f, "write_throughput: {}", self.write_throughput)?;
+        writeln!(f, "storage_kind: {:?}", self.storage_kind)?;
+        writeln!(f, "write_duration_ms: {}", self.write_duration_ms)?;
+        writeln!(
+            f,
+            "annotation: {}",
+            self.annotation.as_deref().unwrap_or("<none>")
+        )?;
+        match self.suspend_timestamp {
+            Some(timestamp) => write!(f, "suspend_timestamp: {}", timestamp),
+            None => write!(f, "suspend_timestamp: <none>"),
+        }
+    }
+}
+
+/// Fail with HibernateError::InvalidAnnotationError if the annotation can't
+/// be stored in the image info.
+pub fn check_annotation(annotation: &str) -> Result<()> {
+    if annotation.len() > MAX_ANNOTATION_LEN {
+        return Err(HibernateError::InvalidAnnotationError(format!(
+            "{} bytes long, the maximum is {}",
+            annotation.len(),
+            MAX_ANNOTATION_LEN
+        )))
+        .context("Invalid annotation");
+    }
+
+    Ok(())
+}
+
+fn image_info_version_1() -> u32 {
+    1
+}
+
+impl ImageInfo {
+    /// Write the image info to the given hibermeta directory.
+    pub fn save(&self, meta: &Path) -> Result<()> {
+        if let Some(annotation) = &self.annotation {
+            check_annotation(annotation)?;
+        }
+
+        self.write_to(&meta.join(IMAGE_INFO_FILE_NAME))
+    }
+
+    /// Read the image info from the given hibermeta directory.
+    pub fn load(meta: &Path) -> Result<Self> {
+        let path = meta.join(IMAGE_INFO_FILE_NAME);
+        let image_info = Self::read_from(&path)?;
+        if image_info.version > IMAGE_INFO_VERSION {
+            return Err(HibernateError::ImageInfoVersionError(format!(
+                "{} has version {}, newest supported is {}",
+                path.display(),
+                image_info.version,
+                IMAGE_INFO_VERSION
+            )))
+            .context("Failed to load image info");
+        }
+
+        Ok(image_info)
+    }
+
+    /// Convert the image info file at `from_path` to the format of
+    /// `target_version` and write it to `to_path`. Fields that didn't exist
+    /// in the original version get their defaults. Downgrades are refused.
+    pub fn migrate(from_path: &Path, to_path: &Path, target_version: u32) -> Result<()> {
+        if target_version == 0 || target_version > IMAGE_INFO_VERSION {
+            return Err(HibernateError::ImageInfoVersionError(format!(
+                "target version {} is not supported",
+                target_version
+            )))
+            .context("Failed to migrate image info");
+        }
+
+        let mut image_info = Self::read_from(from_path)?;
+        if image_info.version > target_version {
+            return Err(HibernateError::ImageInfoVersionError(format!(
+                "cannot downgrade {} from version {} to {}",
+                from_path.display(),
+                image_info.version,
+                target_version
+            )))
+            .context("Failed to migrate image info");
+        }
+
+        if image_info.version == target_version {
+            fs::copy(from_path, to_path).context(format!(
+                "Failed to copy {} to {}",
+                from_path.display(),
+                to_path.display()
+            ))?;
+            return Ok(());
+        }
+
+        image_info.version = target_version;
+        image_info.write_to(to_path)
+    }
+
+    fn read_from(path: &Path) -> Result<Self> {
+        let json =
+            fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&json).context(format!("Failed to parse {}", path.display()))
+    }
+
+    fn write_to(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(self).context("Failed to serialize image info")?;
+        fs::write(path, json).context(format!("Failed to write {}", path.display()))
+    }
+
+    /// How long the image has been on disk at `now` (since the Unix epoch),
+    /// None if the time it was written is unknown.
+    pub fn age(&self, now: Duration) -> Option<Duration> {
+        self.suspend_timestamp
+            .map(|timestamp| now.saturating_sub(Duration::from_secs(timestamp)))
+    }
+
+    /// Estimate how long it takes to read the image back at resume time.
+    /// The throughput measured when writing the image is used as the read
+    /// throughput, reads are typically at least as fast as writes.
+    pub fn resume_duration(&self) -> Duration {
+        let throughput = if self.write_throughput == 0 {
+            self.storage_kind.default_throughput()
+        } else {
+            self.write_throughput
+        };
+
+        Duration::from_secs_f64(self.image_size as f64 / throughput as f64)
+    }
+}
+
+/// Describe the image info file at the given path in human readable form.
+pub fn dump_image_info(path: &Path) -> Result<String> {
+    Ok(ImageInfo::read_from(path)?.to_string())
+}
+
+/// Describe the hibernate image in the hibermeta directory `meta` in human
+/// readable form, given the current hibernate `cookie`. Answers whether there
+/// is an image that would be resumed, without modifying anything. A garbage
+/// image info file is reported as an error.
+pub fn summarize_image(meta: &Path, cookie: &HibernateCookieValue) -> Result<String> {
+    let image_info_path = meta.join(IMAGE_INFO_FILE_NAME);
+    let image_info = if image_info_path.exists() {
+        Some(ImageInfo::load(meta).context("Failed to inspect the hibernate image")?)
+    } else {
+        None
+    };
+    let resumable = *cookie == HibernateCookieValue::ResumeReady && image_info.is_some();
+
+    let mut summary = format!(
+        "cookie: {}\nresumable: {}\n",
+        cookie_description(cookie),
+        if resumable { "yes" } else { "no" }
+    );
+    match &image_info {
+        Some(image_info) => {
+            summary += &format!(
+                "image_info_version: {}\nimage_size: {}\n",
+                image_info.version, image_info.image_size
+            );
+        }
+        None => summary += "image_info: <none>\n",
+    }
+    for name in [CMDLINE_HASH_FILE_NAME, VBOOT_STATE_FILE_NAME] {
+        let state = if meta.join(name).exists() {
+            "present"
+        } else {
+            "missing"
+        };
+        summary += &format!("{}: {}\n", name, state);
+    }
+
+    Ok(summary.trim_end().to_string())
+}
+
+/// Fail with HibernateError::ImageTooOld if the image described in the
+/// hibermeta directory `meta` was written more than `max_age` before `now`
+/// (since the Unix epoch). Images of unknown age are accepted, they predate
+/// recording the timestamp.
+pub fn check_image_age(meta: &Path, max_age: Option<Duration>, now: Duration) -> Result<()> {
+    let age = match ImageInfo::load(meta) {
+        Ok(image_info) => image_info.age(now),
+        Err(e) => {
+            warn!("Failed to get the age of the hibernate image: {:?}", e);
+            None
+        }
+    };
+    let age = match age {
+        Some(age) => age,
+        None => return Ok(()),
+    };
+
+    info!("Hibernate image was written {}s ago", age.as_secs());
+    match max_age {
+        Some(max_age) if age > max_age => Err(HibernateError::ImageTooOld(format!(
+            "written {}s ago, the maximum is {}s",
+            age.as_secs(),
+            max_age.as_secs()
+        )))
+        .context("Refusing to resume"),
+        _ => Ok(()),
+    }
+}
+
+/// Fail with HibernateError::ImageSizeMismatch if the size recorded for the
+/// image in the hibermeta directory `meta` can't belong to an image of this
+/// system: an empty or unaligned image, or one larger than the RAM or the
+/// hiberimage volume. Feeding such an image to the kernel may hang the
+/// machine. Images without image info are accepted, like check_image_age()
+/// does.
+pub fn check_image_size(meta: &Path, ram_size: u64, hiberimage_size: u64) -> Result<()> {
+    let image_size = match ImageInfo::load(meta) {
+        Ok(image_info) => image_info.image_size,
+        Err(e) => {
+            warn!("Failed to get the size of the hibernate image: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    let page_size = get_page_size() as u64;
+    let problem = if image_size == 0 {
+        Some("the image is empty".to_string())
+    } else if image_size % page_size != 0 {
+        Some(format!("not a multiple of the page size {}", page_size))
+    } else if image_size > ram_size {
+        Some(format!("larger than the RAM of {} bytes", ram_size))
+    } else if image_size > hiberimage_size {
+        Some(format!(
+            "larger than the hiberimage volume of {} bytes",
+            hiberimage_size
+        ))
+    } else {
+        None
+    };
+
+    match problem {
+        Some(problem) => Err(HibernateError::ImageSizeMismatch(format!(
+            "{} bytes, {}",
+            image_size, problem
+        )))
+        .context("Refusing to resume"),
+        None => Ok(()),
+    }
+}
+
+/// Estimate how long it takes to resume from the image described in the
+/// given hibermeta directory.
+pub fn estimate_resume_duration(meta: &Path) -> Result<Duration> {
+    Ok(ImageInfo::load(meta)?.resume_duration())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_estimate_resume_duration() {
+        let temp_dir = tempdir().unwrap();
+        let meta = temp_dir.path();
+
+        assert!(estimate_resume_duration(meta).is_err());
+
+        let measured = ImageInfo {
+            version: IMAGE_INFO_VERSION,
+            image_size: 2048 * MB,
+            write_throughput: 512 * MB,
+            storage_kind: StorageKind::Nvme,
+            write_duration_ms: 4000,
+            annotation: None,
+            suspend_timestamp: None,
+        };
+        measured.save(meta).unwrap();
+        assert_eq!(ImageInfo::load(meta).unwrap(), measured);
+        assert_eq!(
+            estimate_resume_duration(meta).unwrap(),
+            Duration::from_secs(4)
+        );
+
+        // Fall back to the default of the storage kind without a measurement.
+        let unmeasured = ImageInfo {
+            version: IMAGE_INFO_VERSION,
+            image_size: 300 * MB,
+            write_throughput: 0,
+            storage_kind: StorageKind::Emmc,
+            write_duration_ms: 0,
+            annotation: None,
+            suspend_timestamp: None,
+        };
+        unmeasured.save(meta).unwrap();
+        assert_eq!(
+            estimate_resume_duration(meta).unwrap(),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn test_migrate_image_info() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let v1_path = dir.join("image_info.v1");
+        let v2_path = dir.join("image_info.v2");
+
+        fs::write(
+            &v1_path,
+            r#"{"image_size":1048576,"write_throughput":524288,"storage_kind":"Emmc"}"#,
+        )
+        .unwrap();
+
+        ImageInfo::migrate(&v1_path, &v2_path, 2).unwrap();
+        assert_eq!(
+            ImageInfo::read_from(&v2_path).unwrap(),
+            ImageInfo {
+                version: 2,
+                image_size: MB,
+                write_throughput: MB / 2,
+                storage_kind: StorageKind::Emmc,
+                write_duration_ms: 0,
+                annotation: None,
+                suspend_timestamp: None,
+            }
+        );
+
+        // Downgrades and unknown versions are refused.
+        for (from, target) in [(&v2_path, 1), (&v1_path, IMAGE_INFO_VERSION + 1)] {
+            let err = ImageInfo::migrate(from, &dir.join("out"), target).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(HibernateError::ImageInfoVersionError(_))
+            ));
+        }
+        assert!(!dir.join("out").exists());
+    }
+
+    #[test]
+    fn test_image_info_annotation() {
+        let temp_dir = tempdir().unwrap();
+        let meta = temp_dir.path();
+
+        let mut image_info = ImageInfo {
+            version: IMAGE_INFO_VERSION,
+            image_size: MB,
+            write_throughput: MB,
+            storage_kind: StorageKind::Nvme,
+            write_duration_ms: 1000,
+            annotation: Some("suspend_stress_test R120-15662.0.0".to_string()),
+            suspend_timestamp: None,
+        };
+        image_info.save(meta).unwrap();
+
+        let dump = dump_image_info(&meta.join(IMAGE_INFO_FILE_NAME)).unwrap();
+        assert!(dump
+            .lines()
+            .any(|line| line == "annotation: suspend_stress_test R120-15662.0.0"));
+
+        // Too long annotations are rejected and nothing is written.
+        image_info.annotation = Some("x".repeat(MAX_ANNOTATION_LEN + 1));
+        let err = image_info.save(meta).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::InvalidAnnotationError(_))
+        ));
+        assert_eq!(
+            ImageInfo::load(meta).unwrap().annotation.as_deref(),
+            Some("suspend_stress_test R120-15662.0.0")
+        );
+    }
+
+    #[test]
+    fn test_summarize_image() {
+        let temp_dir = tempdir().unwrap();
+        let meta = temp_dir.path();
+
+        let summary = summarize_image(meta, &HibernateCookieValue::ResumeReady).unwrap();
+        assert!(summary.contains("resumable: no"));
+        assert!(summary.contains("image_info: <none>"));
+
+        let image_info = ImageInfo {
+            version: IMAGE_INFO_VERSION,
+            image_size: 512 * MB,
+            write_throughput: MB,
+            storage_kind: StorageKind::Nvme,
+            write_duration_ms: 512000,
+            annotation: None,
+            suspend_timestamp: None,
+        };
+        image_info.save(meta).unwrap();
+        fs::write(meta.join(CMDLINE_HASH_FILE_NAME), "hash").unwrap();
+
+        let summary = summarize_image(meta, &HibernateCookieValue::ResumeReady).unwrap();
+        assert_eq!(
+            summary,
+            format!(
+                "cookie: Resume Ready\nresumable: yes\nimage_info_version: {}\n\
+                 image_size: {}\n{}: present\n{}: missing",
+                IMAGE_INFO_VERSION,
+                512 * MB,
+                CMDLINE_HASH_FILE_NAME,
+                VBOOT_STATE_FILE_NAME
+            )
+        );
+        let summary = summarize_image(meta, &HibernateCookieValue::NoResume).unwrap();
+        assert!(summary.contains("resumable: no"));
+
+        // A truncated file is an error, and is left alone.
+        let path = meta.join(IMAGE_INFO_FILE_NAME);
+        let json = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &json[..json.len() / 2]).unwrap();
+        assert!(summarize_image(meta, &HibernateCookieValue::ResumeReady).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), json[..json.len() / 2]);
+    }
+
+    #[test]
+    fn test_check_image_age() {
+        let temp_dir = tempdir().unwrap();
+        let meta = temp_dir.path();
+        let now = Duration::from_secs(1_700_000_000);
+        let max_age = Some(Duration::from_secs(7 * 24 * 3600));
+
+        // No image info at all.
+        check_image_age(meta, max_age, now).unwrap();
+
+        let mut image_info = ImageInfo {
+            version: IMAGE_INFO_VERSION,
+            image_size: MB,
+            write_throughput: MB,
+            storage_kind: StorageKind::Nvme,
+            write_duration_ms: 1000,
+            annotation: None,
+            suspend_timestamp: None,
+        };
+        // Images that predate the timestamp are accepted.
+        image_info.save(meta).unwrap();
+        check_image_age(meta, max_age, now).unwrap();
+
+        image_info.suspend_timestamp = Some(now.as_secs() - 3600);
+        image_info.save(meta).unwrap();
+        assert_eq!(image_info.age(now), Some(Duration::from_secs(3600)));
+        check_image_age(meta, max_age, now).unwrap();
+
+        image_info.suspend_timestamp = Some(now.as_secs() - 8 * 24 * 3600);
+        image_info.save(meta).unwrap();
+        let err = check_image_age(meta, max_age, now).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::ImageTooOld(_))
+        ));
+        // Without a maximum age any image is accepted.
+        check_image_age(meta, None, now).unwrap();
+    }
+
+    #[test]
+    fn test_check_image_size() {
+        let temp_dir = tempdir().unwrap();
+        let meta = temp_dir.path();
+        let page_size = get_page_size() as u64;
+        let ram_size = 4096 * MB;
+        let hiberimage_size = 2048 * MB;
+
+        // No image info at all.
+        check_image_size(meta, ram_size, hiberimage_size).unwrap();
+
+        let mut image_info = ImageInfo {
+            version: IMAGE_INFO_VERSION,
+            image_size: 1024 * MB,
+            write_throughput: MB,
+            storage_kind: StorageKind::Nvme,
+            write_duration_ms: 1000,
+            annotation: None,
+            suspend_timestamp: None,
+        };
+        image_info.save(meta).unwrap();
+        check_image_size(meta, ram_size, hiberimage_size).unwrap();
+
+        // Undersized and oversized images are refused.
+        for image_size in [
+            0,
+            page_size - 1,
+            MB + 1,
+            ram_size + page_size,
+            hiberimage_size + page_size,
+        ] {
+            image_info.image_size = image_size;
+            image_info.save(meta).unwrap();
+            let err = check_image_size(meta, ram_size, hiberimage_size).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref(),
+                    Some(HibernateError::ImageSizeMismatch(_))
+                ),
+                "image size {} was accepted",
+                image_size
+            );
+        }
+
+        // An image filling the whole volume is fine.
+        image_info.image_size = hiberimage_size;
+        image_info.save(meta).unwrap();
+        check_image_size(meta, ram_size, hiberimage_size).unwrap();
+    }
+
+    #[test]
+    fn test_storage_kind_from_block_device() {
+        assert_eq!(
+            StorageKind::from_block_device("/dev/nvme0n1"),
+            StorageKind::Nvme
+        );
+        assert_eq!(
+            StorageKind::from_block_device("/dev/mmcblk0"),
+            StorageKind::Emmc
+        );
+        assert_eq!(
+            StorageKind::from_block_device("/dev/sda"),
+            StorageKind::Other
+        );
+    }
+}
@@ -138,6 +138,21 @@ pub fn lv_remove(volume_group: &str, name: &str) -> Result<()> {
 
 /// Returns the free space in the thinpool.
 pub fn get_free_thinpool_space(volume_group: &str) -> Result<u64> {
+    let (size, percent) = get_thinpool_size_and_usage(volume_group)?;
+    let free_space = (size as f64 * 0.01 * (100.0 - percent)) as u64;
+
+    Ok(free_space)
+}
+
+/// Returns the free space in the thinpool in percent.
+pub fn get_free_thinpool_percent(volume_group: &str) -> Result<f64> {
+    let (_, percent) = get_thinpool_size_and_usage(volume_group)?;
+
+    Ok(100.0 - percent)
+}
+
+/// Returns the size of the thinpool in bytes and its data usage in percent.
+fn get_thinpool_size_and_usage(volume_group: &str) -> Result<(u64, f64)> {
     let volume = full_lv_name(volume_group, "thinpool");
 
     let out = checked_command_output(Command::new("/sbin/lvs").args([
@@ -156,9 +171,8 @@ pub fn get_free_thinpool_space(volume_group: &str) -> Result<u64> {
     let percent_s = split.next().unwrap();
     let size = size_s.parse::<u64>().unwrap();
     let percent = percent_s.parse::<f64>().unwrap();
-    let free_space = (size as f64 * 0.01 * (100.0 - percent)) as u64;
 
-    Ok(free_space)
+    Ok((size, percent))
 }
 
 /// Get the data usage of a thin volume in percent.
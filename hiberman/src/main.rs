@@ -4,11 +4,16 @@
 
 //! Coordinates suspend-to-disk activities.
 
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use getopts::Options;
 use getopts::{self};
 use hiberman::cookie::HibernateCookieValue;
 use hiberman::AbortResumeOptions;
 use hiberman::HibernateOptions;
+use hiberman::PowerDownMode;
 use hiberman::ResumeInitOptions;
 use hiberman::ResumeOptions;
 use hiberman::{self};
@@ -163,6 +168,80 @@ fn hiberman_hibernate(args: &mut std::env::Args) -> std::result::Result<(), ()>
         "reboot",
         "Reboot after creating the snapshot image instead of shutting down",
     );
+    opts.optopt(
+        "t",
+        "trace",
+        "Write a Chrome trace (JSON) of the hibernate phases to the given file",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "metrics-required",
+        "Abort hibernate if the metrics file can't be written, instead of continuing with metrics disabled",
+    );
+    opts.optflag(
+        "",
+        "phase-metrics",
+        "Send the duration of each suspend phase as a separate metric",
+    );
+    opts.optopt(
+        "",
+        "annotation",
+        "Store a label in the image info to identify the image later, e.g. the name of the test",
+        "LABEL",
+    );
+    opts.optopt(
+        "",
+        "post-resume-hook",
+        "Run the given command once after a successful resume. It is killed if it doesn't finish within 10 seconds",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "swappiness",
+        "Swappiness (0-200) while preallocating the memory for the snapshot, defaults to 100",
+        "VALUE",
+    );
+    opts.optopt(
+        "",
+        "low-disk-threshold",
+        "Delete the hibernate data after resuming if less than this percentage (0-100) of the thinpool is free, defaults to 10",
+        "PERCENT",
+    );
+    opts.optopt(
+        "",
+        "freeze-timeout",
+        "Give up on hibernating if userspace doesn't freeze within this many seconds, defaults to 20",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "update-engine-wait",
+        "Wait up to this many seconds for the update engine to go idle instead of failing right away",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "power-down-mode",
+        "How to power down once the image is written: 'poweroff' (default) powers off right away, 'platform' enters the platform hibernate state (e.g. ACPI S4)",
+        "MODE",
+    );
+    opts.optflag(
+        "",
+        "validate-only",
+        "Check that the system could hibernate and prepare for it, but exit before freezing userspace or taking a snapshot",
+    );
+    opts.optflag(
+        "p",
+        "profile",
+        "Create the snapshot image, report its estimated compressed size and write time, then discard it and exit",
+    );
+    opts.optopt(
+        "",
+        "profile-file",
+        "With --profile, measure the write throughput with this scratch file, which must not exist yet and is removed afterwards",
+        "PATH",
+    );
     let args: Vec<String> = args.collect();
     let matches = match opts.parse(args) {
         Ok(m) => m,
@@ -178,9 +257,113 @@ fn hiberman_hibernate(args: &mut std::env::Args) -> std::result::Result<(), ()>
         return Ok(());
     }
 
+    if matches.opt_present("p") {
+        let scratch_path = match matches.opt_str("profile-file") {
+            Some(path) => PathBuf::from(path),
+            None => {
+                error!("--profile requires --profile-file");
+                hibernate_usage(true, &opts);
+                return Err(());
+            }
+        };
+
+        match hiberman::profile_hibernate(&scratch_path) {
+            Ok(report) => println!("{}", report),
+            Err(e) => {
+                error!("Failed to profile hibernate: {:?}", e);
+                return Err(());
+            }
+        }
+
+        return Ok(());
+    }
+
+    let swappiness = match matches.opt_str("swappiness") {
+        Some(value) => match value.parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                error!("Invalid swappiness {}: {}", value, e);
+                hibernate_usage(true, &opts);
+                return Err(());
+            }
+        },
+        None => None,
+    };
+
+    let low_disk_threshold_percent = match matches.opt_str("low-disk-threshold") {
+        Some(value) => match value.parse() {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Invalid low disk threshold {}: {}", value, e);
+                hibernate_usage(true, &opts);
+                return Err(());
+            }
+        },
+        None => HibernateOptions::default().low_disk_threshold_percent,
+    };
+
+    let freeze_timeout = match matches.opt_str("freeze-timeout") {
+        Some(value) => match value.parse() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            Ok(_) => {
+                error!("Invalid freeze timeout {}: must be positive", value);
+                hibernate_usage(true, &opts);
+                return Err(());
+            }
+            Err(e) => {
+                error!("Invalid freeze timeout {}: {}", value, e);
+                hibernate_usage(true, &opts);
+                return Err(());
+            }
+        },
+        None => HibernateOptions::default().freeze_timeout,
+    };
+
+    let update_engine_wait = match matches.opt_str("update-engine-wait") {
+        Some(value) => match value.parse() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(e) => {
+                error!("Invalid update engine wait {}: {}", value, e);
+                hibernate_usage(true, &opts);
+                return Err(());
+            }
+        },
+        None => HibernateOptions::default().update_engine_wait,
+    };
+
+    let power_down_mode = match matches.opt_str("power-down-mode") {
+        Some(value) => match value.as_str() {
+            "poweroff" => PowerDownMode::PowerOff,
+            "platform" => PowerDownMode::Platform,
+            _ => {
+                error!("Invalid power down mode: {}", value);
+                hibernate_usage(true, &opts);
+                return Err(());
+            }
+        },
+        None => HibernateOptions::default().power_down_mode,
+    };
+
+    if matches.opt_present("r") && power_down_mode != PowerDownMode::PowerOff {
+        error!("Cannot use --power-down-mode with --reboot");
+        hibernate_usage(true, &opts);
+        return Err(());
+    }
+
     let options = HibernateOptions {
         dry_run: matches.opt_present("n"),
         reboot: matches.opt_present("r"),
+        trace_path: matches.opt_str("t").map(PathBuf::from),
+        metrics_optional: !matches.opt_present("metrics-required"),
+        phase_metrics: matches.opt_present("phase-metrics"),
+        annotation: matches.opt_str("annotation"),
+        post_resume_hook: matches.opt_str("post-resume-hook").map(PathBuf::from),
+        swappiness,
+        low_disk_threshold_percent,
+        freeze_timeout,
+        validate_only: matches.opt_present("validate-only"),
+        update_engine_wait,
+        power_down_mode,
     };
 
     if let Err(e) = hiberman::hibernate(options) {
@@ -307,6 +490,29 @@ fn hiberman_resume(args: &mut std::env::Args) -> std::result::Result<(), ()> {
     let mut opts = Options::new();
     opts.optflag("h", "help", "Print this help text");
     opts.optflag("n", "dry-run", "Create the hibernate image, but then exit rather than shutting down. This image should only be restored with --dry-run");
+    opts.optflag("", "verify-only", "Load and verify the pending hibernate image, then discard it and continue booting normally. The hibernated session is lost");
+    opts.optflag(
+        "",
+        "ignore-cmdline-mismatch",
+        "Resume even if the kernel command line changed since hibernate",
+    );
+    opts.optopt(
+        "",
+        "block-device",
+        "Resume from the given block device instead of the stateful block device",
+        "DEVICE",
+    );
+    opts.optopt(
+        "",
+        "max-image-age",
+        "Refuse to resume a hibernate image written more than this many seconds ago",
+        "SECONDS",
+    );
+    opts.optflag(
+        "",
+        "prewarm-image",
+        "Hint the kernel to read the hibernate image ahead before loading it",
+    );
     let args: Vec<String> = args.collect();
     let matches = match opts.parse(args) {
         Ok(m) => m,
@@ -322,10 +528,33 @@ fn hiberman_resume(args: &mut std::env::Args) -> std::result::Result<(), ()> {
         return Ok(());
     }
 
+    let max_image_age = match matches.opt_str("max-image-age") {
+        Some(value) => match value.parse() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(e) => {
+                error!("Invalid maximum image age {}: {}", value, e);
+                resume_usage(true, &opts);
+                return Err(());
+            }
+        },
+        None => None,
+    };
+
     let options = ResumeOptions {
         dry_run: matches.opt_present("n"),
+        verify_only: matches.opt_present("verify-only"),
+        ignore_cmdline_mismatch: matches.opt_present("ignore-cmdline-mismatch"),
+        block_device: matches.opt_str("block-device").map(PathBuf::from),
+        max_image_age,
+        prewarm_image: matches.opt_present("prewarm-image"),
     };
 
+    if options.dry_run && options.verify_only {
+        error!("--dry-run and --verify-only are mutually exclusive");
+        resume_usage(true, &opts);
+        return Err(());
+    }
+
     if let Err(e) = hiberman::resume(options) {
         error!("Failed to resume: {:#?}", e);
         return Err(());
@@ -369,6 +598,161 @@ fn hiberman_teardown_hiberimage(args: &mut std::env::Args) -> std::result::Resul
     Ok(())
 }
 
+fn migrate_image_info_usage(error: bool, options: &Options) {
+    let brief = r#"Usage: hiberman migrate-image-info <from> <to> [options]
+Convert the image info file <from> to a newer format version and write it to
+<to>. Fields the original version didn't have get their defaults.
+"#;
+
+    print_usage(&options.usage(brief), error);
+}
+
+fn hiberman_migrate_image_info(args: &mut std::env::Args) -> std::result::Result<(), ()> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help text");
+    opts.optopt(
+        "t",
+        "target-version",
+        "Format version to convert to, defaults to the newest one",
+        "VERSION",
+    );
+    let args: Vec<String> = args.collect();
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Failed to parse arguments: {}", e);
+            migrate_image_info_usage(true, &opts);
+            return Err(());
+        }
+    };
+
+    if matches.opt_present("h") {
+        migrate_image_info_usage(false, &opts);
+        return Ok(());
+    }
+
+    if matches.free.len() != 2 {
+        eprintln!("Expected the source and destination paths");
+        migrate_image_info_usage(true, &opts);
+        return Err(());
+    }
+
+    let target_version = match matches.opt_str("t") {
+        Some(version) => match version.parse() {
+            Ok(version) => version,
+            Err(e) => {
+                eprintln!("Invalid target version {}: {}", version, e);
+                return Err(());
+            }
+        },
+        None => hiberman::IMAGE_INFO_VERSION,
+    };
+
+    if let Err(e) = hiberman::migrate_image_info(
+        Path::new(&matches.free[0]),
+        Path::new(&matches.free[1]),
+        target_version,
+    ) {
+        eprintln!("Failed to migrate image info: {:?}", e);
+        return Err(());
+    }
+
+    Ok(())
+}
+
+fn dump_image_info_usage(error: bool, options: &Options) {
+    let brief = r#"Usage: hiberman dump-image-info <path> [options]
+Print the contents of the image info file <path>.
+"#;
+
+    print_usage(&options.usage(brief), error);
+}
+
+fn hiberman_dump_image_info(args: &mut std::env::Args) -> std::result::Result<(), ()> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help text");
+    let args: Vec<String> = args.collect();
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Failed to parse arguments: {}", e);
+            dump_image_info_usage(true, &opts);
+            return Err(());
+        }
+    };
+
+    if matches.opt_present("h") {
+        dump_image_info_usage(false, &opts);
+        return Ok(());
+    }
+
+    if matches.free.len() != 1 {
+        eprintln!("Expected the path of the image info file");
+        dump_image_info_usage(true, &opts);
+        return Err(());
+    }
+
+    match hiberman::dump_image_info(Path::new(&matches.free[0])) {
+        Ok(dump) => println!("{}", dump),
+        Err(e) => {
+            eprintln!("Failed to dump image info: {:?}", e);
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
+
+fn inspect_image_usage(error: bool, options: &Options) {
+    let brief = r#"Usage: hiberman inspect-image [<hibermeta>] [options]
+Describe the hibernate image and whether it would be resumed, without
+modifying anything. <hibermeta> defaults to /mnt/hibermeta, which must be
+mounted.
+"#;
+
+    print_usage(&options.usage(brief), error);
+}
+
+fn hiberman_inspect_image(args: &mut std::env::Args) -> std::result::Result<(), ()> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help text");
+    let args: Vec<String> = args.collect();
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Failed to parse arguments: {}", e);
+            inspect_image_usage(true, &opts);
+            return Err(());
+        }
+    };
+
+    if matches.opt_present("h") {
+        inspect_image_usage(false, &opts);
+        return Ok(());
+    }
+
+    if matches.free.len() > 1 {
+        eprintln!("Expected at most the path of hibermeta");
+        inspect_image_usage(true, &opts);
+        return Err(());
+    }
+
+    let meta = matches
+        .free
+        .first()
+        .map(String::as_str)
+        .unwrap_or("/mnt/hibermeta");
+    match hiberman::inspect_image(Path::new(meta)) {
+        Ok(summary) => println!("{}", summary),
+        Err(e) => {
+            eprintln!("Failed to inspect the hibernate image: {:?}", e);
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
+
 fn app_usage(error: bool) {
     let usage_msg = r#"Usage: hiberman subcommand [options]
 This application coordinates suspend-to-disk activities. Try
@@ -382,6 +766,9 @@ Valid subcommands are:
     abort-resume -- Send an abort request to an in-progress resume.
     cookie -- Read or write the hibernate cookie.
     teardown-hiberimage -- Tear the hiberimage device down if it exists.
+    migrate-image-info -- Convert an image info file to a newer format.
+    dump-image-info -- Print the contents of an image info file.
+    inspect-image -- Describe the hibernate image without touching it.
 "#;
     print_usage(usage_msg, error);
 }
@@ -412,6 +799,9 @@ fn hiberman_main() -> std::result::Result<(), ()> {
         "resume-init" => hiberman_resume_init(&mut args),
         "resume" => hiberman_resume(&mut args),
         "teardown-hiberimage" => hiberman_teardown_hiberimage(&mut args),
+        "migrate-image-info" => hiberman_migrate_image_info(&mut args),
+        "dump-image-info" => hiberman_dump_image_info(&mut args),
+        "inspect-image" => hiberman_inspect_image(&mut args),
         _ => {
             eprintln!("Unknown subcommand: {}", subcommand);
             Err(())
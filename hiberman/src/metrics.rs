@@ -17,6 +17,7 @@ use std::path::PathBuf;
 use std::process::Command;
 use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Context;
 use anyhow::Result;
@@ -27,19 +28,23 @@ use serde::Deserialize;
 use serde::Serialize;
 
 use crate::files::HIBERMETA_DIR;
+use crate::hiberutil::log_duration;
 use crate::hiberutil::HibernateError;
 
 lazy_static! {
     pub static ref METRICS_LOGGER: Mutex<MetricsLogger> = Mutex::new(MetricsLogger::new());
 
     /// Path of the file with metric samples.
-    static ref METRICS_FILE_PATH: PathBuf = Path::new(HIBERMETA_DIR).join("metrics");
+    pub(crate) static ref METRICS_FILE_PATH: PathBuf = Path::new(HIBERMETA_DIR).join("metrics");
 }
 
 /// Bytes per MB float value.
 pub const BYTES_PER_MB_F64: f64 = 1048576.0;
 /// Max expected IO size for IO metrics.
 pub const MAX_IO_SIZE_KB: isize = 9437000;
+/// Max number of samples kept in the MetricsLogger buffer while they can't be
+/// written to the metrics file. The oldest samples are dropped beyond that.
+const MAX_BUFFERED_SAMPLES: usize = 1024;
 
 pub enum DurationMetricUnit {
     Milliseconds,
@@ -83,15 +88,28 @@ struct MetricsSample<'a> {
 /// Define the hibernate metrics logger.
 pub struct MetricsLogger {
     buf: VecDeque<String>,
+    enabled: bool,
 }
 
 impl MetricsLogger {
     fn new() -> Self {
         Self {
             buf: VecDeque::new(),
+            enabled: true,
         }
     }
 
+    /// Drop all pending and future samples for the rest of the run.
+    pub fn disable(&mut self) {
+        self.enabled = false;
+        self.buf.clear();
+    }
+
+    /// Returns false if metrics were disabled for this run.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
     /// Log a metric to the MetricsLogger buffer.
     pub fn log_metric(&mut self, name: &str, value: isize, min: isize, max: isize, buckets: usize) {
         self.log_metric_internal(HistogramType::Exponential, name, value, min, max, buckets);
@@ -101,9 +119,32 @@ impl MetricsLogger {
         self.log_metric_internal(HistogramType::Linear, name, value, -1, max, 0);
     }
 
-    /// Write the MetricsLogger buffer to the MetricsLogger file.
+    /// Write the MetricsLogger buffer to the MetricsLogger file. Samples that
+    /// couldn't be written stay in the buffer for the next flush.
     pub fn flush(&mut self) -> Result<()> {
-        if self.buf.is_empty() {
+        self.flush_to(METRICS_FILE_PATH.as_path())
+    }
+
+    /// Write the MetricsLogger buffer to the MetricsLogger file. If `optional`
+    /// is set, a failure to write the file disables metrics for the rest of
+    /// the run instead of being returned.
+    pub fn flush_or_disable(&mut self, optional: bool) -> Result<()> {
+        self.flush_or_disable_to(METRICS_FILE_PATH.as_path(), optional)
+    }
+
+    fn flush_or_disable_to(&mut self, path: &Path, optional: bool) -> Result<()> {
+        match self.flush_to(path) {
+            Err(e) if optional => {
+                warn!("Disabling metrics for this run: {:?}", e);
+                self.disable();
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn flush_to(&mut self, path: &Path) -> Result<()> {
+        if !self.enabled || self.buf.is_empty() {
             return Ok(());
         }
 
@@ -112,17 +153,13 @@ impl MetricsLogger {
             .create(true)
             .append(true)
             .custom_flags(libc::O_SYNC)
-            .open(METRICS_FILE_PATH.as_path())
-            .context(format!(
-                "Failed to open metrics file {}",
-                METRICS_FILE_PATH.display()
-            ))?;
-
-        for entry in self.buf.drain(..) {
-            f.write_all(entry.as_bytes())
-                .context("Failed to write metrics file")?;
-            f.write_all("\n".as_bytes())
+            .open(path)
+            .context(format!("Failed to open metrics file {}", path.display()))?;
+
+        while let Some(entry) = self.buf.front() {
+            f.write_all(format!("{}\n", entry).as_bytes())
                 .context("Failed to write metrics file")?;
+            self.buf.pop_front();
         }
 
         Ok(())
@@ -188,6 +225,10 @@ impl MetricsLogger {
         max: isize,
         buckets: usize,
     ) {
+        if !self.enabled {
+            return;
+        }
+
         let sample = MetricsSample {
             name,
             value,
@@ -207,6 +248,11 @@ impl MetricsLogger {
             }
         };
 
+        if self.buf.len() >= MAX_BUFFERED_SAMPLES {
+            warn!("Too many metric samples pending, dropping the oldest");
+            self.buf.pop_front();
+        }
+
         self.buf.push_back(entry);
     }
 
@@ -220,6 +266,44 @@ impl MetricsLogger {
     }
 }
 
+/// Times the phases of hibernate, to log each of them as a separate duration
+/// sample. The phases are only kept in memory until `log_samples()` is called,
+/// so that nothing recorded before the snapshot is logged again by the resumed
+/// image.
+#[derive(Default)]
+pub struct PhaseTimer {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseTimer {
+    /// Run `f` as the phase `name` and record how long it took.
+    pub fn time<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(name, start.elapsed());
+        result
+    }
+
+    /// Record a phase that took `duration`.
+    pub fn record(&mut self, name: &'static str, duration: Duration) {
+        log_duration(name, duration);
+        self.phases.push((name, duration));
+    }
+
+    /// Log a Platform.Hibernate.SuspendPhase.<name> duration sample for each
+    /// recorded phase.
+    pub fn log_samples(&self, metrics_logger: &mut MetricsLogger) {
+        for (name, duration) in &self.phases {
+            metrics_logger.log_duration_sample(
+                &format!("Platform.Hibernate.SuspendPhase.{}", name),
+                *duration,
+                DurationMetricUnit::Milliseconds,
+                120000,
+            );
+        }
+    }
+}
+
 /// Send metrics_client sample.
 fn metrics_send_sample(sample: &MetricsSample) -> Result<()> {
     let status = Command::new("metrics_client")
@@ -326,3 +410,102 @@ pub fn read_and_send_metrics() {
         warn!("Failed to remove {}: {}", METRICS_FILE_PATH.display(), e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_optional_metrics_setup_failure_disables_metrics() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("missing").join("metrics");
+
+        let mut logger = MetricsLogger::new();
+        logger.log_event(HibernateEvent::SuspendAttempt);
+        assert!(logger.flush_or_disable_to(&path, false).is_err());
+        assert!(logger.is_enabled());
+
+        // An optional metrics failure lets the caller continue with metrics
+        // disabled.
+        assert!(logger.flush_or_disable_to(&path, true).is_ok());
+        assert!(!logger.is_enabled());
+
+        logger.log_event(HibernateEvent::SuspendSuccess);
+        assert!(logger.buf.is_empty());
+        assert!(logger.flush_to(&path).is_ok());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_failed_flush_keeps_samples() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path().join("missing");
+        let path = dir.join("metrics");
+
+        let mut logger = MetricsLogger::new();
+        logger.log_event(HibernateEvent::SuspendAttempt);
+        logger.log_event(HibernateEvent::SuspendFailure);
+        assert!(logger.flush_to(&path).is_err());
+        assert_eq!(logger.buf.len(), 2);
+
+        // The samples that failed to be written go out with the next flush.
+        fs::create_dir_all(&dir).unwrap();
+        logger.log_event(HibernateEvent::SuspendAttempt);
+        logger.flush_to(&path).unwrap();
+        assert!(logger.buf.is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn test_pending_samples_are_bounded() {
+        let mut logger = MetricsLogger::new();
+        for value in 0..MAX_BUFFERED_SAMPLES + 10 {
+            logger.log_enum_metric("Platform.Hibernate.Test", value as isize, 2000);
+        }
+
+        assert_eq!(logger.buf.len(), MAX_BUFFERED_SAMPLES);
+        // The oldest samples were dropped.
+        let sample: serde_json::Value = serde_json::from_str(&logger.buf[0]).unwrap();
+        assert_eq!(sample["value"], 10);
+    }
+
+    #[test]
+    fn test_phase_timer_samples() {
+        let phases = [
+            "SetupHibermeta",
+            "PreallocateMemory",
+            "FreezeUserspace",
+            "ThickenHiberimage",
+            "Snapshot",
+            "WriteImage",
+        ];
+
+        let mut phase_timer = PhaseTimer::default();
+        for (i, phase) in phases.iter().enumerate() {
+            if i % 2 == 0 {
+                assert_eq!(phase_timer.time(phase, || i), i);
+            } else {
+                phase_timer.record(phase, Duration::from_millis(i as u64));
+            }
+        }
+
+        let mut logger = MetricsLogger::new();
+        phase_timer.log_samples(&mut logger);
+
+        let names: Vec<String> = logger
+            .buf
+            .iter()
+            .map(|entry| {
+                let sample: serde_json::Value = serde_json::from_str(entry).unwrap();
+                sample["name"].as_str().unwrap().to_string()
+            })
+            .collect();
+        let expected: Vec<String> = phases
+            .iter()
+            .map(|phase| format!("Platform.Hibernate.SuspendPhase.{}", phase))
+            .collect();
+        assert_eq!(names, expected);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implements the compressibility sampler and the estimates reported by a
+//! profiling (dry) hibernate run.
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+
+/// Size of the chunks in which image data is sampled. Matches the page size
+/// used by the kernel for the snapshot image.
+pub const SAMPLE_CHUNK_SIZE: usize = 4096;
+
+/// Result of running the compressibility sampler over a window of the
+/// snapshot image.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompressibilitySample {
+    /// Number of bytes that were sampled.
+    pub sampled_bytes: u64,
+    /// Estimated number of bytes the sampled data compresses to.
+    pub compressed_bytes: u64,
+}
+
+impl CompressibilitySample {
+    /// Returns the estimated ratio of compressed to uncompressed size, in the
+    /// range [0.0, 1.0]. Returns 1.0 if nothing was sampled.
+    pub fn ratio(&self) -> f64 {
+        if self.sampled_bytes == 0 {
+            return 1.0;
+        }
+
+        self.compressed_bytes as f64 / self.sampled_bytes as f64
+    }
+}
+
+/// Estimate the compressed size of a single chunk of data.
+///
+/// This uses the zero-order (byte histogram) entropy of the chunk as a cheap
+/// stand-in for an actual compressor. Chunks consisting of a single repeated
+/// byte (e.g. zero pages) are treated as free.
+fn estimate_compressed_chunk_size(chunk: &[u8]) -> u64 {
+    if chunk.is_empty() || chunk.iter().all(|b| *b == chunk[0]) {
+        return 0;
+    }
+
+    let mut histogram = [0u64; 256];
+    for b in chunk {
+        histogram[*b as usize] += 1;
+    }
+
+    let len = chunk.len() as f64;
+    let entropy_bits: f64 = histogram
+        .iter()
+        .filter(|count| **count != 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+
+    ((entropy_bits * len) / 8.0).ceil() as u64
+}
+
+/// Run the compressibility sampler over at most `window` bytes read from
+/// `source`. Sampling stops early if the source runs dry.
+pub fn sample_compressibility<R: Read>(
+    source: &mut R,
+    window: u64,
+) -> Result<CompressibilitySample> {
+    let mut sample = CompressibilitySample::default();
+    let mut chunk = vec![0u8; SAMPLE_CHUNK_SIZE];
+
+    while sample.sampled_bytes < window {
+        let want = std::cmp::min(SAMPLE_CHUNK_SIZE as u64, window - sample.sampled_bytes) as usize;
+        let len = match source.read(&mut chunk[..want]) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("Failed to read sample data"),
+        };
+
+        sample.sampled_bytes += len as u64;
+        sample.compressed_bytes += estimate_compressed_chunk_size(&chunk[..len]);
+    }
+
+    Ok(sample)
+}
+
+/// Report produced by a profiling hibernate run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProfileReport {
+    /// Size of the uncompressed snapshot image.
+    pub image_size: u64,
+    /// The compressibility sample taken from the image.
+    pub sample: CompressibilitySample,
+    /// Measured write throughput to the scratch file, in bytes per second.
+    pub write_throughput: u64,
+    /// Estimated size of the image if it were compressed.
+    pub estimated_compressed_size: u64,
+    /// Estimated time it takes to write the compressed image.
+    pub estimated_write_duration: Duration,
+}
+
+impl ProfileReport {
+    /// Compute the estimates for an image of the given size from a
+    /// compressibility sample and the measured write throughput.
+    pub fn new(image_size: u64, sample: CompressibilitySample, write_throughput: u64) -> Self {
+        let estimated_compressed_size = (image_size as f64 * sample.ratio()).ceil() as u64;
+        let estimated_write_duration = if write_throughput == 0 {
+            Duration::MAX
+        } else {
+            Duration::from_secs_f64(estimated_compressed_size as f64 / write_throughput as f64)
+        };
+
+        ProfileReport {
+            image_size,
+            sample,
+            write_throughput,
+            estimated_compressed_size,
+            estimated_write_duration,
+        }
+    }
+
+    /// Returns the estimated space savings of compressing the image.
+    pub fn estimated_savings(&self) -> u64 {
+        self.image_size
+            .saturating_sub(self.estimated_compressed_size)
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const MB: u64 = 1024 * 1024;
+
+        writeln!(f, "Image size: {} MB", self.image_size / MB)?;
+        writeln!(
+            f,
+            "Sampled: {} MB, compression ratio {:.2}",
+            self.sample.sampled_bytes / MB,
+            self.sample.ratio()
+        )?;
+        writeln!(f, "Write throughput: {} MB/s", self.write_throughput / MB)?;
+        writeln!(
+            f,
+            "Estimated compressed size: {} MB (saves {} MB)",
+            self.estimated_compressed_size / MB,
+            self.estimated_savings() / MB
+        )?;
+        write!(
+            f,
+            "Estimated write duration: {}.{:03}s",
+            self.estimated_write_duration.as_secs(),
+            self.estimated_write_duration.subsec_millis()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fake snapshot device serving `incompressible` pages of data with every
+    /// byte value occurring equally often, followed by `zero` zero pages.
+    struct FakeSnapshotDevice {
+        incompressible: usize,
+        zero: usize,
+        offset: usize,
+    }
+
+    impl Read for FakeSnapshotDevice {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let total = (self.incompressible + self.zero) * SAMPLE_CHUNK_SIZE;
+            let len = std::cmp::min(buf.len(), total - self.offset);
+            let incompressible_end = self.incompressible * SAMPLE_CHUNK_SIZE;
+
+            for (i, b) in buf[..len].iter_mut().enumerate() {
+                let pos = self.offset + i;
+                *b = if pos < incompressible_end {
+                    pos as u8
+                } else {
+                    0
+                };
+            }
+
+            self.offset += len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_profile_report_estimates() {
+        let mut snap_dev = FakeSnapshotDevice {
+            incompressible: 16,
+            zero: 48,
+            offset: 0,
+        };
+
+        let sample = sample_compressibility(&mut snap_dev, u64::MAX).unwrap();
+        assert_eq!(sample.sampled_bytes, 64 * SAMPLE_CHUNK_SIZE as u64);
+        assert_eq!(sample.compressed_bytes, 16 * SAMPLE_CHUNK_SIZE as u64);
+        assert_eq!(sample.ratio(), 0.25);
+
+        let report = ProfileReport::new(1 << 30, sample, 256 << 20);
+        assert_eq!(report.estimated_compressed_size, 256 << 20);
+        assert_eq!(report.estimated_savings(), 768 << 20);
+        assert_eq!(report.estimated_write_duration, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_sample_compressibility_window() {
+        let mut snap_dev = FakeSnapshotDevice {
+            incompressible: 4,
+            zero: 4,
+            offset: 0,
+        };
+
+        // Only the incompressible leading pages fall into the window.
+        let sample = sample_compressibility(&mut snap_dev, 2 * SAMPLE_CHUNK_SIZE as u64).unwrap();
+        assert_eq!(sample.sampled_bytes, 2 * SAMPLE_CHUNK_SIZE as u64);
+        assert_eq!(sample.ratio(), 1.0);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Reports the progress of writing the hibernate image to a caller supplied
+//! callback.
+
+use std::fs;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::warn;
+
+/// Called with the number of bytes of the image written so far and the total
+/// size of the image.
+pub type ProgressCallback = Box<dyn FnMut(u64, u64) + Send>;
+
+/// How often the progress is reported.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Size of the sectors in /sys/block/<dev>/stat.
+const SECTOR_SIZE: u64 = 512;
+
+/// Calls a progress callback periodically from a separate thread until
+/// stopped. A panicking callback is not called again.
+pub struct ProgressMonitor {
+    channel_tx: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ProgressMonitor {
+    /// Start reporting `read_bytes_written()` out of `total` bytes to
+    /// `callback` every `interval`.
+    pub fn new<F>(
+        mut read_bytes_written: F,
+        total: u64,
+        mut callback: ProgressCallback,
+        interval: Duration,
+    ) -> Self
+    where
+        F: FnMut() -> Result<u64> + Send + 'static,
+    {
+        let (channel_tx, channel_rx): (Sender<()>, Receiver<()>) = channel();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = channel_rx.recv_timeout(interval) {
+                let written = match read_bytes_written() {
+                    Ok(written) => written.min(total),
+                    Err(e) => {
+                        warn!("Failed to get the hibernate image write progress: {:?}", e);
+                        continue;
+                    }
+                };
+
+                if catch_unwind(AssertUnwindSafe(|| callback(written, total))).is_err() {
+                    warn!("Progress callback panicked, not reporting progress any more");
+                    break;
+                }
+            }
+        });
+
+        ProgressMonitor {
+            channel_tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop reporting the progress. The callback is not called any more once
+    /// this returns.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            // The thread is gone already if the callback panicked.
+            let _ = self.channel_tx.send(());
+            if let Err(e) = thread.join() {
+                warn!("Failed to join the progress monitor thread: {:?}", e);
+            }
+        }
+    }
+}
+
+impl Drop for ProgressMonitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Get the path of the I/O statistics of the given block device, e.g.
+/// /sys/block/dm-3/stat for /dev/dm-3.
+pub fn block_device_stat_path(device_path: &Path) -> Result<PathBuf> {
+    let name = device_path
+        .file_name()
+        .context(format!("Invalid block device {}", device_path.display()))?;
+    Ok(Path::new("/sys/block").join(name).join("stat"))
+}
+
+/// Returns a function reading the number of bytes written to a block device
+/// since it was created.
+pub fn block_device_bytes_written(stat_path: PathBuf) -> impl FnMut() -> Result<u64> {
+    move || {
+        let stat = fs::read_to_string(&stat_path)
+            .context(format!("Failed to read {}", stat_path.display()))?;
+        parse_bytes_written(&stat).context(format!("Failed to parse {}", stat_path.display()))
+    }
+}
+
+// The 7th field of the block device stat is the number of sectors written.
+fn parse_bytes_written(stat: &str) -> Result<u64> {
+    let sectors: u64 = stat
+        .split_whitespace()
+        .nth(6)
+        .context("Missing sectors written")?
+        .parse()?;
+    Ok(sectors * SECTOR_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_progress_monitor() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let written = Arc::new(Mutex::new(0));
+
+        let callback_reports = reports.clone();
+        let callback_written = written.clone();
+        let mut monitor = ProgressMonitor::new(
+            move || {
+                let mut written = callback_written.lock().unwrap();
+                *written += 400;
+                Ok(*written)
+            },
+            1000,
+            Box::new(move |written, total| callback_reports.lock().unwrap().push((written, total))),
+            Duration::from_millis(5),
+        );
+        while reports.lock().unwrap().len() < 3 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        monitor.stop();
+
+        let count = reports.lock().unwrap().len();
+        assert_eq!(
+            reports.lock().unwrap()[..3],
+            [(400, 1000), (800, 1000), (1000, 1000)]
+        );
+        // Not called any more after stopping.
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(reports.lock().unwrap().len(), count);
+    }
+
+    #[test]
+    fn test_progress_monitor_panicking_callback() {
+        let calls = Arc::new(Mutex::new(0));
+        let callback_calls = calls.clone();
+        let mut monitor = ProgressMonitor::new(
+            || Ok(0),
+            1000,
+            Box::new(move |_, _| {
+                *callback_calls.lock().unwrap() += 1;
+                panic!("misbehaving callback");
+            }),
+            Duration::from_millis(5),
+        );
+        thread::sleep(Duration::from_millis(50));
+        monitor.stop();
+
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_parse_bytes_written() {
+        let stat = "    1234        0    56789      100     4321        0   102400      200        0      300      300";
+        assert_eq!(parse_bytes_written(stat).unwrap(), 102400 * 512);
+        assert!(parse_bytes_written("1 2 3").is_err());
+    }
+}
@@ -7,7 +7,12 @@
 use std::fs;
 use std::fs::File;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
 use std::time::Duration;
 use std::time::Instant;
 use std::time::UNIX_EPOCH;
@@ -20,6 +25,7 @@ use log::error;
 use log::info;
 use log::warn;
 
+use crate::cmdline::check_cmdline_hash;
 use crate::cookie::cookie_description;
 use crate::cookie::get_hibernate_cookie;
 use crate::cookie::set_hibernate_cookie;
@@ -27,17 +33,23 @@ use crate::cookie::HibernateCookieValue;
 use crate::cryptohome;
 use crate::device_mapper::DeviceMapper;
 use crate::files::remove_resume_in_progress_file;
+use crate::files::HIBERMETA_DIR;
 use crate::hiberlog;
 use crate::hiberlog::redirect_log;
 use crate::hiberlog::redirect_log_to_file;
 use crate::hiberlog::replay_logs;
 use crate::hiberlog::HiberlogOut;
+use crate::hiberutil::check_block_device;
+use crate::hiberutil::get_ram_size;
 use crate::hiberutil::lock_process_memory;
 use crate::hiberutil::path_to_stateful_block;
 use crate::hiberutil::HibernateError;
 use crate::hiberutil::HibernateStage;
 use crate::hiberutil::ResumeOptions;
 use crate::hiberutil::TimestampFile;
+use crate::image_info::check_image_age;
+use crate::image_info::check_image_size;
+use crate::image_info::ImageInfo;
 use crate::lvm::activate_physical_lv;
 use crate::metrics::read_and_send_metrics;
 use crate::metrics::DurationMetricUnit;
@@ -46,9 +58,12 @@ use crate::metrics::METRICS_LOGGER;
 use crate::powerd::PowerdPendingResume;
 use crate::resume_dbus::DBusEvent;
 use crate::resume_dbus::DBusServer;
+use crate::resume_dbus::ResumableImage;
+use crate::resume_failure::record_resume_failure;
 use crate::snapdev::FrozenUserspaceTicket;
 use crate::snapdev::SnapshotDevice;
 use crate::snapdev::SnapshotMode;
+use crate::vboot::VbootState;
 use crate::volume::ActiveMount;
 use crate::volume::PendingStatefulMerge;
 use crate::volume::VolumeManager;
@@ -63,7 +78,7 @@ const TPM_SEED_FILE: &str = "/run/hiberman/tpm_seed";
 /// work in concert to resume the system from hibernation.
 pub struct ResumeConductor {
     options: ResumeOptions,
-    stateful_block_path: String,
+    stateful_block_path: PathBuf,
     tried_to_resume: bool,
     timestamp_start: Duration,
 }
@@ -73,7 +88,7 @@ impl ResumeConductor {
     pub fn new() -> Result<Self> {
         Ok(ResumeConductor {
             options: Default::default(),
-            stateful_block_path: path_to_stateful_block()?,
+            stateful_block_path: PathBuf::from(path_to_stateful_block()?),
             tried_to_resume: false,
             timestamp_start: Duration::ZERO,
         })
@@ -84,6 +99,15 @@ impl ResumeConductor {
     /// the case of resume failure, an error is returned.
     pub fn resume(&mut self, options: ResumeOptions) -> Result<()> {
         info!("Beginning resume");
+        if let Some(block_device) = &options.block_device {
+            check_block_device(block_device)?;
+            info!("Resuming from {}", block_device.display());
+            // The hibernate volumes are activated from the volume group on
+            // the given device. The stateful snapshots still belong to the
+            // booted system.
+            *VOLUME_MANAGER.write().unwrap() = VolumeManager::for_block_device(block_device)?;
+            self.stateful_block_path = block_device.clone();
+        }
         // Ensure the persistent version of the stateful block device is available.
         let _rw_stateful_lv = activate_physical_lv("unencrypted")?;
         self.options = options;
@@ -116,6 +140,12 @@ impl ResumeConductor {
         // Now replay earlier logs. Don't wipe the logs out if this is just a dry
         // run.
         replay_logs(true, !self.options.dry_run);
+        // Leave a record of why the resume failed for the next boot.
+        if self.tried_to_resume {
+            if let Err(e) = &result {
+                record_resume_failure(Path::new(HIBERMETA_DIR), e);
+            }
+        }
         // Remove the resume_in_progress token file if it exists.
         remove_resume_in_progress_file();
         // Since resume_inner() returned, we are no longer in a viable resume
@@ -131,7 +161,7 @@ impl ResumeConductor {
     /// Helper function to perform the meat of the resume action now that the
     /// logging is routed.
     fn resume_inner(&mut self) -> Result<()> {
-        let mut dbus_server = DBusServer::new();
+        let mut dbus_server = DBusServer::new(self.resumable_image());
 
         // Wait for the user to authenticate or a message that hibernate is
         // not supported.
@@ -177,13 +207,51 @@ impl ResumeConductor {
 
         let hibermeta_mount = volume_manager.setup_hibermeta_lv(false)?;
 
+        check_cmdline_hash(
+            Path::new(HIBERMETA_DIR),
+            self.options.ignore_cmdline_mismatch,
+        )?;
+        VbootState::current()?.verify(Path::new(HIBERMETA_DIR))?;
+        check_image_age(
+            Path::new(HIBERMETA_DIR),
+            self.options.max_image_age,
+            self.timestamp_start,
+        )?;
+
         // Set up the snapshot device for resuming
         self.setup_snapshot_device(false, user_key)?;
+        check_image_size(Path::new(HIBERMETA_DIR), get_ram_size(), hiberimage_size()?)?;
 
         volume_manager.lockdown_hiberimage()?;
 
         let _locked_memory = lock_process_memory()?;
-        self.resume_system(hibermeta_mount)
+        self.resume_system(&volume_manager, hibermeta_mount)
+    }
+
+    /// Check cheaply whether there is an image to resume, for reporting over
+    /// D-Bus before the user authenticates. Resume-init already moved the
+    /// cookie of a resumable image to ResumeInProgress. The image size is
+    /// only known if hibermeta happens to be mounted.
+    fn resumable_image(&self) -> ResumableImage {
+        let resumable = match get_hibernate_cookie(Some(&self.stateful_block_path)) {
+            Ok(cookie) => cookie == HibernateCookieValue::ResumeInProgress,
+            Err(e) => {
+                warn!("Failed to get hibernate cookie: {:?}", e);
+                false
+            }
+        };
+        let image_size = if resumable {
+            ImageInfo::load(Path::new(HIBERMETA_DIR))
+                .map(|image_info| image_info.image_size)
+                .unwrap_or(0)
+        } else {
+            0
+        };
+
+        ResumableImage {
+            resumable,
+            image_size,
+        }
     }
 
     /// Helper function to evaluate the hibernate cookie and decide whether or
@@ -232,7 +300,11 @@ impl ResumeConductor {
     }
 
     /// Inner helper function to read the resume image and launch it.
-    fn resume_system(&mut self, mut hibermeta_mount: ActiveMount) -> Result<()> {
+    fn resume_system(
+        &mut self,
+        volume_manager: &VolumeManager,
+        mut hibermeta_mount: ActiveMount,
+    ) -> Result<()> {
         let log_file_path = hiberlog::LogFile::get_path(HibernateStage::Resume);
         let log_file = hiberlog::LogFile::create(log_file_path)?;
         // Start logging to the resume logger.
@@ -246,7 +318,10 @@ impl ResumeConductor {
 
         let start = Instant::now();
         // Load the snapshot image into the kernel
-        let image_size = snap_dev.load_image()?;
+        let image_size = load_resume_image(&mut snap_dev, self.options.prewarm_image, || {
+            let path = DeviceMapper::device_path(VolumeManager::HIBERIMAGE)?;
+            File::open(&path).context(format!("Failed to open {}", path.display()))
+        })?;
         info!("Image loaded with size {}", image_size);
 
         {
@@ -309,6 +384,8 @@ impl ResumeConductor {
             info!("Not launching resume image: in a dry run.");
 
             Ok(())
+        } else if self.options.verify_only {
+            self.discard_resume_image(frozen_userspace, volume_manager)
         } else {
             self.launch_resume_image(frozen_userspace)
         }
@@ -358,8 +435,278 @@ impl ResumeConductor {
         // before control is lost.
         info!("Launching resume image");
         let snap_dev = frozen_userspace.as_mut();
-        let result = snap_dev.atomic_restore();
+        let result = finish_resume(snap_dev, false);
         error!("Resume failed");
         result
     }
+
+    /// Release the already-loaded and verified resume image instead of
+    /// jumping into it, and clear the cookie so that the system boots
+    /// normally from now on.
+    fn discard_resume_image(
+        &mut self,
+        frozen_userspace: FrozenUserspaceTicket,
+        volume_manager: &VolumeManager,
+    ) -> Result<()> {
+        info!("Discarding verified resume image: in verify-only mode.");
+        discard_image(frozen_userspace, volume_manager)?;
+
+        // The image loaded successfully, don't report a resume failure.
+        self.tried_to_resume = false;
+
+        set_hibernate_cookie(
+            Some(&self.stateful_block_path),
+            HibernateCookieValue::NoResume,
+        )
+        .context("Failed to clear hibernate cookie after verification")
+    }
+}
+
+/// Get the size in bytes of the set up hiberimage volume.
+fn hiberimage_size() -> Result<u64> {
+    let path = DeviceMapper::device_path(VolumeManager::HIBERIMAGE)?;
+    File::open(&path)
+        .and_then(|mut f| f.seek(SeekFrom::End(0)))
+        .with_context(|| format!("Failed to get the size of {}", path.display()))
+}
+
+/// Something whose content is about to be read.
+trait ReadAhead {
+    /// Hint the kernel to start reading the whole content ahead.
+    fn will_need(&self) -> Result<()>;
+}
+
+impl ReadAhead for File {
+    fn will_need(&self) -> Result<()> {
+        // Safe because posix_fadvise() doesn't touch memory. A length of 0
+        // covers everything up to the end of the file.
+        let rc = unsafe { libc::posix_fadvise(self.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) };
+        if rc != 0 {
+            return Err(std::io::Error::from_raw_os_error(rc))
+                .context("Failed to fadvise WILLNEED");
+        }
+
+        Ok(())
+    }
+}
+
+/// A snapshot device the resume image is loaded into.
+trait ImageLoader {
+    /// Load the image into the kernel. Returns the size of the image.
+    fn load_image(&mut self) -> Result<u64>;
+}
+
+impl ImageLoader for SnapshotDevice {
+    fn load_image(&mut self) -> Result<u64> {
+        SnapshotDevice::load_image(self)
+    }
+}
+
+/// Load the resume image into the kernel. If `prewarm` is set, open the
+/// hiberimage and hint the kernel to read it ahead first, so that loading
+/// the image reads from warm caches. Failing to prewarm doesn't fail the
+/// resume.
+fn load_resume_image<D: ImageLoader, R: ReadAhead>(
+    snap_dev: &mut D,
+    prewarm: bool,
+    open_image: impl FnOnce() -> Result<R>,
+) -> Result<u64> {
+    if prewarm {
+        if let Err(e) = open_image().and_then(|image| image.will_need()) {
+            warn!("Failed to prewarm the hibernate image: {:?}", e);
+        }
+    }
+
+    snap_dev.load_image()
+}
+
+/// The operations on a snapshot device holding a loaded resume image.
+trait LoadedImage {
+    fn atomic_restore(&mut self) -> Result<()>;
+    fn free_image(&mut self) -> Result<()>;
+}
+
+impl LoadedImage for SnapshotDevice {
+    fn atomic_restore(&mut self) -> Result<()> {
+        SnapshotDevice::atomic_restore(self)
+    }
+
+    fn free_image(&mut self) -> Result<()> {
+        SnapshotDevice::free_image(self)
+    }
+}
+
+impl LoadedImage for FrozenUserspaceTicket<'_> {
+    fn atomic_restore(&mut self) -> Result<()> {
+        self.as_mut().atomic_restore()
+    }
+
+    fn free_image(&mut self) -> Result<()> {
+        self.as_mut().free_image()
+    }
+}
+
+/// The operations on the volumes backing a loaded resume image.
+trait ImageVolumes {
+    fn release_hiberimage(&self) -> Result<()>;
+}
+
+impl ImageVolumes for VolumeManager {
+    fn release_hiberimage(&self) -> Result<()> {
+        // Make sure the thinpool is writable before removing the LVs.
+        self.make_thinpool_rw()?;
+        self.teardown_hiberimage()
+    }
+}
+
+/// Perform the final step of a resume on the loaded image. Normally this
+/// jumps into the image and only returns on failure. In verify-only mode the
+/// image is released instead, and the restore ioctl is never issued.
+fn finish_resume<D: LoadedImage>(snap_dev: &mut D, verify_only: bool) -> Result<()> {
+    if verify_only {
+        snap_dev.free_image()
+    } else {
+        snap_dev.atomic_restore()
+    }
+}
+
+/// Release a verified image in verify-only mode. The image is freed while
+/// userspace is still frozen, then `frozen_userspace` is dropped to thaw it
+/// before the locked hiberimage volumes are torn down, as dmsetup and lvm
+/// would freeze too. The next hibernate sets the hiberimage up afresh.
+fn discard_image<D: LoadedImage, V: ImageVolumes>(
+    mut frozen_userspace: D,
+    volumes: &V,
+) -> Result<()> {
+    finish_resume(&mut frozen_userspace, true)?;
+    mem::drop(frozen_userspace);
+
+    volumes
+        .release_hiberimage()
+        .context("Failed to release the hiberimage after verification")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockSnapshotDevice {
+        restore_calls: usize,
+        free_calls: usize,
+    }
+
+    impl LoadedImage for MockSnapshotDevice {
+        fn atomic_restore(&mut self) -> Result<()> {
+            self.restore_calls += 1;
+            Ok(())
+        }
+
+        fn free_image(&mut self) -> Result<()> {
+            self.free_calls += 1;
+            Ok(())
+        }
+    }
+
+    /// Records the operations on the image and its volumes, in order.
+    #[derive(Default)]
+    struct OperationLog(std::cell::RefCell<Vec<&'static str>>);
+
+    impl OperationLog {
+        fn push(&self, operation: &'static str) {
+            self.0.borrow_mut().push(operation);
+        }
+    }
+
+    struct MockFrozenImage<'a>(&'a OperationLog);
+
+    impl LoadedImage for MockFrozenImage<'_> {
+        fn atomic_restore(&mut self) -> Result<()> {
+            self.0.push("restore");
+            Ok(())
+        }
+
+        fn free_image(&mut self) -> Result<()> {
+            self.0.push("free");
+            Ok(())
+        }
+    }
+
+    impl Drop for MockFrozenImage<'_> {
+        fn drop(&mut self) {
+            self.0.push("thaw");
+        }
+    }
+
+    impl ImageVolumes for OperationLog {
+        fn release_hiberimage(&self) -> Result<()> {
+            self.push("release");
+            Ok(())
+        }
+    }
+
+    struct MockImageFile<'a>(&'a OperationLog);
+
+    impl ReadAhead for MockImageFile<'_> {
+        fn will_need(&self) -> Result<()> {
+            self.0.push("will_need");
+            Ok(())
+        }
+    }
+
+    struct MockImageLoader<'a>(&'a OperationLog);
+
+    impl ImageLoader for MockImageLoader<'_> {
+        fn load_image(&mut self) -> Result<u64> {
+            self.0.push("load");
+            Ok(4096)
+        }
+    }
+
+    #[test]
+    fn test_load_resume_image_prewarm() {
+        let log = OperationLog::default();
+        let image_size =
+            load_resume_image(&mut MockImageLoader(&log), true, || Ok(MockImageFile(&log)))
+                .unwrap();
+        assert_eq!(image_size, 4096);
+        assert_eq!(*log.0.borrow(), ["will_need", "load"]);
+
+        let log = OperationLog::default();
+        load_resume_image(&mut MockImageLoader(&log), false, || {
+            Ok(MockImageFile(&log))
+        })
+        .unwrap();
+        assert_eq!(*log.0.borrow(), ["load"]);
+
+        // Failing to prewarm still loads the image.
+        let log = OperationLog::default();
+        load_resume_image(
+            &mut MockImageLoader(&log),
+            true,
+            || -> Result<MockImageFile> { Err(anyhow::anyhow!("no hiberimage")) },
+        )
+        .unwrap();
+        assert_eq!(*log.0.borrow(), ["load"]);
+    }
+
+    #[test]
+    fn test_verify_only_releases_hiberimage() {
+        let log = OperationLog::default();
+        discard_image(MockFrozenImage(&log), &log).unwrap();
+        assert_eq!(*log.0.borrow(), ["free", "thaw", "release"]);
+    }
+
+    #[test]
+    fn test_verify_only_skips_restore() {
+        let mut snap_dev = MockSnapshotDevice::default();
+        finish_resume(&mut snap_dev, true).unwrap();
+        assert_eq!(snap_dev.restore_calls, 0);
+        assert_eq!(snap_dev.free_calls, 1);
+
+        let mut snap_dev = MockSnapshotDevice::default();
+        finish_resume(&mut snap_dev, false).unwrap();
+        assert_eq!(snap_dev.restore_calls, 1);
+        assert_eq!(snap_dev.free_calls, 0);
+    }
 }
@@ -24,22 +24,33 @@ pub enum DBusEvent {
     AbortRequest { reason: String },
 }
 
+/// Whether there is a hibernate image to resume, and its size in bytes (0 if
+/// unknown).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResumableImage {
+    pub resumable: bool,
+    pub image_size: u64,
+}
+
 /// The hiberman D-Bus server. The D-Bus interface provides functions for notifying
-/// the server about user authentications and for requesting the abort of a pending
-/// resume from hibernate.
+/// the server about user authentications, for requesting the abort of a pending
+/// resume from hibernate and for querying whether there is an image to resume.
 pub struct DBusServer {
     _completion_sender: crossbeam_channel::Sender<()>,
     completion_receiver: Option<crossbeam_channel::Receiver<()>>,
+    resumable_image: ResumableImage,
 }
 
 impl DBusServer {
-    /// Create a DBusServer instance.
-    pub fn new() -> Self {
+    /// Create a DBusServer instance, reporting `resumable_image` to callers
+    /// of HasResumableImage.
+    pub fn new(resumable_image: ResumableImage) -> Self {
         let (sender, receiver) = crossbeam_channel::bounded::<()>(0);
 
         Self {
             _completion_sender: sender,
             completion_receiver: Some(receiver),
+            resumable_image,
         }
     }
 
@@ -68,6 +79,7 @@ impl DBusServer {
         let abort_sender = sender.clone();
         let user_auth_session_sender = sender.clone();
         let user_auth_sender = sender;
+        let resumable_image = self.resumable_image;
 
         let conn = Connection::new_system().context("Failed to start local dbus connection")?;
         conn.request_name(HIBERMAN_DBUS_NAME, false, false, false)
@@ -136,6 +148,13 @@ impl DBusServer {
                     Ok(())
                 },
             );
+
+            b.method(
+                "HasResumableImage",
+                (),
+                ("resumable", "image_size"),
+                move |_, _, ()| Ok((resumable_image.resumable, resumable_image.image_size)),
+            );
         });
 
         // Use an empty context object as we don't have shared state.
@@ -0,0 +1,119 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Leaves a record of why the last resume attempt failed, so that it can be
+//! reported on a later boot even if the logs of the attempt were lost.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::hiberutil::HibernateError;
+
+/// Name of the file on hibermeta with the last resume failure.
+pub const RESUME_FAILURE_FILE_NAME: &str = "last_resume_failure";
+
+/// Why a resume attempt failed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeFailure {
+    /// The kind of HibernateError that failed the resume, e.g.
+    /// "CmdlineMismatch", or "Other" for other errors.
+    pub kind: String,
+    /// The error with all its context.
+    pub message: String,
+    /// When the resume failed, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl ResumeFailure {
+    fn new(err: &anyhow::Error, timestamp: Duration) -> Self {
+        let kind = match err.downcast_ref::<HibernateError>() {
+            // The Debug representation starts with the name of the variant.
+            Some(e) => format!("{:?}", e)
+                .split(|c: char| !c.is_alphanumeric())
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            None => "Other".to_string(),
+        };
+
+        ResumeFailure {
+            kind,
+            message: format!("{:#}", err),
+            timestamp: timestamp.as_secs(),
+        }
+    }
+
+    fn save(&self, meta: &Path) -> Result<()> {
+        let path = meta.join(RESUME_FAILURE_FILE_NAME);
+        let json = serde_json::to_string(self).context("Failed to serialize resume failure")?;
+        fs::write(&path, json).context(format!("Failed to write {}", path.display()))
+    }
+
+    /// Read the last resume failure from the given hibermeta directory, None
+    /// if no resume failure was recorded.
+    pub fn load(meta: &Path) -> Result<Option<Self>> {
+        let path = meta.join(RESUME_FAILURE_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let json =
+            fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&json)
+            .map(Some)
+            .context(format!("Failed to parse {}", path.display()))
+    }
+}
+
+/// Record the error that failed a resume attempt in the given hibermeta
+/// directory, replacing the previous record. Failures to write the record are
+/// only logged.
+pub fn record_resume_failure(meta: &Path, err: &anyhow::Error) {
+    let failure = ResumeFailure::new(err, UNIX_EPOCH.elapsed().unwrap_or(Duration::ZERO));
+    if let Err(e) = failure.save(meta) {
+        warn!("Failed to record resume failure: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_record_resume_failure() {
+        let temp_dir = tempdir().unwrap();
+        let meta = temp_dir.path();
+
+        assert_eq!(ResumeFailure::load(meta).unwrap(), None);
+
+        let err =
+            anyhow::Error::from(HibernateError::CmdlineMismatch()).context("Refusing to resume");
+        let start = UNIX_EPOCH.elapsed().unwrap().as_secs();
+        record_resume_failure(meta, &err);
+
+        let failure = ResumeFailure::load(meta).unwrap().unwrap();
+        assert_eq!(failure.kind, "CmdlineMismatch");
+        assert_eq!(
+            failure.message,
+            "Refusing to resume: Kernel command line changed since hibernate"
+        );
+        assert!(failure.timestamp >= start);
+
+        // A later failure replaces the record.
+        record_resume_failure(meta, &anyhow::anyhow!("Failed to load the image"));
+        let failure = ResumeFailure::load(meta).unwrap().unwrap();
+        assert_eq!(failure.kind, "Other");
+        assert_eq!(failure.message, "Failed to load the image");
+    }
+}
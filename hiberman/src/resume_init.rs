@@ -51,10 +51,12 @@ impl ResumeInitConductor {
 
         match cookie {
             // In the most common case, no resume from hibernate will be imminent.
-            HibernateCookieValue::NoResume | HibernateCookieValue::Uninitialized => {
+            HibernateCookieValue::NoResume
+            | HibernateCookieValue::Uninitialized
+            | HibernateCookieValue::Invalid => {
                 debug!("Hibernate cookie was not set, doing nothing");
 
-                if cookie == HibernateCookieValue::Uninitialized {
+                if cookie != HibernateCookieValue::NoResume {
                     set_hibernate_cookie::<PathBuf>(None, HibernateCookieValue::NoResume)
                         .context("Failed to set hibernate cookie to NoResume")?;
                 }
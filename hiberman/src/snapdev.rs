@@ -4,11 +4,14 @@
 
 //! Implements snapshot device functionality.
 
+use std::fs;
 use std::fs::metadata;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io::Read;
 use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
+use std::time::Duration;
 
 use anyhow::Context;
 use anyhow::Result;
@@ -17,8 +20,10 @@ use libc::c_long;
 use libc::c_ulong;
 use libc::c_void;
 use libc::{self};
+use log::debug;
 use log::error;
 use log::info;
+use log::warn;
 
 use crate::hiberutil::get_device_id;
 use crate::hiberutil::HibernateError;
@@ -30,6 +35,8 @@ use crate::ioctl::ioctl_with_ptr;
 use crate::ioctl::ioctl_with_val;
 
 const SNAPSHOT_PATH: &str = "/dev/snapshot";
+/// How long (in milliseconds) the kernel waits for processes to freeze.
+const FREEZE_TIMEOUT_PATH: &str = "/sys/power/pm_freeze_timeout";
 
 // Define snapshot device ioctl numbers.
 const SNAPSHOT_IOC_MAGIC: u32 = '3' as u32;
@@ -37,6 +44,7 @@ const SNAPSHOT_IOC_MAGIC: u32 = '3' as u32;
 ioctl_io_nr!(SNAPSHOT_FREEZE, SNAPSHOT_IOC_MAGIC, 1);
 ioctl_io_nr!(SNAPSHOT_UNFREEZE, SNAPSHOT_IOC_MAGIC, 2);
 ioctl_io_nr!(SNAPSHOT_ATOMIC_RESTORE, SNAPSHOT_IOC_MAGIC, 4);
+ioctl_io_nr!(SNAPSHOT_FREE, SNAPSHOT_IOC_MAGIC, 5);
 ioctl_ior_nr!(SNAPSHOT_GET_IMAGE_SIZE, SNAPSHOT_IOC_MAGIC, 14, u64);
 ioctl_io_nr!(SNAPSHOT_PLATFORM_SUPPORT, SNAPSHOT_IOC_MAGIC, 15);
 ioctl_io_nr!(SNAPSHOT_POWER_OFF, SNAPSHOT_IOC_MAGIC, 16);
@@ -48,6 +56,7 @@ ioctl_io_nr!(SNAPSHOT_RELEASE_BLOCK_DEVICE, SNAPSHOT_IOC_MAGIC, 42);
 const FREEZE: u64 = SNAPSHOT_FREEZE();
 const UNFREEZE: u64 = SNAPSHOT_UNFREEZE();
 const ATOMIC_RESTORE: u64 = SNAPSHOT_ATOMIC_RESTORE();
+const FREE: u64 = SNAPSHOT_FREE();
 const GET_IMAGE_SIZE: u64 = SNAPSHOT_GET_IMAGE_SIZE();
 const PLATFORM_SUPPORT: u64 = SNAPSHOT_PLATFORM_SUPPORT();
 const POWER_OFF: u64 = SNAPSHOT_POWER_OFF();
@@ -121,6 +130,50 @@ impl SnapshotDevice {
         Ok(FrozenUserspaceTicket { snap_dev: self })
     }
 
+    /// Freeze userspace like freeze_userspace(), but fail with
+    /// HibernateError::FreezeTimeout if some processes don't freeze within
+    /// `timeout`. Userspace is thawed again on failure.
+    pub fn freeze_userspace_with_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<FrozenUserspaceTicket> {
+        let path = Path::new(FREEZE_TIMEOUT_PATH);
+        let previous = match set_freeze_timeout(path, timeout) {
+            Ok(previous) => Some(previous),
+            Err(e) => {
+                warn!("Failed to set the freeze timeout: {:?}", e);
+                None
+            }
+        };
+
+        // This is safe because the ioctl doesn't modify memory in a way that
+        // violates Rust's guarantees.
+        let result = unsafe { self.simple_ioctl(FREEZE, "FREEZE") };
+
+        if let Some(previous) = previous {
+            if let Err(e) = fs::write(path, previous) {
+                warn!("Failed to restore the freeze timeout: {}", e);
+            }
+        }
+
+        if let Err(e) = result {
+            // The kernel thaws the processes it froze when giving up, make sure
+            // nothing is left frozen anyway.
+            if let Err(unfreeze_err) = self.unfreeze_userspace() {
+                error!("Failed to unfreeze userspace: {:?}", unfreeze_err);
+            }
+
+            return match e.downcast_ref::<HibernateError>() {
+                Some(HibernateError::SnapshotIoctlError(_, nix::Error::EBUSY)) => {
+                    Err(HibernateError::FreezeTimeout(timeout).into())
+                }
+                _ => Err(e),
+            };
+        }
+
+        Ok(FrozenUserspaceTicket { snap_dev: self })
+    }
+
     /// Unfreeze userspace, resuming all other previously frozen userspace
     /// processes.
     pub fn unfreeze_userspace(&mut self) -> Result<()> {
@@ -148,6 +201,15 @@ impl SnapshotDevice {
         Ok(in_suspend != 0)
     }
 
+    /// Release the snapshot image created by atomic_snapshot() without
+    /// writing it out. This must be called before unfreezing userspace when
+    /// a snapshot is abandoned.
+    pub fn free_image(&mut self) -> Result<()> {
+        // This is safe because the ioctl only releases kernel memory that
+        // holds the image.
+        unsafe { self.simple_ioctl(FREE, "FREE") }
+    }
+
     pub fn set_block_device(&mut self, path: &Path) -> Result<()> {
         let dev_id = get_device_id(path)?;
         unsafe {
@@ -258,6 +320,14 @@ impl SnapshotDevice {
     }
 }
 
+/// Reading from the snapshot device after atomic_snapshot() returns the
+/// contents of the snapshot image.
+impl Read for SnapshotDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
 /// A structure that wraps the SnapshotDevice, and unfreezes userspace when
 /// dropped.
 pub struct FrozenUserspaceTicket<'a> {
@@ -278,3 +348,40 @@ impl<'a> AsMut<SnapshotDevice> for FrozenUserspaceTicket<'a> {
         self.snap_dev
     }
 }
+
+/// Set the freeze timeout in `path` to `timeout`, returning the previous
+/// contents of the file.
+fn set_freeze_timeout(path: &Path, timeout: Duration) -> Result<String> {
+    let previous =
+        fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    fs::write(path, timeout.as_millis().to_string())
+        .context(format!("Failed to write {}", path.display()))?;
+    debug!(
+        "Set freeze timeout to {}ms, was {}ms",
+        timeout.as_millis(),
+        previous.trim()
+    );
+
+    Ok(previous)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_set_freeze_timeout() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let path = dir.join("pm_freeze_timeout");
+        fs::write(&path, "20000\n").unwrap();
+
+        let previous = set_freeze_timeout(&path, Duration::from_secs(5)).unwrap();
+        assert_eq!(previous, "20000\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "5000");
+
+        assert!(set_freeze_timeout(&dir.join("missing"), Duration::from_secs(5)).is_err());
+    }
+}
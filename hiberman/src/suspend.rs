@@ -4,7 +4,17 @@
 
 //! Implements hibernate suspend functionality.
 
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::mem;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::sync::RwLockReadGuard;
 use std::thread;
 use std::time::Duration;
@@ -21,8 +31,16 @@ use log::error;
 use log::info;
 use log::warn;
 
+use crate::cmdline::read_cmdline;
+use crate::cmdline::save_cmdline_hash;
+use crate::cookie::get_hibernate_cookie;
 use crate::cookie::set_hibernate_cookie;
 use crate::cookie::HibernateCookieValue;
+use crate::device_mapper::DeviceMapper;
+use crate::files::check_low_disk_threshold;
+use crate::files::delete_image_files_if_disk_full;
+use crate::files::has_room_for_image_with;
+use crate::files::HIBERMETA_DIR;
 use crate::hiberlog;
 use crate::hiberlog::redirect_log;
 use crate::hiberlog::redirect_log_to_file;
@@ -30,6 +48,7 @@ use crate::hiberlog::replay_logs;
 use crate::hiberlog::reset_log;
 use crate::hiberlog::HiberlogOut;
 use crate::hiberlog::LogRedirectGuard;
+use crate::hiberutil::check_swappiness;
 use crate::hiberutil::get_kernel_restore_time;
 use crate::hiberutil::get_ram_size;
 use crate::hiberutil::intel_keylocker_enabled;
@@ -38,19 +57,60 @@ use crate::hiberutil::prealloc_mem;
 use crate::hiberutil::HibernateError;
 use crate::hiberutil::HibernateOptions;
 use crate::hiberutil::HibernateStage;
+use crate::hiberutil::PowerDownMode;
+use crate::hiberutil::Swappiness;
 use crate::hiberutil::TimestampFile;
+use crate::hiberutil::DEFAULT_SUSPEND_SWAPPINESS;
+use crate::image_info::check_annotation;
+use crate::image_info::ImageInfo;
+use crate::image_info::StorageKind;
+use crate::image_info::IMAGE_INFO_VERSION;
 use crate::metrics::read_and_send_metrics;
 use crate::metrics::DurationMetricUnit;
 use crate::metrics::HibernateEvent;
+use crate::metrics::PhaseTimer;
 use crate::metrics::METRICS_LOGGER;
+use crate::mmapbuf::MmapBuffer;
+use crate::profile::sample_compressibility;
+use crate::profile::ProfileReport;
+use crate::progress::block_device_bytes_written;
+use crate::progress::block_device_stat_path;
+use crate::progress::ProgressCallback;
+use crate::progress::ProgressMonitor;
+use crate::progress::PROGRESS_INTERVAL;
 use crate::snapdev::FrozenUserspaceTicket;
 use crate::snapdev::SnapshotDevice;
 use crate::snapdev::SnapshotMode;
-use crate::update_engine::is_update_engine_idle;
+use crate::trace::TRACER;
+use crate::update_engine::get_update_engine_status;
+use crate::update_engine::UpdateEngineStatus;
+use crate::vboot::VbootState;
 use crate::volume::ActiveMount;
 use crate::volume::VolumeManager;
 use crate::volume::VOLUME_MANAGER;
 
+/// Number of bytes of the snapshot image run through the compressibility
+/// sampler during a profiling run.
+const PROFILE_SAMPLE_WINDOW: u64 = 256 * 1024 * 1024;
+
+/// Number of bytes written to the scratch file during a profiling run to
+/// measure the write throughput.
+const PROFILE_WRITE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How long the post-resume hook may run before it is killed.
+const POST_RESUME_HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to check whether the post-resume hook exited.
+const POST_RESUME_HOOK_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How often to check whether the update engine went idle while waiting for
+/// it.
+const UPDATE_ENGINE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many times to try powering off after the image was written.
+const POWER_OFF_ATTEMPTS: u32 = 3;
+/// How long to wait before trying to power off again.
+const POWER_OFF_RETRY_DELAY: Duration = Duration::from_millis(500);
+
 /// Reason why an attempt to suspend was aborted
 /// Values need to match CrosHibernateAbortReason in Chromium's enums.xml
 enum SuspendAbortReason {
@@ -64,12 +124,53 @@ enum SuspendAbortReason {
     Count = 5,
 }
 
+/// Outcome of an attempt to suspend, logged once per attempt
+/// Values need to match CrosHibernateSuspendOutcome in Chromium's enums.xml
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SuspendOutcome {
+    // These values are persisted to logs. Entries should not be renumbered and
+    // numeric values should never be reused.
+    Success = 0,
+    Other = 1,
+    NoHiberimage = 2,
+    InsufficientDiskSpace = 3,
+    UpdateEngineBusy = 4,
+    InsufficientFreeMemory = 5,
+    FreezeTimeout = 6,
+    Cancelled = 7,
+    Count = 8,
+}
+
+impl SuspendOutcome {
+    /// Classify the error that failed an attempt to suspend.
+    fn from_error(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<HibernateError>() {
+            Some(HibernateError::NoHiberimageError()) => Self::NoHiberimage,
+            Some(HibernateError::InsufficientDiskSpaceError()) => Self::InsufficientDiskSpace,
+            Some(HibernateError::UpdateEngineBusyError(_)) => Self::UpdateEngineBusy,
+            Some(HibernateError::InsufficientMemoryAvailableError())
+            | Some(HibernateError::SnapshotIoctlError(_, nix::Error::ENOMEM)) => {
+                Self::InsufficientFreeMemory
+            }
+            Some(HibernateError::FreezeTimeout(_)) => Self::FreezeTimeout,
+            Some(HibernateError::Cancelled(_)) => Self::Cancelled,
+            _ => Self::Other,
+        }
+    }
+}
+
 /// The SuspendConductor weaves a delicate baton to guide us through the
 /// symphony of hibernation.
 pub struct SuspendConductor<'a> {
     options: HibernateOptions,
     volume_manager: RwLockReadGuard<'a, VolumeManager>,
     timestamp_resumed: Option<Duration>,
+    phase_timer: PhaseTimer,
+    progress: Option<ProgressCallback>,
+    /// The progress callback along with the stat file of the hiberimage
+    /// device, resolved while userspace can still spawn processes.
+    write_progress: Option<(ProgressCallback, PathBuf)>,
+    cancel: Option<Arc<AtomicBool>>,
 }
 
 impl SuspendConductor<'_> {
@@ -79,20 +180,63 @@ impl SuspendConductor<'_> {
             options: Default::default(),
             volume_manager: VOLUME_MANAGER.read().unwrap(),
             timestamp_resumed: None,
+            phase_timer: PhaseTimer::default(),
+            progress: None,
+            write_progress: None,
+            cancel: None,
         })
     }
 
+    /// Abandon the hibernate attempt if `cancel` gets set before the
+    /// snapshot is taken. Once the snapshot is taken hibernate is committed
+    /// and `cancel` is ignored.
+    pub fn set_cancel_token(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = Some(cancel);
+    }
+
     /// Public entry point that hibernates the system, and returns either upon
     /// failure to hibernate or after the system has resumed from a successful
-    /// hibernation.
-    pub fn hibernate(&mut self, options: HibernateOptions) -> Result<()> {
+    /// hibernation. If given, `progress` is called periodically while the
+    /// image is written.
+    pub fn hibernate(
+        &mut self,
+        options: HibernateOptions,
+        progress: Option<ProgressCallback>,
+    ) -> Result<()> {
+        if let Some(annotation) = &options.annotation {
+            check_annotation(annotation)?;
+        }
+        if let Some(swappiness) = options.swappiness {
+            check_swappiness(swappiness)?;
+        }
+        check_low_disk_threshold(options.low_disk_threshold_percent)?;
+
         self.options = options;
+        self.progress = progress;
+
+        if self.options.trace_path.is_some() {
+            TRACER.lock().unwrap().enable();
+        }
 
         info!("Beginning hibernate");
 
         log_metric_event(HibernateEvent::SuspendAttempt);
 
-        if let Err(e) = self.hibernate_inner() {
+        let result = self.hibernate_inner();
+
+        if let Some(trace_path) = &self.options.trace_path {
+            if let Err(e) = TRACER.lock().unwrap().write_to_file(trace_path) {
+                warn!("Failed to write hibernate trace: {:?}", e);
+            }
+        }
+
+        if let Err(e) = result {
+            // Failing after resuming is not a failure to suspend, the success
+            // was already logged before powering off.
+            if self.timestamp_resumed.is_none() {
+                Self::log_suspend_outcome(SuspendOutcome::from_error(&e));
+            }
+
             let _hibermeta_mount = self.volume_manager.mount_hibermeta()?;
 
             log_metric_event(HibernateEvent::SuspendFailure);
@@ -105,10 +249,88 @@ impl SuspendConductor<'_> {
         Ok(())
     }
 
+    /// Public entry point for a profiling run. This takes a snapshot, runs the
+    /// compressibility sampler over it and measures the write throughput with
+    /// the scratch file at `scratch_path`, then discards the snapshot and
+    /// thaws userspace again. The system is never powered off, and the
+    /// scratch file is removed again.
+    pub fn profile_run(&mut self, scratch_path: &Path) -> Result<ProfileReport> {
+        info!("Beginning hibernate profiling run");
+
+        let _hibermeta_mount = self.volume_manager.setup_hibermeta_lv(true)?;
+
+        let mut scratch_file = create_profile_scratch_file(scratch_path)?;
+        let write_buffer = MmapBuffer::new(PROFILE_WRITE_SIZE)?;
+
+        let log_file_path = hiberlog::LogFile::get_path(HibernateStage::Suspend);
+        let log_file = hiberlog::LogFile::create(log_file_path)?;
+        let redirect_guard = redirect_log_to_file(log_file);
+
+        let result = Self::profile_snapshot(&mut scratch_file, &write_buffer);
+
+        mem::drop(redirect_guard);
+        redirect_log(HiberlogOut::Syslog);
+        replay_logs(false, true);
+
+        mem::drop(scratch_file);
+        if let Err(e) = fs::remove_file(scratch_path) {
+            warn!("Failed to remove {}: {}", scratch_path.display(), e);
+        }
+
+        let report = result?;
+        info!("Hibernate profiling run complete:\n{}", report);
+
+        Ok(report)
+    }
+
+    /// Take a snapshot for a profiling run and measure it. The snapshot is
+    /// always released again before userspace is thawed.
+    fn profile_snapshot(
+        scratch_file: &mut File,
+        write_buffer: &MmapBuffer,
+    ) -> Result<ProfileReport> {
+        let mut snap_dev = SnapshotDevice::new(SnapshotMode::Read)?;
+        info!("Freezing userspace");
+        let mut frozen_userspace = snap_dev.freeze_userspace()?;
+        let snap_dev = frozen_userspace.as_mut();
+
+        if !snap_dev.atomic_snapshot()? {
+            // Nothing should ever restore a profiling snapshot.
+            return Err(HibernateError::SnapshotError(
+                "Unexpectedly resumed from profiling snapshot".to_string(),
+            ))
+            .context("Failed to profile snapshot");
+        }
+
+        let report = Self::measure_snapshot(snap_dev, scratch_file, write_buffer);
+
+        // Userspace can't be thawed while the snapshot image exists.
+        snap_dev.free_image()?;
+
+        report
+    }
+
+    /// Run the compressibility sampler over the snapshot image and measure
+    /// the write throughput with the scratch file.
+    fn measure_snapshot(
+        snap_dev: &mut SnapshotDevice,
+        scratch_file: &mut File,
+        write_buffer: &MmapBuffer,
+    ) -> Result<ProfileReport> {
+        let image_size = snap_dev.get_image_size()?;
+        let sample = sample_compressibility(snap_dev, PROFILE_SAMPLE_WINDOW)?;
+        let write_throughput = measure_write_throughput(scratch_file, write_buffer.u8_slice())?;
+
+        Ok(ProfileReport::new(image_size, sample, write_throughput))
+    }
+
     /// Hibernates the system, and returns either upon failure to hibernate or
     /// after the system has resumed from a successful hibernation.
     fn hibernate_inner(&mut self) -> Result<()> {
-        let hibermeta_mount = self.volume_manager.setup_hibermeta_lv(true)?;
+        let hibermeta_mount = self.phase_timer.time("SetupHibermeta", || {
+            self.volume_manager.setup_hibermeta_lv(true)
+        })?;
+        check_cancelled(self.cancel.as_deref(), "after setting up hibermeta")?;
 
         if !self.volume_manager.hiberimage_exists() {
             Self::log_suspend_abort(SuspendAbortReason::NoHiberimage);
@@ -116,15 +338,18 @@ impl SuspendConductor<'_> {
             return Err(HibernateError::NoHiberimageError().into());
         }
 
+        if let Some(progress) = self.progress.take() {
+            let hiberimage = DeviceMapper::device_path(VolumeManager::HIBERIMAGE)?;
+            self.write_progress = Some((progress, block_device_stat_path(&hiberimage)?));
+        }
+
         if !self.volume_manager.is_hiberimage_thickened()? {
-            let free_thinpool_space = self.volume_manager.get_free_thinpool_space()?;
             // The max image size is half of the system RAM, add a bit of margin.
-            if free_thinpool_space < (get_ram_size() as f64 * 0.75) as u64 {
-                warn!(
-                    "Not enough space ({} MB) in the thinpool for writing the hibernate image",
-                    free_thinpool_space / (1024 * 1024)
-                );
-
+            let max_image_size = (get_ram_size() as f64 * 0.75) as u64;
+            if !has_room_for_image_with(
+                || self.volume_manager.get_free_thinpool_space(),
+                max_image_size,
+            )? {
                 Self::log_suspend_abort(SuspendAbortReason::InsufficientDiskSpace);
                 return Err(HibernateError::InsufficientDiskSpaceError().into());
             }
@@ -135,11 +360,25 @@ impl SuspendConductor<'_> {
         // While an update is "pending reboot", the update engine might do
         // further checks for updates it can apply. So no state except idle is
         // safe.
-        if !is_update_engine_idle()? {
+        let update_engine_status = wait_for_update_engine_idle(
+            get_update_engine_status,
+            self.options.update_engine_wait,
+            UPDATE_ENGINE_POLL_INTERVAL,
+            self.cancel.as_deref(),
+        )?;
+        if update_engine_status != UpdateEngineStatus::Idle {
             Self::log_suspend_abort(SuspendAbortReason::UpdateEngineActive);
-            return Err(HibernateError::UpdateEngineBusyError()).context("Update engine is active");
+            return Err(HibernateError::UpdateEngineBusyError(
+                update_engine_status.to_string(),
+            ))
+            .context("Update engine is active");
         }
 
+        // Collect what is recorded on hibermeta while userspace still runs,
+        // crossystem would be frozen along with it.
+        let cmdline = read_cmdline()?;
+        let vboot_state = VbootState::current()?;
+
         // Stop logging to syslog, and divert instead to a file since the
         // logging daemon's about to be frozen.
         let log_file_path = hiberlog::LogFile::get_path(HibernateStage::Suspend);
@@ -147,14 +386,38 @@ impl SuspendConductor<'_> {
         let redirect_guard = redirect_log_to_file(log_file);
 
         debug!("Syncing filesystems");
-        // This is safe because sync() does not modify memory.
-        unsafe {
-            libc::sync();
+        self.phase_timer.time("Sync", || {
+            // This is safe because sync() does not modify memory.
+            unsafe {
+                libc::sync();
+            }
+        });
+
+        // The previous swappiness is restored when hibernate_inner() returns,
+        // i.e. after resume or a failed hibernate.
+        let _swappiness = Swappiness::set_swappiness(
+            self.options
+                .swappiness
+                .unwrap_or(DEFAULT_SUSPEND_SWAPPINESS),
+        )?;
+
+        let preallocated = self
+            .phase_timer
+            .time("PreallocateMemory", prealloc_mem)
+            .context("Failed to preallocate memory for hibernate")?;
+        if !preallocated.is_complete() {
+            warn!(
+                "Hibernating under memory pressure, {} of {} MB needed are free",
+                preallocated.free / (1024 * 1024),
+                preallocated.target / (1024 * 1024)
+            );
         }
 
-        prealloc_mem().context("Failed to preallocate memory for hibernate")?;
+        if self.options.validate_only {
+            return self.finish_validation(hibermeta_mount, redirect_guard);
+        }
 
-        let result = self.suspend_system(hibermeta_mount, redirect_guard);
+        let result = self.suspend_system(hibermeta_mount, redirect_guard, &cmdline, &vboot_state);
 
         if result.is_ok() {
             log_metric_event(HibernateEvent::ResumeSuccess);
@@ -177,12 +440,84 @@ impl SuspendConductor<'_> {
         // Read the metrics files and send out the samples.
         read_and_send_metrics();
 
+        let resumed = result.is_ok() && self.timestamp_resumed.is_some();
+        post_resume(
+            resumed,
+            self.options.post_resume_hook.as_deref(),
+            POST_RESUME_HOOK_TIMEOUT,
+        );
+
+        if let Err(e) = self.delete_data_if_disk_full() {
+            warn!("Failed to delete hibernate data: {:?}", e);
+        }
+
         result
     }
 
+    /// Called instead of suspend_system() when only validating that the
+    /// system could hibernate. Flushes the metrics of the setup phases and
+    /// replays the logs, without ever freezing userspace or taking a
+    /// snapshot.
+    ///
+    /// The order of the `hibermeta_mount` and `log_redirect_guard` parameters
+    /// must not be changed!!!
+    fn finish_validation(
+        &mut self,
+        hibermeta_mount: ActiveMount,
+        log_redirect_guard: LogRedirectGuard,
+    ) -> Result<()> {
+        info!("Hibernate setup validated, not taking a snapshot");
+        {
+            let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
+            if self.options.phase_metrics {
+                self.phase_timer.log_samples(&mut metrics_logger);
+            }
+            metrics_logger.flush_or_disable(self.options.metrics_optional)?;
+        }
+
+        mem::drop(log_redirect_guard);
+        redirect_log(HiberlogOut::Syslog);
+        replay_logs(false, true);
+        mem::drop(hibermeta_mount);
+
+        read_and_send_metrics();
+        Ok(())
+    }
+
+    /// Delete the data of the last hibernate image, including the
+    /// 'hiberimage' volume, if the disk is almost full. Called after a resume
+    /// or a failed hibernate. Nothing is deleted while the cookie says the
+    /// image may still be resumed. The 'hiberimage' volume is set up again on
+    /// the next boot.
+    fn delete_data_if_disk_full(&self) -> Result<()> {
+        let block_path = path_to_stateful_block()?;
+        let cookie =
+            get_hibernate_cookie(Some(&block_path)).context("Failed to get hibernate cookie")?;
+        if cookie != HibernateCookieValue::NoResume {
+            return Ok(());
+        }
+
+        let free_percent = self.volume_manager.get_free_thinpool_percent()?;
+        if !delete_image_files_if_disk_full(
+            Path::new(HIBERMETA_DIR),
+            free_percent,
+            self.options.low_disk_threshold_percent,
+        ) {
+            return Ok(());
+        }
+
+        if self.volume_manager.hiberimage_exists() {
+            SnapshotDevice::new(SnapshotMode::Read)?.release_block_device()?;
+            self.volume_manager.teardown_hiberimage()?;
+        }
+
+        Ok(())
+    }
+
     /// Inner helper function to actually take the snapshot, save it to disk,
     /// and shut down. Returns upon a failure to hibernate, or after a
-    /// successful hibernation has resumed.
+    /// successful hibernation has resumed. The kernel command line `cmdline`
+    /// and `vboot_state` are recorded on hibermeta for the resume checks.
     ///
     /// The order of the `hibermeta_mount` and `log_redirect_guard` parameters
     /// must not be changed!!!
@@ -190,24 +525,47 @@ impl SuspendConductor<'_> {
         &mut self,
         mut hibermeta_mount: ActiveMount,
         log_redirect_guard: LogRedirectGuard,
+        cmdline: &str,
+        vboot_state: &VbootState,
     ) -> Result<()> {
+        // Memory is preallocated but nothing is frozen and the cookie is
+        // untouched.
+        check_cancelled(self.cancel.as_deref(), "before freezing userspace")?;
+
         let mut snap_dev = SnapshotDevice::new(SnapshotMode::Read)?;
         info!("Freezing userspace");
-        let frozen_userspace = snap_dev.freeze_userspace()?;
+        let snap_dev_ref = &mut snap_dev;
+        let freeze_timeout = self.options.freeze_timeout;
+        let frozen_userspace = self.phase_timer.time("FreezeUserspace", move || {
+            snap_dev_ref.freeze_userspace_with_timeout(freeze_timeout)
+        })?;
 
         {
             let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
-            metrics_logger.flush()?;
+            metrics_logger.flush_or_disable(self.options.metrics_optional)?;
         }
 
+        self.phase_timer.time("WriteMetadata", || -> Result<()> {
+            save_cmdline_hash(Path::new(HIBERMETA_DIR), cmdline)?;
+            vboot_state.save(Path::new(HIBERMETA_DIR))
+        })?;
+
         mem::drop(log_redirect_guard);
         hibermeta_mount.unmount()?;
 
-        self.volume_manager.thicken_hiberimage()?;
+        // Last chance to back out, the cookie is still untouched and
+        // userspace is thawed when `frozen_userspace` gets dropped.
+        let volume_manager = &self.volume_manager;
+        let cancel = self.cancel.as_deref();
+        self.phase_timer.time("ThickenHiberimage", || {
+            preallocate_hiberimage(|| volume_manager.thicken_hiberimage(), cancel)
+        })?;
 
         // Make sure the thinpool has time to commit pending metadata changes
         // to disk. The thinpool workqueue does this every second.
-        thread::sleep(Duration::from_millis(1100));
+        self.phase_timer.time("ThinpoolCommit", || {
+            thread::sleep(Duration::from_millis(1100))
+        });
 
         if let Err(e) = self.snapshot_and_save(frozen_userspace) {
             if let Some(HibernateError::SnapshotIoctlError(_, err)) = e.downcast_ref() {
@@ -229,74 +587,23 @@ impl SuspendConductor<'_> {
     /// resumed.
     fn snapshot_and_save(&mut self, mut frozen_userspace: FrozenUserspaceTicket) -> Result<()> {
         let block_path = path_to_stateful_block()?;
-        let dry_run = self.options.dry_run;
         let snap_dev = frozen_userspace.as_mut();
 
         let timestamp_hibernated = UNIX_EPOCH.elapsed().unwrap_or(Duration::ZERO);
 
         // This is where the suspend path and resume path fork. On success,
         // both halves of these conditions execute, just at different times.
+        let snapshot_start = Instant::now();
         if snap_dev.atomic_snapshot()? {
             // Suspend path. Everything after this point is invisible to the
             // hibernated kernel.
-
-            // Briefly remount 'hibermeta' to write logs and metrics.
-            let mut hibermeta_mount = self.volume_manager.mount_hibermeta()?;
-            let log_file_path = hiberlog::LogFile::get_path(HibernateStage::Suspend);
-            let log_file = hiberlog::LogFile::open(log_file_path)?;
-            let redirect_guard = redirect_log_to_file(log_file);
-
-            let start = Instant::now();
-
-            if let Err(e) = snap_dev.transfer_block_device() {
-                snap_dev.unfreeze_userspace()?;
-                return Err(e);
-            }
-
-            let io_duration = start.elapsed();
-
-            log_metric_event(HibernateEvent::SuspendSuccess);
-
-            {
-                let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
-
-                metrics_logger.metrics_send_io_sample(
-                    "WriteHibernateImage",
-                    snap_dev.get_image_size()?,
-                    io_duration,
-                );
-
-                // Flush the metrics file before unmounting hibermeta. The metrics will be
-                // sent on resume.
-                metrics_logger.flush()?;
-            }
-
-            // Set the hibernate cookie so the next boot knows to start in RO mode.
-            info!("Setting hibernate cookie at {}", block_path);
-            set_hibernate_cookie(Some(&block_path), HibernateCookieValue::ResumeReady)?;
-            if dry_run {
-                info!("Not powering off due to dry run");
-            } else {
-                info!("Powering off");
-            }
-
-            mem::drop(redirect_guard);
-            hibermeta_mount.unmount()?;
-
-            // Power the thing down.
-            if !dry_run {
-                if !self.options.reboot {
-                    if intel_keylocker_enabled()? {
-                        snap_dev.power_off_platform_mode()?;
-                    } else {
-                        Self::power_off()?;
-                    }
-
-                    error!("Returned from power off");
-                } else {
-                    Self::reboot()?;
-                    error!("Returned from reboot");
-                }
+            let snapshot_duration = snapshot_start.elapsed();
+            if let Err(e) = self.save_snapshot(snap_dev, &block_path, snapshot_duration) {
+                return Err(abandon_snapshot(
+                    snap_dev,
+                    || set_hibernate_cookie(Some(&block_path), HibernateCookieValue::NoResume),
+                    e,
+                ));
             }
         } else {
             self.timestamp_resumed = Some(UNIX_EPOCH.elapsed().unwrap_or(Duration::ZERO));
@@ -348,6 +655,121 @@ impl SuspendConductor<'_> {
             .context("Failed to clear hibernate cookie")
     }
 
+    /// Write the snapshot that was just taken to disk, set the cookie and
+    /// power down. Returns upon failure, or if powering down is skipped.
+    fn save_snapshot(
+        &mut self,
+        snap_dev: &mut SnapshotDevice,
+        block_path: &str,
+        snapshot_duration: Duration,
+    ) -> Result<()> {
+        let dry_run = self.options.dry_run;
+
+        // Briefly remount 'hibermeta' to write logs and metrics.
+        let mut hibermeta_mount = self.volume_manager.mount_hibermeta()?;
+        let log_file_path = hiberlog::LogFile::get_path(HibernateStage::Suspend);
+        let log_file = hiberlog::LogFile::open(log_file_path)?;
+        let redirect_guard = redirect_log_to_file(log_file);
+        self.phase_timer.record("Snapshot", snapshot_duration);
+
+        if is_cancelled(self.cancel.as_deref()) {
+            warn!("Ignoring cancellation, the snapshot was already taken");
+        }
+
+        let start = Instant::now();
+
+        let mut progress_monitor = match self.write_progress.take() {
+            Some((callback, stat_path)) => {
+                Some(Self::start_progress_monitor(snap_dev, callback, stat_path)?)
+            }
+            None => None,
+        };
+        let result = self
+            .phase_timer
+            .time("WriteImage", || snap_dev.transfer_block_device());
+        // Don't report any progress past the write.
+        if let Some(monitor) = progress_monitor.as_mut() {
+            monitor.stop();
+        }
+
+        result?;
+
+        let io_duration = start.elapsed();
+        let image_size = snap_dev.get_image_size()?;
+
+        log_metric_event(HibernateEvent::SuspendSuccess);
+        Self::log_suspend_outcome(SuspendOutcome::Success);
+
+        self.record_image_info(block_path, image_size, io_duration);
+
+        {
+            let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
+
+            metrics_logger.metrics_send_io_sample("WriteHibernateImage", image_size, io_duration);
+
+            if self.options.phase_metrics {
+                self.phase_timer.log_samples(&mut metrics_logger);
+            }
+
+            // Flush the metrics file before unmounting hibermeta. The metrics will be
+            // sent on resume.
+            metrics_logger.flush_or_disable(self.options.metrics_optional)?;
+        }
+
+        // Set the hibernate cookie so the next boot knows to start in RO mode.
+        info!("Setting hibernate cookie at {}", block_path);
+        set_hibernate_cookie(Some(block_path), HibernateCookieValue::ResumeReady)?;
+        if dry_run {
+            info!("Not powering off due to dry run");
+        } else {
+            info!("Powering off");
+        }
+
+        mem::drop(redirect_guard);
+        hibermeta_mount.unmount()?;
+
+        // Power the thing down.
+        if !dry_run {
+            if !self.options.reboot {
+                // Key Locker keys only survive the platform hibernate state.
+                if self.options.power_down_mode == PowerDownMode::Platform
+                    || intel_keylocker_enabled()?
+                {
+                    snap_dev.power_off_platform_mode()?;
+                } else {
+                    Self::power_off()?;
+                }
+
+                error!("Returned from power off");
+            } else {
+                Self::reboot()?;
+                error!("Returned from reboot");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start reporting the progress of writing the image to `callback`, from
+    /// the number of bytes written to the hiberimage device.
+    fn start_progress_monitor(
+        snap_dev: &mut SnapshotDevice,
+        callback: ProgressCallback,
+        stat_path: PathBuf,
+    ) -> Result<ProgressMonitor> {
+        let total = snap_dev.get_image_size()?;
+        let mut read_bytes_written = block_device_bytes_written(stat_path);
+        // The device may have been written to before, e.g. by a profiling run.
+        let baseline = read_bytes_written()?;
+
+        Ok(ProgressMonitor::new(
+            move || Ok(read_bytes_written()?.saturating_sub(baseline)),
+            total,
+            callback,
+            PROGRESS_INTERVAL,
+        ))
+    }
+
     /// Record the total resume time.
     fn record_total_resume_time(&self) {
         if self.timestamp_resumed.is_none() {
@@ -388,16 +810,25 @@ impl SuspendConductor<'_> {
         );
     }
 
-    /// Utility function to power the system down immediately.
+    /// Utility function to power the system down immediately. Powering off
+    /// is retried a few times, as staying up after the image was written is
+    /// the worst outcome.
     fn power_off() -> Result<()> {
-        // This is safe because the system either ceases to exist, or does
-        // nothing to memory.
-        unsafe {
-            // On success, we shouldn't be executing, so the return code can be
-            // ignored because we already know it's a failure.
-            let _ = reboot(RB_POWER_OFF);
-            Err(HibernateError::ShutdownError(nix::Error::last())).context("Failed to shut down")
-        }
+        power_off_with_retries(
+            || {
+                // This is safe because the system either ceases to exist, or
+                // does nothing to memory.
+                unsafe {
+                    // On success, we shouldn't be executing, so the return
+                    // code can be ignored because we already know it's a
+                    // failure.
+                    let _ = reboot(RB_POWER_OFF);
+                }
+                nix::Error::last()
+            },
+            POWER_OFF_ATTEMPTS,
+            POWER_OFF_RETRY_DELAY,
+        )
     }
 
     /// Utility function to reboot the system immediately.
@@ -421,6 +852,208 @@ impl SuspendConductor<'_> {
             SuspendAbortReason::Count as isize - 1,
         );
     }
+
+    fn log_suspend_outcome(outcome: SuspendOutcome) {
+        let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
+
+        metrics_logger.log_enum_metric(
+            "Platform.Hibernate.SuspendOutcome",
+            outcome as isize,
+            SuspendOutcome::Count as isize - 1,
+        );
+    }
+
+    /// Record the size of the image and the measured write throughput on
+    /// hibermeta, for estimating the resume duration, along with the
+    /// annotation.
+    fn record_image_info(&self, block_path: &str, image_size: u64, io_duration: Duration) {
+        let image_info = ImageInfo {
+            version: IMAGE_INFO_VERSION,
+            image_size,
+            write_throughput: (image_size as f64 / io_duration.as_secs_f64()) as u64,
+            storage_kind: StorageKind::from_block_device(block_path),
+            write_duration_ms: io_duration.as_millis() as u64,
+            annotation: self.options.annotation.clone(),
+            suspend_timestamp: UNIX_EPOCH.elapsed().ok().map(|now| now.as_secs()),
+        };
+
+        if let Err(e) = image_info.save(Path::new(HIBERMETA_DIR)) {
+            warn!("Failed to record image info: {:?}", e);
+        }
+    }
+}
+
+/// Run the post-resume hook `hook`, if any, once hibernate_inner() `resumed`
+/// from a successful hibernation. Only closing the snapshot device thaws
+/// userspace after a resume, before that the hook would be frozen along with
+/// it. A failing hook is only logged.
+fn post_resume(resumed: bool, hook: Option<&Path>, timeout: Duration) {
+    let hook = match hook {
+        Some(hook) if resumed => hook,
+        _ => return,
+    };
+
+    if let Err(e) = run_post_resume_hook(hook, timeout) {
+        warn!("Post-resume hook failed: {:?}", e);
+    }
+}
+
+/// Run the post-resume hook and wait for it to exit. The hook is killed if it
+/// doesn't exit within `timeout`, so that it can't hold up the resume.
+fn run_post_resume_hook(hook: &Path, timeout: Duration) -> Result<()> {
+    info!("Running post-resume hook {}", hook.display());
+    let mut child = Command::new(hook)
+        .spawn()
+        .context(format!("Failed to spawn {}", hook.display()))?;
+
+    let start = Instant::now();
+    loop {
+        if let Some(exit_status) = child.try_wait().context("Failed to wait for hook")? {
+            if !exit_status.success() {
+                let code = exit_status.code().unwrap_or(-2);
+                return Err(HibernateError::SpawnedProcessError(code)).context(format!(
+                    "{} failed with code {}",
+                    hook.display(),
+                    code
+                ));
+            }
+
+            debug!("Post-resume hook took {:?}", start.elapsed());
+            return Ok(());
+        }
+
+        if start.elapsed() >= timeout {
+            warn!("Killing post-resume hook {}", hook.display());
+            if let Err(e) = child.kill() {
+                warn!("Failed to kill post-resume hook: {}", e);
+            }
+            let _ = child.wait();
+            return Err(HibernateError::CommandTimeoutError(format!(
+                "{} didn't exit within {:?}",
+                hook.display(),
+                timeout
+            ))
+            .into());
+        }
+
+        thread::sleep(POST_RESUME_HOOK_POLL_INTERVAL);
+    }
+}
+
+/// Call `power_off` up to `attempts` times, waiting `delay` between the
+/// attempts. `power_off` only returns if powering off failed, with the error.
+fn power_off_with_retries(
+    mut power_off: impl FnMut() -> nix::Error,
+    attempts: u32,
+    delay: Duration,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let err = power_off();
+        if attempt >= attempts {
+            return Err(HibernateError::ShutdownError(err)).context("Failed to shut down");
+        }
+
+        warn!(
+            "Failed to power off (attempt {}/{}): {}",
+            attempt, attempts, err
+        );
+        attempt += 1;
+        thread::sleep(delay);
+    }
+}
+
+fn is_cancelled(cancel: Option<&AtomicBool>) -> bool {
+    matches!(cancel, Some(cancel) if cancel.load(Ordering::SeqCst))
+}
+
+/// Fail with HibernateError::Cancelled if `cancel` is set. Called at the
+/// points where hibernate can still be abandoned cleanly.
+fn check_cancelled(cancel: Option<&AtomicBool>, checkpoint: &str) -> Result<()> {
+    if is_cancelled(cancel) {
+        info!("Hibernate cancelled {}", checkpoint);
+        return Err(HibernateError::Cancelled(checkpoint.to_string()).into());
+    }
+
+    Ok(())
+}
+
+/// Preallocate the hiberimage with `thicken`, then fail with
+/// HibernateError::Cancelled if `cancel` got set in the meantime.
+fn preallocate_hiberimage(
+    thicken: impl FnOnce() -> Result<()>,
+    cancel: Option<&AtomicBool>,
+) -> Result<()> {
+    thicken()?;
+    check_cancelled(cancel, "after preallocating the hiberimage")
+}
+
+/// Get the update engine status from `get_status` until the update engine is
+/// idle or `max_wait` has passed, and return the last status. Gives up with
+/// HibernateError::Cancelled if `cancel` gets set while waiting.
+fn wait_for_update_engine_idle(
+    mut get_status: impl FnMut() -> Result<UpdateEngineStatus>,
+    max_wait: Duration,
+    poll_interval: Duration,
+    cancel: Option<&AtomicBool>,
+) -> Result<UpdateEngineStatus> {
+    let start = Instant::now();
+    loop {
+        let status = get_status()?;
+        let elapsed = start.elapsed();
+        if status == UpdateEngineStatus::Idle || elapsed >= max_wait {
+            return Ok(status);
+        }
+
+        check_cancelled(cancel, "while waiting for the update engine")?;
+        debug!("Waiting for the update engine, status is {}", status);
+        thread::sleep(poll_interval.min(max_wait - elapsed));
+    }
+}
+
+/// The operations on a snapshot device needed to back out of a snapshot
+/// taken on the suspend path.
+trait TakenSnapshot {
+    fn free_image(&mut self) -> Result<()>;
+    fn unfreeze_userspace(&mut self) -> Result<()>;
+}
+
+impl TakenSnapshot for SnapshotDevice {
+    fn free_image(&mut self) -> Result<()> {
+        SnapshotDevice::free_image(self)
+    }
+
+    fn unfreeze_userspace(&mut self) -> Result<()> {
+        SnapshotDevice::unfreeze_userspace(self)
+    }
+}
+
+/// Back out of a snapshot that couldn't be saved, or whose power off
+/// failed, by clearing the cookie with `clear_cookie`, releasing the snapshot
+/// image and thawing userspace, then return `err`. The cookie may already
+/// say ResumeReady, and the next boot must not resume into an image older
+/// than the disk the system keeps running on. The kernel ignores requests to thaw
+/// userspace while the snapshot image exists, so dropping the
+/// FrozenUserspaceTicket alone doesn't do it.
+fn abandon_snapshot<S, C>(snap_dev: &mut S, clear_cookie: C, err: anyhow::Error) -> anyhow::Error
+where
+    S: TakenSnapshot,
+    C: FnOnce() -> Result<()>,
+{
+    warn!("Abandoning the snapshot: {:?}", err);
+    if let Err(e) = clear_cookie() {
+        error!("Failed to clear the hibernate cookie: {:?}", e);
+    }
+
+    if let Err(e) = snap_dev.free_image() {
+        error!("Failed to release the snapshot image: {:?}", e);
+    }
+
+    if let Err(e) = snap_dev.unfreeze_userspace() {
+        error!("Failed to unfreeze userspace: {:?}", e);
+    }
+
+    err
 }
 
 /// Logs a hibernate metric event.
@@ -428,3 +1061,373 @@ fn log_metric_event(event: HibernateEvent) {
     let mut metrics_logger = METRICS_LOGGER.lock().unwrap();
     metrics_logger.log_event(event);
 }
+
+/// Create the scratch file of a profiling run. It must not exist yet, so that
+/// a profiling run never overwrites existing data, like the hiberimage.
+fn create_profile_scratch_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .context(format!("Failed to create {}", path.display()))
+}
+
+/// Write `data` to `file` and flush it to the storage. Returns the write
+/// throughput in bytes per second.
+fn measure_write_throughput(file: &mut File, data: &[u8]) -> Result<u64> {
+    let start = Instant::now();
+    file.write_all(data)
+        .and_then(|_| file.sync_data())
+        .context("Failed to write to the profiling scratch file")?;
+    let write_duration = start.elapsed();
+
+    Ok((data.len() as f64 / write_duration.as_secs_f64()) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use anyhow::anyhow;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn write_hook(dir: &Path, name: &str, script: &str) -> std::path::PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_run_post_resume_hook() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let invocations = dir.join("invocations");
+
+        let hook = write_hook(
+            dir,
+            "hook",
+            &format!("echo resumed >> {}", invocations.display()),
+        );
+        run_post_resume_hook(&hook, Duration::from_secs(5)).unwrap();
+        assert_eq!(std::fs::read_to_string(&invocations).unwrap(), "resumed\n");
+
+        // A hung hook is killed once the timeout expires.
+        let hung_hook = write_hook(dir, "hung_hook", "exec sleep 60");
+        let start = Instant::now();
+        let err = run_post_resume_hook(&hung_hook, Duration::from_millis(100)).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::CommandTimeoutError(_))
+        ));
+
+        let failing_hook = write_hook(dir, "failing_hook", "exit 3");
+        let err = run_post_resume_hook(&failing_hook, Duration::from_secs(5)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::SpawnedProcessError(3))
+        ));
+    }
+
+    #[test]
+    fn test_post_resume() {
+        let temp_dir = tempdir().unwrap();
+        let dir = temp_dir.path();
+        let invocations = dir.join("invocations");
+        let hook = write_hook(
+            dir,
+            "hook",
+            &format!("echo resumed >> {}", invocations.display()),
+        );
+
+        // Failed hibernates and dry runs don't run the hook.
+        post_resume(false, Some(&hook), Duration::from_secs(5));
+        assert!(!invocations.exists());
+
+        post_resume(true, Some(&hook), Duration::from_secs(5));
+        assert_eq!(std::fs::read_to_string(&invocations).unwrap(), "resumed\n");
+
+        // A failing hook doesn't fail the resume.
+        let failing_hook = write_hook(dir, "failing_hook", "exit 3");
+        post_resume(true, Some(&failing_hook), Duration::from_secs(5));
+        post_resume(true, None, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_check_cancelled() {
+        check_cancelled(None, "checkpoint").unwrap();
+
+        let cancel = AtomicBool::new(false);
+        check_cancelled(Some(&cancel), "checkpoint").unwrap();
+
+        cancel.store(true, Ordering::SeqCst);
+        let err = check_cancelled(Some(&cancel), "checkpoint").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::Cancelled(checkpoint)) if checkpoint == "checkpoint"
+        ));
+    }
+
+    #[test]
+    fn test_preallocate_hiberimage() {
+        let cancel = AtomicBool::new(false);
+        let thickened = Cell::new(false);
+        let thicken = || {
+            thickened.set(true);
+            Ok(())
+        };
+        preallocate_hiberimage(thicken, Some(&cancel)).unwrap();
+        assert!(thickened.get());
+
+        // Cancelling while thickening abandons hibernate once it's done.
+        thickened.set(false);
+        let err = preallocate_hiberimage(
+            || {
+                cancel.store(true, Ordering::SeqCst);
+                thickened.set(true);
+                Ok(())
+            },
+            Some(&cancel),
+        )
+        .unwrap_err();
+        assert!(thickened.get());
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::Cancelled(checkpoint))
+                if checkpoint == "after preallocating the hiberimage"
+        ));
+
+        // A failure to thicken is reported as is.
+        let err = preallocate_hiberimage(
+            || Err(HibernateError::InsufficientDiskSpaceError().into()),
+            Some(&cancel),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::InsufficientDiskSpaceError())
+        ));
+    }
+
+    #[test]
+    fn test_wait_for_update_engine_idle() {
+        // Without waiting, the first status is final.
+        let calls = Cell::new(0);
+        let busy = || {
+            calls.set(calls.get() + 1);
+            Ok(UpdateEngineStatus::Unknown(1000))
+        };
+        let status = wait_for_update_engine_idle(busy, Duration::ZERO, Duration::ZERO, None);
+        assert_eq!(status.unwrap(), UpdateEngineStatus::Unknown(1000));
+        assert_eq!(calls.get(), 1);
+
+        // Becomes idle on the third check.
+        calls.set(0);
+        let status = wait_for_update_engine_idle(
+            || {
+                calls.set(calls.get() + 1);
+                if calls.get() < 3 {
+                    Ok(UpdateEngineStatus::Unknown(1000))
+                } else {
+                    Ok(UpdateEngineStatus::Idle)
+                }
+            },
+            Duration::from_secs(5),
+            Duration::from_millis(1),
+            None,
+        );
+        assert_eq!(status.unwrap(), UpdateEngineStatus::Idle);
+        assert_eq!(calls.get(), 3);
+
+        // Gives up once the time is up.
+        let start = Instant::now();
+        let status = wait_for_update_engine_idle(
+            || Ok(UpdateEngineStatus::Unknown(1000)),
+            Duration::from_millis(20),
+            Duration::from_millis(5),
+            None,
+        );
+        assert_eq!(status.unwrap(), UpdateEngineStatus::Unknown(1000));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // Cancelling stops the wait.
+        let cancel = AtomicBool::new(true);
+        let err = wait_for_update_engine_idle(
+            || Ok(UpdateEngineStatus::Unknown(1000)),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            Some(&cancel),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::Cancelled(_))
+        ));
+    }
+
+    #[test]
+    fn test_power_off_with_retries() {
+        let attempts = Cell::new(0);
+        let err = power_off_with_retries(
+            || {
+                attempts.set(attempts.get() + 1);
+                nix::Error::EBUSY
+            },
+            3,
+            Duration::ZERO,
+        )
+        .unwrap_err();
+        assert_eq!(attempts.get(), 3);
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::ShutdownError(nix::Error::EBUSY))
+        ));
+
+        // A single attempt isn't retried.
+        attempts.set(0);
+        power_off_with_retries(
+            || {
+                attempts.set(attempts.get() + 1);
+                nix::Error::EIO
+            },
+            1,
+            Duration::ZERO,
+        )
+        .unwrap_err();
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn test_suspend_outcome_from_error() {
+        let outcome = |e: HibernateError| {
+            SuspendOutcome::from_error(&anyhow::Error::from(e).context("Failed to hibernate"))
+        };
+
+        assert_eq!(
+            outcome(HibernateError::UpdateEngineBusyError(
+                "Downloading".to_string()
+            )),
+            SuspendOutcome::UpdateEngineBusy
+        );
+        assert_eq!(
+            outcome(HibernateError::FreezeTimeout(Duration::from_secs(20))),
+            SuspendOutcome::FreezeTimeout
+        );
+        assert_eq!(
+            outcome(HibernateError::InsufficientDiskSpaceError()),
+            SuspendOutcome::InsufficientDiskSpace
+        );
+        assert_eq!(
+            outcome(HibernateError::SnapshotIoctlError(
+                "Failed to snapshot".to_string(),
+                nix::Error::ENOMEM
+            )),
+            SuspendOutcome::InsufficientFreeMemory
+        );
+        assert_eq!(
+            outcome(HibernateError::SnapshotIoctlError(
+                "Failed to snapshot".to_string(),
+                nix::Error::EIO
+            )),
+            SuspendOutcome::Other
+        );
+        assert_eq!(
+            SuspendOutcome::from_error(&anyhow::anyhow!("Failed to mount hibermeta")),
+            SuspendOutcome::Other
+        );
+    }
+
+    struct MockSnapshot<'a> {
+        image: bool,
+        frozen: bool,
+        resume_ready: &'a Cell<bool>,
+        thawed_resume_ready: bool,
+    }
+
+    impl<'a> MockSnapshot<'a> {
+        fn new(resume_ready: &'a Cell<bool>) -> Self {
+            MockSnapshot {
+                image: true,
+                frozen: true,
+                resume_ready,
+                thawed_resume_ready: false,
+            }
+        }
+    }
+
+    impl TakenSnapshot for MockSnapshot<'_> {
+        fn free_image(&mut self) -> Result<()> {
+            self.image = false;
+            Ok(())
+        }
+
+        fn unfreeze_userspace(&mut self) -> Result<()> {
+            // Like the kernel, refuse to thaw while the image exists.
+            if !self.image {
+                self.frozen = false;
+                self.thawed_resume_ready = self.resume_ready.get();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_abandon_snapshot_thaws() {
+        let resume_ready = Cell::new(false);
+        let mut snap_dev = MockSnapshot::new(&resume_ready);
+        let write_error = anyhow::Error::from(HibernateError::SnapshotIoctlError(
+            "Failed to transfer block device".to_string(),
+            nix::Error::EIO,
+        ));
+
+        let err = abandon_snapshot(&mut snap_dev, || Ok(()), write_error);
+
+        assert!(!snap_dev.image);
+        assert!(!snap_dev.frozen);
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::SnapshotIoctlError(_, nix::Error::EIO))
+        ));
+    }
+
+    #[test]
+    fn test_abandon_snapshot_clears_cookie() {
+        // Powering off failed after the cookie was set.
+        let resume_ready = Cell::new(true);
+        let mut snap_dev = MockSnapshot::new(&resume_ready);
+        let power_off_error = anyhow!("Failed to power off");
+
+        abandon_snapshot(
+            &mut snap_dev,
+            || {
+                resume_ready.set(false);
+                Ok(())
+            },
+            power_off_error,
+        );
+
+        assert!(!resume_ready.get());
+        assert!(!snap_dev.frozen);
+        assert!(!snap_dev.thawed_resume_ready);
+    }
+
+    #[test]
+    fn test_profile_write_throughput() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("scratch");
+        let data = vec![0u8; 1024 * 1024];
+
+        let mut file = create_profile_scratch_file(&path).unwrap();
+        assert!(measure_write_throughput(&mut file, &data).unwrap() > 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), data.len() as u64);
+
+        // An existing file is never overwritten.
+        assert!(create_profile_scratch_file(&path).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), data.len() as u64);
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Records the hibernate phases as Chrome trace events, which can be loaded
+//! into perfetto or chrome://tracing to view the hibernate timeline.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+lazy_static! {
+    pub static ref TRACER: Mutex<Tracer> = Mutex::new(Tracer::new());
+}
+
+/// A single event in the Chrome trace event format.
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    /// Phase of the event, always "X" (complete).
+    ph: &'static str,
+    /// Timestamp of the beginning of the event in microseconds.
+    ts: u64,
+    /// Duration of the event in microseconds.
+    dur: u64,
+    pid: u32,
+    tid: u32,
+}
+
+/// Collects trace events for the hibernate phases. Nothing is recorded until
+/// the tracer is enabled.
+pub struct Tracer {
+    enabled: bool,
+    start: Instant,
+    events: Vec<TraceEvent>,
+}
+
+impl Tracer {
+    fn new() -> Self {
+        Tracer {
+            enabled: false,
+            start: Instant::now(),
+            events: vec![],
+        }
+    }
+
+    /// Start recording trace events.
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Record a phase that ended just now and lasted for `duration`.
+    pub fn record_phase(&mut self, name: &str, duration: Duration) {
+        if !self.enabled {
+            return;
+        }
+
+        let begin = self.start.elapsed().saturating_sub(duration);
+        let pid = std::process::id();
+
+        self.events.push(TraceEvent {
+            name: name.to_string(),
+            ph: "X",
+            ts: begin.as_micros() as u64,
+            dur: duration.as_micros() as u64,
+            pid,
+            tid: pid,
+        });
+    }
+
+    /// Write the recorded events as a Chrome trace JSON array.
+    pub fn write<W: Write>(&self, writer: W) -> Result<()> {
+        serde_json::to_writer(writer, &self.events).context("Failed to write trace events")
+    }
+
+    /// Write the recorded events to the file at the given path.
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .context(format!("Failed to create trace file {}", path.display()))?;
+        self.write(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_phases_complete() {
+        let phases = [
+            "Created 'hiberimage' logical volume",
+            "Wrote hibernate image",
+        ];
+        let mut tracer = Tracer::new();
+
+        // Nothing is recorded while tracing is disabled.
+        tracer.record_phase("Ignored", Duration::from_millis(1));
+
+        tracer.enable();
+        for phase in phases {
+            tracer.record_phase(phase, Duration::from_millis(5));
+        }
+
+        let mut json = vec![];
+        tracer.write(&mut json).unwrap();
+        let events: Vec<serde_json::Value> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(events.len(), phases.len());
+
+        for (phase, event) in phases.iter().zip(&events) {
+            assert_eq!(event["name"], *phase);
+            assert_eq!(event["ph"], "X");
+            assert_eq!(event["dur"], 5000);
+            assert_eq!(event["pid"], event["tid"]);
+        }
+    }
+}
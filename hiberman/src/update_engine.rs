@@ -4,12 +4,14 @@
 
 //! Implements a client interface to the update_engine.
 
+use std::fmt;
 use std::time::Duration;
 
 use anyhow::Context as AnyhowContext;
 use anyhow::Result;
 use dbus::blocking::Connection;
 use log::info;
+use protobuf::EnumOrUnknown;
 use protobuf::Message;
 use system_api::update_engine::Operation;
 use system_api::update_engine::StatusResult;
@@ -19,14 +21,46 @@ use update_engine_dbus::client::OrgChromiumUpdateEngineInterface;
 /// call responses.
 const UPDATE_ENGINE_DBUS_PROXY_TIMEOUT: Duration = Duration::from_secs(30);
 
-pub fn is_update_engine_idle() -> Result<bool> {
+/// What the update engine is currently doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateEngineStatus {
+    Idle,
+    /// Busy with the given operation, e.g. downloading or waiting for a
+    /// reboot.
+    Busy(Operation),
+    /// Busy with an operation unknown to this version of hiberman.
+    Unknown(i32),
+}
+
+impl UpdateEngineStatus {
+    fn from_operation(operation: EnumOrUnknown<Operation>) -> Self {
+        match operation.enum_value() {
+            Ok(Operation::IDLE) => UpdateEngineStatus::Idle,
+            Ok(operation) => UpdateEngineStatus::Busy(operation),
+            Err(value) => UpdateEngineStatus::Unknown(value),
+        }
+    }
+}
+
+impl fmt::Display for UpdateEngineStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateEngineStatus::Idle => write!(f, "IDLE"),
+            UpdateEngineStatus::Busy(operation) => write!(f, "{:?}", operation),
+            UpdateEngineStatus::Unknown(value) => write!(f, "unknown operation {}", value),
+        }
+    }
+}
+
+/// Get what the update engine is currently doing.
+pub fn get_update_engine_status() -> Result<UpdateEngineStatus> {
     let status = get_status().context("Failed to get update engine status")?;
-    let current_operation = status.current_operation.enum_value();
-    if current_operation != Ok(Operation::IDLE) {
-        info!("Update engine status is {:?}", status.current_operation);
+    let update_engine_status = UpdateEngineStatus::from_operation(status.current_operation);
+    if update_engine_status != UpdateEngineStatus::Idle {
+        info!("Update engine status is {}", update_engine_status);
     }
 
-    Ok(current_operation == Ok(Operation::IDLE))
+    Ok(update_engine_status)
 }
 
 fn get_status() -> Result<StatusResult> {
@@ -48,3 +82,27 @@ fn get_status() -> Result<StatusResult> {
     // Parse the resulting protobuf back into a structure.
     StatusResult::parse_from_bytes(&result).context("Failed to parse StatusResult protobuf")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_engine_status() {
+        assert_eq!(
+            UpdateEngineStatus::from_operation(Operation::IDLE.into()),
+            UpdateEngineStatus::Idle
+        );
+
+        let downloading = UpdateEngineStatus::from_operation(Operation::DOWNLOADING.into());
+        assert_eq!(
+            downloading,
+            UpdateEngineStatus::Busy(Operation::DOWNLOADING)
+        );
+        assert_eq!(downloading.to_string(), "DOWNLOADING");
+
+        let unknown = UpdateEngineStatus::from_operation(EnumOrUnknown::from_i32(1000));
+        assert_eq!(unknown, UpdateEngineStatus::Unknown(1000));
+        assert_eq!(unknown.to_string(), "unknown operation 1000");
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Guards against resuming across a verified boot state change, e.g. when
+//! developer mode was toggled between hibernate and resume.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Context;
+use anyhow::Result;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::hiberutil::checked_command_output;
+use crate::hiberutil::HibernateError;
+
+/// Name of the file on hibermeta with the verified boot state at hibernate
+/// time.
+pub const VBOOT_STATE_FILE_NAME: &str = "vboot_state";
+
+const CROSSYSTEM_PATH: &str = "/usr/bin/crossystem";
+
+/// The verified boot state of the current boot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VbootState {
+    /// Whether the system booted in developer mode.
+    pub dev_mode: bool,
+    /// The type of the main firmware that booted, e.g. "normal" or
+    /// "developer".
+    pub mainfw_type: String,
+}
+
+impl VbootState {
+    /// Get the verified boot state of the current boot from crossystem.
+    pub fn current() -> Result<Self> {
+        Ok(VbootState {
+            dev_mode: crossystem("devsw_boot")? == "1",
+            mainfw_type: crossystem("mainfw_type")?,
+        })
+    }
+
+    /// Write the verified boot state to the given hibermeta directory.
+    pub fn save(&self, meta: &Path) -> Result<()> {
+        let path = meta.join(VBOOT_STATE_FILE_NAME);
+        let json = serde_json::to_string(self).context("Failed to serialize vboot state")?;
+        fs::write(&path, json).context(format!("Failed to write {}", path.display()))
+    }
+
+    /// Fail with HibernateError::VbootStateMismatch if the verified boot
+    /// state recorded in the given hibermeta directory differs from this one.
+    /// Images without a recorded state are accepted.
+    pub fn verify(&self, meta: &Path) -> Result<()> {
+        let path = meta.join(VBOOT_STATE_FILE_NAME);
+        if !path.exists() {
+            warn!("No verified boot state recorded at hibernate time");
+            return Ok(());
+        }
+
+        let json =
+            fs::read_to_string(&path).context(format!("Failed to read {}", path.display()))?;
+        let recorded: VbootState =
+            serde_json::from_str(&json).context(format!("Failed to parse {}", path.display()))?;
+        if recorded == *self {
+            return Ok(());
+        }
+
+        Err(HibernateError::VbootStateMismatch(format!(
+            "{:?} at hibernate, {:?} now",
+            recorded, self
+        )))
+        .context("Refusing to resume")
+    }
+}
+
+fn crossystem(name: &str) -> Result<String> {
+    let output = checked_command_output(Command::new(CROSSYSTEM_PATH).arg(name))
+        .context(format!("Failed to get {} from crossystem", name))?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_vboot_state_mismatch() {
+        let temp_dir = tempdir().unwrap();
+        let meta = temp_dir.path();
+
+        let normal = VbootState {
+            dev_mode: false,
+            mainfw_type: "normal".to_string(),
+        };
+        let developer = VbootState {
+            dev_mode: true,
+            mainfw_type: "developer".to_string(),
+        };
+
+        // Nothing was recorded.
+        assert!(developer.verify(meta).is_ok());
+
+        normal.save(meta).unwrap();
+        assert!(normal.verify(meta).is_ok());
+
+        let err = developer.verify(meta).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(HibernateError::VbootStateMismatch(_))
+        ));
+    }
+}
@@ -34,6 +34,7 @@ use crate::cookie::set_hibernate_cookie;
 use crate::cookie::HibernateCookieValue;
 use crate::device_mapper::DeviceMapper;
 use crate::files::HIBERMETA_DIR;
+use crate::hiberutil::block_partition_one;
 use crate::hiberutil::checked_command;
 use crate::hiberutil::checked_command_output;
 use crate::hiberutil::emergency_reboot;
@@ -49,6 +50,7 @@ use crate::hiberutil::unmount_filesystem;
 use crate::hiberutil::HibernateError;
 use crate::lvm::activate_lv;
 use crate::lvm::create_thin_volume;
+use crate::lvm::get_free_thinpool_percent;
 use crate::lvm::get_free_thinpool_space;
 use crate::lvm::get_lvs;
 use crate::lvm::get_thin_volume_usage_percent;
@@ -185,6 +187,15 @@ impl VolumeManager {
         Ok(Self { vg_name })
     }
 
+    /// Create a VolumeManager for the volume group on the first partition of
+    /// `blockdev` instead of the stateful block device.
+    pub fn for_block_device(blockdev: &Path) -> Result<Self> {
+        let partition1 = block_partition_one(&blockdev.to_string_lossy());
+        let vg_name = get_vg_name(&partition1)
+            .context(format!("Failed to get the volume group on {}", partition1))?;
+        Ok(Self { vg_name })
+    }
+
     /// Activate the thinpool in RO mode.
     pub fn activate_thinpool_ro(&self) -> Result<()> {
         activate_lv(&self.vg_name, THINPOOL_NAME).context("Failed to activate thinpool")?;
@@ -339,6 +350,11 @@ impl VolumeManager {
         get_free_thinpool_space(&self.vg_name)
     }
 
+    /// Returns the free space in the thinpool in percent.
+    pub fn get_free_thinpool_percent(&self) -> Result<f64> {
+        get_free_thinpool_percent(&self.vg_name)
+    }
+
     /// Check whether the 'hiberimage' DM device exists.
     pub fn hiberimage_exists(&self) -> bool {
         DeviceMapper::device_exists(Self::HIBERIMAGE)
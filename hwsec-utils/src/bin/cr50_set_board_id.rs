@@ -10,8 +10,8 @@ use hwsec_utils::context::Context;
 use hwsec_utils::context::RealContext;
 use hwsec_utils::cr50::check_cr50_support_partial_board_id;
 use hwsec_utils::cr50::check_device;
-use hwsec_utils::cr50::cr50_check_board_id_and_flag;
 use hwsec_utils::cr50::cr50_set_board_id_and_flag;
+use hwsec_utils::cr50::BoardId;
 use hwsec_utils::cr50::Cr50SetBoardIDVerdict;
 use hwsec_utils::cr50::GSC_NAME;
 
@@ -28,9 +28,14 @@ fn exit_if_not_support_partial_board_id(ctx: &mut impl Context) {
 fn main() {
     let mut real_ctx = RealContext::new();
     let args_string: Vec<String> = env::args().collect();
-    let args: Vec<&str> = args_string.iter().map(|s| s.as_str()).collect();
+    let mut args: Vec<&str> = args_string.iter().map(|s| s.as_str()).collect();
+    // In a dry run, everything is checked but the board id is not written.
+    let dry_run = args.len() > 1 && args[1] == "-n";
+    if dry_run {
+        args.remove(1);
+    }
     if args.len() <= 1 || args.len() >= 4 {
-        die(&format!("Usage: {} phase [board_id]", args[0]));
+        die(&format!("Usage: {} [-n] phase [board_id]", args[0]));
     }
     let phase: &str = args[1];
     if phase == "check_device" {
@@ -39,7 +44,7 @@ fn main() {
             .unwrap();
     }
     let mut rlz: &str = if args.len() == 3 { args[2] } else { "" };
-    let flag: u16 = if phase == "whitelabel_pvt_flags" {
+    let flag: u32 = if phase == "whitelabel_pvt_flags" {
         // Whitelabel flags are set by using 0xffffffff as the rlz and the
         // whitelabel flags. Cr50 images that support partial board id will ignore
         // the board id type if it's 0xffffffff and only set the flags.
@@ -129,13 +134,33 @@ fn main() {
         u32::from_be_bytes(rlz.as_bytes().try_into().unwrap())
     };
 
-    cr50_check_board_id_and_flag(&mut real_ctx, rlz, flag)
-        .map_err(|e| exit(e as i32))
-        .unwrap();
+    let board_id = BoardId::from_id(rlz, flag)
+        .unwrap_or_else(|_| die(&format!("Invalid board id {:08x}:{:08x}.", rlz, flag)));
 
-    cr50_set_board_id_and_flag(&mut real_ctx, rlz, flag)
+    let outcome = cr50_set_board_id_and_flag(&mut real_ctx, &board_id, dry_run)
         .map_err(|e| exit(e as i32))
         .unwrap();
+    let previous = match outcome.previous {
+        Some(previous) => format!(
+            "{:08x}:{:08x}:{:08x}",
+            previous.id(),
+            previous.inverse(),
+            previous.flags()
+        ),
+        None => "erased".to_string(),
+    };
+    println!(
+        "Board ID: {} -> {:08x}:{:08x}:{:08x}",
+        previous,
+        outcome.written.id(),
+        outcome.written.inverse(),
+        outcome.written.flags()
+    );
+
+    if dry_run {
+        println!("Board ID has not been set yet.");
+        exit(0);
+    }
 
     println!("{}", hooray_message);
 }
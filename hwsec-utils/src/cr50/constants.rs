@@ -27,3 +27,48 @@ pub const GSC_METRICS_PREFIX: &str = "Platform.Ti50";
 pub const GSC_METRICS_PREFIX: &str = "Platform.Cr50";
 
 pub const GSCTOOL_CMD_NAME: &str = "/usr/sbin/gsctool";
+
+/// Asserts the invariants among the constants above, so that a mistyped
+/// constant is caught by the unit tests instead of on a device.
+pub fn validate() {
+    assert!(!GSC_NAME.is_empty(), "GSC_NAME is empty");
+    assert!(
+        GSC_NAME
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit()),
+        "GSC_NAME {} is not a lowercase name",
+        GSC_NAME
+    );
+
+    let image_path = format!("/opt/google/{0}/firmware/{0}.bin", GSC_NAME);
+    assert_eq!(
+        GSC_IMAGE_BASE_NAME, image_path,
+        "GSC_IMAGE_BASE_NAME doesn't match GSC_NAME"
+    );
+
+    let metrics_prefix = format!(
+        "Platform.{}{}",
+        &GSC_NAME[..1].to_uppercase(),
+        &GSC_NAME[1..]
+    );
+    assert_eq!(
+        GSC_METRICS_PREFIX, metrics_prefix,
+        "GSC_METRICS_PREFIX doesn't match GSC_NAME"
+    );
+
+    assert!(
+        GSCTOOL_CMD_NAME.starts_with('/') && GSCTOOL_CMD_NAME.ends_with("/gsctool"),
+        "GSCTOOL_CMD_NAME {} is not an absolute path to gsctool",
+        GSCTOOL_CMD_NAME
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate;
+
+    #[test]
+    fn test_validate() {
+        validate();
+    }
+}
@@ -5,6 +5,8 @@
 use std::fmt;
 use std::fmt::Display;
 
+use crate::error::HwsecError;
+
 pub type SnBits = [u8; 12];
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -14,7 +16,7 @@ pub struct RmaSnBits {
     pub sn_bits: SnBits,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct Version {
     pub epoch: u8,
     pub major: u8,
@@ -35,3 +37,146 @@ impl Display for Version {
         write!(f, "{}.{}.{}", self.epoch, self.major, self.minor)
     }
 }
+
+/// The versions of the RO and RW firmware running on the GSC.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Cr50Version {
+    pub ro: Version,
+    pub rw: Version,
+}
+
+/// The case closed debugging (CCD) state, as in STATE= of 'gsctool -a -I -M'.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CcdState {
+    Locked,
+    Unlocked,
+    Opened,
+    /// A state this crate doesn't know about, e.g. from newer firmware.
+    Unknown(String),
+}
+
+/// The CCD configuration reported by 'gsctool -a -I -M'.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CcdInfo {
+    pub state: CcdState,
+    pub password_set: bool,
+    pub flags: u32,
+    /// Names of the capabilities currently enabled.
+    pub capabilities: Vec<&'static str>,
+    /// Bits of enabled capabilities this crate has no name for.
+    pub unknown_capabilities: Vec<u32>,
+}
+
+/// A board id to provision on the GSC: the board id type (usually the RLZ
+/// brand code) and the flags. The GSC keeps the inverse of the type next to
+/// it. Unlike tpm2::BoardID, the raw board id space read from the GSC, this
+/// is always a value that can be written; a type of 0xffffffff only sets the
+/// flags, on images supporting partial board ids.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BoardId {
+    id: u32,
+    flags: u32,
+}
+
+const ERASED: u32 = 0xffffffff;
+
+impl BoardId {
+    /// Builds a board id from a type and the inverse that is supposed to go
+    /// with it, e.g. as read back from the GSC or handed in by an operator.
+    /// Fails with HwsecError::InvalidArgumentError if they don't match, or if
+    /// the type is zero or the flags are erased.
+    pub fn new(id: u32, inverse: u32, flags: u32) -> Result<Self, HwsecError> {
+        if id == ERASED && inverse == ERASED {
+            eprintln!("Board id type is erased");
+            return Err(HwsecError::InvalidArgumentError);
+        }
+        if inverse != !id {
+            eprintln!(
+                "Board id type {:08x} doesn't match its inverse {:08x}",
+                id, inverse
+            );
+            return Err(HwsecError::InvalidArgumentError);
+        }
+        if id == 0 {
+            eprintln!("Board id type is zero");
+            return Err(HwsecError::InvalidArgumentError);
+        }
+        if flags == ERASED {
+            eprintln!("Board id flags are erased");
+            return Err(HwsecError::InvalidArgumentError);
+        }
+        Ok(Self { id, flags })
+    }
+
+    /// Builds a board id from a type, deriving its inverse. Fails like new().
+    pub fn from_id(id: u32, flags: u32) -> Result<Self, HwsecError> {
+        Self::new(id, !id, flags)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn inverse(&self) -> u32 {
+        !self.id
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BoardId;
+    use crate::error::HwsecError;
+
+    #[test]
+    fn test_board_id_new_ok() {
+        let board_id = BoardId::new(0x5a5a4352, 0xa5a5bcad, 0x3f80).unwrap();
+        assert_eq!(board_id, BoardId::from_id(0x5a5a4352, 0x3f80).unwrap());
+        assert_eq!(board_id.id(), 0x5a5a4352);
+        assert_eq!(board_id.inverse(), 0xa5a5bcad);
+        assert_eq!(board_id.flags(), 0x3f80);
+
+        // Only setting the flags, on images supporting partial board ids.
+        assert!(BoardId::new(0xffffffff, 0x00000000, 0x3f80).is_ok());
+    }
+
+    #[test]
+    fn test_board_id_new_mismatched_inverse() {
+        assert_eq!(
+            BoardId::new(0x5a5a4352, 0xa5a5bcac, 0x3f80),
+            Err(HwsecError::InvalidArgumentError)
+        );
+        assert_eq!(
+            BoardId::new(0x5a5a4352, 0x5a5a4352, 0x3f80),
+            Err(HwsecError::InvalidArgumentError)
+        );
+        // An erased board id is not something that can be provisioned.
+        assert_eq!(
+            BoardId::new(0xffffffff, 0xffffffff, 0x3f80),
+            Err(HwsecError::InvalidArgumentError)
+        );
+    }
+
+    #[test]
+    fn test_board_id_new_malformed() {
+        assert_eq!(
+            BoardId::new(0x00000000, 0xffffffff, 0x3f80),
+            Err(HwsecError::InvalidArgumentError)
+        );
+        assert_eq!(
+            BoardId::new(0x5a5a4352, 0xa5a5bcad, 0xffffffff),
+            Err(HwsecError::InvalidArgumentError)
+        );
+        assert_eq!(
+            BoardId::from_id(0x00000000, 0x3f80),
+            Err(HwsecError::InvalidArgumentError)
+        );
+        assert_eq!(
+            BoardId::from_id(0x5a5a4352, 0xffffffff),
+            Err(HwsecError::InvalidArgumentError)
+        );
+    }
+}
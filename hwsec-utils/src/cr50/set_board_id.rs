@@ -6,12 +6,16 @@ use std::fmt;
 use std::fmt::Display;
 
 use super::extract_board_id_from_gsctool_response;
+use super::retry_gsctool;
 use super::run_gsctool_cmd;
+use super::BoardId;
 use super::Version;
+use super::GSCTOOL_QUERY_RETRIES;
 use crate::command_runner::CommandRunner;
 use crate::context::Context;
 use crate::cr50::get_value_from_gsctool_output;
 use crate::cr50::parse_version;
+use crate::tpm2::BoardID;
 use crate::tpm2::ERASED_BOARD_ID;
 
 pub const WHITELABEL: u32 = 0x4000;
@@ -52,41 +56,56 @@ impl From<Cr50SetBoardIDVerdict> for i32 {
     }
 }
 
-pub fn cr50_check_board_id_and_flag(
-    ctx: &mut impl Context,
-    new_board_id: u32,
-    new_flag: u16,
-) -> Result<(), Cr50SetBoardIDVerdict> {
+/// What cr50_set_board_id_and_flag() changed: the board id that was written
+/// and the one that had been set before, if any. The board id type can only
+/// be written while it is erased, so the previous board id, if there is one,
+/// only has its flags set.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SetBoardIdOutcome {
+    pub written: BoardId,
+    pub previous: Option<BoardId>,
+}
+
+fn read_board_id(ctx: &mut impl Context) -> Result<BoardID, Cr50SetBoardIDVerdict> {
     let board_id_output = {
         let gsctool_raw_response =
-            run_gsctool_cmd(ctx, vec!["--any", "--board_id"]).map_err(|_| {
-                eprintln!("Failed to run gsctool.");
-                Cr50SetBoardIDVerdict::GeneralError
-            })?;
+            retry_gsctool(ctx, vec!["--any", "--board_id"], GSCTOOL_QUERY_RETRIES).map_err(
+                |_| {
+                    eprintln!("Failed to run gsctool.");
+                    Cr50SetBoardIDVerdict::GeneralError
+                },
+            )?;
         let board_id_output = std::str::from_utf8(&gsctool_raw_response.stdout).unwrap();
         extract_board_id_from_gsctool_response(board_id_output)
     };
-    let board_id = board_id_output.map_err(|e| {
+    board_id_output.map_err(|e| {
         eprintln!(
             "Failed to execute gsctool or failed to read board id - {}",
             e
         );
         Cr50SetBoardIDVerdict::GeneralError
-    })?;
+    })
+}
 
+fn check_board_id_and_flag(
+    board_id: &BoardID,
+    new_board_id: &BoardId,
+) -> Result<(), Cr50SetBoardIDVerdict> {
+    let new_flag = new_board_id.flags();
+    let new_board_id = new_board_id.id();
     if board_id.part_1 == ERASED_BOARD_ID.part_1 && board_id.part_2 == ERASED_BOARD_ID.part_2 {
         // Board ID is type cleared, it's ok to go ahead and set it.
         Ok(())
     } else if board_id.part_1 != new_board_id {
         eprintln!("Board ID had been set differently.");
         Err(Cr50SetBoardIDVerdict::AlreadySetDifferentlyError)
-    } else if (board_id.flag ^ (new_flag as u32)) == WHITELABEL {
+    } else if (board_id.flag ^ new_flag) == WHITELABEL {
         // The 0x4000 bit is the difference between MP and whitelabel flags. Factory
         // scripts can ignore this mismatch if it's the only difference between the set
         // board id and the new board id.
         eprintln!("Board ID and flag have already been set. Whitelabel mismatched.");
         Err(Cr50SetBoardIDVerdict::AlreadySetError)
-    } else if board_id.flag != new_flag as u32 {
+    } else if board_id.flag != new_flag {
         eprintln!("Flag had been set differently.");
         Err(Cr50SetBoardIDVerdict::AlreadySetDifferentlyError)
     } else {
@@ -95,12 +114,51 @@ pub fn cr50_check_board_id_and_flag(
     }
 }
 
-pub fn cr50_set_board_id_and_flag(
+// Whether the board id space read back from the GSC holds `board_id`. When
+// only the flags are set, the type is left as it was.
+fn board_id_written(board_id_space: &BoardID, board_id: &BoardId) -> bool {
+    let type_written = if board_id.id() == ERASED_BOARD_ID.part_1 {
+        board_id_space.part_1 == ERASED_BOARD_ID.part_1
+            && board_id_space.part_2 == ERASED_BOARD_ID.part_2
+    } else {
+        board_id_space.part_1 == board_id.id() && board_id_space.part_2 == board_id.inverse()
+    };
+    type_written && board_id_space.flag == board_id.flags()
+}
+
+pub fn cr50_check_board_id_and_flag(
     ctx: &mut impl Context,
-    board_id: u32,
-    flag: u16,
+    new_board_id: &BoardId,
 ) -> Result<(), Cr50SetBoardIDVerdict> {
-    let updater_arg = &format!("0x{:08x}:0x{:08x}", board_id, flag);
+    let board_id = read_board_id(ctx)?;
+    check_board_id_and_flag(&board_id, new_board_id)
+}
+
+/// Writes `board_id` to the GSC, which can't be undone. The current board id
+/// is always read first, and nothing is written if it is already set, see
+/// cr50_check_board_id_and_flag(), and it is read back after writing. In a
+/// dry run, only prints the gsctool command that would write it.
+pub fn cr50_set_board_id_and_flag(
+    ctx: &mut impl Context,
+    board_id: &BoardId,
+    dry_run: bool,
+) -> Result<SetBoardIdOutcome, Cr50SetBoardIDVerdict> {
+    let board_id_space = read_board_id(ctx)?;
+    check_board_id_and_flag(&board_id_space, board_id)?;
+    let outcome = SetBoardIdOutcome {
+        written: *board_id,
+        previous: if board_id_space == ERASED_BOARD_ID {
+            None
+        } else {
+            BoardId::from_id(ERASED_BOARD_ID.part_1, board_id_space.flag).ok()
+        },
+    };
+
+    let updater_arg = &format!("0x{:08x}:0x{:08x}", board_id.id(), board_id.flags());
+    if dry_run {
+        println!("Would run: gsctool --any --board_id {}", updater_arg);
+        return Ok(outcome);
+    }
     let update_output =
         run_gsctool_cmd(ctx, vec!["--any", "--board_id", updater_arg]).map_err(|_| {
             eprintln!("Failed to run gsctool.");
@@ -108,9 +166,24 @@ pub fn cr50_set_board_id_and_flag(
         })?;
     if !update_output.status.success() {
         eprintln!("Failed to update with {}.", updater_arg);
-        Err(Cr50SetBoardIDVerdict::GeneralError)
-    } else {
-        Ok(())
+        return Err(Cr50SetBoardIDVerdict::GeneralError);
+    }
+
+    // The board id has been written by now, so failing to read it back
+    // doesn't make this fail.
+    match read_board_id(ctx) {
+        Ok(board_id_space) if !board_id_written(&board_id_space, board_id) => {
+            eprintln!(
+                "Board ID space reads {:08x}:{:08x}:{:08x} after updating with {}.",
+                board_id_space.part_1, board_id_space.part_2, board_id_space.flag, updater_arg
+            );
+            Err(Cr50SetBoardIDVerdict::GeneralError)
+        }
+        Ok(_) => Ok(outcome),
+        Err(_) => {
+            eprintln!("Failed to read back the board id, assuming it is set.");
+            Ok(outcome)
+        }
     }
 }
 
@@ -247,7 +320,9 @@ mod tests {
     use crate::cr50::check_device;
     use crate::cr50::cr50_check_board_id_and_flag;
     use crate::cr50::cr50_set_board_id_and_flag;
+    use crate::cr50::BoardId;
     use crate::cr50::Cr50SetBoardIDVerdict;
+    use crate::cr50::SetBoardIdOutcome;
 
     #[test]
     fn test_cr50_check_board_id_and_flag_ok() {
@@ -259,7 +334,10 @@ mod tests {
             "",
         );
 
-        let result = cr50_check_board_id_and_flag(&mut mock_ctx, 0x00000000, 0x0000);
+        let result = cr50_check_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x12345678, 0x0000).unwrap(),
+        );
         assert_eq!(result, Ok(()));
     }
 
@@ -273,7 +351,10 @@ mod tests {
             "",
         );
 
-        let result = cr50_check_board_id_and_flag(&mut mock_ctx, 0x1234567a, 0x0000);
+        let result = cr50_check_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x1234567a, 0x0000).unwrap(),
+        );
         assert_eq!(
             result,
             Err(Cr50SetBoardIDVerdict::AlreadySetDifferentlyError)
@@ -290,7 +371,10 @@ mod tests {
             "",
         );
 
-        let result = cr50_check_board_id_and_flag(&mut mock_ctx, 0x12345678, 0x4087);
+        let result = cr50_check_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x12345678, 0x4087).unwrap(),
+        );
         assert_eq!(result, Err(Cr50SetBoardIDVerdict::AlreadySetError));
     }
 
@@ -304,7 +388,10 @@ mod tests {
             "",
         );
 
-        let result = cr50_check_board_id_and_flag(&mut mock_ctx, 0x12345678, 0x4087);
+        let result = cr50_check_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x12345678, 0x4087).unwrap(),
+        );
         assert_eq!(
             result,
             Err(Cr50SetBoardIDVerdict::AlreadySetDifferentlyError)
@@ -321,27 +408,257 @@ mod tests {
             "",
         );
 
-        let result = cr50_check_board_id_and_flag(&mut mock_ctx, 0x12345678, 0x1234);
+        let result = cr50_check_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x12345678, 0x1234).unwrap(),
+        );
         assert_eq!(result, Err(Cr50SetBoardIDVerdict::AlreadySetError));
     }
 
     #[test]
     fn test_cr50_set_board_id_and_flag_ok() {
         let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: ffffffff:ffffffff:ffffffff",
+            "",
+        );
         mock_ctx.cmd_runner().add_gsctool_interaction(
             vec!["--any", "--board_id", "0x12345678:0x0000abcd"],
             0,
             "",
             "",
         );
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: 12345678:edcba987:0000abcd",
+            "",
+        );
 
-        let result = cr50_set_board_id_and_flag(&mut mock_ctx, 0x12345678, 0xabcd);
-        assert_eq!(result, Ok(()));
+        let result = cr50_set_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x12345678, 0xabcd).unwrap(),
+            false,
+        );
+        assert_eq!(
+            result,
+            Ok(SetBoardIdOutcome {
+                written: BoardId::from_id(0x12345678, 0xabcd).unwrap(),
+                previous: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_cr50_set_board_id_and_flag_read_back_failed() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: ffffffff:ffffffff:ffffffff",
+            "",
+        );
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id", "0x12345678:0x0000abcd"],
+            0,
+            "",
+            "",
+        );
+        mock_ctx
+            .cmd_runner()
+            .add_gsctool_interaction(vec!["--any", "--board_id"], 1, "", "");
+
+        // The board id has already been written.
+        let result = cr50_set_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x12345678, 0xabcd).unwrap(),
+            false,
+        );
+        assert_eq!(
+            result,
+            Ok(SetBoardIdOutcome {
+                written: BoardId::from_id(0x12345678, 0xabcd).unwrap(),
+                previous: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_cr50_set_board_id_and_flag_read_back_mismatched() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: ffffffff:ffffffff:ffffffff",
+            "",
+        );
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id", "0x12345678:0x0000abcd"],
+            0,
+            "",
+            "",
+        );
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: 12345678:edcba987:0000abce",
+            "",
+        );
+
+        let result = cr50_set_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x12345678, 0xabcd).unwrap(),
+            false,
+        );
+        assert_eq!(result, Err(Cr50SetBoardIDVerdict::GeneralError));
+    }
+
+    #[test]
+    fn test_cr50_set_board_id_and_flag_flags_set() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: ffffffff:ffffffff:00003f80",
+            "",
+        );
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id", "0x5a5a4352:0x00003f80"],
+            0,
+            "",
+            "",
+        );
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: 5a5a4352:a5a5bcad:00003f80",
+            "",
+        );
+
+        let result = cr50_set_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x5a5a4352, 0x3f80).unwrap(),
+            false,
+        );
+        assert_eq!(
+            result,
+            Ok(SetBoardIdOutcome {
+                written: BoardId::from_id(0x5a5a4352, 0x3f80).unwrap(),
+                previous: Some(BoardId::from_id(0xffffffff, 0x3f80).unwrap()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_cr50_set_board_id_and_flag_only_flags() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: ffffffff:ffffffff:ffffffff",
+            "",
+        );
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id", "0xffffffff:0x00003f80"],
+            0,
+            "",
+            "",
+        );
+        // Images supporting partial board ids leave the type erased.
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: ffffffff:ffffffff:00003f80",
+            "",
+        );
+
+        let result = cr50_set_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0xffffffff, 0x3f80).unwrap(),
+            false,
+        );
+        assert_eq!(
+            result,
+            Ok(SetBoardIdOutcome {
+                written: BoardId::from_id(0xffffffff, 0x3f80).unwrap(),
+                previous: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_cr50_set_board_id_and_flag_dry_run() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: ffffffff:ffffffff:ffffffff",
+            "",
+        );
+
+        // Nothing is written, the mock fails on unexpected commands.
+        let board_id = BoardId::from_id(0x12345678, 0xabcd).unwrap();
+        assert_eq!(
+            cr50_set_board_id_and_flag(&mut mock_ctx, &board_id, true),
+            Ok(SetBoardIdOutcome {
+                written: BoardId::from_id(0x12345678, 0xabcd).unwrap(),
+                previous: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_cr50_set_board_id_and_flag_already_set() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "finding_device 18d1:5014\n\
+            Found device.\n\
+            found interface 3 endpoint 4, chunk_len 64\n\
+            READY\n\
+            -------\n\
+            Board ID space: 12345678:edcba987:0000abcd\n",
+            "",
+        );
+
+        // Refused without trying to write, the mock fails on unexpected
+        // commands.
+        let board_id = BoardId::from_id(0x12345678, 0xabcd).unwrap();
+        assert_eq!(
+            cr50_set_board_id_and_flag(&mut mock_ctx, &board_id, false),
+            Err(Cr50SetBoardIDVerdict::AlreadySetError)
+        );
+    }
+
+    #[test]
+    fn test_cr50_set_board_id_and_flag_set_differently() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: 5a5a4352:a5a5bcad:00003f80\n",
+            "",
+        );
+
+        let board_id = BoardId::from_id(0x12345678, 0xabcd).unwrap();
+        assert_eq!(
+            cr50_set_board_id_and_flag(&mut mock_ctx, &board_id, true),
+            Err(Cr50SetBoardIDVerdict::AlreadySetDifferentlyError)
+        );
     }
 
     #[test]
     fn test_cr50_set_board_id_and_flag_failed() {
         let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "Board ID space: ffffffff:ffffffff:ffffffff",
+            "",
+        );
         mock_ctx.cmd_runner().add_gsctool_interaction(
             vec!["--any", "--board_id", "0x12345678:0x0000abcd"],
             1,
@@ -349,7 +666,11 @@ mod tests {
             "",
         );
 
-        let result = cr50_set_board_id_and_flag(&mut mock_ctx, 0x12345678, 0xabcd);
+        let result = cr50_set_board_id_and_flag(
+            &mut mock_ctx,
+            &BoardId::from_id(0x12345678, 0xabcd).unwrap(),
+            false,
+        );
         assert_eq!(result, Err(Cr50SetBoardIDVerdict::GeneralError));
     }
 
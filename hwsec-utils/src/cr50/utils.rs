@@ -6,12 +6,17 @@ use std::fmt::Write;
 
 use regex::Regex;
 
+use super::CcdInfo;
+use super::CcdState;
+use super::Cr50Version;
 use super::Version;
 use super::GSCTOOL_CMD_NAME;
+use super::GSC_NAME;
 use crate::command_runner::CommandRunner;
 use crate::context::Context;
 use crate::error::HwsecError;
 use crate::output::HwsecOutput;
+use crate::tpm2::nv_read;
 use crate::tpm2::BoardID;
 use crate::tpm2::FactoryConfig;
 
@@ -46,8 +51,9 @@ pub fn get_value_from_gsctool_output<'a>(
 ) -> Result<&'a str, HwsecError> {
     let prefix = index.to_owned() + "=";
     let Some(line) = gsctool_output
-    .lines()
-    .find(|line| line.starts_with(&prefix)) else {
+        .lines()
+        .find(|line| line.starts_with(&prefix))
+    else {
         eprintln!("Cannot find a line starts with {}", index);
         return Err(HwsecError::InternalError);
     };
@@ -73,6 +79,54 @@ pub fn run_gsctool_cmd(
         .map_err(|_| HwsecError::CommandRunnerError)
 }
 
+/// How many times read-only gsctool queries are retried.
+pub const GSCTOOL_QUERY_RETRIES: u32 = 2;
+
+/// gsctool messages of failures to reach the GSC that usually go away when
+/// trying again, e.g. while another process is talking to it.
+const TRANSIENT_GSCTOOL_ERRORS: [&str; 4] = [
+    "Device or resource busy",
+    "Can't find device",
+    "Timed out",
+    "Resource temporarily unavailable",
+];
+
+fn is_transient_gsctool_failure(output: &HwsecOutput) -> bool {
+    if output.status.success() {
+        return false;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    TRANSIENT_GSCTOOL_ERRORS
+        .iter()
+        .any(|msg| stdout.contains(msg) || stderr.contains(msg))
+}
+
+/// Like run_gsctool_cmd(), but retries up to `retries` times, waiting 1, 2,
+/// 4... seconds in between, as long as gsctool fails to reach the GSC.
+/// Only for queries: a command writing to the GSC may have been partially
+/// applied when it failed, and must not be run again blindly.
+pub fn retry_gsctool(
+    ctx: &mut impl Context,
+    options: Vec<&str>,
+    retries: u32,
+) -> Result<HwsecOutput, HwsecError> {
+    let mut attempt = 0;
+    loop {
+        let output = run_gsctool_cmd(ctx, options.clone())?;
+        if attempt >= retries || !is_transient_gsctool_failure(&output) {
+            return Ok(output);
+        }
+        eprintln!(
+            "gsctool {} failed to reach the {}, retrying.",
+            options.join(" "),
+            GSC_NAME
+        );
+        ctx.sleep(1 << attempt);
+        attempt += 1;
+    }
+}
+
 pub fn run_metrics_client(
     ctx: &mut impl Context,
     options: Vec<&str>,
@@ -121,13 +175,181 @@ pub fn extract_board_id_from_gsctool_response(raw_response: &str) -> Result<Boar
     }
 }
 
+/// Reads the versions of the running RO and RW firmware, reported in
+/// 'gsctool -afM' output as RO_FW_VER=<epoch>.<major>.<minor> and
+/// RW_FW_VER=<epoch>.<major>.<minor>.
+pub fn get_version(ctx: &mut impl Context) -> Result<Cr50Version, HwsecError> {
+    let gsctool_raw_response = retry_gsctool(
+        ctx,
+        vec!["--any", "--fwver", "--machine"],
+        GSCTOOL_QUERY_RETRIES,
+    )?;
+    if !gsctool_raw_response.status.success() {
+        return Err(HwsecError::GsctoolError(
+            gsctool_raw_response.status.code().unwrap_or(-1),
+        ));
+    }
+    let output = std::str::from_utf8(&gsctool_raw_response.stdout)
+        .map_err(|_| HwsecError::GsctoolResponseBadFormatError)?;
+
+    let parse = |index| {
+        let version_str = get_value_from_gsctool_output(output, index)
+            .map_err(|_| HwsecError::GsctoolResponseBadFormatError)?;
+        parse_version(version_str).ok_or_else(|| {
+            eprintln!("Failed to parse {} {} into version", index, version_str);
+            HwsecError::GsctoolResponseBadFormatError
+        })
+    };
+    Ok(Cr50Version {
+        ro: parse("RO_FW_VER")?,
+        rw: parse("RW_FW_VER")?,
+    })
+}
+
+/// Reads the board id (id, inverse id and flags) provisioned on the GSC.
+/// Fails with HwsecError::GsctoolError if gsctool can't talk to the GSC.
 pub fn get_board_id_with_gsctool(ctx: &mut impl Context) -> Result<BoardID, HwsecError> {
-    let gsctool_raw_response = run_gsctool_cmd(ctx, vec!["--any", "--board_id"])?;
+    let gsctool_raw_response =
+        retry_gsctool(ctx, vec!["--any", "--board_id"], GSCTOOL_QUERY_RETRIES)?;
+    if !gsctool_raw_response.status.success() {
+        return Err(HwsecError::GsctoolError(
+            gsctool_raw_response.status.code().unwrap_or(-1),
+        ));
+    }
     let board_id_output = std::str::from_utf8(&gsctool_raw_response.stdout)
         .map_err(|_| HwsecError::GsctoolResponseBadFormatError)?;
     extract_board_id_from_gsctool_response(board_id_output)
 }
 
+/// Names of the CCD capabilities, indexed by their bit in the CAPABILITIES
+/// reported by 'gsctool -a -I -M'.
+const CCD_CAPABILITY_NAMES: [&str; 21] = [
+    "UartGscRxAPTx",
+    "UartGscTxAPRx",
+    "UartGscRxECTx",
+    "UartGscTxECRx",
+    "FlashAP",
+    "FlashEC",
+    "OverrideWP",
+    "RebootECAP",
+    "GscFullConsole",
+    "UnlockNoReboot",
+    "UnlockNoShortPP",
+    "OpenNoTPMWipe",
+    "OpenNoLongPP",
+    "BatteryBypassPP",
+    "UpdateNoTPMWipe",
+    "I2C",
+    "FlashRead",
+    "OpenNoDevMode",
+    "OpenFromUSB",
+    "OverrideBatt",
+    "AllowUnverifiedRo",
+];
+
+fn parse_gsctool_hex(value: &str) -> Result<u64, HwsecError> {
+    u64::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map_err(|_| HwsecError::GsctoolResponseBadFormatError)
+}
+
+/// Parses the output of 'gsctool -a -I -M'. States and capabilities that are
+/// not known here are reported as such instead of failing.
+pub fn parse_ccd_info(ccd_info_output: &str) -> Result<CcdInfo, HwsecError> {
+    let value = |index| {
+        get_value_from_gsctool_output(ccd_info_output, index)
+            .map_err(|_| HwsecError::GsctoolResponseBadFormatError)
+    };
+
+    let state = match value("STATE")? {
+        "Locked" => CcdState::Locked,
+        "Unlocked" => CcdState::Unlocked,
+        "Opened" => CcdState::Opened,
+        other => CcdState::Unknown(other.to_string()),
+    };
+    let password_set = value("PASSWORD")? != "None";
+    let flags = parse_gsctool_hex(value("CCD_FLAGS")?)? as u32;
+    let capabilities_bitmap = parse_gsctool_hex(value("CAPABILITIES")?)?;
+
+    let mut capabilities = Vec::new();
+    let mut unknown_capabilities = Vec::new();
+    for bit in 0..u64::BITS {
+        if capabilities_bitmap & (1 << bit) == 0 {
+            continue;
+        }
+        match CCD_CAPABILITY_NAMES.get(bit as usize) {
+            Some(name) => capabilities.push(*name),
+            None => unknown_capabilities.push(bit),
+        }
+    }
+
+    Ok(CcdInfo {
+        state,
+        password_set,
+        flags,
+        capabilities,
+        unknown_capabilities,
+    })
+}
+
+/// Returns the CCD state and the enabled capabilities.
+pub fn get_ccd_state(ctx: &mut impl Context) -> Result<CcdInfo, HwsecError> {
+    let gsctool_raw_response = retry_gsctool(ctx, vec!["-a", "-I", "-M"], GSCTOOL_QUERY_RETRIES)?;
+    if !gsctool_raw_response.status.success() {
+        return Err(HwsecError::GsctoolError(
+            gsctool_raw_response.status.code().unwrap_or(-1),
+        ));
+    }
+    let ccd_info_output = std::str::from_utf8(&gsctool_raw_response.stdout)
+        .map_err(|_| HwsecError::GsctoolResponseBadFormatError)?;
+    parse_ccd_info(ccd_info_output)
+}
+
+/// Bit in the CCD_FLAGS reported by 'gsctool -a -I -M' that is set while the
+/// RMA factory mode is enabled.
+const CCD_FLAG_FACTORY_MODE_ENABLED: u32 = 1 << 2;
+
+/// Returns whether an RMA open left the GSC in the prepared (factory mode
+/// enabled) state.
+pub fn get_rma_prepared(ctx: &mut impl Context) -> Result<bool, HwsecError> {
+    Ok(get_ccd_state(ctx)?.flags & CCD_FLAG_FACTORY_MODE_ENABLED != 0)
+}
+
+/// Clears the RMA prepared state. Does nothing if the state is not set.
+pub fn clear_rma_prepared(ctx: &mut impl Context) -> Result<(), HwsecError> {
+    if !get_rma_prepared(ctx)? {
+        return Ok(());
+    }
+
+    let gsctool_raw_response = run_gsctool_cmd(ctx, vec!["-a", "-F", "disable"])?;
+    if !gsctool_raw_response.status.success() {
+        return Err(HwsecError::GsctoolError(
+            gsctool_raw_response.status.code().unwrap_or(-1),
+        ));
+    }
+    Ok(())
+}
+
+/// Virtual NV index through which the GSC exposes its G2F attestation
+/// certificate.
+const G2F_CERT_INDEX: u32 = 0x013fff02;
+
+/// TPM_RC_HANDLE, returned by firmware that doesn't expose the index.
+const TPM_RC_HANDLE: u32 = 0x0000018b;
+
+/// Tag of the ASN.1 SEQUENCE that starts a DER encoded certificate.
+const DER_SEQUENCE_TAG: u8 = 0x30;
+
+/// Returns whether the G2F attestation certificate is provisioned on the chip.
+/// Fails with HwsecError::UnsupportedFirmwareError if the firmware is too old
+/// to expose the certificate.
+pub fn has_attestation_cert(ctx: &mut impl Context) -> Result<bool, HwsecError> {
+    match nv_read(ctx, G2F_CERT_INDEX, 1) {
+        Ok(cert_head) => Ok(cert_head.first() == Some(&DER_SEQUENCE_TAG)),
+        Err(HwsecError::Tpm2Error(TPM_RC_HANDLE)) => Err(HwsecError::UnsupportedFirmwareError),
+        Err(e) => Err(e),
+    }
+}
+
 pub fn clear_terminal() {
     print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
 }
@@ -160,10 +382,55 @@ pub fn get_factory_config_with_gsctool(
 
 #[cfg(test)]
 mod tests {
+    use super::clear_rma_prepared;
+    use super::get_board_id_with_gsctool;
+    use super::get_ccd_state;
+    use super::get_rma_prepared;
     use super::get_value_from_gsctool_output;
+    use super::get_version;
+    use super::has_attestation_cert;
+    use super::parse_ccd_info;
     use super::parse_version;
+    use super::retry_gsctool;
+    use crate::context::mock::MockContext;
+    use crate::context::Context;
+    use crate::cr50::CcdInfo;
+    use crate::cr50::CcdState;
+    use crate::cr50::Cr50Version;
     use crate::cr50::Version;
     use crate::error::HwsecError;
+    use crate::tpm2::tests::split_into_hex_strtok;
+    use crate::tpm2::BoardID;
+
+    fn add_g2f_cert_read_interaction(mock_ctx: &mut MockContext, out: &str) {
+        mock_ctx.cmd_runner().set_trunksd_running(true);
+        mock_ctx.cmd_runner().add_tpm_interaction(
+            "trunks_send",
+            vec!["--raw"],
+            split_into_hex_strtok(
+                "80 02 00 00 00 23 00 00 \
+                01 4e 01 3f ff 02 01 3f \
+                ff 02 00 00 00 09 40 00 \
+                00 09 00 00 00 00 00 00 \
+                01 00 00",
+            ),
+            0,
+            out,
+            "",
+        );
+    }
+
+    fn add_ccd_info_interaction(mock_ctx: &mut MockContext, ccd_flags: &str) {
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["-a", "-I", "-M"],
+            0,
+            &format!(
+                "STATE=Opened\nPASSWORD=None\nCCD_FLAGS={}\nCAPABILITIES=0x0000000000000000\n",
+                ccd_flags
+            ),
+            "",
+        );
+    }
 
     #[test]
     fn test_parse_version_ok() {
@@ -213,4 +480,324 @@ mod tests {
         let result = get_value_from_gsctool_output("ABC=", "INDEX");
         assert_eq!(result, Err(HwsecError::InternalError));
     }
+
+    #[test]
+    fn test_get_ccd_state_opened() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["-a", "-I", "-M"],
+            0,
+            "STATE=Opened\nPASSWORD=Set\nCCD_FLAGS=0x000004\nCAPABILITIES=0x0000000000000053\n",
+            "",
+        );
+
+        assert_eq!(
+            get_ccd_state(&mut mock_ctx),
+            Ok(CcdInfo {
+                state: CcdState::Opened,
+                password_set: true,
+                flags: 0x4,
+                capabilities: vec!["UartGscRxAPTx", "UartGscTxAPRx", "FlashAP", "OverrideWP"],
+                unknown_capabilities: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_ccd_info_unknown_state_and_capabilities() {
+        let info = parse_ccd_info(
+            "STATE=Restricted\nPASSWORD=None\nCCD_FLAGS=0x000000\n\
+            CAPABILITIES=0x0000000100100000\n",
+        )
+        .unwrap();
+
+        assert_eq!(info.state, CcdState::Unknown("Restricted".to_string()));
+        assert!(!info.password_set);
+        assert_eq!(info.capabilities, vec!["AllowUnverifiedRo"]);
+        assert_eq!(info.unknown_capabilities, vec![32]);
+    }
+
+    #[test]
+    fn test_parse_ccd_info_bad_format() {
+        assert_eq!(
+            parse_ccd_info("STATE=Locked\nPASSWORD=None\n"),
+            Err(HwsecError::GsctoolResponseBadFormatError)
+        );
+        assert_eq!(
+            parse_ccd_info("STATE=Locked\nPASSWORD=None\nCCD_FLAGS=0x0\nCAPABILITIES=zz\n"),
+            Err(HwsecError::GsctoolResponseBadFormatError)
+        );
+    }
+
+    #[test]
+    fn test_version_ordering() {
+        let version = |epoch, major, minor| Version {
+            epoch,
+            major,
+            minor,
+        };
+        assert!(version(0, 5, 120) > version(0, 5, 9));
+        assert!(version(0, 6, 0) > version(0, 5, 120));
+        assert!(version(1, 0, 0) > version(0, 6, 0));
+        assert_eq!(
+            version(0, 3, 24).cmp(&version(0, 4, 2)),
+            version(0, 3, 24).to_ord().cmp(&version(0, 4, 2).to_ord())
+        );
+    }
+
+    #[test]
+    fn test_get_version_ok() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--fwver", "--machine"],
+            0,
+            "RO_FW_VER=0.0.11\nRW_FW_VER=0.5.120\n",
+            "",
+        );
+
+        assert_eq!(
+            get_version(&mut mock_ctx),
+            Ok(Cr50Version {
+                ro: Version {
+                    epoch: 0,
+                    major: 0,
+                    minor: 11,
+                },
+                rw: Version {
+                    epoch: 0,
+                    major: 5,
+                    minor: 120,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_get_version_bad_format() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--fwver", "--machine"],
+            0,
+            "RO_FW_VER=0.0.11\nRW_FW_VER=0.5\n",
+            "",
+        );
+        assert_eq!(
+            get_version(&mut mock_ctx),
+            Err(HwsecError::GsctoolResponseBadFormatError)
+        );
+
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--fwver", "--machine"],
+            0,
+            "RW_FW_VER=0.5.120\n",
+            "",
+        );
+        assert_eq!(
+            get_version(&mut mock_ctx),
+            Err(HwsecError::GsctoolResponseBadFormatError)
+        );
+    }
+
+    #[test]
+    fn test_get_board_id_with_gsctool_ok() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx
+            .cmd_runner()
+            .add_successful_gsctool_read_board_id_interaction(BoardID {
+                part_1: 0x5a5a4352,
+                part_2: 0xa5a5bcad,
+                flag: 0x00003f80,
+            });
+
+        assert_eq!(
+            get_board_id_with_gsctool(&mut mock_ctx),
+            Ok(BoardID {
+                part_1: 0x5a5a4352,
+                part_2: 0xa5a5bcad,
+                flag: 0x00003f80,
+            })
+        );
+    }
+
+    #[test]
+    fn test_get_board_id_with_gsctool_unavailable() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            3,
+            "",
+            "Error: Could not open TPM",
+        );
+
+        assert_eq!(
+            get_board_id_with_gsctool(&mut mock_ctx),
+            Err(HwsecError::GsctoolError(3))
+        );
+    }
+
+    #[test]
+    fn test_get_board_id_with_gsctool_retried() {
+        let mut mock_ctx = MockContext::new();
+        for _ in 0..2 {
+            mock_ctx.cmd_runner().add_gsctool_interaction(
+                vec!["--any", "--board_id"],
+                1,
+                "",
+                "Error: Can't find device\n",
+            );
+        }
+        mock_ctx
+            .cmd_runner()
+            .add_successful_gsctool_read_board_id_interaction(BoardID {
+                part_1: 0x5a5a4352,
+                part_2: 0xa5a5bcad,
+                flag: 0x00003f80,
+            });
+
+        assert_eq!(
+            get_board_id_with_gsctool(&mut mock_ctx),
+            Ok(BoardID {
+                part_1: 0x5a5a4352,
+                part_2: 0xa5a5bcad,
+                flag: 0x00003f80,
+            })
+        );
+    }
+
+    #[test]
+    fn test_retry_gsctool_gives_up() {
+        let mut mock_ctx = MockContext::new();
+        for _ in 0..2 {
+            mock_ctx.cmd_runner().add_gsctool_interaction(
+                vec!["--any", "--fwver"],
+                1,
+                "",
+                "open /dev/tpm0: Device or resource busy\n",
+            );
+        }
+
+        // Only the last failure is returned, and not retried again.
+        let output = retry_gsctool(&mut mock_ctx, vec!["--any", "--fwver"], 1).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+    }
+
+    #[test]
+    fn test_get_board_id_with_gsctool_bad_format() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["--any", "--board_id"],
+            0,
+            "finding_device 18d1:5014\nFound device.\nBoard ID space: 5a5a4352:a5a5\n",
+            "",
+        );
+
+        assert_eq!(
+            get_board_id_with_gsctool(&mut mock_ctx),
+            Err(HwsecError::GsctoolResponseBadFormatError)
+        );
+    }
+
+    #[test]
+    fn test_get_rma_prepared_true() {
+        let mut mock_ctx = MockContext::new();
+        add_ccd_info_interaction(&mut mock_ctx, "0x000004");
+        assert_eq!(get_rma_prepared(&mut mock_ctx), Ok(true));
+    }
+
+    #[test]
+    fn test_get_rma_prepared_false() {
+        let mut mock_ctx = MockContext::new();
+        add_ccd_info_interaction(&mut mock_ctx, "0xc00003");
+        assert_eq!(get_rma_prepared(&mut mock_ctx), Ok(false));
+    }
+
+    #[test]
+    fn test_get_rma_prepared_retried() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["-a", "-I", "-M"],
+            1,
+            "",
+            "open /dev/tpm0: Device or resource busy\n",
+        );
+        add_ccd_info_interaction(&mut mock_ctx, "0x000004");
+        assert_eq!(get_rma_prepared(&mut mock_ctx), Ok(true));
+    }
+
+    #[test]
+    fn test_get_rma_prepared_bad_format() {
+        let mut mock_ctx = MockContext::new();
+        mock_ctx.cmd_runner().add_gsctool_interaction(
+            vec!["-a", "-I", "-M"],
+            0,
+            "STATE=Opened\n",
+            "",
+        );
+        assert_eq!(
+            get_rma_prepared(&mut mock_ctx),
+            Err(HwsecError::GsctoolResponseBadFormatError)
+        );
+    }
+
+    #[test]
+    fn test_clear_rma_prepared_idempotent() {
+        let mut mock_ctx = MockContext::new();
+        add_ccd_info_interaction(&mut mock_ctx, "0x000004");
+        mock_ctx
+            .cmd_runner()
+            .add_gsctool_interaction(vec!["-a", "-F", "disable"], 0, "", "");
+        // Once cleared, the state is no longer reported and nothing else is run.
+        add_ccd_info_interaction(&mut mock_ctx, "0x000000");
+
+        assert_eq!(clear_rma_prepared(&mut mock_ctx), Ok(()));
+        assert_eq!(clear_rma_prepared(&mut mock_ctx), Ok(()));
+    }
+
+    #[test]
+    fn test_clear_rma_prepared_failed() {
+        let mut mock_ctx = MockContext::new();
+        add_ccd_info_interaction(&mut mock_ctx, "0x000004");
+        mock_ctx
+            .cmd_runner()
+            .add_gsctool_interaction(vec!["-a", "-F", "disable"], 1, "", "");
+
+        assert_eq!(
+            clear_rma_prepared(&mut mock_ctx),
+            Err(HwsecError::GsctoolError(1))
+        );
+    }
+
+    #[test]
+    fn test_has_attestation_cert_present() {
+        let mut mock_ctx = MockContext::new();
+        add_g2f_cert_read_interaction(
+            &mut mock_ctx,
+            "80020000001600000000000000030001300000010000",
+        );
+
+        assert_eq!(has_attestation_cert(&mut mock_ctx), Ok(true));
+    }
+
+    #[test]
+    fn test_has_attestation_cert_absent() {
+        let mut mock_ctx = MockContext::new();
+        add_g2f_cert_read_interaction(
+            &mut mock_ctx,
+            "80020000001600000000000000030001FF0000010000",
+        );
+
+        assert_eq!(has_attestation_cert(&mut mock_ctx), Ok(false));
+    }
+
+    #[test]
+    fn test_has_attestation_cert_unsupported() {
+        let mut mock_ctx = MockContext::new();
+        add_g2f_cert_read_interaction(&mut mock_ctx, "80010000000A0000018B");
+
+        assert_eq!(
+            has_attestation_cert(&mut mock_ctx),
+            Err(HwsecError::UnsupportedFirmwareError)
+        );
+    }
 }
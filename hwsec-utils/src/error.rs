@@ -21,6 +21,7 @@ pub enum HwsecError {
     GbbFlagOperationError,
     SystemRebootError,
     SystemTimeError,
+    UnsupportedFirmwareError,
     InternalError,
 }
 
@@ -45,6 +46,7 @@ impl Display for HwsecError {
             HwsecError::GbbFlagOperationError => write!(f, "GbbFlagOperationError"),
             HwsecError::SystemTimeError => write!(f, "SystemTimeError"),
             HwsecError::SystemRebootError => write!(f, "SystemRebootError"),
+            HwsecError::UnsupportedFirmwareError => write!(f, "UnsupportedFirmwareError"),
             HwsecError::InternalError => write!(f, "InternalError"),
         }
     }
@@ -40,6 +40,7 @@ pub mod rand;
 pub mod scoped_path;
 pub mod secure_blob;
 pub mod signal;
+pub mod sys;
 pub mod syslog;
 
 use std::fs::File;
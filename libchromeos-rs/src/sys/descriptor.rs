@@ -0,0 +1,66 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Helpers for owned file descriptors.
+
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::Result;
+
+/// Duplicates a descriptor with `FD_CLOEXEC` set on the duplicate, whatever the flags of the
+/// original are, so that the duplicate doesn't leak into children exec'ed by another thread.
+pub trait TryCloneCloexec {
+    fn try_clone_cloexec(&self) -> Result<OwnedFd>;
+}
+
+impl<T: AsRawFd + ?Sized> TryCloneCloexec for T {
+    fn try_clone_cloexec(&self) -> Result<OwnedFd> {
+        // F_DUPFD_CLOEXEC sets the flag atomically, unlike dup() followed by F_SETFD.
+        let fd = fcntl(self.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(0))?;
+        // Safe because the descriptor was just duplicated and nothing else owns it.
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::{Read, Write};
+
+    use nix::fcntl::FdFlag;
+
+    use crate::pipe;
+
+    fn is_cloexec(fd: &dyn AsRawFd) -> bool {
+        let flags = fcntl(fd.as_raw_fd(), FcntlArg::F_GETFD).unwrap();
+        FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC)
+    }
+
+    #[test]
+    fn clone_pipe_sets_cloexec() {
+        let (rx, tx) = pipe(false).unwrap();
+        assert!(!is_cloexec(&rx));
+
+        let mut rx_clone = File::from(rx.try_clone_cloexec().unwrap());
+        let mut tx_clone = File::from(tx.try_clone_cloexec().unwrap());
+        assert!(is_cloexec(&rx_clone));
+        assert!(is_cloexec(&tx_clone));
+        // The originals are left alone.
+        assert!(!is_cloexec(&rx));
+
+        // Both the originals and the clones refer to the same pipe.
+        let (mut rx, mut tx) = (rx, tx);
+        tx_clone.write_all(b"from clone").unwrap();
+        let mut buf = [0u8; 10];
+        rx.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"from clone");
+
+        tx.write_all(b"from first").unwrap();
+        rx_clone.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"from first");
+    }
+}
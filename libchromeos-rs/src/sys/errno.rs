@@ -0,0 +1,79 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Predicates for the errnos that retry loops have to tell apart.
+
+use std::io;
+
+use nix::errno::Errno;
+
+/// Tells the errnos apart that call for retrying an operation, for both the errors of the sys
+/// helpers and the ones of std I/O.
+pub trait ErrnoExt {
+    /// Returns the errno of the error, None if it doesn't have one.
+    fn errno(&self) -> Option<Errno>;
+
+    /// True if a non-blocking operation would have blocked, EAGAIN or EWOULDBLOCK.
+    fn is_would_block(&self) -> bool {
+        self.errno() == Some(Errno::EWOULDBLOCK)
+    }
+
+    /// True if the operation was interrupted by a signal before it did anything, EINTR.
+    fn is_interrupted(&self) -> bool {
+        self.errno() == Some(Errno::EINTR)
+    }
+
+    /// True if the operation timed out, ETIMEDOUT.
+    fn is_timed_out(&self) -> bool {
+        self.errno() == Some(Errno::ETIMEDOUT)
+    }
+}
+
+impl ErrnoExt for Errno {
+    fn errno(&self) -> Option<Errno> {
+        Some(*self)
+    }
+}
+
+impl ErrnoExt for io::Error {
+    fn errno(&self) -> Option<Errno> {
+        self.raw_os_error().map(Errno::from_i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errno_predicates() {
+        assert!(Errno::EAGAIN.is_would_block());
+        assert!(Errno::EWOULDBLOCK.is_would_block());
+        assert!(!Errno::EAGAIN.is_interrupted());
+
+        assert!(Errno::EINTR.is_interrupted());
+        assert!(!Errno::EINTR.is_would_block());
+
+        assert!(Errno::ETIMEDOUT.is_timed_out());
+        assert!(!Errno::ETIMEDOUT.is_would_block());
+
+        for errno in [Errno::EBADF, Errno::EINVAL] {
+            assert!(!errno.is_would_block());
+            assert!(!errno.is_interrupted());
+            assert!(!errno.is_timed_out());
+        }
+    }
+
+    #[test]
+    fn io_error_predicates() {
+        assert!(io::Error::from_raw_os_error(libc::EAGAIN).is_would_block());
+        assert!(io::Error::from(Errno::EINTR).is_interrupted());
+        assert!(io::Error::from_raw_os_error(libc::ETIMEDOUT).is_timed_out());
+
+        // Errors that don't come from the kernel have no errno.
+        let err = io::Error::new(io::ErrorKind::WouldBlock, "not an errno");
+        assert_eq!(err.errno(), None);
+        assert!(!err.is_would_block());
+    }
+}
@@ -0,0 +1,21 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Thin wrappers around Linux system primitives.
+
+mod descriptor;
+mod errno;
+mod scm;
+mod shm;
+mod vsock;
+mod wait;
+mod watcher;
+
+pub use descriptor::*;
+pub use errno::*;
+pub use scm::*;
+pub use shm::*;
+pub use vsock::*;
+pub use wait::*;
+pub use watcher::*;
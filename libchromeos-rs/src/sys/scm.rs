@@ -0,0 +1,215 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Passing file descriptors over unix sockets with `SCM_RIGHTS`, optionally with a timeout.
+
+use std::convert::TryFrom;
+use std::io::{IoSlice, IoSliceMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::sys::socket::{
+    getsockopt, recvmsg, sendmsg, setsockopt, sockopt, ControlMessage, ControlMessageOwned,
+    GetSockOpt, MsgFlags, SetSockOpt, UnixAddr,
+};
+use nix::sys::time::{TimeVal, TimeValLike};
+use nix::Result;
+
+/// The most descriptors received by a single recv_with_fds() call.
+pub const SCM_MAX_FDS: usize = 32;
+
+/// Sends `data` along with `fds` over the unix socket `socket`. Returns the number of bytes of
+/// `data` sent; the descriptors are sent with the first byte.
+pub fn send_with_fds(socket: &dyn AsRawFd, data: &[u8], fds: &[RawFd]) -> Result<usize> {
+    let iov = [IoSlice::new(data)];
+    let cmsgs = if fds.is_empty() {
+        vec![]
+    } else {
+        vec![ControlMessage::ScmRights(fds)]
+    };
+
+    loop {
+        match sendmsg::<UnixAddr>(socket.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None) {
+            Err(Errno::EINTR) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Receives data into `buf` along with up to [`SCM_MAX_FDS`] descriptors from the unix socket
+/// `socket`. The received descriptors are close-on-exec.
+///
+/// Returns `Errno::EMSGSIZE` if the peer sent more descriptors than fit, the received ones are
+/// closed then.
+pub fn recv_with_fds(socket: &dyn AsRawFd, buf: &mut [u8]) -> Result<(usize, Vec<OwnedFd>)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; SCM_MAX_FDS]);
+
+    let msg = loop {
+        match recvmsg::<UnixAddr>(
+            socket.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        ) {
+            Err(Errno::EINTR) => continue,
+            result => break result?,
+        }
+    };
+
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            // Safe because the kernel just installed the descriptors and nothing else owns them.
+            fds.extend(
+                received
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    Ok((msg.bytes, fds))
+}
+
+/// Like [`send_with_fds`], but returns `Errno::ETIMEDOUT` if the data can't be sent within
+/// `timeout`. The previous send timeout of the socket is restored afterwards.
+pub fn send_with_fds_timeout(
+    socket: &dyn AsRawFd,
+    data: &[u8],
+    fds: &[RawFd],
+    timeout: Duration,
+) -> Result<usize> {
+    let _timeout = SocketTimeout::set(socket.as_raw_fd(), sockopt::SendTimeout, timeout)?;
+    send_with_fds(socket, data, fds).map_err(timed_out)
+}
+
+/// Like [`recv_with_fds`], but returns `Errno::ETIMEDOUT` if nothing is received within
+/// `timeout`. The previous receive timeout of the socket is restored afterwards.
+pub fn recv_with_fds_timeout(
+    socket: &dyn AsRawFd,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> Result<(usize, Vec<OwnedFd>)> {
+    let _timeout = SocketTimeout::set(socket.as_raw_fd(), sockopt::ReceiveTimeout, timeout)?;
+    recv_with_fds(socket, buf).map_err(timed_out)
+}
+
+// The socket timeouts fail blocking calls with EAGAIN.
+fn timed_out(err: Errno) -> Errno {
+    match err {
+        Errno::EAGAIN => Errno::ETIMEDOUT,
+        err => err,
+    }
+}
+
+// Sets a socket timeout option and restores its previous value when dropped.
+struct SocketTimeout<O>
+where
+    O: GetSockOpt<Val = TimeVal> + SetSockOpt<Val = TimeVal> + Copy,
+{
+    fd: RawFd,
+    opt: O,
+    previous: TimeVal,
+}
+
+impl<O> SocketTimeout<O>
+where
+    O: GetSockOpt<Val = TimeVal> + SetSockOpt<Val = TimeVal> + Copy,
+{
+    fn set(fd: RawFd, opt: O, timeout: Duration) -> Result<Self> {
+        let previous = getsockopt(fd, opt)?;
+        // A zero timeout means blocking forever, round it up to the shortest one instead.
+        let micros = i64::try_from(timeout.as_micros())
+            .unwrap_or(i64::MAX)
+            .max(1);
+        setsockopt(fd, opt, &TimeVal::microseconds(micros))?;
+        Ok(SocketTimeout { fd, opt, previous })
+    }
+}
+
+impl<O> Drop for SocketTimeout<O>
+where
+    O: GetSockOpt<Val = TimeVal> + SetSockOpt<Val = TimeVal> + Copy,
+{
+    fn drop(&mut self) {
+        let _ = setsockopt(self.fd, self.opt, &self.previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::time::Instant;
+
+    use crate::pipe;
+
+    #[test]
+    fn send_and_recv_fds() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (pipe_rx, mut pipe_tx) = pipe(true).unwrap();
+
+        assert_eq!(
+            send_with_fds_timeout(&a, b"fd", &[pipe_rx.as_raw_fd()], Duration::from_secs(10)),
+            Ok(2)
+        );
+        drop(pipe_rx);
+
+        let mut buf = [0u8; 8];
+        let (len, mut fds) = recv_with_fds_timeout(&b, &mut buf, Duration::from_secs(10)).unwrap();
+        assert_eq!(&buf[..len], b"fd");
+        assert_eq!(fds.len(), 1);
+
+        // The received descriptor is the read end of the pipe.
+        pipe_tx.write_all(b"data").unwrap();
+        let mut received = File::from(fds.remove(0));
+        let mut data = [0u8; 4];
+        received.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"data");
+    }
+
+    #[test]
+    fn recv_times_out_on_silent_peer() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let previous = Duration::from_secs(30);
+        a.set_read_timeout(Some(previous)).unwrap();
+
+        let start = Instant::now();
+        let mut buf = [0u8; 8];
+        assert!(matches!(
+            recv_with_fds_timeout(&a, &mut buf, Duration::from_millis(50)),
+            Err(Errno::ETIMEDOUT)
+        ));
+        assert!(start.elapsed() < previous);
+
+        // The previous timeout is back in place.
+        assert_eq!(a.read_timeout().unwrap(), Some(previous));
+    }
+
+    #[test]
+    fn send_times_out_on_full_socket() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let data = vec![0u8; 64 * 1024];
+
+        // The peer never reads, so the socket buffer fills up eventually.
+        loop {
+            match send_with_fds_timeout(&a, &data, &[], Duration::from_millis(50)) {
+                Ok(_) => continue,
+                Err(err) => {
+                    assert_eq!(err, Errno::ETIMEDOUT);
+                    break;
+                }
+            }
+        }
+        assert_eq!(a.write_timeout().unwrap(), None);
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Memfd backed shared memory that can grow in place, and sealed memfds for handing data to
+//! less trusted processes.
+
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::num::NonZeroUsize;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::slice;
+
+use libc::{c_void, off_t};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag, SealFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::ftruncate;
+use nix::Result;
+
+// A shared mapping of a memfd, unmapped when dropped.
+struct Mapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+// Safe because the mapping is owned by the struct and only handed out through borrows of it.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // Safe because the mapping was created by ResizableSharedMemory::new() and nothing
+        // borrows it any more.
+        let _ = unsafe { munmap(self.addr as *mut c_void, self.len) };
+    }
+}
+
+/// Shared memory backed by a memfd whose size can grow up to a fixed maximum.
+///
+/// The whole maximum size is mapped up front so that growing never moves the mapping, but only
+/// the current size is ever exposed: touching the mapping past the end of the memfd would raise
+/// `SIGBUS`.
+pub struct ResizableSharedMemory {
+    fd: OwnedFd,
+    mapping: Mapping,
+    size: usize,
+}
+
+impl ResizableSharedMemory {
+    /// Creates shared memory named `name` of `size` bytes that can grow up to `max_size` bytes.
+    ///
+    /// Returns `Errno::EINVAL` if `max_size` is zero or smaller than `size`. The memfd is created
+    /// with sealing allowed, so that its size can be sealed before it is handed out.
+    pub fn new(name: &CStr, size: usize, max_size: usize) -> Result<Self> {
+        let map_size = NonZeroUsize::new(max_size).ok_or(Errno::EINVAL)?;
+        if size > max_size {
+            return Err(Errno::EINVAL);
+        }
+
+        let raw_fd = memfd_create(
+            name,
+            MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+        )?;
+        // Safe because the descriptor was just created and nothing else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+        ftruncate(fd.as_raw_fd(), to_off_t(size)?)?;
+
+        // Safe because a new mapping is created rather than replacing an existing one, and it is
+        // only accessed within the size of the memfd.
+        let addr = unsafe {
+            mmap(
+                None,
+                map_size,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )?
+        };
+
+        Ok(ResizableSharedMemory {
+            fd,
+            mapping: Mapping {
+                addr: addr as *mut u8,
+                len: max_size,
+            },
+            size,
+        })
+    }
+
+    /// Returns the current size in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the size in bytes the shared memory can grow to.
+    pub fn max_size(&self) -> usize {
+        self.mapping.len
+    }
+
+    /// Grows the shared memory to `new_size` bytes. The new bytes are zeroed.
+    ///
+    /// Returns `Errno::EINVAL` if `new_size` is smaller than the current size, as other
+    /// processes may still access the shrunk part, and `Errno::ENOSPC` if it exceeds the max size.
+    pub fn grow(&mut self, new_size: usize) -> Result<()> {
+        if new_size < self.size {
+            return Err(Errno::EINVAL);
+        }
+        if new_size > self.mapping.len {
+            return Err(Errno::ENOSPC);
+        }
+
+        ftruncate(self.fd.as_raw_fd(), to_off_t(new_size)?)?;
+        self.size = new_size;
+        Ok(())
+    }
+
+    /// Returns the contents of the shared memory up to its current size.
+    pub fn as_slice(&self) -> &[u8] {
+        // Safe because the mapping is valid for max_size bytes, of which the first size bytes are
+        // backed by the memfd.
+        unsafe { slice::from_raw_parts(self.mapping.addr, self.size) }
+    }
+
+    /// Returns the contents of the shared memory up to its current size for writing.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safe for the same reasons as as_slice(), and the borrow of self is exclusive.
+        unsafe { slice::from_raw_parts_mut(self.mapping.addr, self.size) }
+    }
+
+    /// Unmaps the shared memory and seals it against any further change of its size or contents.
+    /// Returns a read-only descriptor of the memory meant to be passed to other processes.
+    pub fn into_sealed(self) -> Result<OwnedFd> {
+        let ResizableSharedMemory { fd, mapping, .. } = self;
+        // F_SEAL_WRITE fails while there are writable shared mappings.
+        drop(mapping);
+
+        fcntl(
+            fd.as_raw_fd(),
+            FcntlArg::F_ADD_SEALS(
+                SealFlag::F_SEAL_SHRINK
+                    | SealFlag::F_SEAL_GROW
+                    | SealFlag::F_SEAL_WRITE
+                    | SealFlag::F_SEAL_SEAL,
+            ),
+        )?;
+
+        // Reopening the memfd is the only way to drop the write access of the descriptor itself.
+        let read_only = open(
+            format!("/proc/self/fd/{}", fd.as_raw_fd()).as_str(),
+            OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?;
+        // Safe because the descriptor was just opened and nothing else owns it.
+        Ok(unsafe { OwnedFd::from_raw_fd(read_only) })
+    }
+}
+
+impl AsRawFd for ResizableSharedMemory {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Creates a memfd named `name` holding `data`, sealed against any further change and returned as
+/// a read-only descriptor.
+pub fn sealed_memfd(name: &CStr, data: &[u8]) -> Result<OwnedFd> {
+    // Mappings can't be empty.
+    let mut shm = ResizableSharedMemory::new(name, data.len(), data.len().max(1))?;
+    shm.as_mut_slice().copy_from_slice(data);
+    shm.into_sealed()
+}
+
+fn to_off_t(size: usize) -> Result<off_t> {
+    off_t::try_from(size).map_err(|_| Errno::EFBIG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+
+    fn name() -> &'static CStr {
+        CStr::from_bytes_with_nul(b"resizable_shm_test\0").unwrap()
+    }
+
+    #[test]
+    fn grow_keeps_contents() {
+        let mut shm = ResizableSharedMemory::new(name(), 4, 4096).unwrap();
+        shm.as_mut_slice().copy_from_slice(b"abcd");
+
+        shm.grow(8).unwrap();
+        assert_eq!(shm.size(), 8);
+        assert_eq!(shm.as_slice(), b"abcd\0\0\0\0");
+        shm.as_mut_slice()[4..].copy_from_slice(b"efgh");
+
+        // The memfd itself has grown, not only the view of it.
+        let file = File::from(shm.fd.try_clone().unwrap());
+        assert_eq!(file.metadata().unwrap().len(), 8);
+        let mut data = [0u8; 8];
+        file.read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(&data, b"abcdefgh");
+    }
+
+    #[test]
+    fn grow_rejects_shrink() {
+        let mut shm = ResizableSharedMemory::new(name(), 16, 4096).unwrap();
+        assert_eq!(shm.grow(8), Err(Errno::EINVAL));
+        assert_eq!(shm.size(), 16);
+        // Growing to the same size is a no-op.
+        shm.grow(16).unwrap();
+    }
+
+    #[test]
+    fn slices_are_bounded_by_size() {
+        let mut shm = ResizableSharedMemory::new(name(), 0, 4096).unwrap();
+        assert!(shm.as_slice().is_empty());
+
+        assert_eq!(shm.grow(4097), Err(Errno::ENOSPC));
+        assert!(shm.as_mut_slice().is_empty());
+
+        shm.grow(4096).unwrap();
+        assert_eq!(shm.as_slice().len(), 4096);
+        assert_eq!(shm.as_mut_slice().len(), 4096);
+    }
+
+    #[test]
+    fn sealed_memfd_rejects_writes() {
+        let sealed = sealed_memfd(name(), b"sealed data").unwrap();
+        let seals =
+            SealFlag::from_bits_truncate(fcntl(sealed.as_raw_fd(), FcntlArg::F_GET_SEALS).unwrap());
+        assert!(seals.contains(
+            SealFlag::F_SEAL_SHRINK
+                | SealFlag::F_SEAL_GROW
+                | SealFlag::F_SEAL_WRITE
+                | SealFlag::F_SEAL_SEAL
+        ));
+
+        // The descriptor is read-only.
+        assert_eq!(
+            nix::unistd::write(sealed.as_raw_fd(), b"x"),
+            Err(Errno::EBADF)
+        );
+        // Even reopening the memfd for writing doesn't get around the seals.
+        let writable = open(
+            format!("/proc/self/fd/{}", sealed.as_raw_fd()).as_str(),
+            OFlag::O_RDWR | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .unwrap();
+        let writable = unsafe { OwnedFd::from_raw_fd(writable) };
+        assert_eq!(
+            nix::unistd::write(writable.as_raw_fd(), b"x"),
+            Err(Errno::EPERM)
+        );
+        assert_eq!(ftruncate(writable.as_raw_fd(), 0), Err(Errno::EPERM));
+        assert_eq!(ftruncate(writable.as_raw_fd(), 4096), Err(Errno::EPERM));
+
+        // A duplicate, as received by another process, reads the data.
+        let file = File::from(sealed.try_clone().unwrap());
+        let mut data = [0u8; 11];
+        file.read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(&data, b"sealed data");
+        assert_eq!(file.metadata().unwrap().len(), 11);
+    }
+
+    #[test]
+    fn new_rejects_invalid_sizes() {
+        assert!(matches!(
+            ResizableSharedMemory::new(name(), 0, 0),
+            Err(Errno::EINVAL)
+        ));
+        assert!(matches!(
+            ResizableSharedMemory::new(name(), 8192, 4096),
+            Err(Errno::EINVAL)
+        ));
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Stream sockets over vsock, the host <-> VM transport.
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::socket::{
+    accept4, bind, connect, getpeername, getsockname, listen, socket, AddressFamily, SockFlag,
+    SockType, VsockAddr,
+};
+use nix::unistd::{read, write};
+use nix::Result;
+
+pub use libc::{VMADDR_CID_ANY, VMADDR_CID_HOST, VMADDR_CID_LOCAL, VMADDR_PORT_ANY};
+
+/// The number of pending connections the listener queues before refusing new ones.
+const LISTEN_BACKLOG: usize = 128;
+
+fn vsock_socket() -> Result<OwnedFd> {
+    let fd = socket(
+        AddressFamily::Vsock,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // Safe because the descriptor was just created and nothing else owns it.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn set_nonblocking(fd: RawFd, nonblocking: bool) -> Result<()> {
+    let mut flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    flags.set(OFlag::O_NONBLOCK, nonblocking);
+    fcntl(fd, FcntlArg::F_SETFL(flags)).map(drop)
+}
+
+/// A vsock server socket listening for connections.
+///
+/// vsock can't carry `SCM_RIGHTS`, descriptors have to be passed over a unix socket instead.
+pub struct VsockListener {
+    fd: OwnedFd,
+}
+
+impl VsockListener {
+    /// Listens on `port` of `cid`, usually `VMADDR_CID_ANY`. With `VMADDR_PORT_ANY` the kernel
+    /// picks a free port, see [`VsockListener::local_addr`].
+    pub fn bind(cid: u32, port: u32) -> Result<Self> {
+        let fd = vsock_socket()?;
+        bind(fd.as_raw_fd(), &VsockAddr::new(cid, port))?;
+        listen(fd.as_raw_fd(), LISTEN_BACKLOG)?;
+        Ok(VsockListener { fd })
+    }
+
+    /// Returns the address the listener is bound to.
+    pub fn local_addr(&self) -> Result<VsockAddr> {
+        getsockname(self.fd.as_raw_fd())
+    }
+
+    /// Accepts a connection along with the address of the peer. In non-blocking mode this
+    /// returns `Errno::EAGAIN` if there is no pending connection.
+    pub fn accept(&self) -> Result<(VsockStream, VsockAddr)> {
+        let fd = accept4(self.fd.as_raw_fd(), SockFlag::SOCK_CLOEXEC)?;
+        // Safe because the descriptor was just accepted and nothing else owns it.
+        let stream = VsockStream {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let peer = stream.peer_addr()?;
+        Ok((stream, peer))
+    }
+
+    /// Switches accept() between blocking and non-blocking mode. Accepted streams are blocking
+    /// either way.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        set_nonblocking(self.fd.as_raw_fd(), nonblocking)
+    }
+}
+
+impl AsRawFd for VsockListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// A connected vsock stream socket.
+pub struct VsockStream {
+    fd: OwnedFd,
+}
+
+impl VsockStream {
+    /// Connects to `port` of `cid`.
+    pub fn connect(cid: u32, port: u32) -> Result<Self> {
+        let fd = vsock_socket()?;
+        connect(fd.as_raw_fd(), &VsockAddr::new(cid, port))?;
+        Ok(VsockStream { fd })
+    }
+
+    /// Returns the address of the other end of the stream.
+    pub fn peer_addr(&self) -> Result<VsockAddr> {
+        getpeername(self.fd.as_raw_fd())
+    }
+
+    /// Switches reads and writes between blocking and non-blocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        set_nonblocking(self.fd.as_raw_fd(), nonblocking)
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read(self.fd.as_raw_fd(), buf).map_err(io::Error::from)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write(self.fd.as_raw_fd(), buf).map_err(io::Error::from)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for VsockStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nix::errno::Errno;
+
+    #[test]
+    fn nonblocking_accept_without_connection() {
+        let listener = VsockListener::bind(VMADDR_CID_ANY, VMADDR_PORT_ANY).unwrap();
+        assert_ne!(listener.local_addr().unwrap().port(), VMADDR_PORT_ANY);
+
+        listener.set_nonblocking(true).unwrap();
+        assert!(matches!(listener.accept(), Err(Errno::EAGAIN)));
+    }
+
+    // Loopback needs the vsock_loopback transport, which not every kernel has. Run it with
+    // `cargo test -- --ignored` where it is loaded.
+    #[test]
+    #[ignore]
+    fn loopback_connection() {
+        let listener = VsockListener::bind(VMADDR_CID_LOCAL, VMADDR_PORT_ANY).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut client = VsockStream::connect(VMADDR_CID_LOCAL, port).unwrap();
+        let (mut server, peer) = listener.accept().unwrap();
+        assert_eq!(peer.cid(), VMADDR_CID_LOCAL);
+
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        server.write_all(b"pong").unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+}
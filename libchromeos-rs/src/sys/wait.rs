@@ -0,0 +1,119 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Helpers for reaping child processes without blocking forever.
+
+use std::convert::TryFrom;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+use nix::Result;
+
+/// Opens a pidfd referring to the process `pid`. The returned descriptor becomes readable once the
+/// process exits.
+pub fn pidfd_open(pid: Pid) -> Result<OwnedFd> {
+    // Safe because the syscall does not touch memory and the returned descriptor is checked
+    // before it is owned.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if fd < 0 {
+        return Err(Errno::last());
+    }
+
+    // Safe because the descriptor was just created and nothing else owns it.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as c_int) })
+}
+
+/// Waits for the child process `pid` to exit and reaps it.
+///
+/// Returns `Errno::ETIMEDOUT` if the child is still running after `timeout`. In that case the
+/// child is left running and has not been reaped.
+pub fn wait_timeout(pid: Pid, timeout: Duration) -> Result<WaitStatus> {
+    let pidfd = pidfd_open(pid)?;
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout_ms = c_int::try_from(remaining.as_millis()).unwrap_or(c_int::MAX);
+        let mut fds = [PollFd::new(pidfd.as_raw_fd(), PollFlags::POLLIN)];
+
+        match poll(&mut fds, timeout_ms) {
+            Ok(0) => return Err(Errno::ETIMEDOUT),
+            Ok(_) => break,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    waitpid(pid, None)
+}
+
+/// Like [`wait_timeout`], but kills and reaps the child with `SIGKILL` if it is still running
+/// after `timeout`. `Errno::ETIMEDOUT` is still returned in that case.
+pub fn wait_timeout_or_kill(pid: Pid, timeout: Duration) -> Result<WaitStatus> {
+    match wait_timeout(pid, timeout) {
+        Err(Errno::ETIMEDOUT) => {
+            kill(pid, Signal::SIGKILL)?;
+            waitpid(pid, None)?;
+            Err(Errno::ETIMEDOUT)
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process::{Child, Command};
+
+    // The returned child is reaped by the helpers under test rather than through `Child::wait`.
+    fn spawn(args: &[&str]) -> (Child, Pid) {
+        let child = Command::new(args[0]).args(&args[1..]).spawn().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+        (child, pid)
+    }
+
+    #[test]
+    fn wait_timeout_reaps_exited_child() {
+        let (_child, pid) = spawn(&["true"]);
+        assert_eq!(
+            wait_timeout(pid, Duration::from_secs(10)),
+            Ok(WaitStatus::Exited(pid, 0))
+        );
+        // The child has been reaped, so there is nothing left to wait for.
+        assert_eq!(waitpid(pid, None), Err(Errno::ECHILD));
+    }
+
+    #[test]
+    fn wait_timeout_leaves_running_child() {
+        let (_child, pid) = spawn(&["sleep", "60"]);
+        assert_eq!(
+            wait_timeout(pid, Duration::from_millis(50)),
+            Err(Errno::ETIMEDOUT)
+        );
+        kill(pid, Signal::SIGKILL).unwrap();
+        assert_eq!(
+            waitpid(pid, None),
+            Ok(WaitStatus::Signaled(pid, Signal::SIGKILL, false))
+        );
+    }
+
+    #[test]
+    fn wait_timeout_or_kill_kills_running_child() {
+        let (_child, pid) = spawn(&["sleep", "60"]);
+        let start = Instant::now();
+        assert_eq!(
+            wait_timeout_or_kill(pid, Duration::from_millis(50)),
+            Err(Errno::ETIMEDOUT)
+        );
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(waitpid(pid, None), Err(Errno::ECHILD));
+    }
+}
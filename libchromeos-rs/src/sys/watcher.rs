@@ -0,0 +1,210 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A minimal poll(2) based reactor for daemons that don't need an async runtime.
+
+use std::convert::TryFrom;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd};
+use nix::Result;
+
+pub use nix::poll::PollFlags;
+
+/// A descriptor that became ready, along with the token it was registered with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadyEvent<T> {
+    pub token: T,
+    pub events: PollFlags,
+}
+
+impl<T> ReadyEvent<T> {
+    /// True if the descriptor can be read without blocking.
+    pub fn readable(&self) -> bool {
+        self.events.contains(PollFlags::POLLIN)
+    }
+
+    /// True if the descriptor can be written without blocking.
+    pub fn writable(&self) -> bool {
+        self.events.contains(PollFlags::POLLOUT)
+    }
+
+    /// True if the other end hung up. Reported whether asked for or not.
+    pub fn hungup(&self) -> bool {
+        self.events.contains(PollFlags::POLLHUP)
+    }
+}
+
+/// Watches a set of descriptors for readiness. Each descriptor is registered with the events it
+/// is interested in and a token reported back when it is ready.
+///
+/// The watcher doesn't own the descriptors, they have to be removed before they are closed.
+pub struct DescriptorWatcher<T> {
+    fds: Vec<PollFd>,
+    tokens: Vec<T>,
+}
+
+impl<T: Copy> DescriptorWatcher<T> {
+    pub fn new() -> Self {
+        DescriptorWatcher {
+            fds: Vec::new(),
+            tokens: Vec::new(),
+        }
+    }
+
+    /// Registers `fd` for the `interest` events, e.g. `PollFlags::POLLIN`. Registering a
+    /// descriptor again replaces its interest and token.
+    pub fn add(&mut self, fd: &dyn AsRawFd, interest: PollFlags, token: T) {
+        let fd = fd.as_raw_fd();
+        match self.position(fd) {
+            Some(i) => {
+                self.fds[i] = PollFd::new(fd, interest);
+                self.tokens[i] = token;
+            }
+            None => {
+                self.fds.push(PollFd::new(fd, interest));
+                self.tokens.push(token);
+            }
+        }
+    }
+
+    /// Stops watching `fd`. Returns whether it was registered.
+    pub fn remove(&mut self, fd: &dyn AsRawFd) -> bool {
+        match self.position(fd.as_raw_fd()) {
+            Some(i) => {
+                self.fds.remove(i);
+                self.tokens.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the number of registered descriptors.
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+
+    /// Returns whether no descriptor is registered.
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// Waits until at least one descriptor is ready or `timeout` passed, forever if it is None.
+    /// Returns the ready descriptors, none if the timeout passed.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Vec<ReadyEvent<T>>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let timeout_ms = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    c_int::try_from(remaining.as_millis()).unwrap_or(c_int::MAX)
+                }
+                None => -1,
+            };
+
+            match poll(&mut self.fds, timeout_ms) {
+                Ok(_) => break,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(self
+            .fds
+            .iter()
+            .zip(&self.tokens)
+            .filter_map(|(fd, token)| match fd.revents() {
+                Some(events) if !events.is_empty() => Some(ReadyEvent {
+                    token: *token,
+                    events,
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn position(&self, fd: RawFd) -> Option<usize> {
+        self.fds
+            .iter()
+            .position(|poll_fd| poll_fd.as_raw_fd() == fd)
+    }
+}
+
+impl<T: Copy> Default for DescriptorWatcher<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use crate::pipe;
+
+    #[test]
+    fn reports_readable_pipes() {
+        let (rx1, _tx1) = pipe(true).unwrap();
+        let (rx2, mut tx2) = pipe(true).unwrap();
+
+        let mut watcher = DescriptorWatcher::new();
+        watcher.add(&rx1, PollFlags::POLLIN, 1);
+        watcher.add(&rx2, PollFlags::POLLIN, 2);
+        assert_eq!(watcher.len(), 2);
+
+        // Nothing was written yet.
+        assert_eq!(watcher.wait(Some(Duration::from_millis(10))), Ok(vec![]));
+
+        tx2.write_all(b"ready").unwrap();
+        let events = watcher.wait(Some(Duration::from_secs(10))).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token, 2);
+        assert!(events[0].readable());
+        assert!(!events[0].hungup());
+
+        // The data is still there, so the pipe stays readable until it is removed.
+        assert_eq!(watcher.wait(None).unwrap()[0].token, 2);
+        assert!(watcher.remove(&rx2));
+        assert!(!watcher.remove(&rx2));
+        assert_eq!(watcher.wait(Some(Duration::ZERO)), Ok(vec![]));
+    }
+
+    #[test]
+    fn reports_hangup() {
+        let (rx, tx) = pipe(true).unwrap();
+
+        let mut watcher = DescriptorWatcher::new();
+        watcher.add(&rx, PollFlags::POLLIN, "rx");
+        drop(tx);
+
+        let events = watcher.wait(Some(Duration::from_secs(10))).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token, "rx");
+        assert!(events[0].hungup());
+    }
+
+    #[test]
+    fn add_replaces_registration() {
+        let (_rx, tx) = pipe(true).unwrap();
+
+        let mut watcher = DescriptorWatcher::new();
+        watcher.add(&tx, PollFlags::POLLIN, 1);
+        assert_eq!(watcher.wait(Some(Duration::ZERO)), Ok(vec![]));
+
+        // An empty pipe is writable.
+        watcher.add(&tx, PollFlags::POLLOUT, 2);
+        assert_eq!(watcher.len(), 1);
+        let events = watcher.wait(Some(Duration::ZERO)).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token, 2);
+        assert!(events[0].writable());
+    }
+}
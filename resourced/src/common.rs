@@ -7,6 +7,7 @@ use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
 use log::{error, info, warn};
@@ -20,7 +21,7 @@ use crate::power::PowerSourceProvider;
 use crate::cpu_scaling::{double_min_freq, intel_i7_or_above, set_min_cpu_freq};
 
 #[cfg(target_arch = "x86_64")]
-use crate::gpu_freq_scaling::intel_device;
+use crate::gpu_freq_scaling::{apply_to_gpus, intel_device};
 
 #[cfg(target_arch = "x86_64")]
 use crate::cgroup_x86_64::{media_dynamic_cgroup, MediaDynamicCgroupAction};
@@ -39,6 +40,10 @@ const DEVICE_RPS_DEFAULT_PATH_DOWN: &str =
 const GAMEMODE_RPS_UP: u64 = 95;
 const GAMEMODE_RPS_DOWN: u64 = 85;
 
+// How long RTC audio and fullscreen video changes must persist before the power preferences
+// follow, unless the config sets another duration.
+const DEFAULT_POWER_PREFERENCES_HOLD: Duration = Duration::from_secs(3);
+
 const TUNED_SWAPPINESS_VALUE: u32 = 30;
 const DEFAULT_SWAPPINESS_VALUE: u32 = 60;
 
@@ -111,6 +116,13 @@ pub fn set_game_mode(
         );
     }
 
+    // Keep the device interrupts away from the big cores the game runs on.
+    if (old_mode == GameMode::Off) != (mode == GameMode::Off) {
+        if let Err(e) = power::set_irq_affinity_for_gaming(&root, mode != GameMode::Off) {
+            warn!("Failed to set IRQ affinity for game mode: {:?}", e);
+        }
+    }
+
     #[cfg(target_arch = "x86_64")]
     if old_mode != GameMode::Borealis && mode == GameMode::Borealis {
         match intel_device::run_active_gpu_tuning(GPU_TUNING_POLLING_INTERVAL_MS) {
@@ -301,9 +313,22 @@ pub enum THPMode {
     Always = 1,
 }
 
-pub fn update_power_preferences(
-    power_preference_manager: &dyn power::PowerPreferencesManager,
-) -> Result<()> {
+static POWER_PREFERENCES_DEBOUNCER: Lazy<
+    Mutex<power::PowerPreferencesDebouncer<power::SystemClock>>,
+> = Lazy::new(|| {
+    Mutex::new(power::PowerPreferencesDebouncer::new(
+        power::SystemClock,
+        DEFAULT_POWER_PREFERENCES_HOLD,
+    ))
+});
+
+// Calls `update` with the current activities and the power preferences debouncer.
+fn with_power_activities<T>(
+    update: impl FnOnce(
+        &mut power::PowerPreferencesDebouncer<power::SystemClock>,
+        power::PowerActivities,
+    ) -> Result<T>,
+) -> Result<T> {
     // We need to ensure that any function that locks more than one lock does it
     // in the same order to avoid any dead locks.
     match RTC_AUDIO_ACTIVE.lock() {
@@ -311,9 +336,19 @@ pub fn update_power_preferences(
             Ok(fsv_data) => match GAME_MODE.lock() {
                 Ok(game_data) => match VMBOOT_MODE.lock() {
                     Ok(boot_data) => match BATTERY_SAVER_MODE.lock() {
-                        Ok(bsm_data) => power_preference_manager.update_power_preferences(
-                            *rtc_data, *fsv_data, *game_data, *boot_data, *bsm_data,
-                        )?,
+                        Ok(bsm_data) => match POWER_PREFERENCES_DEBOUNCER.lock() {
+                            Ok(mut debouncer) => update(
+                                &mut debouncer,
+                                power::PowerActivities {
+                                    rtc: *rtc_data,
+                                    fullscreen: *fsv_data,
+                                    game: *game_data,
+                                    vmboot: *boot_data,
+                                    batterysaver: *bsm_data,
+                                },
+                            ),
+                            Err(_) => bail!("Failed to lock power preferences debouncer"),
+                        },
                         Err(_) => bail!("Failed to get battery saver mode"),
                     },
                     Err(_) => bail!("Failed to get VM boot mode"),
@@ -324,26 +359,75 @@ pub fn update_power_preferences(
         },
         Err(_) => bail!("Failed to get rtd audio mode!"),
     }
+}
+
+/// Sets the observer called with the previous and the new activities whenever the power
+/// preferences of other activities are applied.
+pub fn set_power_mode_observer(observer: power::PowerModeObserver) -> Result<()> {
+    match POWER_PREFERENCES_DEBOUNCER.lock() {
+        Ok(mut debouncer) => debouncer.set_observer(observer),
+        Err(_) => bail!("Failed to lock power preferences debouncer"),
+    }
+    Ok(())
+}
+
+/// Sets how long RTC audio and fullscreen video changes must persist before the power preferences
+/// follow.
+pub fn set_power_preferences_hold(hold: Duration) -> Result<()> {
+    match POWER_PREFERENCES_DEBOUNCER.lock() {
+        Ok(mut debouncer) => debouncer.set_hold(hold),
+        Err(_) => bail!("Failed to lock power preferences debouncer"),
+    }
+    Ok(())
+}
+
+pub fn update_power_preferences(
+    power_preference_manager: &dyn power::PowerPreferencesManager,
+) -> Result<()> {
+    with_power_activities(|debouncer, activities| {
+        debouncer.apply(power_preference_manager, activities)
+    })
+}
+
+/// Updates the power preferences if the thermal clamp must be applied or lifted.
+pub fn update_thermal_clamp(
+    power_preference_manager: &dyn power::PowerPreferencesManager,
+) -> Result<()> {
+    if power_preference_manager.is_thermal_clamp_outdated()? {
+        update_power_preferences(power_preference_manager)?;
+    }
     Ok(())
 }
 
+/// Like [update_power_preferences], but holds back RTC audio and fullscreen video changes until
+/// they persisted for the hold duration. Returns the remaining time if the change is held
+/// back, after which this must be called again.
+pub fn update_debounced_power_preferences(
+    power_preference_manager: &dyn power::PowerPreferencesManager,
+) -> Result<Option<Duration>> {
+    with_power_activities(|debouncer, activities| {
+        debouncer.update(power_preference_manager, activities)
+    })
+}
+
+/// Sets the RTC audio activity. Returns the time after which
+/// [update_debounced_power_preferences] must be called if the power preferences don't follow yet.
 pub fn set_rtc_audio_active(
     power_preference_manager: &dyn power::PowerPreferencesManager,
     mode: RTCAudioActive,
-) -> Result<()> {
+) -> Result<Option<Duration>> {
     match RTC_AUDIO_ACTIVE.lock() {
         Ok(mut data) => {
             *data = mode;
             #[cfg(target_arch = "x86_64")]
-            if let Err(err) = set_gt_boost_freq_mhz(mode) {
-                error!("Set boost freq not supported: {:#}", err)
+            if let Err(err) = set_gpu_rtc_audio_active(mode, get_battery_saver_mode()?) {
+                error!("Set GPU frequency for RTC audio not supported: {:#}", err)
             }
         }
         Err(_) => bail!("Failed to set RTC audio activity"),
     }
 
-    update_power_preferences(power_preference_manager)?;
-    Ok(())
+    update_debounced_power_preferences(power_preference_manager)
 }
 
 pub fn get_rtc_audio_active() -> Result<RTCAudioActive> {
@@ -353,10 +437,12 @@ pub fn get_rtc_audio_active() -> Result<RTCAudioActive> {
     }
 }
 
+/// Sets the fullscreen video activity. Returns the time after which
+/// [update_debounced_power_preferences] must be called if the power preferences don't follow yet.
 pub fn set_fullscreen_video(
     power_preference_manager: &dyn power::PowerPreferencesManager,
     mode: FullscreenVideo,
-) -> Result<()> {
+) -> Result<Option<Duration>> {
     match FULLSCREEN_VIDEO.lock() {
         Ok(mut data) => {
             *data = mode;
@@ -364,7 +450,7 @@ pub fn set_fullscreen_video(
         Err(_) => bail!("Failed to set full screen video activity"),
     }
 
-    update_power_preferences(power_preference_manager)?;
+    let pending = update_debounced_power_preferences(power_preference_manager)?;
 
     #[cfg(target_arch = "x86_64")]
     match mode {
@@ -372,7 +458,7 @@ pub fn set_fullscreen_video(
         FullscreenVideo::Inactive => media_dynamic_cgroup(MediaDynamicCgroupAction::Stop)?,
     }
 
-    Ok(())
+    Ok(pending)
 }
 
 pub fn get_fullscreen_video() -> Result<FullscreenVideo> {
@@ -382,6 +468,13 @@ pub fn get_fullscreen_video() -> Result<FullscreenVideo> {
     }
 }
 
+pub fn get_battery_saver_mode() -> Result<BatterySaverMode> {
+    match BATTERY_SAVER_MODE.lock() {
+        Ok(data) => Ok(*data),
+        Err(_) => bail!("Failed to get battery saver mode"),
+    }
+}
+
 pub fn on_battery_saver_mode_change(
     power_preference_manager: &dyn power::PowerPreferencesManager,
     mode: BatterySaverMode,
@@ -393,6 +486,15 @@ pub fn on_battery_saver_mode_change(
         Err(_) => bail!("Failed to set Battery saver mode activity"),
     }
 
+    // Not holding the battery saver lock, RTC_AUDIO_ACTIVE is always locked first.
+    #[cfg(target_arch = "x86_64")]
+    if let Err(err) = set_gpu_battery_saver(mode, get_rtc_audio_active()?) {
+        error!(
+            "Set GPU frequency for battery saver not supported: {:#}",
+            err
+        )
+    }
+
     if mode == BatterySaverMode::Inactive {
         hotplug_cpus(HotplugCpuAction::OnlineAll)?;
     } else {
@@ -458,15 +560,47 @@ fn set_rps_thresholds(up: u64, down: u64) -> Result<()> {
 }
 
 #[cfg(target_arch = "x86_64")]
-fn set_gt_boost_freq_mhz(mode: RTCAudioActive) -> Result<()> {
-    set_gt_boost_freq_mhz_impl(Path::new("/"), mode)
+fn set_gpu_rtc_audio_active(mode: RTCAudioActive, battery_saver: BatterySaverMode) -> Result<()> {
+    set_gpu_rtc_audio_active_impl(Path::new("/"), mode, battery_saver)
 }
 
 // Extract the impl function for unittest.
 #[cfg(target_arch = "x86_64")]
-fn set_gt_boost_freq_mhz_impl(root: &Path, mode: RTCAudioActive) -> Result<()> {
-    let mut gpu_config = intel_device::IntelGpuDeviceConfig::new(root.to_owned(), 100)?;
-    gpu_config.set_rtc_audio_active(mode == RTCAudioActive::Active)
+fn set_gpu_rtc_audio_active_impl(
+    root: &Path,
+    mode: RTCAudioActive,
+    battery_saver: BatterySaverMode,
+) -> Result<()> {
+    apply_to_gpus(root, |gpu| {
+        gpu.set_rtc_audio_active(mode == RTCAudioActive::Active)?;
+        // Lifting the RTC cap must not lift the battery saver one.
+        if mode == RTCAudioActive::Inactive && battery_saver == BatterySaverMode::Active {
+            gpu.set_battery_saver(true)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+fn set_gpu_battery_saver(mode: BatterySaverMode, rtc: RTCAudioActive) -> Result<()> {
+    set_gpu_battery_saver_impl(Path::new("/"), mode, rtc)
+}
+
+// Extract the impl function for unittest.
+#[cfg(target_arch = "x86_64")]
+fn set_gpu_battery_saver_impl(
+    root: &Path,
+    mode: BatterySaverMode,
+    rtc: RTCAudioActive,
+) -> Result<()> {
+    apply_to_gpus(root, |gpu| {
+        gpu.set_battery_saver(mode == BatterySaverMode::Active)?;
+        // Restoring the max must not lift the RTC cap.
+        if rtc == RTCAudioActive::Active {
+            gpu.set_rtc_audio_active(true)?;
+        }
+        Ok(())
+    })
 }
 
 fn set_tph(mode: THPMode) -> Result<()> {
@@ -480,7 +614,14 @@ fn set_tph(mode: THPMode) -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use crate::test_utils::tests::{get_intel_gpu_boost, MockPowerPreferencesManager};
+    use crate::test_utils::tests::{
+        get_amd_gpu_card_mode, get_amd_gpu_mode, get_amd_gpu_sclk, setup_mock_amd_gpu_card,
+        setup_mock_amd_gpu_dev_dirs, setup_mock_amd_gpu_files, setup_mock_intel_gpu_card,
+        DRM_CLASS_PATH,
+    };
+    use crate::test_utils::tests::{
+        get_intel_gpu_boost, get_intel_gpu_max, MockPowerPreferencesManager,
+    };
     use crate::test_utils::tests::{
         set_intel_gpu_boost, set_intel_gpu_max, set_intel_gpu_min, setup_mock_cpu_dev_dirs,
         setup_mock_cpu_files, setup_mock_intel_gpu_dev_dirs, setup_mock_intel_gpu_files,
@@ -518,7 +659,7 @@ mod tests {
 
     #[test]
     #[cfg(target_arch = "x86_64")]
-    fn test_set_gt_boost_freq_mhz() {
+    fn test_set_gpu_rtc_audio_active_intel() {
         let tmp_root = tempdir().unwrap();
         let root = tmp_root.path();
 
@@ -529,7 +670,7 @@ mod tests {
             "filter_out",
             "Intel(R) Core(TM) i3-10110U CPU @ 2.10GHz",
         );
-        set_gt_boost_freq_mhz_impl(root, RTCAudioActive::Active)
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Active, BatterySaverMode::Inactive)
             .expect_err("Should return error on non-intel CPUs");
 
         write_mock_cpuinfo(
@@ -541,19 +682,176 @@ mod tests {
         set_intel_gpu_max(root, 1100);
 
         set_intel_gpu_boost(root, 0);
-        set_gt_boost_freq_mhz_impl(root, RTCAudioActive::Active)
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Active, BatterySaverMode::Inactive)
             .expect_err("Should return error when gpu_boost is 0");
 
         set_intel_gpu_boost(root, 500);
-        set_gt_boost_freq_mhz_impl(root, RTCAudioActive::Active).unwrap();
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Active, BatterySaverMode::Inactive)
+            .unwrap();
 
         assert_eq!(get_intel_gpu_boost(root), 300);
 
-        set_gt_boost_freq_mhz_impl(root, RTCAudioActive::Inactive).unwrap();
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Inactive, BatterySaverMode::Inactive)
+            .unwrap();
 
         assert_eq!(get_intel_gpu_boost(root), 1100);
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_set_gpu_rtc_audio_active_amd() {
+        let tmp_root = tempdir().unwrap();
+        let root = tmp_root.path();
+
+        // No GPU driver.
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Active, BatterySaverMode::Inactive)
+            .expect_err("Should return error without a GPU");
+
+        setup_mock_amd_gpu_dev_dirs(root);
+        setup_mock_amd_gpu_files(root);
+        write_mock_cpuinfo(
+            root,
+            "AuthenticAMD",
+            "AMD Ryzen 3 7320C with Radeon Graphics",
+        );
+
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Active, BatterySaverMode::Inactive)
+            .unwrap();
+        assert_eq!(get_amd_gpu_mode(root), "manual");
+        assert_eq!(get_amd_gpu_sclk(root), "0");
+
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Inactive, BatterySaverMode::Inactive)
+            .unwrap();
+        assert_eq!(get_amd_gpu_mode(root), "auto");
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_set_gpu_rtc_audio_active_multiple_gpus() {
+        let tmp_root = tempdir().unwrap();
+        let root = tmp_root.path();
+
+        // An integrated Intel GPU at card0 and a discrete AMD GPU at card1, along with a
+        // connector and a render node that aren't cards.
+        setup_mock_intel_gpu_dev_dirs(root);
+        setup_mock_intel_gpu_files(root);
+        setup_mock_amd_gpu_card(root, "card1");
+        fs::create_dir_all(root.join(DRM_CLASS_PATH).join("card0-eDP-1")).unwrap();
+        fs::create_dir_all(root.join(DRM_CLASS_PATH).join("renderD128")).unwrap();
+        write_mock_cpuinfo(
+            root,
+            "GenuineIntel",
+            "Intel(R) Core(TM) i3-10110U CPU @ 2.10GHz",
+        );
+
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Active, BatterySaverMode::Inactive)
+            .unwrap();
+        assert_eq!(get_intel_gpu_boost(root), 200);
+        assert_eq!(get_amd_gpu_card_mode(root, "card1"), "manual");
+
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Inactive, BatterySaverMode::Inactive)
+            .unwrap();
+        assert_eq!(get_intel_gpu_boost(root), 1000);
+        assert_eq!(get_amd_gpu_card_mode(root, "card1"), "auto");
+
+        // A card missing an attribute doesn't keep the other cards from being set.
+        setup_mock_intel_gpu_card(root, "card2");
+        fs::remove_file(root.join(DRM_CLASS_PATH).join("card2/gt_boost_freq_mhz")).unwrap();
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Active, BatterySaverMode::Inactive)
+            .unwrap();
+        assert_eq!(get_intel_gpu_boost(root), 200);
+        assert_eq!(get_amd_gpu_card_mode(root, "card1"), "manual");
+
+        // Unless no card could be set.
+        let tmp_root = tempdir().unwrap();
+        let root = tmp_root.path();
+        setup_mock_intel_gpu_card(root, "card1");
+        fs::remove_file(root.join(DRM_CLASS_PATH).join("card1/gt_boost_freq_mhz")).unwrap();
+        fs::create_dir_all(root.join("proc")).unwrap();
+        write_mock_cpuinfo(
+            root,
+            "GenuineIntel",
+            "Intel(R) Core(TM) i3-10110U CPU @ 2.10GHz",
+        );
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Active, BatterySaverMode::Inactive)
+            .expect_err("Should return error when no card could be set");
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_set_gpu_battery_saver_intel() {
+        let tmp_root = tempdir().unwrap();
+        let root = tmp_root.path();
+
+        setup_mock_intel_gpu_dev_dirs(root);
+        setup_mock_intel_gpu_files(root);
+        write_mock_cpuinfo(
+            root,
+            "GenuineIntel",
+            "Intel(R) Core(TM) i3-10110U CPU @ 2.10GHz",
+        );
+
+        // Halfway between the min of 200 and the hardware max of 1100.
+        set_gpu_battery_saver_impl(root, BatterySaverMode::Active, RTCAudioActive::Inactive)
+            .unwrap();
+        assert_eq!(get_intel_gpu_max(root), 650);
+        assert_eq!(get_intel_gpu_boost(root), 650);
+
+        // Ending RTC audio keeps the battery saver cap.
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Active, BatterySaverMode::Active)
+            .unwrap();
+        assert_eq!(get_intel_gpu_boost(root), 200);
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Inactive, BatterySaverMode::Active)
+            .unwrap();
+        assert_eq!(get_intel_gpu_max(root), 650);
+        assert_eq!(get_intel_gpu_boost(root), 650);
+
+        // Ending battery saver keeps the RTC cap.
+        set_gpu_battery_saver_impl(root, BatterySaverMode::Active, RTCAudioActive::Active).unwrap();
+        set_gpu_battery_saver_impl(root, BatterySaverMode::Inactive, RTCAudioActive::Active)
+            .unwrap();
+        assert_eq!(get_intel_gpu_max(root), 1100);
+        assert_eq!(get_intel_gpu_boost(root), 200);
+
+        set_gpu_battery_saver_impl(root, BatterySaverMode::Inactive, RTCAudioActive::Inactive)
+            .unwrap();
+        assert_eq!(get_intel_gpu_max(root), 1100);
+        assert_eq!(get_intel_gpu_boost(root), 1100);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_set_gpu_battery_saver_amd() {
+        let tmp_root = tempdir().unwrap();
+        let root = tmp_root.path();
+
+        setup_mock_amd_gpu_dev_dirs(root);
+        setup_mock_amd_gpu_files(root);
+
+        set_gpu_battery_saver_impl(root, BatterySaverMode::Active, RTCAudioActive::Inactive)
+            .unwrap();
+        assert_eq!(get_amd_gpu_mode(root), "manual");
+        assert_eq!(get_amd_gpu_sclk(root), "0");
+
+        // Ending RTC audio keeps the battery saver cap. The mock sclk file only lists the levels
+        // before it is written, so reset it.
+        setup_mock_amd_gpu_files(root);
+        set_gpu_rtc_audio_active_impl(root, RTCAudioActive::Inactive, BatterySaverMode::Active)
+            .unwrap();
+        assert_eq!(get_amd_gpu_mode(root), "manual");
+        assert_eq!(get_amd_gpu_sclk(root), "0");
+
+        // Ending battery saver keeps the RTC cap.
+        setup_mock_amd_gpu_files(root);
+        set_gpu_battery_saver_impl(root, BatterySaverMode::Inactive, RTCAudioActive::Active)
+            .unwrap();
+        assert_eq!(get_amd_gpu_mode(root), "manual");
+
+        set_gpu_battery_saver_impl(root, BatterySaverMode::Inactive, RTCAudioActive::Inactive)
+            .unwrap();
+        assert_eq!(get_amd_gpu_mode(root), "auto");
+    }
+
     #[test]
     fn test_get_set_game_mode() {
         let tmp_root = tempdir().unwrap();
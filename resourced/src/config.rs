@@ -5,6 +5,7 @@
 use std::fs::DirEntry;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
 
@@ -18,6 +19,12 @@ pub trait ConfigProvider {
         power_source_type: PowerSourceType,
         power_preference_type: PowerPreferencesType,
     ) -> Result<Option<PowerPreferences>>;
+
+    fn read_thermal_clamp(&self) -> Result<Option<ThermalClamp>>;
+
+    /// Reads how long RTC audio and fullscreen video changes must persist before the power
+    /// preferences follow.
+    fn read_power_preferences_hold(&self) -> Result<Option<Duration>>;
 }
 
 pub trait FromDir {
@@ -109,10 +116,34 @@ impl FromDir for EnergyPerformancePreference {
     }
 }
 
+/// The minimum and maximum CPU performance as a percentage of the maximum supported performance.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CpuPerfPercent {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// The system-wide utilization clamp (uclamp) range as a percentage of the CPU capacity.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UclampPercent {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Caps the max CPU frequency, whatever the power preferences are, while a thermal zone is past
+/// its hot trip point. Past the critical trip point the CPUs run at their min frequency.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ThermalClamp {
+    /// The max CPU frequency as a percentage of the max supported frequency.
+    pub max_perf_pct: u32,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct PowerPreferences {
     pub governor: Option<Governor>,
     pub epp: Option<EnergyPerformancePreference>,
+    pub cpu_perf_pct: Option<CpuPerfPercent>,
+    pub uclamp: Option<UclampPercent>,
 }
 
 #[derive(Copy, Clone)]
@@ -192,6 +223,66 @@ fn parse_ondemand_governor(path: &Path) -> Result<Governor> {
     })
 }
 
+// Reads a range of percentages from the `min_name` and `max_name` files in path.
+fn parse_pct_range(path: &Path, min_name: &str, max_name: &str) -> Result<(u32, u32)> {
+    let read_pct = |name: &str| -> Result<u32> {
+        let pct_path = path.join(name);
+        let pct = read_file_to_u64(&pct_path)
+            .with_context(|| format!("Error reading {} from {}", name, pct_path.display()))?;
+        if pct > 100 {
+            bail!("Invalid {} {} in {}", name, pct, pct_path.display());
+        }
+        Ok(pct as u32)
+    };
+
+    let min = read_pct(min_name)?;
+    let max = read_pct(max_name)?;
+
+    if min > max {
+        bail!(
+            "{} {} is greater than {} {} in {}",
+            min_name,
+            min,
+            max_name,
+            max,
+            path.display()
+        );
+    }
+
+    Ok((min, max))
+}
+
+fn parse_cpu_perf_pct(path: &Path) -> Result<CpuPerfPercent> {
+    let (min, max) = parse_pct_range(path, "min-perf-pct", "max-perf-pct")?;
+    Ok(CpuPerfPercent { min, max })
+}
+
+fn parse_thermal_clamp(path: &Path) -> Result<ThermalClamp> {
+    let max_perf_pct_path = path.join("max-perf-pct");
+    let max_perf_pct = read_file_to_u64(&max_perf_pct_path).with_context(|| {
+        format!(
+            "Error reading max-perf-pct from {}",
+            max_perf_pct_path.display()
+        )
+    })?;
+    if max_perf_pct > 100 {
+        bail!(
+            "Invalid max-perf-pct {} in {}",
+            max_perf_pct,
+            max_perf_pct_path.display()
+        );
+    }
+
+    Ok(ThermalClamp {
+        max_perf_pct: max_perf_pct as u32,
+    })
+}
+
+fn parse_uclamp(path: &Path) -> Result<UclampPercent> {
+    let (min, max) = parse_pct_range(path, "min-pct", "max-pct")?;
+    Ok(UclampPercent { min, max })
+}
+
 // Returns Ok(None) when there is no sub directory in path.
 // Returns error when there are multiple sub directories in path or when the
 // sub directory name is not a supported governor.
@@ -224,6 +315,12 @@ fn parse_config_from_path<T: FromDir>(path: &Path) -> Result<Option<T>> {
  *     * borealis-gaming-power-preferences/governor/..
  *     * arcvm-gaming-power-preferences/governor/..
  *     * default-power-preferences/governor/..
+ *     * {type}-power-preferences/cpu-perf-pct/
+ *       * min-perf-pct
+ *       * max-perf-pct
+ *   * thermal-clamp/
+ *     * max-perf-pct
+ *   * power-preferences-hold-ms
  */
 #[derive(Clone, Debug)]
 pub struct DirectoryConfigProvider {
@@ -249,6 +346,8 @@ impl ConfigProvider for DirectoryConfigProvider {
         let mut preferences: PowerPreferences = PowerPreferences {
             governor: None,
             epp: None,
+            cpu_perf_pct: None,
+            uclamp: None,
         };
 
         let governor_path = path.join("governor");
@@ -261,8 +360,43 @@ impl ConfigProvider for DirectoryConfigProvider {
             preferences.epp = parse_config_from_path::<EnergyPerformancePreference>(&epp_path)?;
         }
 
+        let cpu_perf_pct_path = path.join("cpu-perf-pct");
+        if cpu_perf_pct_path.exists() {
+            preferences.cpu_perf_pct = Some(parse_cpu_perf_pct(&cpu_perf_pct_path)?);
+        }
+
+        let uclamp_path = path.join("uclamp");
+        if uclamp_path.exists() {
+            preferences.uclamp = Some(parse_uclamp(&uclamp_path)?);
+        }
+
         Ok(Some(preferences))
     }
+
+    fn read_thermal_clamp(&self) -> Result<Option<ThermalClamp>> {
+        let path = self.root.join(RESOURCED_CONFIG_PATH).join("thermal-clamp");
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        parse_thermal_clamp(&path).map(Some)
+    }
+
+    fn read_power_preferences_hold(&self) -> Result<Option<Duration>> {
+        let path = self
+            .root
+            .join(RESOURCED_CONFIG_PATH)
+            .join("power-preferences-hold-ms");
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let hold_ms =
+            read_file_to_u64(&path).with_context(|| format!("Error reading {}", path.display()))?;
+        Ok(Some(Duration::from_millis(hold_ms)))
+    }
 }
 
 #[cfg(test)]
@@ -337,6 +471,8 @@ mod tests {
         let expected = PowerPreferences {
             governor: None,
             epp: Some(EnergyPerformancePreference::BalancePerformance),
+            cpu_perf_pct: None,
+            uclamp: None,
         };
 
         assert_eq!(expected, actual.unwrap());
@@ -393,6 +529,8 @@ mod tests {
                         sampling_rate: None,
                     }),
                     epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
                 };
 
                 assert_eq!(expected, actual.unwrap());
@@ -410,6 +548,8 @@ mod tests {
                         sampling_rate: None,
                     }),
                     epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
                 };
 
                 assert_eq!(expected, actual.unwrap());
@@ -427,12 +567,138 @@ mod tests {
                         sampling_rate: Some(16000),
                     }),
                     epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
                 };
 
                 assert_eq!(expected, actual.unwrap());
             }
         }
 
+        Ok(())
+    }
+    #[test]
+    fn test_config_provider_cpu_perf_pct() -> Result<()> {
+        let root = tempdir()?;
+        let cpu_perf_pct_path = root
+            .path()
+            .join(RESOURCED_CONFIG_PATH)
+            .join("dc")
+            .join("default-power-preferences")
+            .join("cpu-perf-pct");
+        fs::create_dir_all(&cpu_perf_pct_path)?;
+        fs::write(cpu_perf_pct_path.join("min-perf-pct"), b"20")?;
+        fs::write(cpu_perf_pct_path.join("max-perf-pct"), b"80")?;
+
+        let provider = DirectoryConfigProvider {
+            root: root.path().to_path_buf(),
+        };
+
+        let actual =
+            provider.read_power_preferences(PowerSourceType::DC, PowerPreferencesType::Default)?;
+
+        let expected = PowerPreferences {
+            governor: None,
+            epp: None,
+            cpu_perf_pct: Some(CpuPerfPercent { min: 20, max: 80 }),
+            uclamp: None,
+        };
+
+        assert_eq!(expected, actual.unwrap());
+
+        // min-perf-pct must not exceed max-perf-pct.
+        fs::write(cpu_perf_pct_path.join("min-perf-pct"), b"90")?;
+        assert!(provider
+            .read_power_preferences(PowerSourceType::DC, PowerPreferencesType::Default)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_provider_uclamp() -> Result<()> {
+        let root = tempdir()?;
+        let uclamp_path = root
+            .path()
+            .join(RESOURCED_CONFIG_PATH)
+            .join("ac")
+            .join("borealis-gaming-power-preferences")
+            .join("uclamp");
+        fs::create_dir_all(&uclamp_path)?;
+        fs::write(uclamp_path.join("min-pct"), b"30")?;
+        fs::write(uclamp_path.join("max-pct"), b"100")?;
+
+        let provider = DirectoryConfigProvider {
+            root: root.path().to_path_buf(),
+        };
+
+        let actual = provider
+            .read_power_preferences(PowerSourceType::AC, PowerPreferencesType::BorealisGaming)?;
+
+        let expected = PowerPreferences {
+            governor: None,
+            epp: None,
+            cpu_perf_pct: None,
+            uclamp: Some(UclampPercent { min: 30, max: 100 }),
+        };
+
+        assert_eq!(expected, actual.unwrap());
+
+        fs::write(uclamp_path.join("max-pct"), b"101")?;
+        assert!(provider
+            .read_power_preferences(PowerSourceType::AC, PowerPreferencesType::BorealisGaming)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_provider_thermal_clamp() -> Result<()> {
+        let root = tempdir()?;
+        let provider = DirectoryConfigProvider {
+            root: root.path().to_path_buf(),
+        };
+
+        assert_eq!(provider.read_thermal_clamp()?, None);
+
+        let thermal_clamp_path = root
+            .path()
+            .join(RESOURCED_CONFIG_PATH)
+            .join("thermal-clamp");
+        fs::create_dir_all(&thermal_clamp_path)?;
+        fs::write(thermal_clamp_path.join("max-perf-pct"), b"60")?;
+
+        assert_eq!(
+            provider.read_thermal_clamp()?,
+            Some(ThermalClamp { max_perf_pct: 60 })
+        );
+
+        fs::write(thermal_clamp_path.join("max-perf-pct"), b"120")?;
+        assert!(provider.read_thermal_clamp().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_provider_power_preferences_hold() -> Result<()> {
+        let root = tempdir()?;
+        let provider = DirectoryConfigProvider {
+            root: root.path().to_path_buf(),
+        };
+
+        assert_eq!(provider.read_power_preferences_hold()?, None);
+
+        let resource_path = root.path().join(RESOURCED_CONFIG_PATH);
+        fs::create_dir_all(&resource_path)?;
+        fs::write(resource_path.join("power-preferences-hold-ms"), b"1500")?;
+        assert_eq!(
+            provider.read_power_preferences_hold()?,
+            Some(Duration::from_millis(1500))
+        );
+
+        fs::write(resource_path.join("power-preferences-hold-ms"), b"soon")?;
+        assert!(provider.read_power_preferences_hold().is_err());
+
         Ok(())
     }
 }
@@ -0,0 +1,224 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+
+use crate::common;
+use crate::power;
+
+// The multicast group the kernel sends uevents to.
+const UEVENT_KERNEL_GROUP: u32 = 1;
+
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+const CPU_DEVPATH_PREFIX: &str = "/devices/system/cpu/cpu";
+
+// Returns the CPU number if the uevent announces that a CPU came online. The uevent starts with a
+// "<action>@<devpath>" header terminated by a NUL, e.g. "online@/devices/system/cpu/cpu3".
+fn parse_cpu_online_uevent(msg: &[u8]) -> Option<u32> {
+    let header = msg.split(|b| *b == 0).next()?;
+    let (action, devpath) = std::str::from_utf8(header).ok()?.split_once('@')?;
+
+    if action != "online" {
+        return None;
+    }
+
+    devpath.strip_prefix(CPU_DEVPATH_PREFIX)?.parse().ok()
+}
+
+fn open_uevent_socket() -> Result<File> {
+    // Safe because socket() doesn't modify memory and the return value is checked.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        bail!(
+            "Failed to open uevent socket: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    // Safe because fd is a newly opened socket owned by nothing else.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // Safe because sockaddr_nl is a plain C struct for which all zeroes is a valid value.
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = UEVENT_KERNEL_GROUP;
+
+    // Safe because addr outlives the call and the size passed matches its type.
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        bail!(
+            "Failed to bind uevent socket: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    Ok(File::from(fd))
+}
+
+// A CPU coming online may bring up its cpufreq policy with the default governor and frequency
+// limits. Re-applying the power preferences picks the profile in effect right now, based on the
+// current activity state.
+fn on_cpu_online(power_preferences_manager: &dyn power::PowerPreferencesManager, cpu: u32) {
+    info!(
+        "CPU {} is online, applying the active power preferences",
+        cpu
+    );
+
+    if let Err(e) = common::update_power_preferences(power_preferences_manager) {
+        error!("Failed to apply power preferences to CPU {}: {:#}", cpu, e);
+    }
+}
+
+fn on_uevent(power_preferences_manager: &dyn power::PowerPreferencesManager, msg: &[u8]) {
+    if let Some(cpu) = parse_cpu_online_uevent(msg) {
+        on_cpu_online(power_preferences_manager, cpu);
+    }
+}
+
+/// Starts a thread that applies the active power preferences to every CPU that comes online.
+pub fn start_cpu_online_watcher<M>(power_preferences_manager: Arc<M>) -> Result<()>
+where
+    M: power::PowerPreferencesManager + Send + Sync + 'static,
+{
+    let mut socket = open_uevent_socket()?;
+
+    std::thread::Builder::new()
+        .name("cpu_online_watcher".to_string())
+        .spawn(move || {
+            let mut buf = [0u8; UEVENT_BUFFER_SIZE];
+            loop {
+                let len = match socket.read(&mut buf) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("Failed to read uevent, stop watching CPU hotplug: {}", e);
+                        return;
+                    }
+                };
+
+                on_uevent(power_preferences_manager.as_ref(), &buf[..len]);
+            }
+        })
+        .context("Failed to spawn the CPU online watcher")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::config;
+    use crate::test_utils::tests::*;
+
+    #[test]
+    fn test_parse_cpu_online_uevent() {
+        let tests: [(&[u8], Option<u32>); 6] = [
+            (
+                b"online@/devices/system/cpu/cpu3\0ACTION=online\0SUBSYSTEM=cpu\0",
+                Some(3),
+            ),
+            (b"online@/devices/system/cpu/cpu12\0", Some(12)),
+            (b"offline@/devices/system/cpu/cpu3\0ACTION=offline\0", None),
+            (b"online@/devices/system/memory/memory3\0", None),
+            (b"online@/devices/system/cpu/cpufreq\0", None),
+            (b"", None),
+        ];
+
+        for (msg, expected) in tests {
+            assert_eq!(parse_cpu_online_uevent(msg), expected);
+        }
+    }
+
+    // The same power preferences for every activity, whichever the other tests leave active.
+    struct FixedConfigProvider;
+
+    impl config::ConfigProvider for FixedConfigProvider {
+        fn read_power_preferences(
+            &self,
+            _power_source_type: config::PowerSourceType,
+            _power_preference_type: config::PowerPreferencesType,
+        ) -> Result<Option<config::PowerPreferences>> {
+            Ok(Some(config::PowerPreferences {
+                governor: None,
+                epp: None,
+                cpu_perf_pct: Some(config::CpuPerfPercent { min: 20, max: 80 }),
+                uclamp: None,
+            }))
+        }
+
+        fn read_thermal_clamp(&self) -> Result<Option<config::ThermalClamp>> {
+            Ok(None)
+        }
+
+        fn read_power_preferences_hold(&self) -> Result<Option<std::time::Duration>> {
+            Ok(None)
+        }
+    }
+
+    struct AcPowerSourceProvider;
+
+    impl power::PowerSourceProvider for AcPowerSourceProvider {
+        fn get_power_source(&self) -> Result<config::PowerSourceType> {
+            Ok(config::PowerSourceType::AC)
+        }
+    }
+
+    #[test]
+    fn test_cpu_online_uevent_applies_active_profile() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        setup_mock_cpu_dev_dirs(root)?;
+        setup_mock_cpu_files(root)?;
+        let policy1_path = root.join("sys/devices/system/cpu/cpufreq/policy1");
+        let cpu1_online_path = root.join("sys/devices/system/cpu/cpu1/online");
+        fs::create_dir_all(cpu1_online_path.parent().unwrap())?;
+
+        // CPU 1 is offline when the profile is applied.
+        fs::write(&cpu1_online_path, "0")?;
+        let manager = power::DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            FixedConfigProvider,
+            AcPowerSourceProvider,
+        );
+        common::update_power_preferences(&manager)?;
+        assert_eq!(get_cpu0_freq_min(root), 820000);
+        assert_eq!(get_cpu0_freq_max(root), 3280000);
+        let read = |attr| fs::read_to_string(policy1_path.join(attr)).unwrap();
+        assert_eq!(read("scaling_min_freq"), "400000");
+        assert_eq!(read("scaling_max_freq"), "4100000");
+
+        // It comes online with the default limits, and gets the ones of the active profile.
+        fs::write(&cpu1_online_path, "1")?;
+        on_uevent(
+            &manager,
+            b"online@/devices/system/cpu/cpu1\0ACTION=online\0SUBSYSTEM=cpu\0",
+        );
+        assert_eq!(read("scaling_min_freq"), "820000");
+        assert_eq!(read("scaling_max_freq"), "3280000");
+
+        Ok(())
+    }
+}
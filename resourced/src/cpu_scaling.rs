@@ -1,7 +1,7 @@
 // Copyright 2022 The ChromiumOS Authors
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use glob::glob;
 use log::info;
 use regex::Regex;
@@ -10,11 +10,12 @@ use std::io::BufRead;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::str;
+use std::thread;
+use std::time::Duration;
 
 use crate::common;
-
-/// Base path for power_limit relative to rootdir.
-const DEVICE_POWER_LIMIT_PATH: &str = "sys/class/powercap/intel-rapl:0";
+use crate::cpu_utils;
+use crate::power;
 
 /// Base path for cpufreq relative to rootdir.
 const DEVICE_CPUFREQ_PATH: &str = "sys/devices/system/cpu/cpufreq";
@@ -146,6 +147,44 @@ impl DeviceCpuStatus {
         Ok(self.energy_max)
     }
 
+    /// Reads the current power draw of the package.
+    ///
+    /// Samples `energy_uj` twice, `interval` apart.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - Time to wait between the two samples.
+    ///
+    /// # Return
+    ///
+    /// u64 representing the average power draw over `interval` in microwatts.
+    pub fn read_power_draw_uw(&self, interval: Duration) -> Result<u64> {
+        let start = self.get_energy_curr()?;
+        thread::sleep(interval);
+        let end = self.get_energy_curr()?;
+        self.power_draw_uw(start, end, interval)
+    }
+
+    // The energy counter wraps to 0 once it reaches max_energy_range_uj.
+    fn power_draw_uw(&self, start_uj: u64, end_uj: u64, interval: Duration) -> Result<u64> {
+        if interval.is_zero() {
+            bail!("Power draw sampling interval must not be 0");
+        }
+        let energy_uj = if end_uj >= start_uj {
+            end_uj - start_uj
+        } else if start_uj <= self.energy_max {
+            self.energy_max - start_uj + end_uj
+        } else {
+            bail!(
+                "Energy counter {} above max_energy_range_uj {}",
+                start_uj,
+                self.energy_max
+            );
+        };
+        let power_uw = energy_uj as u128 * 1_000_000 / interval.as_micros();
+        Ok(u64::try_from(power_uw)?)
+    }
+
     /// Getter for `cpuinfo_min_freq_default` (default min freq).
     ///
     /// Return the cpuinfo_min_freq_default value on supported device.
@@ -181,6 +220,9 @@ impl DeviceCpuStatus {
             let cpu_min_path =
                 PathBuf::from(str::replace(&curr_cpu.display().to_string(), "max", "min"));
             let val_min = common::read_file_to_u64(cpu_min_path)?;
+            // Snap to a frequency step the policy supports before checking the threshold.
+            let policy_path = curr_cpu.parent().context("Invalid cpufreq policy path")?;
+            let val_max = cpu_utils::snap_to_available_frequency(policy_path, val_max)?;
             if (val_max - val_min) > threshold {
                 std::fs::write(curr_cpu, val_max.to_string().as_bytes())?;
             } else {
@@ -215,6 +257,8 @@ impl DeviceCpuStatus {
             let cpu_max_path =
                 PathBuf::from(str::replace(&curr_cpu.display().to_string(), "min", "max"));
             let val_max = common::read_file_to_u64(cpu_max_path)?;
+            let policy_path = curr_cpu.parent().context("Invalid cpufreq policy path")?;
+            let val_min = cpu_utils::snap_to_available_frequency(policy_path, val_min)?;
             if (val_max - val_min) > threshold {
                 std::fs::write(curr_cpu, val_min.to_string().as_bytes())?;
             } else {
@@ -328,22 +372,18 @@ impl DeviceCpuStatus {
     /// New CpuDevice object with associated functionality.
     pub fn new(root: PathBuf) -> Result<DeviceCpuStatus> {
         info!("Creating CPU device structure");
-        let power_limit_0_current_path = root
-            .join(DEVICE_POWER_LIMIT_PATH)
-            .join("constraint_0_power_limit_uw");
-        let power_limit_0_max_path = root
-            .join(DEVICE_POWER_LIMIT_PATH)
-            .join("constraint_0_max_power_uw");
-        let power_limit_1_current_path = root
-            .join(DEVICE_POWER_LIMIT_PATH)
-            .join("constraint_1_power_limit_uw");
-        let power_limit_1_max_path = root
-            .join(DEVICE_POWER_LIMIT_PATH)
-            .join("constraint_1_max_power_uw");
-        let energy_curr_path = root.join(DEVICE_POWER_LIMIT_PATH).join("energy_uj");
-        let energy_max_path = root
-            .join(DEVICE_POWER_LIMIT_PATH)
-            .join("max_energy_range_uj");
+        // The package isn't always intel-rapl:0, the platform (psys) domain may come first.
+        let power_limit_path = power::enumerate_rapl_domains(&root)?
+            .into_iter()
+            .find(|domain| domain.subdomain.is_none() && domain.name.starts_with("package"))
+            .map(|domain| domain.path)
+            .context("Could not find the RAPL package domain")?;
+        let power_limit_0_current_path = power_limit_path.join("constraint_0_power_limit_uw");
+        let power_limit_0_max_path = power_limit_path.join("constraint_0_max_power_uw");
+        let power_limit_1_current_path = power_limit_path.join("constraint_1_power_limit_uw");
+        let power_limit_1_max_path = power_limit_path.join("constraint_1_max_power_uw");
+        let energy_curr_path = power_limit_path.join("energy_uj");
+        let energy_max_path = power_limit_path.join("max_energy_range_uj");
 
         let cpu_max_freq_path = root
             .join(DEVICE_CPUFREQ_PATH)
@@ -441,6 +481,7 @@ impl DeviceCpuStatus {
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
+    use std::fs;
 
     use super::*;
     use crate::test_utils::tests::*;
@@ -470,6 +511,87 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_pl0_package_after_psys() -> Result<()> {
+        let root = tempdir()?;
+        setup_mock_cpu_dev_dirs(root.path())?;
+        setup_mock_cpu_files(root.path())?;
+        write_mock_pl0(root.path(), 15000000)?;
+
+        // Move the package to intel-rapl:1 and put the platform domain in its place.
+        let powercap_path = root.path().join("sys/class/powercap");
+        fs::rename(
+            powercap_path.join("intel-rapl:0"),
+            powercap_path.join("intel-rapl:1"),
+        )?;
+        let psys_path = powercap_path.join("intel-rapl:0");
+        fs::create_dir_all(&psys_path)?;
+        fs::write(psys_path.join("name"), "psys")?;
+        fs::write(psys_path.join("constraint_0_name"), "long_term")?;
+        fs::write(psys_path.join("constraint_0_power_limit_uw"), "28000000")?;
+
+        let mock_cpu_dev = DeviceCpuStatus::new(PathBuf::from(root.path()))?;
+        assert_eq!(mock_cpu_dev.get_pl0_curr()?, 15000000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_power_draw() -> Result<()> {
+        let root = tempdir()?;
+        setup_mock_cpu_dev_dirs(root.path())?;
+        setup_mock_cpu_files(root.path())?;
+        write_mock_energy(root.path(), 1000000, 10000000)?;
+        let mock_cpu_dev = DeviceCpuStatus::new(PathBuf::from(root.path()))?;
+
+        // The counter doesn't move in the mock.
+        assert_eq!(
+            mock_cpu_dev.read_power_draw_uw(Duration::from_millis(1))?,
+            0
+        );
+
+        let start = mock_cpu_dev.get_energy_curr()?;
+        write_mock_energy(root.path(), 6000000, 10000000)?;
+        let end = mock_cpu_dev.get_energy_curr()?;
+        assert_eq!(
+            mock_cpu_dev.power_draw_uw(start, end, Duration::from_secs(1))?,
+            5000000
+        );
+        assert_eq!(
+            mock_cpu_dev.power_draw_uw(start, end, Duration::from_millis(250))?,
+            20000000
+        );
+        assert!(mock_cpu_dev
+            .power_draw_uw(start, end, Duration::ZERO)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_power_draw_counter_wrap() -> Result<()> {
+        let root = tempdir()?;
+        setup_mock_cpu_dev_dirs(root.path())?;
+        setup_mock_cpu_files(root.path())?;
+        write_mock_energy(root.path(), 9000000, 10000000)?;
+        let mock_cpu_dev = DeviceCpuStatus::new(PathBuf::from(root.path()))?;
+
+        let start = mock_cpu_dev.get_energy_curr()?;
+        write_mock_energy(root.path(), 1000000, 10000000)?;
+        let end = mock_cpu_dev.get_energy_curr()?;
+        assert_eq!(
+            mock_cpu_dev.power_draw_uw(start, end, Duration::from_millis(500))?,
+            4000000
+        );
+
+        // A counter above the max can't have wrapped.
+        assert!(mock_cpu_dev
+            .power_draw_uw(20000000, end, Duration::from_secs(1))
+            .is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_cpu_info_parsing() {
         let root = tempdir().unwrap();
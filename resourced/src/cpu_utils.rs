@@ -168,6 +168,33 @@ pub fn hotplug_cpus(action: HotplugCpuAction) -> Result<()> {
     hotplug_cpus_impl(root, action)
 }
 
+// Snaps `freq` to the nearest frequency listed in the policy's scaling_available_frequencies.
+// Policies that don't have the file accept any value in a continuous range, so `freq` is returned
+// unchanged.
+// * `policy_path` - The cpufreq policy directory, e.g. /sys/devices/system/cpu/cpufreq/policy0.
+pub fn snap_to_available_frequency(policy_path: &Path, freq: u64) -> Result<u64> {
+    let available_path = policy_path.join("scaling_available_frequencies");
+
+    if !available_path.exists() {
+        return Ok(freq);
+    }
+
+    let available = std::fs::read_to_string(&available_path).with_context(|| {
+        format!(
+            "Failed to read available frequencies from {}",
+            available_path.display()
+        )
+    })?;
+
+    available
+        .split_whitespace()
+        .map(|value| value.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()?
+        .into_iter()
+        .min_by_key(|available_freq| available_freq.abs_diff(freq))
+        .with_context(|| format!("No available frequencies in {}", available_path.display()))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::test_utils::tests::*;
@@ -189,6 +216,60 @@ mod tests {
         assert_eq!(value, expected);
     }
 
+    fn test_write_available_frequencies(root: &Path, frequencies: &str) -> std::path::PathBuf {
+        let policy_path = root.join("sys/devices/system/cpu/cpufreq/policy0");
+        std::fs::create_dir_all(&policy_path).unwrap();
+        std::fs::write(
+            policy_path.join("scaling_available_frequencies"),
+            frequencies,
+        )
+        .unwrap();
+        policy_path
+    }
+
+    #[test]
+    fn test_snap_to_available_frequency() {
+        let root = TempDir::new().unwrap();
+        let policy_path = test_write_available_frequencies(
+            root.path(),
+            "2400000 2000000 1600000 1200000 800000 \n",
+        );
+
+        let tests = [
+            // Exact matches are unchanged.
+            (1600000, 1600000),
+            // Snaps down to the nearest step.
+            (1700000, 1600000),
+            (2100000, 2000000),
+            (1300000, 1200000),
+            // Snaps up to the nearest step.
+            (1900000, 2000000),
+            (1500000, 1600000),
+            // Out of range values snap to the bounds.
+            (100000, 800000),
+            (3000000, 2400000),
+        ];
+
+        for (requested, expected) in tests {
+            assert_eq!(
+                snap_to_available_frequency(&policy_path, requested).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_snap_to_available_frequency_continuous_range() {
+        let root = TempDir::new().unwrap();
+        let policy_path = root.path().join("sys/devices/system/cpu/cpufreq/policy0");
+        std::fs::create_dir_all(&policy_path).unwrap();
+
+        assert_eq!(
+            snap_to_available_frequency(&policy_path, 1234567).unwrap(),
+            1234567
+        );
+    }
+
     #[test]
     fn test_hotplug_cpus() {
         // Setup.
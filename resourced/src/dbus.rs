@@ -21,14 +21,20 @@ use dbus_tokio::connection;
 use log::error;
 use log::LevelFilter;
 use system_api::battery_saver::BatterySaverModeState;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 use crate::common;
 use crate::config;
+use crate::config::ConfigProvider;
+use crate::cpu_hotplug;
+#[cfg(target_arch = "x86_64")]
+use crate::cpu_scaling;
 use crate::feature;
 use crate::memory;
 use crate::power;
 use crate::psi;
 use crate::qos;
+use crate::sysfs_audit;
 
 const SERVICE_NAME: &str = "org.chromium.ResourceManager";
 const PATH_NAME: &str = "/org/chromium/ResourceManager";
@@ -44,6 +50,13 @@ const DEFAULT_DBUS_TIMEOUT: Duration = Duration::from_secs(5);
 // 60seconds which is long enough for booting a VM on low-end DUTs.
 const DEFAULT_VM_BOOT_TIMEOUT: Duration = Duration::from_secs(60);
 
+// How often the thermal zones are checked for applying or lifting the thermal clamp.
+const THERMAL_CLAMP_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long GetPowerDrawMicrowatts averages the package power draw over.
+#[cfg(target_arch = "x86_64")]
+const POWER_DRAW_SAMPLING_INTERVAL: Duration = Duration::from_millis(500);
+
 const VARIABLE_TIME_MEMORY_SIGNAL_FEATURE_NAME: &str =
     "CrOSLateBootResourcedVariableTimeMemorySignal";
 
@@ -56,6 +69,9 @@ type PowerPreferencesManager = power::DirectoryPowerPreferencesManager<
 #[derive(Clone)]
 struct DbusContext {
     power_preferences_manager: Arc<PowerPreferencesManager>,
+    // Wakes up the debounced power preferences task with the time after which the held back
+    // change is due.
+    debounced_power_preferences: UnboundedSender<Duration>,
 
     // Timer ids for skipping out-of-dated timer events.
     reset_game_mode_timer_id: Arc<AtomicUsize>,
@@ -63,6 +79,43 @@ struct DbusContext {
     reset_vm_boot_mode_timer_id: Arc<AtomicUsize>,
 }
 
+// Applies the power preferences held back by the debouncing once they are due.
+fn schedule_debounced_power_preferences(
+    debounced_power_preferences: &UnboundedSender<Duration>,
+    pending: Option<Duration>,
+) {
+    let Some(timeout) = pending else {
+        return;
+    };
+    if debounced_power_preferences.send(timeout).is_err() {
+        error!("Debounced power preferences task is gone");
+    }
+}
+
+// The single task applying the held back power preferences. A wake up while a change is already
+// held back is covered by the update after the current timeout, which reports the remaining time
+// of the latest change.
+async fn run_debounced_power_preferences(
+    power_preferences_manager: Arc<PowerPreferencesManager>,
+    mut receiver: UnboundedReceiver<Duration>,
+) {
+    while let Some(mut timeout) = receiver.recv().await {
+        loop {
+            tokio::time::sleep(timeout).await;
+            while receiver.try_recv().is_ok() {}
+
+            match common::update_debounced_power_preferences(power_preferences_manager.as_ref()) {
+                Ok(Some(remaining)) => timeout = remaining,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Update debounced power preferences failed: {:#}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
 fn is_unspported_error(e: &anyhow::Error) -> bool {
     return e
         .downcast_ref::<std::io::Error>()
@@ -86,6 +139,33 @@ fn send_pressure_signal(
     }
 }
 
+// The D-Bus representation of the activities resourced picks the power preferences from.
+fn power_mode_to_dbus(mode: power::PowerActivities) -> (u8, u8, u8, u8, u8) {
+    (
+        mode.game as u8,
+        mode.fullscreen as u8,
+        mode.rtc as u8,
+        mode.batterysaver as u8,
+        mode.vmboot as u8,
+    )
+}
+
+fn send_power_mode_signal(
+    conn: &SyncConnection,
+    previous: power::PowerActivities,
+    new: power::PowerActivities,
+) {
+    let msg = Message::signal(
+        &PATH_NAME.into(),
+        &INTERFACE_NAME.into(),
+        &"PowerModeChanged".into(),
+    )
+    .append2(power_mode_to_dbus(previous), power_mode_to_dbus(new));
+    if conn.send(msg).is_err() {
+        error!("Send power mode changed signal failed.");
+    }
+}
+
 // Call swap_management SwapSetSwappiness when set_game_mode returns TuneSwappiness.
 fn set_game_mode_and_tune_swappiness(
     power_preferences_manager: &dyn power::PowerPreferencesManager,
@@ -375,7 +455,13 @@ fn register_interface(cr: &mut Crossroads, conn: Arc<SyncConnection>) -> IfaceTo
                     context.power_preferences_manager.as_ref(),
                     active,
                 ) {
-                    Ok(()) => Ok(()),
+                    Ok(pending) => {
+                        schedule_debounced_power_preferences(
+                            &context.debounced_power_preferences,
+                            pending,
+                        );
+                        Ok(())
+                    }
                     Err(e) => {
                         error!("set_rtc_audeio_active failed: {:#}", e);
                         Err(MethodErr::failed("Failed to set RTC audio activity"))
@@ -398,12 +484,14 @@ fn register_interface(cr: &mut Crossroads, conn: Arc<SyncConnection>) -> IfaceTo
                     .map_err(|_| MethodErr::failed("Unsupported fullscreen video value"))?;
                 let timeout = Duration::from_secs(timeout_raw.into());
 
-                common::set_fullscreen_video(context.power_preferences_manager.as_ref(), mode)
-                    .map_err(|e| {
-                        error!("set_fullscreen_video failed: {:#}", e);
+                let pending =
+                    common::set_fullscreen_video(context.power_preferences_manager.as_ref(), mode)
+                        .map_err(|e| {
+                            error!("set_fullscreen_video failed: {:#}", e);
 
-                        MethodErr::failed("Failed to set full screen video mode")
-                    })?;
+                            MethodErr::failed("Failed to set full screen video mode")
+                        })?;
+                schedule_debounced_power_preferences(&context.debounced_power_preferences, pending);
 
                 context
                     .reset_fullscreen_video_timer_id
@@ -412,24 +500,150 @@ fn register_interface(cr: &mut Crossroads, conn: Arc<SyncConnection>) -> IfaceTo
                     .reset_fullscreen_video_timer_id
                     .load(Ordering::Relaxed);
                 let power_preferences_manager = context.power_preferences_manager.clone();
+                let debounced_power_preferences = context.debounced_power_preferences.clone();
                 let reset_fullscreen_video_timer_id =
                     context.reset_fullscreen_video_timer_id.clone();
                 tokio::spawn(async move {
                     tokio::time::sleep(timeout).await;
-                    if timer_id == reset_fullscreen_video_timer_id.load(Ordering::Relaxed)
-                        && common::set_fullscreen_video(
-                            power_preferences_manager.as_ref(),
-                            common::FullscreenVideo::Inactive,
-                        )
-                        .is_err()
-                    {
-                        error!("Reset fullscreen video mode failed.");
+                    if timer_id != reset_fullscreen_video_timer_id.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    match common::set_fullscreen_video(
+                        power_preferences_manager.as_ref(),
+                        common::FullscreenVideo::Inactive,
+                    ) {
+                        Ok(pending) => schedule_debounced_power_preferences(
+                            &debounced_power_preferences,
+                            pending,
+                        ),
+                        Err(_) => error!("Reset fullscreen video mode failed."),
                     }
                 });
 
                 Ok(())
             },
         );
+        b.method(
+            "RequestRenderBoost",
+            ("frames",),
+            (),
+            move |_, context, (frames,): (u32,)| {
+                let power_preferences_manager = context.power_preferences_manager.clone();
+                let timeout =
+                    power::request_render_boost(power_preferences_manager.as_ref(), frames)
+                        .map_err(|e| {
+                            error!("request_render_boost failed: {:#}", e);
+
+                            MethodErr::failed("Failed to request render boost")
+                        })?;
+
+                // Release the boost after it expired. Overlapping requests extend the boost, so
+                // keep waiting until no time remains.
+                tokio::spawn(async move {
+                    let mut timeout = timeout;
+                    loop {
+                        tokio::time::sleep(timeout).await;
+                        match power::update_render_boost(power_preferences_manager.as_ref()) {
+                            Ok(Some(remaining)) => timeout = remaining,
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("Release render boost failed: {:#}", e);
+                                break;
+                            }
+                        }
+                    }
+                });
+
+                Ok(())
+            },
+        );
+        b.method(
+            "SetRefreshRate",
+            ("refresh_rate_hz",),
+            (),
+            move |_, _, (refresh_rate_hz,): (u32,)| {
+                power::set_render_refresh_rate(refresh_rate_hz).map_err(|e| {
+                    error!("set_render_refresh_rate failed: {:#}", e);
+
+                    MethodErr::failed("Failed to set refresh rate")
+                })
+            },
+        );
+        b.method(
+            "GetFrequencyResidency",
+            ("policy",),
+            ("residency",),
+            move |ctx, _, (policy,): (u32,)| {
+                // Each caller gets the residency since its own previous call.
+                let sender = match ctx.message().sender() {
+                    Some(sender) => sender.to_string(),
+                    None => return Err(MethodErr::failed("Failed to get sender id")),
+                };
+
+                // Pairs of frequency in kHz and time in milliseconds.
+                match power::frequency_residency(Path::new("/"), &sender, policy) {
+                    Ok(residency) => Ok((residency
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(freq, time)| (freq, time.as_millis() as u64))
+                        .collect::<Vec<(u64, u64)>>(),)),
+                    Err(e) => {
+                        error!("frequency_residency failed: {:#}", e);
+                        Err(MethodErr::failed("Failed to get frequency residency"))
+                    }
+                }
+            },
+        );
+        #[cfg(target_arch = "x86_64")]
+        b.method_with_cr_async(
+            "GetPowerDrawMicrowatts",
+            (),
+            ("power_draw_uw",),
+            move |mut sender_context, _, ()| async move {
+                // Sampling the energy counter sleeps, keep it off the D-Bus thread.
+                let result = tokio::task::spawn_blocking(|| {
+                    cpu_scaling::DeviceCpuStatus::new(PathBuf::from("/"))?
+                        .read_power_draw_uw(POWER_DRAW_SAMPLING_INTERVAL)
+                })
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|result| result);
+                match result {
+                    Ok(power_draw_uw) => sender_context.reply(Ok((power_draw_uw,))),
+                    Err(e) => {
+                        error!("read_power_draw_uw failed: {:#}", e);
+                        sender_context.reply(Err(MethodErr::failed("Failed to read power draw")))
+                    }
+                }
+            },
+        );
+        b.method(
+            "GetSysfsWriteAuditLog",
+            (),
+            ("records",),
+            move |_, _, ()| match sysfs_audit::get_records() {
+                Ok(records) => Ok((records
+                    .iter()
+                    .map(|record| record.to_string())
+                    .collect::<Vec<String>>(),)),
+                Err(e) => {
+                    error!("get_records failed: {:#}", e);
+                    Err(MethodErr::failed("Failed to get sysfs write audit log"))
+                }
+            },
+        );
+        b.method(
+            "SetSysfsWriteAuditSyslog",
+            ("enabled",),
+            (),
+            move |_, _, (enabled,): (bool,)| {
+                sysfs_audit::set_log_to_syslog(enabled).map_err(|e| {
+                    error!("set_log_to_syslog failed: {:#}", e);
+
+                    MethodErr::failed("Failed to set sysfs write audit syslog")
+                })
+            },
+        );
         b.method("PowerSupplyChange", (), (), move |_, context, ()| {
             match common::update_power_preferences(context.power_preferences_manager.as_ref()) {
                 Ok(()) => Ok(()),
@@ -516,6 +730,11 @@ fn register_interface(cr: &mut Crossroads, conn: Arc<SyncConnection>) -> IfaceTo
             "MemoryPressureArcvm",
             ("pressure_level", "reclaim_target_kb"),
         );
+        // The modes are (game mode, fullscreen video, RTC audio, battery saver, VM boot mode).
+        b.signal::<((u8, u8, u8, u8, u8), (u8, u8, u8, u8, u8)), _>(
+            "PowerModeChanged",
+            ("previous_mode", "new_mode"),
+        );
     })
 }
 
@@ -649,13 +868,53 @@ fn report_notification_count(notification_count: i32) -> Result<()> {
 
 pub async fn service_main() -> Result<()> {
     let root = Path::new("/");
+    let power_preferences_manager = Arc::new(power::new_directory_power_preferences_manager(root));
+    match power_preferences_manager
+        .config_provider
+        .read_power_preferences_hold()
+    {
+        Ok(Some(hold)) => {
+            if let Err(e) = common::set_power_preferences_hold(hold) {
+                error!("Failed to set the power preferences hold: {:#}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to read the power preferences hold: {:#}", e),
+    }
+    let (debounced_power_preferences, receiver) = unbounded_channel();
+    tokio::spawn(run_debounced_power_preferences(
+        power_preferences_manager.clone(),
+        receiver,
+    ));
+
     let context = DbusContext {
-        power_preferences_manager: Arc::new(power::new_directory_power_preferences_manager(root)),
+        power_preferences_manager,
+        debounced_power_preferences,
         reset_game_mode_timer_id: Arc::new(AtomicUsize::new(0)),
         reset_fullscreen_video_timer_id: Arc::new(AtomicUsize::new(0)),
         reset_vm_boot_mode_timer_id: Arc::new(AtomicUsize::new(0)),
     };
 
+    if let Err(e) = cpu_hotplug::start_cpu_online_watcher(context.power_preferences_manager.clone())
+    {
+        error!("Failed to watch CPU hotplug: {:#}", e);
+    }
+
+    // Applies the thermal clamp when a thermal zone gets hot and lifts it once they cooled down,
+    // without waiting for the next change of the power preferences.
+    let thermal_power_preferences_manager = context.power_preferences_manager.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(THERMAL_CLAMP_POLL_INTERVAL).await;
+
+            if let Err(err) =
+                common::update_thermal_clamp(thermal_power_preferences_manager.as_ref())
+            {
+                error!("Failed to update the thermal clamp: {:#}", err);
+            }
+        }
+    });
+
     let (io_resource, conn) = connection::new_system_sync()?;
 
     // io_resource must be awaited to start receiving D-Bus message.
@@ -666,6 +925,11 @@ pub async fn service_main() -> Result<()> {
 
     conn.request_name(SERVICE_NAME, false, true, false).await?;
 
+    let signal_conn = conn.clone();
+    common::set_power_mode_observer(Box::new(move |previous, new| {
+        send_power_mode_signal(&signal_conn, previous, new)
+    }))?;
+
     let mut cr = Crossroads::new();
 
     // Enable asynchronous methods. Incoming method calls are spawned as separate tasks if
@@ -2,9 +2,105 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+
+// Class path of the DRM devices: the GPU cards along with their connectors and render nodes.
+const DRM_CLASS_PATH: &str = "sys/class/drm";
+
+/// Vendor independent GPU frequency controls used by the power preference logic.
+pub trait GpuController {
+    /// Caps the GPU frequency while RTC audio is active, and lifts the cap once it isn't.
+    fn set_rtc_audio_active(&mut self, active: bool) -> Result<()>;
+
+    /// Lowers the GPU max frequency in battery saver mode, and restores the hardware max once
+    /// it ends.
+    fn set_battery_saver(&mut self, active: bool) -> Result<()>;
+}
+
+// Returns the GPU cards, e.g. sys/class/drm/card0 and sys/class/drm/card1. Their connectors,
+// e.g. card0-eDP-1, and the render nodes aren't cards.
+fn gpu_cards(root: &Path) -> Result<Vec<PathBuf>> {
+    let drm_path = root.join(DRM_CLASS_PATH);
+    let mut cards = Vec::new();
+    for entry in fs::read_dir(&drm_path)
+        .with_context(|| format!("Couldn't read DRM devices {}", drm_path.display()))?
+    {
+        let path = entry?.path();
+        let is_card = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("card"))
+            .is_some_and(|num| !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()));
+        if is_card {
+            cards.push(path);
+        }
+    }
+    cards.sort();
+    Ok(cards)
+}
+
+// Returns a controller for the GPU card, picked by its kernel driver.
+fn gpu_card_controller(root: &Path, card_path: &Path) -> Result<Box<dyn GpuController>> {
+    let device_path = card_path.join("device");
+    let driver_path = device_path.join("driver");
+    let driver = fs::read_link(&driver_path)
+        .with_context(|| format!("Couldn't read GPU driver {}", driver_path.display()))?;
+
+    match driver.file_name().and_then(|name| name.to_str()) {
+        // The polling interval only matters for active GPU tuning, which isn't started here.
+        Some("i915") => Ok(Box::new(intel_device::IntelGpuDeviceConfig::with_card(
+            root.to_owned(),
+            card_path,
+            100,
+        )?)),
+        Some("amdgpu") => Ok(Box::new(amd_device::AmdDeviceConfig::from_device_dir(
+            &device_path,
+        ))),
+        _ => bail!("Unsupported GPU driver {}", driver.display()),
+    }
+}
+
+/// Calls `apply` with the controller of every GPU card, picked by its kernel driver. A card
+/// failing doesn't keep the others from being set, only an error of every card is returned.
+///
+/// # Arguments
+///
+/// * `root` - root path of device.  Used for using relative paths for testing.  Should
+/// always be '/' for device.
+/// * `apply` - Called once for each GPU card.
+pub fn apply_to_gpus<F>(root: &Path, mut apply: F) -> Result<()>
+where
+    F: FnMut(&mut dyn GpuController) -> Result<()>,
+{
+    let mut last_error = None;
+    let mut applied = false;
+    for card_path in gpu_cards(root)? {
+        match gpu_card_controller(root, &card_path).and_then(|mut gpu| apply(gpu.as_mut())) {
+            Ok(()) => applied = true,
+            Err(err) => {
+                warn!("Couldn't set GPU {}: {:#}", card_path.display(), err);
+                last_error = Some(err);
+            }
+        }
+    }
+
+    match last_error {
+        Some(err) if !applied => Err(err),
+        None if !applied => bail!("No GPU card found"),
+        _ => Ok(()),
+    }
+}
+
 pub mod intel_device {
+    use super::GpuController;
     use crate::{
-        common::{self, GameMode},
+        common::{self, BatterySaverMode, GameMode},
         cpu_scaling::DeviceCpuStatus,
     };
     use anyhow::{bail, Context, Result};
@@ -13,7 +109,7 @@ pub mod intel_device {
     use std::{
         fs::{self, File},
         io::{BufRead, BufReader},
-        path::PathBuf,
+        path::{Path, PathBuf},
         sync::Mutex,
         thread,
         time::Duration,
@@ -40,6 +136,8 @@ pub mod intel_device {
 
         turbo_freq_path: PathBuf,
 
+        hw_max_freq_path: PathBuf,
+
         // pub(crate) for sanity unit testing
         /// `power_liit_thr` is a table of tuple containing a power_limit_0 value and
         /// a max_gpu_freq.  Any power_limit that falls within index i and i+1
@@ -152,15 +250,27 @@ pub mod intel_device {
         ///
         /// New Intel GPU device object.
         pub fn new(root: PathBuf, polling_interval_ms: u64) -> Result<IntelGpuDeviceConfig> {
+            let card_path = root.join(GPU0_DEVICE_PATH);
+            IntelGpuDeviceConfig::with_card(root, &card_path, polling_interval_ms)
+        }
+
+        /// New Intel GPU device object for the GPU card at `card_path`, e.g.
+        /// /sys/class/drm/card1.
+        pub fn with_card(
+            root: PathBuf,
+            card_path: &Path,
+            polling_interval_ms: u64,
+        ) -> Result<IntelGpuDeviceConfig> {
             if !is_intel_device(root.to_owned()) {
                 bail!("Not an intel device");
             }
 
             let gpu_dev = IntelGpuDeviceConfig {
                 root: root.to_owned(),
-                min_freq_path: root.join(GPU0_DEVICE_PATH).join("gt_min_freq_mhz"),
-                max_freq_path: root.join(GPU0_DEVICE_PATH).join("gt_max_freq_mhz"),
-                turbo_freq_path: root.join(GPU0_DEVICE_PATH).join("gt_boost_freq_mhz"),
+                min_freq_path: card_path.join("gt_min_freq_mhz"),
+                max_freq_path: card_path.join("gt_max_freq_mhz"),
+                turbo_freq_path: card_path.join("gt_boost_freq_mhz"),
+                hw_max_freq_path: card_path.join("gt_RP0_freq_mhz"),
                 power_limit_thr: vec![
                     (15000000, EXPECTED_GPU_MAX_FREQ),
                     (14500000, 900),
@@ -271,6 +381,11 @@ pub mod intel_device {
             while common::get_game_mode()? == GameMode::Borealis {
                 thread::sleep(Duration::from_millis(self.polling_interval_ms));
 
+                // Battery saver takes precedence over gaming, leave its cap alone.
+                if common::get_battery_saver_mode()? == BatterySaverMode::Active {
+                    continue;
+                }
+
                 let current_pl = cpu_dev.get_pl0_curr()?;
                 if current_pl == last_pl_val {
                     // No change in powerlimit since last check, no action needed.
@@ -333,6 +448,11 @@ pub mod intel_device {
         pub fn tuning_cleanup(&self) -> Result<()> {
             info!("Active Gpu Tuning STOP requested");
 
+            if common::get_battery_saver_mode()? == BatterySaverMode::Active {
+                info!("Battery saver active, keeping the GPU max frequency");
+                return Ok(());
+            }
+
             // Swallow any potential errors when resetting.
             let gpu_max_default = self.power_limit_thr.first().unwrap_or(&(1000, 1000)).1;
             self.set_gpu_max_freq(gpu_max_default)?;
@@ -341,6 +461,34 @@ pub mod intel_device {
             Ok(())
         }
     }
+
+    impl GpuController for IntelGpuDeviceConfig {
+        fn set_rtc_audio_active(&mut self, active: bool) -> Result<()> {
+            IntelGpuDeviceConfig::set_rtc_audio_active(self, active)
+        }
+
+        // Battery saver caps the max, and with it the boost, halfway between the min and the
+        // hardware max.
+        fn set_battery_saver(&mut self, active: bool) -> Result<()> {
+            let hw_max_freq = common::read_file_to_u64(&self.hw_max_freq_path)?;
+            let gpu_stats = self.get_gpu_stats()?;
+            let max_freq = if active {
+                gpu_stats.min_freq + hw_max_freq.saturating_sub(gpu_stats.min_freq) / 2
+            } else {
+                hw_max_freq
+            };
+
+            info!("Setting GPU max to {} for battery saver", max_freq);
+            // Lower the boost first and raise it last, it must not exceed the max.
+            if active {
+                self.set_gpu_turbo_freq(max_freq)?;
+                self.set_gpu_max_freq(max_freq)
+            } else {
+                self.set_gpu_max_freq(max_freq)?;
+                self.set_gpu_turbo_freq(max_freq)
+            }
+        }
+    }
 }
 
 /// Mod for util functions to handle AMD devices.
@@ -349,6 +497,7 @@ pub mod amd_device {
     // TODO: removeme once other todos addressed.
     #![allow(dead_code)]
 
+    use super::GpuController;
     use anyhow::{bail, Context, Result};
     use glob::glob;
     use log::{error, info};
@@ -415,6 +564,16 @@ pub mod amd_device {
             }
         }
 
+        /// Creates a new AMD device object for the GPU with the given PCI device directory,
+        /// e.g. _/sys/class/drm/card0/device_.
+        pub fn from_device_dir(dev_dir: &Path) -> AmdDeviceConfig {
+            AmdDeviceConfig {
+                gpu_mode_path: dev_dir.join(AMDGPU_DPM_FORCE_PERFORMANCE_LEVEL),
+                sclk_mode_path: dev_dir.join(AMDGPU_PP_DPM_SCLK),
+                clk_voltage_path: dev_dir.join(AMDGPU_PP_OD_CLK_VOLTAGE),
+            }
+        }
+
         /// Static function to check if device has a supported AMD GPU.
         ///
         /// # Return
@@ -549,19 +708,28 @@ pub mod amd_device {
 
         let amd_dev_dir = find_amd_dev_dir().context("No AMD device detected")?;
 
-        let concat_and_get_string = |file_name: &str| {
-            return amd_dev_dir.join(file_name).display().to_string();
-        };
+        Ok(AmdDeviceConfig::from_device_dir(&amd_dev_dir))
+    }
 
-        let gpu_mode_str = concat_and_get_string(AMDGPU_DPM_FORCE_PERFORMANCE_LEVEL);
-        let sclk_mode_str = concat_and_get_string(AMDGPU_PP_DPM_SCLK);
-        let clk_voltage_str = concat_and_get_string(AMDGPU_PP_OD_CLK_VOLTAGE);
+    impl GpuController for AmdDeviceConfig {
+        // AMD GPUs have no boost frequency to lower, pin the system clock to the lowest
+        // level instead.
+        fn set_rtc_audio_active(&mut self, active: bool) -> Result<()> {
+            if !self.is_amd_device() {
+                bail!("Not a supported AMD device");
+            }
+            if active {
+                self.set_gpu_mode(AmdGpuMode::Manual)?;
+                self.set_sclk_mode(0)
+            } else {
+                self.set_gpu_mode(AmdGpuMode::Auto)
+            }
+        }
 
-        Ok(AmdDeviceConfig::new(
-            &gpu_mode_str,
-            &sclk_mode_str,
-            &clk_voltage_str,
-        ))
+        // Same as for RTC audio, the lowest system clock is the only cap.
+        fn set_battery_saver(&mut self, active: bool) -> Result<()> {
+            GpuController::set_rtc_audio_active(self, active)
+        }
     }
 
     /// Init function to setup device, perform validity check, and set GPU
@@ -4,6 +4,7 @@
 
 mod common;
 mod config;
+mod cpu_hotplug;
 mod cpu_utils;
 mod dbus;
 mod feature;
@@ -11,6 +12,8 @@ mod memory;
 mod power;
 mod psi;
 mod qos;
+mod sysfs_audit;
+mod thermal;
 
 #[cfg(test)]
 mod test_utils;
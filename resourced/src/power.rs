@@ -2,22 +2,78 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::HashMap;
 use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use glob::glob;
-use log::{error, info};
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
 
 use crate::common;
 use crate::common::{BatterySaverMode, FullscreenVideo, GameMode, RTCAudioActive, VmBootMode};
 use crate::config;
+use crate::cpu_utils;
+use crate::sysfs_audit;
+use crate::thermal;
+use crate::thermal::ThrottleBand;
 
 const POWER_SUPPLY_PATH: &str = "sys/class/power_supply";
 const POWER_SUPPLY_ONLINE: &str = "online";
 const POWER_SUPPLY_STATUS: &str = "status";
+const POWER_SUPPLY_CHARGE_BEHAVIOUR: &str = "charge_behaviour";
 const GLOBAL_ONDEMAND_PATH: &str = "sys/devices/system/cpu/cpufreq/ondemand";
+const CPUFREQ_POLICY_PATTERN: &str = "sys/devices/system/cpu/cpufreq/policy*";
+const INTEL_PSTATE_PATH: &str = "sys/devices/system/cpu/intel_pstate";
+const POWERCAP_PATH: &str = "sys/class/powercap";
+const HWMON_PATH: &str = "sys/class/hwmon";
+const CPUINFO_PATH: &str = "proc/cpuinfo";
+const PROC_IRQ_PATH: &str = "proc/irq";
+const PROC_INTERRUPTS_PATH: &str = "proc/interrupts";
+const PROC_UPTIME_PATH: &str = "proc/uptime";
+const THERMAL_THROTTLE_PATTERN: &str = "sys/devices/system/cpu/cpu[0-9]*/thermal_throttle";
+// Counters of the power limit notifications the CPU raised because the firmware RAPL limits were
+// hit.
+const POWER_LIMIT_COUNTERS: [&str; 2] = ["package_power_limit_count", "core_power_limit_count"];
+const UCLAMP_MIN_PATH: &str = "proc/sys/kernel/sched_util_clamp_min";
+const UCLAMP_MAX_PATH: &str = "proc/sys/kernel/sched_util_clamp_max";
+const INTEL_GPU_BOOST_PATTERN: &str = "sys/class/drm/card*/gt_boost_freq_mhz";
+// The uclamp values are in units of the CPU capacity, SCHED_CAPACITY_SCALE in the kernel.
+const UCLAMP_CAPACITY_SCALE: u32 = 1024;
+// The upstart job writing the uclamp sysctls, which the resourced user doesn't own.
+const UPSTART_SERVICE_NAME: &str = "com.ubuntu.Upstart";
+const UPSTART_JOB_INTERFACE: &str = "com.ubuntu.Upstart0_6.Job";
+const UCLAMP_JOB_PATH: &str = "/com/ubuntu/Upstart/jobs/resourced_2duclamp";
+const UCLAMP_JOB_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Refresh rate assumed for render boosts until the compositor reports one.
+const DEFAULT_REFRESH_RATE_HZ: u32 = 60;
+// The frequency floor during a render boost, in percent of the max frequency.
+const RENDER_BOOST_MIN_FREQ_PCT: u64 = 70;
+// The longest render boost a single request can ask for, 10 seconds at 60 Hz.
+const RENDER_BOOST_MAX_FRAMES: u32 = 600;
+
+// Number of increments in which apply_profile_ramped() moves the CPU performance range.
+const RAMP_STEPS: u32 = 4;
+
+// IRQs firing at least this often on average since boot are steered away from the big cores in
+// game mode.
+const HIGH_RATE_IRQ_PER_SEC: f64 = 100.0;
+
+// The cpufreq stats report the time spent at each frequency in units of 10ms.
+const TIME_IN_STATE_UNIT_MS: u64 = 10;
+
+// The CPU performance range in battery saver mode, capping the max frequency harder than any
+// activity does.
+const BATTERY_SAVER_CPU_PERF_PCT: config::CpuPerfPercent =
+    config::CpuPerfPercent { min: 0, max: 60 };
+// How long entering battery saver takes to bring the CPU performance range down, so that the
+// slowdown isn't a perceptible cliff.
+const BATTERY_SAVER_RAMP_DURATION: Duration = Duration::from_millis(200);
 
 pub trait PowerSourceProvider {
     /// Returns the current power source of the system.
@@ -54,6 +110,131 @@ impl FromStr for PowerSupplyStatus {
     }
 }
 
+/// See the `POWER_SUPPLY_CHARGE_BEHAVIOUR_` enum in the linux kernel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChargeBehavior {
+    Auto,
+    InhibitCharge,
+    ForceDischarge,
+}
+
+impl ChargeBehavior {
+    fn to_name(self) -> &'static str {
+        match self {
+            ChargeBehavior::Auto => "auto",
+            ChargeBehavior::InhibitCharge => "inhibit-charge",
+            ChargeBehavior::ForceDischarge => "force-discharge",
+        }
+    }
+}
+
+impl FromStr for ChargeBehavior {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ChargeBehavior::Auto),
+            "inhibit-charge" => Ok(ChargeBehavior::InhibitCharge),
+            "force-discharge" => Ok(ChargeBehavior::ForceDischarge),
+            _ => bail!("Unknown charge behavior: '{}'", s),
+        }
+    }
+}
+
+// Parses the content of a charge_behaviour node, e.g. "[auto] inhibit-charge force-discharge",
+// into the current behavior and the available behaviors. Behaviors unknown to resourced are
+// skipped.
+fn parse_charge_behaviors(s: &str) -> Result<(ChargeBehavior, Vec<ChargeBehavior>)> {
+    let mut current = None;
+    let mut available = Vec::new();
+
+    for token in s.split_whitespace() {
+        let (name, selected) = match token.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            Some(name) => (name, true),
+            None => (token, false),
+        };
+
+        if let Ok(behavior) = ChargeBehavior::from_str(name) {
+            if selected {
+                current = Some(behavior);
+            }
+            available.push(behavior);
+        }
+    }
+
+    let current = current.with_context(|| format!("No current charge behavior in '{}'", s))?;
+    Ok((current, available))
+}
+
+// Returns the charge_behaviour node of the first power supply that has one.
+fn find_charge_behavior_path(root: &Path) -> Result<Option<PathBuf>> {
+    let pattern = root
+        .join(POWER_SUPPLY_PATH)
+        .join("*")
+        .join(POWER_SUPPLY_CHARGE_BEHAVIOUR)
+        .to_str()
+        .context("Cannot convert charge_behaviour path to string")?
+        .to_owned();
+
+    Ok(glob(&pattern)?.next().transpose()?)
+}
+
+/// Returns the current charge behavior of the battery, or None if the platform doesn't support
+/// controlling it.
+pub fn get_charge_behavior(root: &Path) -> Result<Option<ChargeBehavior>> {
+    let path = match find_charge_behavior_path(root)? {
+        Some(path) => path,
+        None => {
+            info!("Charge behavior is not supported");
+            return Ok(None);
+        }
+    };
+
+    let content = read_to_string(&path)
+        .with_context(|| format!("Failed to read charge behavior from {}", path.display()))?;
+    Ok(Some(parse_charge_behaviors(&content)?.0))
+}
+
+/// Sets the charge behavior of the battery. Fails if the behavior is not one of the options
+/// offered by the platform. Does nothing if the platform doesn't support controlling it.
+pub fn set_charge_behavior(root: &Path, behavior: ChargeBehavior) -> Result<()> {
+    let path = match find_charge_behavior_path(root)? {
+        Some(path) => path,
+        None => {
+            info!(
+                "Charge behavior is not supported, not setting {:?}",
+                behavior
+            );
+            return Ok(());
+        }
+    };
+
+    let content = read_to_string(&path)
+        .with_context(|| format!("Failed to read charge behavior from {}", path.display()))?;
+    let (current, available) = parse_charge_behaviors(&content)?;
+
+    if !available.contains(&behavior) {
+        bail!(
+            "Charge behavior {:?} is not available in {}",
+            behavior,
+            path.display()
+        );
+    }
+
+    if current != behavior {
+        sysfs_audit::write(&path, behavior.to_name(), "charge behavior").with_context(|| {
+            format!(
+                "Failed to set charge behavior {:?} to {}",
+                behavior,
+                path.display()
+            )
+        })?;
+        info!("Updating charge behavior to {:?}", behavior);
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Debug)]
 pub struct DirectoryPowerSourceProvider {
     pub root: PathBuf,
@@ -136,6 +317,7 @@ pub trait PowerPreferencesManager {
     /// to determine which [power preference](config::PowerPreferences) to apply. If there is no
     /// power preference defined for an activity, the next activity in the list will be tried.
     ///
+    /// 0) Battery saver, which is built in and overrides all activities, including gaming
     /// 1) [Borealis Gaming](config::PowerPreferencesType::BorealisGaming)
     /// 2) [ARCVM Gaming](config::PowerPreferencesType::ArcvmGaming)
     /// 3) [WebRTC](config::PowerPreferencesType::WebRTC)
@@ -152,9 +334,18 @@ pub trait PowerPreferencesManager {
         vmboot: common::VmBootMode,
         batterysaver: common::BatterySaverMode,
     ) -> Result<()>;
+
+    /// Returns whether a thermal zone got hot or all of them cooled down since the power
+    /// preferences were last updated, i.e. they must be updated again to apply or lift the
+    /// thermal clamp.
+    fn is_thermal_clamp_outdated(&self) -> Result<bool>;
+
+    /// Sets whether a render boost raises the CPU frequency limits. Takes effect with the next
+    /// update of the power preferences, which keep the boost in place until it is unset.
+    fn set_render_boost(&self, active: bool) -> Result<()>;
 }
 
-fn write_to_cpu_policy_patterns(pattern: &str, new_value: &str) -> Result<()> {
+fn write_to_cpu_policy_patterns(pattern: &str, new_value: &str, reason: &str) -> Result<()> {
     let mut applied: bool = false;
     let entries: Vec<_> = glob(pattern)?.collect();
 
@@ -167,7 +358,7 @@ fn write_to_cpu_policy_patterns(pattern: &str, new_value: &str) -> Result<()> {
         // Allow read fail due to CPU may be offlined.
         if let Ok(current_value) = read_to_string(&path) {
             if current_value.trim_end_matches('\n') != new_value {
-                std::fs::write(&path, new_value).with_context(|| {
+                sysfs_audit::write(&path, new_value, reason).with_context(|| {
                     format!(
                         "Failed to set attribute to {}, new value: {}",
                         path.display(),
@@ -187,7 +378,7 @@ fn write_to_cpu_policy_patterns(pattern: &str, new_value: &str) -> Result<()> {
     Ok(())
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 /// Applies [power preferences](config::PowerPreferences) to the system by writing to
 /// the system's sysfs nodes.
 ///
@@ -197,9 +388,182 @@ pub struct DirectoryPowerPreferencesManager<C: config::ConfigProvider, P: PowerS
     pub root: PathBuf,
     pub config_provider: C,
     pub power_source_provider: P,
+    // Whether the battery saver CPU cap is in place, so that it is lifted once battery saver ends
+    // even if the next power preferences don't set a CPU performance range.
+    battery_saver_cpu_capped: Mutex<bool>,
+    // The throttling band the CPU max frequency is clamped for, None while no thermal zone is hot.
+    thermal_clamped: Mutex<ThrottleBand>,
+    // The charge behavior from before the thermal clamp inhibited charging, restored once the
+    // clamp is lifted.
+    charge_behavior_baseline: Mutex<Option<ChargeBehavior>>,
+    // The boost frequency of each Intel GPU card from before game mode raised it.
+    gpu_boost_baselines: Mutex<HashMap<PathBuf, u64>>,
+    // The last value of each power limit counter, keyed by the path of the counter.
+    power_limit_baselines: Mutex<HashMap<PathBuf, u64>>,
+    // Whether a render boost is requested.
+    render_boost_requested: Mutex<bool>,
+    // Whether the render boost floor is in place, so that it is lowered once the boost ends even
+    // if the next power preferences don't set a CPU performance range.
+    render_boosted: Mutex<bool>,
+}
+
+// Returns the cpufreq policies with at least one online CPU. A policy whose CPUs are all offline,
+// e.g. for thermal reasons, is inactive and its limits can't be read or written. CPUs without an
+// online attribute, like cpu0 on most devices, can't be offlined.
+fn online_cpufreq_policies(root: &Path) -> Result<Vec<PathBuf>> {
+    let pattern = root
+        .join(CPUFREQ_POLICY_PATTERN)
+        .to_str()
+        .context("Cannot convert cpufreq policy path to string")?
+        .to_owned();
+
+    let mut policies = Vec::new();
+    for entry in glob(&pattern)? {
+        let policy_path = entry?;
+        // The policies are named after their first CPU.
+        let related_cpus_path = policy_path.join("related_cpus");
+        let cpus = if related_cpus_path.exists() {
+            read_to_string(&related_cpus_path)
+                .with_context(|| format!("Failed to read {}", related_cpus_path.display()))?
+        } else {
+            policy_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("policy"))
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let mut online = false;
+        for cpu in cpus.split_whitespace() {
+            let online_path = root.join(format!("sys/devices/system/cpu/cpu{}/online", cpu));
+            if !online_path.exists() || common::read_file_to_u64(&online_path)? == 1 {
+                online = true;
+                break;
+            }
+        }
+
+        if online {
+            policies.push(policy_path);
+        } else {
+            info!(
+                "Skipping {}, all its CPUs are offline",
+                policy_path.display()
+            );
+        }
+    }
+
+    Ok(policies)
+}
+
+// Returns the max frequency of a cpufreq policy. Not all ARM cpufreq drivers provide
+// cpuinfo_max_freq, fall back to the highest of scaling_available_frequencies then. None if
+// neither is there.
+fn policy_max_freq(policy_path: &Path) -> Result<Option<u64>> {
+    let cpuinfo_max_path = policy_path.join("cpuinfo_max_freq");
+    if cpuinfo_max_path.exists() {
+        return common::read_file_to_u64(&cpuinfo_max_path)
+            .map(Some)
+            .with_context(|| format!("Failed to read max frequency of {}", policy_path.display()));
+    }
+
+    let available_path = policy_path.join("scaling_available_frequencies");
+    if !available_path.exists() {
+        return Ok(None);
+    }
+    let available = read_to_string(&available_path).with_context(|| {
+        format!(
+            "Failed to read available frequencies from {}",
+            available_path.display()
+        )
+    })?;
+    Ok(available
+        .split_whitespace()
+        .map(|value| value.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()?
+        .into_iter()
+        .max())
+}
+
+// Returns the online cpufreq policies along with their max frequency. Policies without a max
+// frequency are skipped.
+fn online_policy_max_freqs(root: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut policies = Vec::new();
+    for policy_path in online_cpufreq_policies(root)? {
+        match policy_max_freq(&policy_path)? {
+            Some(max_freq) => policies.push((policy_path, max_freq)),
+            None => warn!(
+                "No max frequency for {}, leaving its limits alone",
+                policy_path.display()
+            ),
+        }
+    }
+
+    Ok(policies)
+}
+
+// Returns the thermal zone furthest into throttling along with its throttling band and its
+// temperature in millidegree Celsius, None if no thermal zone reached its hot trip point.
+fn hottest_thermal_zone(root: &Path) -> Result<Option<(ThrottleBand, PathBuf, i64)>> {
+    let mut hottest: Option<(ThrottleBand, PathBuf, i64)> = None;
+    for zone in thermal::get_thermal_zones(root)? {
+        // The sensors of powered down devices fail to read.
+        let temp = match zone.get_temp() {
+            Ok(temp) => temp,
+            Err(_) => continue,
+        };
+        let band = zone.throttle_thresholds().band(temp);
+        if band == ThrottleBand::None {
+            continue;
+        }
+        if hottest
+            .as_ref()
+            .is_none_or(|(hottest_band, _, hottest_temp)| {
+                (band, temp) > (*hottest_band, *hottest_temp)
+            })
+        {
+            hottest = Some((band, zone.path, temp));
+        }
+    }
+
+    Ok(hottest)
+}
+
+// The kind of cores of a cpufreq policy on hybrid CPUs, told apart by their max frequency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CoreType {
+    // The cores with the highest max frequency. All cores are performance cores if every policy
+    // has the same max frequency.
+    Performance,
+    Efficiency,
+}
+
+impl CoreType {
+    fn classify(max_freq: u64, performance_max_freq: u64) -> Self {
+        if max_freq < performance_max_freq {
+            CoreType::Efficiency
+        } else {
+            CoreType::Performance
+        }
+    }
 }
 
 impl<C: config::ConfigProvider, P: PowerSourceProvider> DirectoryPowerPreferencesManager<C, P> {
+    pub fn new(root: PathBuf, config_provider: C, power_source_provider: P) -> Self {
+        DirectoryPowerPreferencesManager {
+            root,
+            config_provider,
+            power_source_provider,
+            battery_saver_cpu_capped: Mutex::new(false),
+            thermal_clamped: Mutex::new(ThrottleBand::None),
+            charge_behavior_baseline: Mutex::new(None),
+            gpu_boost_baselines: Mutex::new(HashMap::new()),
+            power_limit_baselines: Mutex::new(HashMap::new()),
+            render_boost_requested: Mutex::new(false),
+            render_boosted: Mutex::new(false),
+        }
+    }
+
     // The global ondemand parameters are in /sys/devices/system/cpu/cpufreq/ondemand/.
     fn set_global_ondemand_governor_value(&self, attr: &str, value: u32) -> Result<()> {
         let path = self.root.join(GLOBAL_ONDEMAND_PATH).join(attr);
@@ -211,9 +575,9 @@ impl<C: config::ConfigProvider, P: PowerSourceProvider> DirectoryPowerPreference
         // Check current value before writing to avoid permission error when the new value and
         // current value are the same but resourced didn't own the parameter file.
         if current_value != value {
-            std::fs::write(&path, value.to_string()).with_context(|| {
-                format!("Error writing {} {} to {}", attr, value, path.display())
-            })?;
+            sysfs_audit::write(&path, value.to_string(), "ondemand governor").with_context(
+                || format!("Error writing {} {} to {}", attr, value, path.display()),
+            )?;
 
             info!("Updating ondemand {} to {}", attr, value);
         }
@@ -231,11 +595,37 @@ impl<C: config::ConfigProvider, P: PowerSourceProvider> DirectoryPowerPreference
             .to_str()
             .context("Cannot convert ondemand path to string")?
             .to_owned();
-        write_to_cpu_policy_patterns(&pattern, &value.to_string())
+        write_to_cpu_policy_patterns(&pattern, &value.to_string(), "ondemand governor")
     }
 
-    fn set_scaling_governor(&self, new_governor: &str) -> Result<()> {
+    // Returns the policies that don't offer the governor in scaling_available_governors. ARM
+    // boards often only build a few of the governors. Policies without the list are assumed to
+    // offer all of them.
+    fn policies_without_governor(&self, governor: &str) -> Result<Vec<PathBuf>> {
+        let mut missing = Vec::new();
+        for policy_path in online_cpufreq_policies(&self.root)? {
+            if let Ok(available) = read_to_string(policy_path.join("scaling_available_governors")) {
+                if !available.split_whitespace().any(|name| name == governor) {
+                    missing.push(policy_path);
+                }
+            }
+        }
+        Ok(missing)
+    }
+
+    // Returns false without changing any policy if the governor is not available on all of them.
+    fn set_scaling_governor(&self, new_governor: &str) -> Result<bool> {
         const GOVERNOR_PATTERN: &str = "sys/devices/system/cpu/cpufreq/policy*/scaling_governor";
+        let missing = self.policies_without_governor(new_governor)?;
+        if let Some(policy_path) = missing.first() {
+            warn!(
+                "Governor {} is not available on {}, keeping the current governor",
+                new_governor,
+                policy_path.display()
+            );
+            return Ok(false);
+        }
+
         let pattern = self
             .root
             .join(GOVERNOR_PATTERN)
@@ -243,11 +633,14 @@ impl<C: config::ConfigProvider, P: PowerSourceProvider> DirectoryPowerPreference
             .context("Cannot convert scaling_governor path to string")?
             .to_owned();
 
-        write_to_cpu_policy_patterns(&pattern, new_governor)
+        write_to_cpu_policy_patterns(&pattern, new_governor, "scaling governor")?;
+        Ok(true)
     }
 
     fn apply_governor_preferences(&self, governor: config::Governor) -> Result<()> {
-        self.set_scaling_governor(governor.to_name())?;
+        if !self.set_scaling_governor(governor.to_name())? {
+            return Ok(());
+        }
 
         if let config::Governor::Ondemand {
             powersave_bias,
@@ -307,470 +700,3434 @@ impl<C: config::ConfigProvider, P: PowerSourceProvider> DirectoryPowerPreference
             .to_str()
             .context("Cannot convert epp path to string")?
             .to_owned();
-        write_to_cpu_policy_patterns(&pattern, epp.to_name())
+        write_to_cpu_policy_patterns(&pattern, epp.to_name(), "energy performance preference")
     }
 
-    fn apply_power_preferences(&self, preferences: config::PowerPreferences) -> Result<()> {
-        if let Some(epp) = preferences.epp {
-            self.set_epp(epp)?
-        }
-        if let Some(governor) = preferences.governor {
-            self.apply_governor_preferences(governor)?
+    // Returns the scaling driver of the first cpufreq policy, or None if there is no cpufreq
+    // policy.
+    fn scaling_driver(&self) -> Result<Option<String>> {
+        const CPU0_SCALING_DRIVER_PATH: &str =
+            "sys/devices/system/cpu/cpufreq/policy0/scaling_driver";
+        let path = self.root.join(CPU0_SCALING_DRIVER_PATH);
+        if !path.exists() {
+            return Ok(None);
         }
 
+        let driver = read_to_string(&path)
+            .with_context(|| format!("Failed to read scaling driver from {}", path.display()))?;
+        Ok(Some(driver.trim_end().to_string()))
+    }
+
+    // intel_pstate in active mode limits the performance with the global min_perf_pct and
+    // max_perf_pct instead of per-policy frequencies.
+    fn has_intel_pstate_perf_pct(&self) -> Result<bool> {
+        Ok(self.scaling_driver()?.as_deref() == Some("intel_pstate")
+            && self
+                .root
+                .join(INTEL_PSTATE_PATH)
+                .join("max_perf_pct")
+                .exists())
+    }
+
+    fn set_intel_pstate_perf_pct(&self, attr: &str, value: u32, reason: &str) -> Result<()> {
+        let path = self.root.join(INTEL_PSTATE_PATH).join(attr);
+        sysfs_audit::write(&path, value.to_string(), reason)
+            .with_context(|| format!("Error writing {} {} to {}", attr, value, path.display()))?;
+
+        info!("Updating intel_pstate {} to {}", attr, value);
+
         Ok(())
     }
-}
 
-impl<C: config::ConfigProvider, P: PowerSourceProvider> PowerPreferencesManager
-    for DirectoryPowerPreferencesManager<C, P>
-{
-    fn update_power_preferences(
+    // Sets scaling_{min,max}_freq of every policy to the percentage of the max frequency of the
+    // performance cores. The percentage is an absolute ceiling: efficiency cores are clamped to
+    // their own max frequency instead of being throttled in proportion, so a cap costs the
+    // expensive top of the performance cores' range first.
+    fn set_per_policy_perf_pct(
         &self,
-        rtc: RTCAudioActive,
-        fullscreen: FullscreenVideo,
-        game: GameMode,
-        vmboot: VmBootMode,
-        batterysaver: BatterySaverMode,
+        cpu_perf_pct: config::CpuPerfPercent,
+        reason: &str,
     ) -> Result<()> {
-        let mut preferences: Option<config::PowerPreferences> = None;
+        let policies = online_policy_max_freqs(&self.root)?;
+        let performance_max_freq = policies
+            .iter()
+            .map(|(_, max_freq)| *max_freq)
+            .max()
+            .unwrap_or_default();
+
+        for (policy_path, max_freq) in policies {
+            let core_type = CoreType::classify(max_freq, performance_max_freq);
+            let clamp = |pct: u32| {
+                let freq = performance_max_freq * pct as u64 / 100;
+                match core_type {
+                    CoreType::Performance => freq,
+                    CoreType::Efficiency => freq.min(max_freq),
+                }
+            };
 
-        let power_source = self.power_source_provider.get_power_source()?;
+            // Write the max first so that raising the min never exceeds the old max.
+            for (attr, pct) in [
+                ("scaling_max_freq", cpu_perf_pct.max),
+                ("scaling_min_freq", cpu_perf_pct.min),
+            ] {
+                let freq = cpu_utils::snap_to_available_frequency(&policy_path, clamp(pct))?;
+                let path = policy_path.join(attr);
+                sysfs_audit::write(&path, freq.to_string(), reason).with_context(|| {
+                    format!("Error writing {} {} to {}", attr, freq, path.display())
+                })?;
+            }
+        }
 
-        info!("Power source {:?}", power_source);
+        Ok(())
+    }
+
+    fn firmware_power_limit_active(&self) -> Result<bool> {
+        let mut baselines = match self.power_limit_baselines.lock() {
+            Ok(baselines) => baselines,
+            Err(_) => bail!("Failed to lock power limit baselines"),
+        };
+        firmware_power_limit_active(&self.root, &mut baselines)
+    }
 
+    fn apply_cpu_perf_pct(
+        &self,
+        cpu_perf_pct: config::CpuPerfPercent,
+        batterysaver: BatterySaverMode,
+    ) -> Result<()> {
+        // The firmware is already capping the power, a lower max of the power preferences on top
+        // of it only wastes the remaining headroom. Battery saver keeps capping the max.
+        let firmware_limited = self.firmware_power_limit_active()?;
+        let mut max = if firmware_limited {
+            100
+        } else {
+            cpu_perf_pct.max
+        };
         if batterysaver == BatterySaverMode::Active {
-            preferences = if self.has_epp()? {
-                Some(config::PowerPreferences {
-                    governor: None,
-                    epp: Some(config::EnergyPerformancePreference::BalancePower),
-                })
-            } else {
-                Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Conservative),
-                    epp: None,
-                })
-            };
-        } else if game == GameMode::Borealis {
-            preferences = self.config_provider.read_power_preferences(
-                power_source,
-                config::PowerPreferencesType::BorealisGaming,
-            )?;
-        } else if game == GameMode::Arc {
-            preferences = self
-                .config_provider
-                .read_power_preferences(power_source, config::PowerPreferencesType::ArcvmGaming)?;
+            max = max.min(BATTERY_SAVER_CPU_PERF_PCT.max);
         }
+        let reason = if max > cpu_perf_pct.max {
+            info!("Firmware power limit active, not capping the max performance");
+            "cpu perf pct, firmware power limit active"
+        } else {
+            "cpu perf pct"
+        };
+        let cpu_perf_pct = config::CpuPerfPercent {
+            min: cpu_perf_pct.min.min(max),
+            max,
+        };
 
-        if preferences.is_none() && rtc == RTCAudioActive::Active {
-            preferences = self
-                .config_provider
-                .read_power_preferences(power_source, config::PowerPreferencesType::WebRTC)?;
+        // Entering battery saver is ramped. track_battery_saver_cpu_cap() holds the cap lock while
+        // it lifts the cap, which is never in battery saver.
+        let entering_battery_saver = batterysaver == BatterySaverMode::Active
+            && match self.battery_saver_cpu_capped.lock() {
+                Ok(capped) => !*capped,
+                Err(_) => bail!("Failed to lock battery saver CPU cap"),
+            };
+        if entering_battery_saver {
+            info!("Entering battery saver, ramping the CPU performance range down");
+            self.apply_profile_ramped(
+                &SystemClock,
+                cpu_perf_pct,
+                BATTERY_SAVER_RAMP_DURATION,
+                "cpu perf pct, entering battery saver",
+            )
+        } else {
+            self.set_cpu_perf_pct(cpu_perf_pct, reason)
         }
+    }
 
-        if preferences.is_none() && fullscreen == FullscreenVideo::Active {
-            preferences = self
-                .config_provider
-                .read_power_preferences(power_source, config::PowerPreferencesType::Fullscreen)?;
+    fn set_cpu_perf_pct(&self, cpu_perf_pct: config::CpuPerfPercent, reason: &str) -> Result<()> {
+        if self.has_intel_pstate_perf_pct()? {
+            self.set_intel_pstate_perf_pct("max_perf_pct", cpu_perf_pct.max, reason)?;
+            self.set_intel_pstate_perf_pct("min_perf_pct", cpu_perf_pct.min, reason)
+        } else {
+            self.set_per_policy_perf_pct(cpu_perf_pct, reason)
         }
+    }
 
-        if preferences.is_none() && vmboot == VmBootMode::Active {
-            preferences = self
-                .config_provider
-                .read_power_preferences(power_source, config::PowerPreferencesType::VmBoot)?;
+    // Returns the CPU performance range in place, in the percentages set_cpu_perf_pct() takes.
+    // None if there is no policy to read it from.
+    fn current_cpu_perf_pct(&self) -> Result<Option<config::CpuPerfPercent>> {
+        if self.has_intel_pstate_perf_pct()? {
+            let read = |attr: &str| -> Result<u32> {
+                let path = self.root.join(INTEL_PSTATE_PATH).join(attr);
+                Ok(common::read_file_to_u64(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?
+                    as u32)
+            };
+            return Ok(Some(config::CpuPerfPercent {
+                min: read("min_perf_pct")?,
+                max: read("max_perf_pct")?,
+            }));
         }
 
-        if preferences.is_none() {
-            preferences = self
-                .config_provider
-                .read_power_preferences(power_source, config::PowerPreferencesType::Default)?;
+        // The percentages are relative to the max frequency of the performance cores.
+        let (policy_path, max_freq) = match online_policy_max_freqs(&self.root)?
+            .into_iter()
+            .max_by_key(|(_, max_freq)| *max_freq)
+        {
+            Some(policy) => policy,
+            None => return Ok(None),
+        };
+        let read = |attr: &str| -> Result<u32> {
+            let path = policy_path.join(attr);
+            let freq = common::read_file_to_u64(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            Ok((freq * 100 / max_freq.max(1)).min(100) as u32)
+        };
+        Ok(Some(config::CpuPerfPercent {
+            min: read("scaling_min_freq")?,
+            max: read("scaling_max_freq")?,
+        }))
+    }
+
+    // Moves the CPU performance range from the one in place to `profile` in equal increments over
+    // `duration`, through the same backend as set_cpu_perf_pct(). The target is reached exactly at
+    // the end. Blocks for `duration`.
+    fn apply_profile_ramped<K: Clock>(
+        &self,
+        clock: &K,
+        profile: config::CpuPerfPercent,
+        duration: Duration,
+        reason: &str,
+    ) -> Result<()> {
+        let current = match self.current_cpu_perf_pct()? {
+            Some(current) => current,
+            None => return self.set_cpu_perf_pct(profile, reason),
+        };
+        let step_at = |current: u32, target: u32, step: u32| {
+            if target >= current {
+                current + (target - current) * step / RAMP_STEPS
+            } else {
+                current - (current - target) * step / RAMP_STEPS
+            }
+        };
+
+        for step in 1..=RAMP_STEPS {
+            clock.sleep(duration / RAMP_STEPS);
+            let cpu_perf_pct = config::CpuPerfPercent {
+                min: step_at(current.min, profile.min, step),
+                max: step_at(current.max, profile.max, step),
+            };
+            self.set_cpu_perf_pct(cpu_perf_pct, reason)?;
         }
 
-        if let Some(preferences) = preferences {
-            self.apply_power_preferences(preferences)?
+        Ok(())
+    }
+
+    // Remembers that the battery saver CPU cap is in place, or lifts it once battery saver ended
+    // and the new power preferences didn't replace it with their own range.
+    fn track_battery_saver_cpu_cap(&self, active: bool, replaced: bool) -> Result<()> {
+        let mut capped = match self.battery_saver_cpu_capped.lock() {
+            Ok(capped) => capped,
+            Err(_) => bail!("Failed to lock battery saver CPU cap"),
+        };
+
+        let was_capped = std::mem::replace(&mut *capped, active);
+        if !active && was_capped && !replaced {
+            info!("Battery saver ended, lifting the CPU performance cap");
+            self.apply_cpu_perf_pct(
+                config::CpuPerfPercent { min: 0, max: 100 },
+                BatterySaverMode::Inactive,
+            )?;
         }
 
-        if batterysaver == BatterySaverMode::Active {
-            return Ok(());
-        } else if power_source == config::PowerSourceType::DC
-            && (rtc == RTCAudioActive::Active || fullscreen == FullscreenVideo::Active)
-        {
-            if let Err(err) = self.set_epp(config::EnergyPerformancePreference::BalancePower) {
-                error!("Failed to set energy performance preference: {:#}", err);
+        Ok(())
+    }
+
+    // Raises gt_boost_freq_mhz of the Intel GPU cards to their gt_max_freq_mhz while a game runs,
+    // and restores the previous boost, never above the max, once game mode ends. A card failing
+    // doesn't keep the others from being set.
+    fn update_gpu_boost(&self, game_active: bool) -> Result<()> {
+        let pattern = self
+            .root
+            .join(INTEL_GPU_BOOST_PATTERN)
+            .to_str()
+            .context("Cannot convert GPU boost path to string")?
+            .to_owned();
+
+        let mut baselines = match self.gpu_boost_baselines.lock() {
+            Ok(baselines) => baselines,
+            Err(_) => bail!("Failed to lock GPU boost baselines"),
+        };
+
+        for entry in glob(&pattern)? {
+            let boost_path = entry?;
+            if let Err(err) = update_card_gpu_boost(&mut baselines, &boost_path, game_active) {
+                error!(
+                    "Failed to update GPU boost {}: {:#}",
+                    boost_path.display(),
+                    err
+                );
             }
-        } else {
-            self.set_epp(config::EnergyPerformancePreference::BalancePerformance)?;
-            // Default EPP
         }
 
         Ok(())
     }
-}
 
-pub fn new_directory_power_preferences_manager(
-    root: &Path,
-) -> DirectoryPowerPreferencesManager<config::DirectoryConfigProvider, DirectoryPowerSourceProvider>
-{
-    DirectoryPowerPreferencesManager {
-        root: root.to_path_buf(),
-        config_provider: config::DirectoryConfigProvider {
-            root: root.to_path_buf(),
-        },
-        power_source_provider: DirectoryPowerSourceProvider {
-            root: root.to_path_buf(),
-        },
+    // Returns the thermal clamp along with the throttling band, the thermal zone and its
+    // temperature if a thermal zone reached its hot trip point.
+    fn hot_thermal_zone(
+        &self,
+    ) -> Result<Option<(config::ThermalClamp, ThrottleBand, PathBuf, i64)>> {
+        let clamp = match self.config_provider.read_thermal_clamp()? {
+            Some(clamp) => clamp,
+            None => return Ok(None),
+        };
+
+        Ok(hottest_thermal_zone(&self.root)?.map(|(band, zone, temp)| (clamp, band, zone, temp)))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use anyhow::bail;
-    use std::fs;
-    use std::path::Path;
-    use tempfile::tempdir;
+    // Caps scaling_max_freq of every policy to the thermal clamp, as an absolute ceiling like
+    // set_per_policy_perf_pct(), on top of what the power preferences requested. Past the critical
+    // trip point the policies are capped to their min frequency instead. Charging is inhibited for
+    // as long as the clamp is in place, so that the battery doesn't add to the heat under load.
+    fn apply_thermal_clamp(
+        &self,
+        clamp: config::ThermalClamp,
+        band: ThrottleBand,
+        zone: &Path,
+        temp: i64,
+        game: GameMode,
+    ) -> Result<()> {
+        let mut clamped = match self.thermal_clamped.lock() {
+            Ok(clamped) => clamped,
+            Err(_) => bail!("Failed to lock thermal clamp"),
+        };
 
-    #[test]
-    fn test_parse_power_supply_status() -> anyhow::Result<()> {
-        assert_eq!(
-            PowerSupplyStatus::from_str("Unknown\n")?,
-            PowerSupplyStatus::Unknown
-        );
-        assert_eq!(
-            PowerSupplyStatus::from_str("Charging\n")?,
-            PowerSupplyStatus::Charging
-        );
-        assert_eq!(
-            PowerSupplyStatus::from_str("Discharging\n")?,
-            PowerSupplyStatus::Discharging
-        );
-        assert_eq!(
-            PowerSupplyStatus::from_str("Not charging\n")?,
-            PowerSupplyStatus::NotCharging
-        );
-        assert_eq!(
-            PowerSupplyStatus::from_str("Full\n")?,
-            PowerSupplyStatus::Full
-        );
+        let policies = online_policy_max_freqs(&self.root)?;
+        let performance_max_freq = policies
+            .iter()
+            .map(|(_, max_freq)| *max_freq)
+            .max()
+            .unwrap_or_default();
+
+        let mut overridden = false;
+        for (policy_path, max_freq) in policies {
+            let freq = if band == ThrottleBand::Critical {
+                let min_path = policy_path.join("cpuinfo_min_freq");
+                common::read_file_to_u64(&min_path)
+                    .with_context(|| format!("Failed to read {}", min_path.display()))?
+            } else {
+                let freq = (performance_max_freq * clamp.max_perf_pct as u64 / 100).min(max_freq);
+                cpu_utils::snap_to_available_frequency(&policy_path, freq)?
+            };
 
-        assert!(PowerSupplyStatus::from_str("").is_err());
-        assert!(PowerSupplyStatus::from_str("abc").is_err());
+            // Write the max first, the min only has to follow when it is above the cap.
+            for attr in ["scaling_max_freq", "scaling_min_freq"] {
+                let path = policy_path.join(attr);
+                let requested = common::read_file_to_u64(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                if requested <= freq {
+                    continue;
+                }
+                sysfs_audit::write(&path, freq.to_string(), "thermal clamp").with_context(
+                    || format!("Error writing {} {} to {}", attr, freq, path.display()),
+                )?;
+                overridden = true;
+            }
+        }
+
+        if overridden {
+            let cap = match band {
+                ThrottleBand::Critical => "the min frequency".to_string(),
+                _ => format!("{}%", clamp.max_perf_pct),
+            };
+            info!(
+                "{} at {} millicelsius is {:?}, clamping the CPU max frequency to {} over the \
+                 requested power preferences (game mode {:?})",
+                zone.display(),
+                temp,
+                band,
+                cap,
+                game
+            );
+        }
+        if *clamped == ThrottleBand::None {
+            self.inhibit_charging();
+        }
+        *clamped = band;
 
         Ok(())
     }
 
-    #[test]
-    fn test_power_source_provider_empty_root() -> Result<()> {
-        let root = tempdir()?;
-
-        let provider = DirectoryPowerSourceProvider {
-            root: root.path().to_path_buf(),
+    // Restores the max frequency of every policy and the charge behavior once the thermal zones
+    // cooled down. The power preferences applied afterwards put their own cap back.
+    fn lift_thermal_clamp(&self) -> Result<()> {
+        let mut clamped = match self.thermal_clamped.lock() {
+            Ok(clamped) => clamped,
+            Err(_) => bail!("Failed to lock thermal clamp"),
         };
+        if std::mem::replace(&mut *clamped, ThrottleBand::None) == ThrottleBand::None {
+            return Ok(());
+        }
 
-        let power_source = provider.get_power_source()?;
-
-        assert_eq!(power_source, config::PowerSourceType::DC);
+        info!("Thermal zones cooled down, lifting the thermal clamp");
+        self.restore_charging();
+        for (policy_path, max_freq) in online_policy_max_freqs(&self.root)? {
+            let path = policy_path.join("scaling_max_freq");
+            sysfs_audit::write(&path, max_freq.to_string(), "thermal clamp lifted")
+                .with_context(|| format!("Error writing {} to {}", max_freq, path.display()))?;
+        }
 
         Ok(())
     }
 
-    const POWER_SUPPLY_PATH: &str = "sys/class/power_supply";
-
-    #[test]
-    fn test_power_source_provider_empty_path() -> Result<()> {
-        let root = tempdir()?;
+    // Returns whether battery saver or the thermal clamp caps the CPU max frequency, which the
+    // render boost must not lift.
+    fn is_cpu_max_freq_capped(&self) -> Result<bool> {
+        let battery_saver_capped = match self.battery_saver_cpu_capped.lock() {
+            Ok(capped) => *capped,
+            Err(_) => bail!("Failed to lock battery saver CPU cap"),
+        };
+        let thermal_clamped = match self.thermal_clamped.lock() {
+            Ok(clamped) => *clamped != ThrottleBand::None,
+            Err(_) => bail!("Failed to lock thermal clamp"),
+        };
 
-        let path = root.path().join(POWER_SUPPLY_PATH);
-        fs::create_dir_all(path)?;
+        Ok(battery_saver_capped || thermal_clamped)
+    }
 
-        let provider = DirectoryPowerSourceProvider {
-            root: root.path().to_path_buf(),
+    // While a render boost is requested, raises the min frequency of every policy, and the max up
+    // to cpuinfo_max_freq unless battery saver or the thermal clamp caps it. A capped max stays in
+    // place and bounds the min. Once the boost ended, the min goes back down unless the new power
+    // preferences replaced it with their own range.
+    fn update_render_boost(&self, replaced: bool) -> Result<()> {
+        let requested = match self.render_boost_requested.lock() {
+            Ok(requested) => *requested,
+            Err(_) => bail!("Failed to lock render boost"),
+        };
+        let mut boosted = match self.render_boosted.lock() {
+            Ok(boosted) => boosted,
+            Err(_) => bail!("Failed to lock render boost"),
         };
 
-        let power_source = provider.get_power_source()?;
+        if !requested {
+            if std::mem::replace(&mut *boosted, false) && !replaced {
+                info!("Render boost ended, lowering the CPU min frequency");
+                for policy_path in online_cpufreq_policies(&self.root)? {
+                    let min_freq =
+                        common::read_file_to_u64(policy_path.join("cpuinfo_min_freq")).unwrap_or(0);
+                    sysfs_audit::write(
+                        &policy_path.join("scaling_min_freq"),
+                        min_freq.to_string(),
+                        "render boost ended",
+                    )?;
+                }
+            }
+            return Ok(());
+        }
 
-        assert_eq!(power_source, config::PowerSourceType::DC);
+        // Set before writing, so that a partially applied boost is lowered too.
+        *boosted = true;
+        let capped = self.is_cpu_max_freq_capped()?;
+        for policy_path in online_cpufreq_policies(&self.root)? {
+            let cpuinfo_max_freq = common::read_file_to_u64(policy_path.join("cpuinfo_max_freq"))?;
+            let max_freq = if capped {
+                common::read_file_to_u64(policy_path.join("scaling_max_freq"))?
+            } else {
+                cpuinfo_max_freq
+            };
+            let boost_min_freq = cpu_utils::snap_to_available_frequency(
+                &policy_path,
+                cpuinfo_max_freq * RENDER_BOOST_MIN_FREQ_PCT / 100,
+            )?
+            .min(max_freq);
+
+            if !capped {
+                sysfs_audit::write(
+                    &policy_path.join("scaling_max_freq"),
+                    max_freq.to_string(),
+                    "render boost",
+                )?;
+            }
+            sysfs_audit::write(
+                &policy_path.join("scaling_min_freq"),
+                boost_min_freq.to_string(),
+                "render boost",
+            )?;
+        }
 
         Ok(())
     }
 
-    /// Tests that the `DirectoryPowerSourceProvider` can parse the charger sysfs
-    /// `online` and `status` attributes.
-    #[test]
-    fn test_power_source_provider_disconnected_then_connected() -> Result<()> {
-        let root = tempdir()?;
-
-        let path = root.path().join(POWER_SUPPLY_PATH);
-        fs::create_dir_all(&path)?;
+    // Failing to control charging is logged rather than returned, it must not keep the CPU from
+    // being clamped.
+    fn inhibit_charging(&self) {
+        let mut baseline = match self.charge_behavior_baseline.lock() {
+            Ok(baseline) => baseline,
+            Err(_) => {
+                error!("Failed to lock charge behavior baseline");
+                return;
+            }
+        };
+        if baseline.is_some() {
+            return;
+        }
 
-        let provider = DirectoryPowerSourceProvider {
-            root: root.path().to_path_buf(),
+        let current = match get_charge_behavior(&self.root) {
+            Ok(Some(current)) => current,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Failed to get charge behavior: {:#}", e);
+                return;
+            }
         };
+        if current == ChargeBehavior::InhibitCharge {
+            return;
+        }
 
-        let charger = path.join("charger-1");
-        fs::create_dir_all(&charger)?;
-        let online = charger.join("online");
+        match set_charge_behavior(&self.root, ChargeBehavior::InhibitCharge) {
+            Ok(()) => *baseline = Some(current),
+            Err(e) => error!(
+                "Failed to inhibit charging under the thermal clamp: {:#}",
+                e
+            ),
+        }
+    }
 
-        fs::write(&online, b"0")?;
-        let power_source = provider.get_power_source()?;
-        assert_eq!(power_source, config::PowerSourceType::DC);
+    fn restore_charging(&self) {
+        let mut baseline = match self.charge_behavior_baseline.lock() {
+            Ok(baseline) => baseline,
+            Err(_) => {
+                error!("Failed to lock charge behavior baseline");
+                return;
+            }
+        };
+        let behavior = match baseline.take() {
+            Some(behavior) => behavior,
+            None => return,
+        };
 
-        let status = charger.join("status");
-        fs::write(&online, b"1")?;
-        fs::write(&status, b"Charging\n")?;
-        let power_source = provider.get_power_source()?;
-        assert_eq!(power_source, config::PowerSourceType::AC);
+        if let Err(e) = set_charge_behavior(&self.root, behavior) {
+            error!("Failed to restore charge behavior {:?}: {:#}", behavior, e);
+        }
+    }
 
-        fs::write(&online, b"1")?;
-        fs::write(&status, b"Not Charging\n")?;
-        let power_source = provider.get_power_source()?;
-        assert_eq!(power_source, config::PowerSourceType::DC);
+    fn apply_power_preferences(
+        &self,
+        preferences: config::PowerPreferences,
+        batterysaver: BatterySaverMode,
+    ) -> Result<()> {
+        if let Some(epp) = preferences.epp {
+            self.set_epp(epp)?
+        }
+        if let Some(governor) = preferences.governor {
+            self.apply_governor_preferences(governor)?
+        }
+        if let Some(cpu_perf_pct) = preferences.cpu_perf_pct {
+            self.apply_cpu_perf_pct(cpu_perf_pct, batterysaver)?
+        }
+        if let Some(uclamp) = preferences.uclamp {
+            set_uclamp(&self.root, uclamp.min, uclamp.max)?
+        }
 
         Ok(())
     }
+}
 
-    struct FakeConfigProvider {
-        default_power_preferences:
-            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
-        web_rtc_power_preferences:
-            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
-        fullscreen_power_preferences:
-            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
-        vm_boot_power_preferences:
-            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
-        borealis_gaming_power_preferences:
-            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
-        arcvm_gaming_power_preferences:
-            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
-    }
+impl<C: config::ConfigProvider, P: PowerSourceProvider> PowerPreferencesManager
+    for DirectoryPowerPreferencesManager<C, P>
+{
+    fn update_power_preferences(
+        &self,
+        rtc: RTCAudioActive,
+        fullscreen: FullscreenVideo,
+        game: GameMode,
+        vmboot: VmBootMode,
+        batterysaver: BatterySaverMode,
+    ) -> Result<()> {
+        let mut preferences: Option<config::PowerPreferences> = None;
 
-    impl Default for FakeConfigProvider {
-        fn default() -> FakeConfigProvider {
-            FakeConfigProvider {
-                // We bail on default to ensure the tests correctly setup a default power
-                // preference.
-                default_power_preferences: |_| bail!("Default not Implemented"),
-                web_rtc_power_preferences: |_| bail!("WebRTC not Implemented"),
-                fullscreen_power_preferences: |_| bail!("Fullscreen not Implemented"),
-                vm_boot_power_preferences: |_| bail!("VM boot mode not Implemented"),
-                borealis_gaming_power_preferences: |_| bail!("Borealis gaming not Implemented"),
-                arcvm_gaming_power_preferences: |_| bail!("ARCVM gaming not Implemented"),
+        let power_source = self.power_source_provider.get_power_source()?;
+
+        info!("Power source {:?}", power_source);
+
+        let hot_thermal_zone = match self.hot_thermal_zone() {
+            Ok(hot_thermal_zone) => hot_thermal_zone,
+            Err(err) => {
+                error!("Failed to read the thermal zones: {:#}", err);
+                None
+            }
+        };
+        if hot_thermal_zone.is_none() {
+            if let Err(err) = self.lift_thermal_clamp() {
+                error!("Failed to lift the thermal clamp: {:#}", err);
             }
         }
-    }
 
-    impl config::ConfigProvider for FakeConfigProvider {
-        fn read_power_preferences(
-            &self,
-            power_source_type: config::PowerSourceType,
-            power_preference_type: config::PowerPreferencesType,
-        ) -> Result<Option<config::PowerPreferences>> {
-            match power_preference_type {
-                config::PowerPreferencesType::Default => {
-                    (self.default_power_preferences)(power_source_type)
-                }
-                config::PowerPreferencesType::WebRTC => {
-                    (self.web_rtc_power_preferences)(power_source_type)
-                }
-                config::PowerPreferencesType::Fullscreen => {
-                    (self.fullscreen_power_preferences)(power_source_type)
-                }
-                config::PowerPreferencesType::VmBoot => {
-                    (self.vm_boot_power_preferences)(power_source_type)
-                }
-                config::PowerPreferencesType::BorealisGaming => {
-                    (self.borealis_gaming_power_preferences)(power_source_type)
-                }
-                config::PowerPreferencesType::ArcvmGaming => {
-                    (self.arcvm_gaming_power_preferences)(power_source_type)
-                }
-            }
+        if batterysaver == BatterySaverMode::Active {
+            preferences = if self.has_epp()? {
+                Some(config::PowerPreferences {
+                    governor: None,
+                    epp: Some(config::EnergyPerformancePreference::BalancePower),
+                    cpu_perf_pct: Some(BATTERY_SAVER_CPU_PERF_PCT),
+                    uclamp: None,
+                })
+            } else {
+                Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Conservative),
+                    epp: None,
+                    cpu_perf_pct: Some(BATTERY_SAVER_CPU_PERF_PCT),
+                    uclamp: None,
+                })
+            };
+        } else if game == GameMode::Borealis {
+            preferences = self.config_provider.read_power_preferences(
+                power_source,
+                config::PowerPreferencesType::BorealisGaming,
+            )?;
+        } else if game == GameMode::Arc {
+            preferences = self
+                .config_provider
+                .read_power_preferences(power_source, config::PowerPreferencesType::ArcvmGaming)?;
         }
-    }
 
-    struct FakePowerSourceProvider {
-        power_source: config::PowerSourceType,
-    }
+        if preferences.is_none() && rtc == RTCAudioActive::Active {
+            preferences = self
+                .config_provider
+                .read_power_preferences(power_source, config::PowerPreferencesType::WebRTC)?;
+        }
 
-    impl PowerSourceProvider for FakePowerSourceProvider {
-        fn get_power_source(&self) -> Result<config::PowerSourceType> {
-            Ok(self.power_source)
+        if preferences.is_none() && fullscreen == FullscreenVideo::Active {
+            preferences = self
+                .config_provider
+                .read_power_preferences(power_source, config::PowerPreferencesType::Fullscreen)?;
         }
-    }
 
-    fn write_global_powersave_bias(root: &Path, value: u32) -> Result<()> {
-        let ondemand_path = root.join("sys/devices/system/cpu/cpufreq/ondemand");
-        fs::create_dir_all(&ondemand_path)?;
+        if preferences.is_none() && vmboot == VmBootMode::Active {
+            preferences = self
+                .config_provider
+                .read_power_preferences(power_source, config::PowerPreferencesType::VmBoot)?;
+        }
 
-        std::fs::write(
-            ondemand_path.join("powersave_bias"),
-            value.to_string() + "\n",
+        if preferences.is_none() {
+            preferences = self
+                .config_provider
+                .read_power_preferences(power_source, config::PowerPreferencesType::Default)?;
+        }
+
+        if let Some(preferences) = preferences {
+            self.apply_power_preferences(preferences, batterysaver)?
+        }
+        self.track_battery_saver_cpu_cap(
+            batterysaver == BatterySaverMode::Active,
+            preferences.and_then(|p| p.cpu_perf_pct).is_some(),
         )?;
 
-        Ok(())
-    }
+        if let Some((clamp, band, zone, temp)) = hot_thermal_zone {
+            if let Err(err) = self.apply_thermal_clamp(clamp, band, &zone, temp, game) {
+                error!("Failed to apply the thermal clamp: {:#}", err);
+            }
+        }
 
-    fn read_global_powersave_bias(root: &Path) -> Result<String> {
-        let powersave_bias_path = root
-            .join("sys/devices/system/cpu/cpufreq/ondemand")
-            .join("powersave_bias");
+        self.update_render_boost(preferences.and_then(|p| p.cpu_perf_pct).is_some())?;
 
-        let mut powersave_bias = std::fs::read_to_string(powersave_bias_path)?;
-        if powersave_bias.ends_with('\n') {
-            powersave_bias.pop();
+        // RTC audio and battery saver lower the GPU boost themselves, leave it to them.
+        if rtc == RTCAudioActive::Inactive && batterysaver == BatterySaverMode::Inactive {
+            if let Err(err) = self.update_gpu_boost(game != GameMode::Off) {
+                error!("Failed to update the GPU boost for game mode: {:#}", err);
+            }
         }
 
-        Ok(powersave_bias)
-    }
+        // An EPP from the power preferences is already applied and wins over the default.
+        if batterysaver == BatterySaverMode::Active || preferences.and_then(|p| p.epp).is_some() {
+            return Ok(());
+        }
 
-    fn write_global_sampling_rate(root: &Path, value: u32) -> Result<()> {
-        let ondemand_path = root.join("sys/devices/system/cpu/cpufreq/ondemand");
-        fs::create_dir_all(&ondemand_path)?;
+        match default_epp(power_source, rtc, fullscreen, game) {
+            config::EnergyPerformancePreference::BalancePower => {
+                if let Err(err) = self.set_epp(config::EnergyPerformancePreference::BalancePower) {
+                    error!("Failed to set energy performance preference: {:#}", err);
+                }
+            }
+            epp => self.set_epp(epp)?,
+        }
 
-        std::fs::write(ondemand_path.join("sampling_rate"), value.to_string())?;
+        Ok(())
+    }
+
+    fn is_thermal_clamp_outdated(&self) -> Result<bool> {
+        let band = match self.hot_thermal_zone()? {
+            Some((_, band, _, _)) => band,
+            None => ThrottleBand::None,
+        };
+        let clamped = match self.thermal_clamped.lock() {
+            Ok(clamped) => *clamped,
+            Err(_) => bail!("Failed to lock thermal clamp"),
+        };
+
+        Ok(band != clamped)
+    }
+
+    fn set_render_boost(&self, active: bool) -> Result<()> {
+        match self.render_boost_requested.lock() {
+            Ok(mut requested) => *requested = active,
+            Err(_) => bail!("Failed to lock render boost"),
+        }
+        Ok(())
+    }
+}
+
+fn update_card_gpu_boost(
+    baselines: &mut HashMap<PathBuf, u64>,
+    boost_path: &Path,
+    game_active: bool,
+) -> Result<()> {
+    let max_path = boost_path.with_file_name("gt_max_freq_mhz");
+    let max_freq = common::read_file_to_u64(&max_path)
+        .with_context(|| format!("Failed to read {}", max_path.display()))?;
+    let boost_freq = common::read_file_to_u64(boost_path)?;
+    let new_boost_freq = if game_active {
+        baselines
+            .entry(boost_path.to_path_buf())
+            .or_insert(boost_freq);
+        max_freq
+    } else {
+        match baselines.remove(boost_path) {
+            Some(baseline) => baseline.min(max_freq),
+            None => return Ok(()),
+        }
+    };
+
+    if new_boost_freq != boost_freq {
+        sysfs_audit::write(
+            boost_path,
+            new_boost_freq.to_string(),
+            "gpu boost, game mode",
+        )
+        .with_context(|| {
+            format!(
+                "Error writing GPU boost {} to {}",
+                new_boost_freq,
+                boost_path.display()
+            )
+        })?;
+        info!(
+            "Updating GPU boost of {} to {}",
+            boost_path.display(),
+            new_boost_freq
+        );
+    }
+
+    Ok(())
+}
+
+// The EPP applied when the power preferences don't have one. Video and RTC on battery save
+// power. Borealis games on AC get the full performance, the GPU tuning already caps the power
+// they draw.
+fn default_epp(
+    power_source: config::PowerSourceType,
+    rtc: RTCAudioActive,
+    fullscreen: FullscreenVideo,
+    game: GameMode,
+) -> config::EnergyPerformancePreference {
+    if power_source == config::PowerSourceType::DC
+        && (rtc == RTCAudioActive::Active || fullscreen == FullscreenVideo::Active)
+    {
+        config::EnergyPerformancePreference::BalancePower
+    } else if power_source == config::PowerSourceType::AC && game == GameMode::Borealis {
+        config::EnergyPerformancePreference::Performance
+    } else {
+        config::EnergyPerformancePreference::BalancePerformance
+    }
+}
+
+/// Source of the current time, replaceable in unit tests.
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// The activities the power preferences are picked from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowerActivities {
+    pub rtc: RTCAudioActive,
+    pub fullscreen: FullscreenVideo,
+    pub game: GameMode,
+    pub vmboot: VmBootMode,
+    pub batterysaver: BatterySaverMode,
+}
+
+/// Called with the previous and the new activities when other power preferences are applied.
+pub type PowerModeObserver = Box<dyn FnMut(PowerActivities, PowerActivities) + Send>;
+
+/// Holds back RTC audio and fullscreen video changes until they persisted for a while, so that
+/// quickly toggling them doesn't thrash the CPU frequencies. Changes of the other activities, game
+/// mode first of all, are applied immediately along with any held back change.
+pub struct PowerPreferencesDebouncer<C: Clock> {
+    clock: C,
+    hold: Duration,
+    observer: Option<PowerModeObserver>,
+    // The activities of the power preferences in place. None until the first update.
+    applied: Option<PowerActivities>,
+    // The activities held back, and since when they are the current ones.
+    pending: Option<(PowerActivities, Instant)>,
+}
+
+impl<C: Clock> PowerPreferencesDebouncer<C> {
+    pub fn new(clock: C, hold: Duration) -> Self {
+        PowerPreferencesDebouncer {
+            clock,
+            hold,
+            observer: None,
+            applied: None,
+            pending: None,
+        }
+    }
+
+    /// Sets how long the changes are held back, which also applies to a change already held back.
+    pub fn set_hold(&mut self, hold: Duration) {
+        self.hold = hold;
+    }
+
+    /// Sets the observer of the transitions between activities. Re-applying the same activities,
+    /// and the very first ones, aren't transitions.
+    pub fn set_observer(&mut self, observer: PowerModeObserver) {
+        self.observer = Some(observer);
+    }
+
+    /// Applies the power preferences of `activities` right away, e.g. after the power source
+    /// changed. An RTC audio or fullscreen video change still held back stays so: the applied
+    /// ones are kept until [update](Self::update) applies the change once it held.
+    pub fn apply(
+        &mut self,
+        manager: &dyn PowerPreferencesManager,
+        activities: PowerActivities,
+    ) -> Result<()> {
+        let activities = match (self.applied, self.pending) {
+            (Some(applied), Some((pending, _))) if is_same_debounced(pending, activities) => {
+                PowerActivities {
+                    rtc: applied.rtc,
+                    fullscreen: applied.fullscreen,
+                    ..activities
+                }
+            }
+            _ => {
+                self.pending = None;
+                activities
+            }
+        };
+        self.apply_now(manager, activities)
+    }
+
+    fn apply_now(
+        &mut self,
+        manager: &dyn PowerPreferencesManager,
+        activities: PowerActivities,
+    ) -> Result<()> {
+        manager.update_power_preferences(
+            activities.rtc,
+            activities.fullscreen,
+            activities.game,
+            activities.vmboot,
+            activities.batterysaver,
+        )?;
+
+        let previous = self.applied.replace(activities);
+        if let (Some(previous), Some(observer)) = (previous, self.observer.as_mut()) {
+            if previous != activities {
+                observer(previous, activities);
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the power preferences of `activities` once they held for the hold duration.
+    /// Returns the remaining time if they are held back, after which `update` must be called
+    /// again.
+    pub fn update(
+        &mut self,
+        manager: &dyn PowerPreferencesManager,
+        activities: PowerActivities,
+    ) -> Result<Option<Duration>> {
+        let applied = match self.applied {
+            // Switching back before the hold expired doesn't change anything.
+            Some(applied) if applied == activities => {
+                self.pending = None;
+                return Ok(None);
+            }
+            Some(applied) => applied,
+            None => return self.apply_now(manager, activities).map(|_| None),
+        };
+
+        let debounced = applied.game == activities.game
+            && applied.vmboot == activities.vmboot
+            && applied.batterysaver == activities.batterysaver;
+        if debounced {
+            let now = self.clock.now();
+            let since = match self.pending {
+                Some((pending, since)) if is_same_debounced(pending, activities) => since,
+                _ => now,
+            };
+            self.pending = Some((activities, since));
+
+            let held = now - since;
+            if held < self.hold {
+                return Ok(Some(self.hold - held));
+            }
+        }
+
+        self.pending = None;
+        self.apply_now(manager, activities).map(|_| None)
+    }
+}
+
+// Whether the debounced activities, RTC audio and fullscreen video, are the same in both.
+fn is_same_debounced(a: PowerActivities, b: PowerActivities) -> bool {
+    a.rtc == b.rtc && a.fullscreen == b.fullscreen
+}
+
+/// Raises the CPU frequency limits for a number of frames, e.g. while the compositor is dragging
+/// or animating. Overlapping requests extend the boost. The boost is an input of the power
+/// preferences, so updating them for other reasons keeps it in place until it is released.
+pub struct RenderBoost<C: Clock> {
+    clock: C,
+    refresh_rate_hz: u32,
+    // The boost is released at this time. None when no boost is active.
+    deadline: Option<Instant>,
+}
+
+impl<C: Clock> RenderBoost<C> {
+    pub fn new(clock: C) -> Self {
+        RenderBoost {
+            clock,
+            refresh_rate_hz: DEFAULT_REFRESH_RATE_HZ,
+            deadline: None,
+        }
+    }
+
+    pub fn set_refresh_rate(&mut self, refresh_rate_hz: u32) -> Result<()> {
+        if refresh_rate_hz == 0 {
+            bail!("Invalid refresh rate 0");
+        }
+        self.refresh_rate_hz = refresh_rate_hz;
+        Ok(())
+    }
+
+    /// Boosts for `frames` frames at the current refresh rate. Returns the time until the boost
+    /// should be released with [update](Self::update).
+    pub fn request(
+        &mut self,
+        manager: &dyn PowerPreferencesManager,
+        frames: u32,
+    ) -> Result<Duration> {
+        if frames > RENDER_BOOST_MAX_FRAMES {
+            bail!(
+                "Invalid render boost of {} frames, the max is {}",
+                frames,
+                RENDER_BOOST_MAX_FRAMES
+            );
+        }
+
+        let now = self.clock.now();
+        let deadline = now + Duration::from_secs_f64(frames as f64 / self.refresh_rate_hz as f64);
+
+        if self.deadline.is_none() {
+            if let Err(err) = set_render_boost(manager, true) {
+                if let Err(release_err) = set_render_boost(manager, false) {
+                    error!("Failed to release the render boost: {:#}", release_err);
+                }
+                return Err(err);
+            }
+        }
+        let deadline = self.deadline.map_or(deadline, |d| d.max(deadline));
+        self.deadline = Some(deadline);
+
+        Ok(deadline - now)
+    }
+
+    /// Releases the boost once it expired. Returns the remaining time if it is still active.
+    pub fn update(&mut self, manager: &dyn PowerPreferencesManager) -> Result<Option<Duration>> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(None),
+        };
+
+        let now = self.clock.now();
+        if now < deadline {
+            return Ok(Some(deadline - now));
+        }
+
+        self.deadline = None;
+        set_render_boost(manager, false)?;
+        Ok(None)
+    }
+}
+
+// Applies or releases the render boost through the power preferences, which put their own limits,
+// battery saver and the thermal clamp back on release.
+fn set_render_boost(manager: &dyn PowerPreferencesManager, active: bool) -> Result<()> {
+    manager.set_render_boost(active)?;
+    common::update_power_preferences(manager)
+}
+
+static RENDER_BOOST: Lazy<Mutex<RenderBoost<SystemClock>>> =
+    Lazy::new(|| Mutex::new(RenderBoost::new(SystemClock)));
+
+/// Requests a render boost for `frames` frames. Returns the time after which
+/// [update_render_boost] must be called to release it.
+pub fn request_render_boost(
+    manager: &dyn PowerPreferencesManager,
+    frames: u32,
+) -> Result<Duration> {
+    match RENDER_BOOST.lock() {
+        Ok(mut boost) => boost.request(manager, frames),
+        Err(_) => bail!("Failed to lock render boost"),
+    }
+}
+
+/// Sets the display refresh rate used to compute the duration of render boosts.
+pub fn set_render_refresh_rate(refresh_rate_hz: u32) -> Result<()> {
+    match RENDER_BOOST.lock() {
+        Ok(mut boost) => boost.set_refresh_rate(refresh_rate_hz),
+        Err(_) => bail!("Failed to lock render boost"),
+    }
+}
+
+/// Releases the render boost if it expired. Returns the remaining time if it is still active.
+pub fn update_render_boost(manager: &dyn PowerPreferencesManager) -> Result<Option<Duration>> {
+    match RENDER_BOOST.lock() {
+        Ok(mut boost) => boost.update(manager),
+        Err(_) => bail!("Failed to lock render boost"),
+    }
+}
+
+/// Sets the system-wide uclamp range, in percentages of the CPU capacity. Does nothing if the
+/// kernel doesn't support uclamp.
+pub fn set_uclamp(root: &Path, min_pct: u32, max_pct: u32) -> Result<()> {
+    if min_pct > 100 || max_pct > 100 {
+        bail!("Invalid uclamp range {}%-{}%", min_pct, max_pct);
+    }
+    if min_pct > max_pct {
+        bail!(
+            "uclamp min {}% is greater than uclamp max {}%",
+            min_pct,
+            max_pct
+        );
+    }
+
+    let min_path = root.join(UCLAMP_MIN_PATH);
+    let max_path = root.join(UCLAMP_MAX_PATH);
+    if !min_path.exists() || !max_path.exists() {
+        info!("uclamp is not supported");
+        return Ok(());
+    }
+
+    let min = min_pct * UCLAMP_CAPACITY_SCALE / 100;
+    let max = max_pct * UCLAMP_CAPACITY_SCALE / 100;
+
+    // The kernel rejects a min above the max, so lower the min first if the new max is below the
+    // current min.
+    let current_min = common::read_file_to_u64(&min_path)
+        .with_context(|| format!("Failed to read {}", min_path.display()))?;
+    let writes = if (max as u64) < current_min {
+        [(&min_path, min), (&max_path, max)]
+    } else {
+        [(&max_path, max), (&min_path, min)]
+    };
+
+    for (path, value) in writes {
+        match sysfs_audit::write(path, value.to_string(), "uclamp") {
+            Ok(()) => {}
+            Err(err)
+                if err
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|err| err.kind() == std::io::ErrorKind::PermissionDenied) =>
+            {
+                start_uclamp_job(min, max)?;
+                break;
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Error writing {} to {}", value, path.display()))
+            }
+        }
+    }
+
+    info!("Updating uclamp to {}%-{}%", min_pct, max_pct);
+
+    Ok(())
+}
+
+// Writes the uclamp range through the resourced-uclamp upstart job, which runs as root. Waits
+// for the job to finish.
+fn start_uclamp_job(min: u32, max: u32) -> Result<()> {
+    let conn = dbus::blocking::Connection::new_system()?;
+    let proxy = conn.with_proxy(UPSTART_SERVICE_NAME, UCLAMP_JOB_PATH, UCLAMP_JOB_TIMEOUT);
+    let env = vec![format!("UCLAMP_MIN={}", min), format!("UCLAMP_MAX={}", max)];
+    let _: (dbus::Path,) = proxy
+        .method_call(UPSTART_JOB_INTERFACE, "Start", (env, true))
+        .context("Failed to start the resourced-uclamp job")?;
+    Ok(())
+}
+
+// Pairs of frequency in kHz and time spent at the frequency, as reported by the cpufreq stats.
+type TimeInState = Vec<(u64, u64)>;
+
+// Reads the cpufreq stats/time_in_state of the policy. Each line is "<freq> <time>", with the
+// frequency in kHz. Returns None if the kernel doesn't provide cpufreq stats.
+fn read_time_in_state(path: &Path) -> Result<Option<TimeInState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .lines()
+        .map(|line| {
+            let (freq, time) = line
+                .split_once(' ')
+                .with_context(|| format!("Malformed line in {}: '{}'", path.display(), line))?;
+            Ok((freq.parse()?, time.trim().parse()?))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+// Returns the time spent at each frequency of `current` since `baseline`. Frequencies missing from
+// the baseline count from zero.
+fn residency_delta(baseline: &[(u64, u64)], current: &[(u64, u64)]) -> Vec<(u64, Duration)> {
+    current
+        .iter()
+        .map(|&(freq, time)| {
+            let baseline_time = baseline
+                .iter()
+                .find(|(baseline_freq, _)| *baseline_freq == freq)
+                .map_or(0, |&(_, baseline_time)| baseline_time);
+            let ticks = time.saturating_sub(baseline_time);
+            (freq, Duration::from_millis(ticks * TIME_IN_STATE_UNIT_MS))
+        })
+        .collect()
+}
+
+// The number of callers whose baselines are kept. The least recent caller is forgotten first.
+const MAX_RESIDENCY_CALLERS: usize = 8;
+
+// The last time_in_state snapshot of each policy, keyed by the path of the stats file.
+type ResidencyBaselines = HashMap<PathBuf, TimeInState>;
+
+// The baselines of each caller, from the least to the most recent caller.
+static RESIDENCY_BASELINES: Lazy<Mutex<Vec<(String, ResidencyBaselines)>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Returns the time `policy` spent at each frequency (in kHz) since the previous call for the
+/// policy by `caller`, or since boot on the first call. Returns None if the kernel doesn't provide
+/// cpufreq stats.
+pub fn frequency_residency(
+    root: &Path,
+    caller: &str,
+    policy: u32,
+) -> Result<Option<Vec<(u64, Duration)>>> {
+    let path = root
+        .join("sys/devices/system/cpu/cpufreq")
+        .join(format!("policy{}", policy))
+        .join("stats/time_in_state");
+
+    let current = match read_time_in_state(&path)? {
+        Some(current) => current,
+        None => return Ok(None),
+    };
+
+    let mut callers = match RESIDENCY_BASELINES.lock() {
+        Ok(callers) => callers,
+        Err(_) => bail!("Failed to lock frequency residency baselines"),
+    };
+    let mut baselines = match callers.iter().position(|(name, _)| name == caller) {
+        Some(index) => callers.remove(index).1,
+        None => {
+            if callers.len() >= MAX_RESIDENCY_CALLERS {
+                callers.remove(0);
+            }
+            HashMap::new()
+        }
+    };
+    let delta = residency_delta(
+        baselines
+            .get(&path)
+            .map_or(&[], |baseline| baseline.as_slice()),
+        &current,
+    );
+    baselines.insert(path, current);
+    callers.push((caller.to_string(), baselines));
+
+    Ok(Some(delta))
+}
+
+// Returns whether the firmware throttled the CPUs to stay within its RAPL power limits since the
+// counters were recorded in `baselines`, i.e. whether any CPU raised a package or core power limit
+// notification, and records the current counters. Returns false if the counters are not
+// available.
+fn firmware_power_limit_active(root: &Path, baselines: &mut HashMap<PathBuf, u64>) -> Result<bool> {
+    let pattern = root
+        .join(THERMAL_THROTTLE_PATTERN)
+        .to_str()
+        .context("Cannot convert thermal throttle path to string")?
+        .to_owned();
+
+    let mut active = false;
+    for entry in glob(&pattern)? {
+        let throttle_path = entry?;
+        for counter in POWER_LIMIT_COUNTERS {
+            let path = throttle_path.join(counter);
+            if !path.exists() {
+                continue;
+            }
+
+            let count = common::read_file_to_u64(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            if let Some(baseline) = baselines.insert(path, count) {
+                active |= count > baseline;
+            }
+        }
+    }
+
+    Ok(active)
+}
+
+/// A power limit of a RAPL domain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RaplConstraint {
+    /// The name of the constraint, e.g. "long_term" or "short_term".
+    pub name: String,
+    pub power_limit_uw: u64,
+}
+
+/// A RAPL (Running Average Power Limit) domain in the powercap sysfs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RaplDomain {
+    pub path: PathBuf,
+    /// The package of the domain, N in intel-rapl:N[:M].
+    pub package: u32,
+    /// The subdomain of the package, M in intel-rapl:N:M. None for the package domain itself.
+    pub subdomain: Option<u32>,
+    /// The name of the domain, e.g. "package-0", "core", "uncore" or "dram".
+    pub name: String,
+    pub enabled: bool,
+    pub constraints: Vec<RaplConstraint>,
+    /// The energy counter of the domain in microjoules, None if the domain doesn't have one.
+    pub energy_uj: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuVendor {
+    Intel,
+    Amd,
+    Other,
+}
+
+// Returns the vendor of the CPUs from the vendor_id of the first CPU in /proc/cpuinfo.
+fn cpu_vendor(root: &Path) -> Result<CpuVendor> {
+    let path = root.join(CPUINFO_PATH);
+    if !path.exists() {
+        return Ok(CpuVendor::Other);
+    }
+
+    let cpuinfo =
+        read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let vendor_id = cpuinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("vendor_id"))
+        .and_then(|value| value.split(':').nth(1))
+        .map(str::trim);
+
+    Ok(match vendor_id {
+        Some("GenuineIntel") => CpuVendor::Intel,
+        Some("AuthenticAMD") => CpuVendor::Amd,
+        _ => CpuVendor::Other,
+    })
+}
+
+// Parses the package and subdomain from a RAPL domain directory name, e.g. "intel-rapl:0:2".
+fn parse_rapl_domain_id(dir_name: &str) -> Option<(u32, Option<u32>)> {
+    let mut ids = dir_name.strip_prefix("intel-rapl:")?.split(':');
+    let package = ids.next()?.parse().ok()?;
+    let subdomain = match ids.next() {
+        Some(id) => Some(id.parse().ok()?),
+        None => None,
+    };
+
+    if ids.next().is_some() {
+        return None;
+    }
+
+    Some((package, subdomain))
+}
+
+fn read_rapl_domain(path: PathBuf, package: u32, subdomain: Option<u32>) -> Result<RaplDomain> {
+    let read = |name: &str| -> Result<String> {
+        let attr_path = path.join(name);
+        Ok(read_to_string(&attr_path)
+            .with_context(|| format!("Failed to read {}", attr_path.display()))?
+            .trim_end()
+            .to_string())
+    };
+
+    let name = read("name")?;
+    let enabled = !path.join("enabled").exists() || read("enabled")? == "1";
+
+    let mut constraints = Vec::new();
+    for n in 0.. {
+        let limit_path = path.join(format!("constraint_{}_power_limit_uw", n));
+        if !limit_path.exists() {
+            break;
+        }
+
+        constraints.push(RaplConstraint {
+            name: read(&format!("constraint_{}_name", n))?,
+            power_limit_uw: common::read_file_to_u64(&limit_path)
+                .with_context(|| format!("Failed to read {}", limit_path.display()))?,
+        });
+    }
+
+    let energy_path = path.join("energy_uj");
+    let energy_uj = if energy_path.exists() {
+        Some(
+            common::read_file_to_u64(&energy_path)
+                .with_context(|| format!("Failed to read {}", energy_path.display()))?,
+        )
+    } else {
+        None
+    };
+
+    Ok(RaplDomain {
+        path,
+        package,
+        subdomain,
+        name,
+        enabled,
+        constraints,
+        energy_uj,
+    })
+}
+
+// AMD CPUs without RAPL support in the powercap sysfs report the energy of each socket through
+// the amd_energy hwmon driver, as energyN_input with the label "Esocket<socket>". The cores are
+// reported too but are not RAPL domains. amd_energy doesn't support power limits.
+fn read_amd_energy_domains(root: &Path) -> Result<Vec<RaplDomain>> {
+    let pattern = root
+        .join(HWMON_PATH)
+        .join("hwmon*")
+        .to_str()
+        .context("Cannot convert hwmon path to string")?
+        .to_owned();
+
+    let mut domains = Vec::new();
+    for entry in glob(&pattern)? {
+        let hwmon_path = entry?;
+        match read_to_string(hwmon_path.join("name")) {
+            Ok(name) if name.trim_end() == "amd_energy" => {}
+            _ => continue,
+        }
+
+        let label_pattern = hwmon_path
+            .join("energy*_label")
+            .to_str()
+            .context("Cannot convert hwmon path to string")?
+            .to_owned();
+        for entry in glob(&label_pattern)? {
+            let label_path = entry?;
+            let label = read_to_string(&label_path)
+                .with_context(|| format!("Failed to read {}", label_path.display()))?;
+            let package = match label.trim_end().strip_prefix("Esocket") {
+                Some(socket) => socket
+                    .parse()
+                    .with_context(|| format!("Invalid socket in {}", label_path.display()))?,
+                None => continue,
+            };
+
+            let input_path = PathBuf::from(
+                label_path
+                    .to_str()
+                    .context("Cannot convert hwmon path to string")?
+                    .replace("_label", "_input"),
+            );
+            domains.push(RaplDomain {
+                path: hwmon_path.clone(),
+                package,
+                subdomain: None,
+                name: format!("package-{}", package),
+                enabled: true,
+                constraints: Vec::new(),
+                energy_uj: Some(
+                    common::read_file_to_u64(&input_path)
+                        .with_context(|| format!("Failed to read {}", input_path.display()))?,
+                ),
+            });
+        }
+    }
+
+    Ok(domains)
+}
+
+/// Returns all the RAPL domains with their current power limits and energy counters, ordered by
+/// package and subdomain. Subdomains are found both at the top level of the powercap sysfs and
+/// nested in their package directory. On AMD CPUs without RAPL in the powercap sysfs, the
+/// sockets reported by the amd_energy hwmon driver are returned instead.
+pub fn enumerate_rapl_domains(root: &Path) -> Result<Vec<RaplDomain>> {
+    let powercap_path = root.join(POWERCAP_PATH);
+    let mut domains: Vec<RaplDomain> = Vec::new();
+
+    for pattern in ["intel-rapl:*", "intel-rapl:*/intel-rapl:*"] {
+        let pattern = powercap_path
+            .join(pattern)
+            .to_str()
+            .context("Cannot convert powercap path to string")?
+            .to_owned();
+
+        for entry in glob(&pattern)? {
+            let path = entry?;
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(parse_rapl_domain_id);
+            let (package, subdomain) = match id {
+                Some(id) => id,
+                None => continue,
+            };
+
+            // The same domain may be both at the top level and nested in its package.
+            if domains
+                .iter()
+                .any(|domain| (domain.package, domain.subdomain) == (package, subdomain))
+            {
+                continue;
+            }
+
+            domains.push(read_rapl_domain(path, package, subdomain)?);
+        }
+    }
+
+    if domains.is_empty() && cpu_vendor(root)? == CpuVendor::Amd {
+        domains = read_amd_energy_domains(root)?;
+    }
+
+    domains.sort_by_key(|domain| (domain.package, domain.subdomain));
+    Ok(domains)
+}
+
+// Returns the IRQs from /proc/interrupts that fired at least HIGH_RATE_IRQ_PER_SEC on average
+// since boot. Architecture specific interrupts like "NMI" or "LOC" are not numbered and skipped.
+fn high_rate_irqs(root: &Path) -> Result<Vec<u32>> {
+    let uptime_path = root.join(PROC_UPTIME_PATH);
+    let uptime: f64 = read_to_string(&uptime_path)
+        .with_context(|| format!("Failed to read {}", uptime_path.display()))?
+        .split_whitespace()
+        .next()
+        .and_then(|uptime| uptime.parse().ok())
+        .with_context(|| format!("Failed to parse {}", uptime_path.display()))?;
+
+    let interrupts_path = root.join(PROC_INTERRUPTS_PATH);
+    let interrupts = read_to_string(&interrupts_path)
+        .with_context(|| format!("Failed to read {}", interrupts_path.display()))?;
+    let mut lines = interrupts.lines();
+    // The header has a column per CPU.
+    let num_cpus = lines.next().unwrap_or_default().split_whitespace().count();
+
+    let mut irqs = Vec::new();
+    for line in lines {
+        let mut fields = line.split_whitespace();
+        let irq = match fields.next().and_then(|irq| irq.strip_suffix(':')) {
+            Some(irq) => match irq.parse() {
+                Ok(irq) => irq,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        let count: u64 = fields
+            .take(num_cpus)
+            .map_while(|count| count.parse::<u64>().ok())
+            .sum();
+
+        if count as f64 >= HIGH_RATE_IRQ_PER_SEC * uptime {
+            irqs.push(irq);
+        }
+    }
+
+    Ok(irqs)
+}
+
+// Formats a CPU list as an smp_affinity mask: hex words of 32 CPUs separated by commas, the
+// highest CPUs first.
+fn cpus_to_affinity_mask(cpus: &[u32]) -> String {
+    let num_words = cpus.iter().max().map_or(1, |max| *max as usize / 32 + 1);
+    let mut words = vec![0u32; num_words];
+    for cpu in cpus {
+        words[*cpu as usize / 32] |= 1 << (cpu % 32);
+    }
+
+    words
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, word)| {
+            if i == 0 {
+                format!("{:x}", word)
+            } else {
+                format!("{:08x}", word)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Parses a CPU list like "0,1" or "0-3".
+fn parse_cpu_list(cpus: &str) -> Result<Vec<u32>> {
+    let mut list = Vec::new();
+    for part in cpus.trim().split(',').filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => list.extend(start.trim().parse::<u32>()?..=end.trim().parse()?),
+            None => list.push(part.trim().parse()?),
+        }
+    }
+
+    Ok(list)
+}
+
+// The original smp_affinity of the IRQs steered by set_irq_affinity_for_gaming(), keyed by the
+// path of the smp_affinity file.
+static IRQ_AFFINITY_BACKUP: Lazy<Mutex<HashMap<PathBuf, String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Steers the high-rate IRQs to the little cores while `enable` is true, so that they don't
+/// interrupt the game on the big cores, and restores their original affinity when it is false.
+/// IRQs whose affinity can't be changed, e.g. managed IRQs, are left alone. Does nothing on
+/// systems without little cores.
+pub fn set_irq_affinity_for_gaming(root: &Path, enable: bool) -> Result<()> {
+    let mut backup = match IRQ_AFFINITY_BACKUP.lock() {
+        Ok(backup) => backup,
+        Err(_) => bail!("Failed to lock IRQ affinity backup"),
+    };
+
+    if !enable {
+        let steered: Vec<PathBuf> = backup
+            .keys()
+            .filter(|path| path.starts_with(root))
+            .cloned()
+            .collect();
+        for path in steered {
+            let mask = backup.remove(&path).unwrap_or_default();
+            if let Err(e) = sysfs_audit::write(&path, &mask, "game mode irq affinity") {
+                error!("Failed to restore {} to {}: {}", path.display(), mask, e);
+            }
+        }
+        return Ok(());
+    }
+
+    if !cpu_utils::is_big_little_supported(root).unwrap_or(false) {
+        info!("No little cores to steer IRQs to");
+        return Ok(());
+    }
+    let mask = cpus_to_affinity_mask(&parse_cpu_list(&cpu_utils::get_little_cores(root)?)?);
+
+    for irq in high_rate_irqs(root)? {
+        let path = root
+            .join(PROC_IRQ_PATH)
+            .join(irq.to_string())
+            .join("smp_affinity");
+        // Keep the mask from before game mode if it was already enabled.
+        if !backup.contains_key(&path) {
+            match read_to_string(&path) {
+                Ok(original) => {
+                    backup.insert(path.clone(), original.trim_end().to_string());
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to read {}, skipping IRQ {}: {}",
+                        path.display(),
+                        irq,
+                        e
+                    );
+                    continue;
+                }
+            }
+        }
+
+        if let Err(e) = sysfs_audit::write(&path, &mask, "game mode irq affinity") {
+            // The kernel refuses to change the affinity of managed IRQs.
+            warn!(
+                "Failed to write {} to {}, skipping IRQ {}: {}",
+                mask,
+                path.display(),
+                irq,
+                e
+            );
+            backup.remove(&path);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn new_directory_power_preferences_manager(
+    root: &Path,
+) -> DirectoryPowerPreferencesManager<config::DirectoryConfigProvider, DirectoryPowerSourceProvider>
+{
+    DirectoryPowerPreferencesManager::new(
+        root.to_path_buf(),
+        config::DirectoryConfigProvider {
+            root: root.to_path_buf(),
+        },
+        DirectoryPowerSourceProvider {
+            root: root.to_path_buf(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tests::{
+        get_cpu0_freq_max, get_intel_gpu_boost, get_intel_gpu_card_boost, read_mock_epp,
+        remove_mock_cpu_policy, set_intel_gpu_boost, set_intel_gpu_card_boost, set_intel_gpu_max,
+        setup_mock_arm_cpufreq_files, setup_mock_cpu_dev_dirs, setup_mock_cpu_files,
+        setup_mock_intel_gpu_card, setup_mock_intel_gpu_dev_dirs, setup_mock_intel_gpu_files,
+        test_write_ui_use_flags, write_mock_amd_cpuinfo, write_mock_cpu_governors,
+        write_mock_cpu_online, write_mock_cpuinfo, write_mock_epp, write_mock_hybrid_cpus,
+        write_mock_intel_cpuinfo, write_mock_thermal_zone, DEVICE_CPUFREQ_PATH, DRM_CLASS_PATH,
+        MOCK_NUM_CPU,
+    };
+    use anyhow::bail;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_power_supply_status() -> anyhow::Result<()> {
+        assert_eq!(
+            PowerSupplyStatus::from_str("Unknown\n")?,
+            PowerSupplyStatus::Unknown
+        );
+        assert_eq!(
+            PowerSupplyStatus::from_str("Charging\n")?,
+            PowerSupplyStatus::Charging
+        );
+        assert_eq!(
+            PowerSupplyStatus::from_str("Discharging\n")?,
+            PowerSupplyStatus::Discharging
+        );
+        assert_eq!(
+            PowerSupplyStatus::from_str("Not charging\n")?,
+            PowerSupplyStatus::NotCharging
+        );
+        assert_eq!(
+            PowerSupplyStatus::from_str("Full\n")?,
+            PowerSupplyStatus::Full
+        );
+
+        assert!(PowerSupplyStatus::from_str("").is_err());
+        assert!(PowerSupplyStatus::from_str("abc").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_source_provider_empty_root() -> Result<()> {
+        let root = tempdir()?;
+
+        let provider = DirectoryPowerSourceProvider {
+            root: root.path().to_path_buf(),
+        };
+
+        let power_source = provider.get_power_source()?;
+
+        assert_eq!(power_source, config::PowerSourceType::DC);
+
+        Ok(())
+    }
+
+    const POWER_SUPPLY_PATH: &str = "sys/class/power_supply";
+
+    #[test]
+    fn test_power_source_provider_empty_path() -> Result<()> {
+        let root = tempdir()?;
+
+        let path = root.path().join(POWER_SUPPLY_PATH);
+        fs::create_dir_all(path)?;
+
+        let provider = DirectoryPowerSourceProvider {
+            root: root.path().to_path_buf(),
+        };
+
+        let power_source = provider.get_power_source()?;
+
+        assert_eq!(power_source, config::PowerSourceType::DC);
+
+        Ok(())
+    }
+
+    /// Tests that the `DirectoryPowerSourceProvider` can parse the charger sysfs
+    /// `online` and `status` attributes.
+    #[test]
+    fn test_power_source_provider_disconnected_then_connected() -> Result<()> {
+        let root = tempdir()?;
+
+        let path = root.path().join(POWER_SUPPLY_PATH);
+        fs::create_dir_all(&path)?;
+
+        let provider = DirectoryPowerSourceProvider {
+            root: root.path().to_path_buf(),
+        };
+
+        let charger = path.join("charger-1");
+        fs::create_dir_all(&charger)?;
+        let online = charger.join("online");
+
+        fs::write(&online, b"0")?;
+        let power_source = provider.get_power_source()?;
+        assert_eq!(power_source, config::PowerSourceType::DC);
+
+        let status = charger.join("status");
+        fs::write(&online, b"1")?;
+        fs::write(&status, b"Charging\n")?;
+        let power_source = provider.get_power_source()?;
+        assert_eq!(power_source, config::PowerSourceType::AC);
+
+        fs::write(&online, b"1")?;
+        fs::write(&status, b"Not Charging\n")?;
+        let power_source = provider.get_power_source()?;
+        assert_eq!(power_source, config::PowerSourceType::DC);
+
+        Ok(())
+    }
+
+    struct FakeConfigProvider {
+        default_power_preferences:
+            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
+        web_rtc_power_preferences:
+            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
+        fullscreen_power_preferences:
+            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
+        vm_boot_power_preferences:
+            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
+        borealis_gaming_power_preferences:
+            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
+        arcvm_gaming_power_preferences:
+            fn(config::PowerSourceType) -> Result<Option<config::PowerPreferences>>,
+        thermal_clamp: fn() -> Result<Option<config::ThermalClamp>>,
+    }
+
+    impl Default for FakeConfigProvider {
+        fn default() -> FakeConfigProvider {
+            FakeConfigProvider {
+                // We bail on default to ensure the tests correctly setup a default power
+                // preference.
+                default_power_preferences: |_| bail!("Default not Implemented"),
+                web_rtc_power_preferences: |_| bail!("WebRTC not Implemented"),
+                fullscreen_power_preferences: |_| bail!("Fullscreen not Implemented"),
+                vm_boot_power_preferences: |_| bail!("VM boot mode not Implemented"),
+                borealis_gaming_power_preferences: |_| bail!("Borealis gaming not Implemented"),
+                arcvm_gaming_power_preferences: |_| bail!("ARCVM gaming not Implemented"),
+                thermal_clamp: || Ok(None),
+            }
+        }
+    }
+
+    impl config::ConfigProvider for FakeConfigProvider {
+        fn read_power_preferences(
+            &self,
+            power_source_type: config::PowerSourceType,
+            power_preference_type: config::PowerPreferencesType,
+        ) -> Result<Option<config::PowerPreferences>> {
+            match power_preference_type {
+                config::PowerPreferencesType::Default => {
+                    (self.default_power_preferences)(power_source_type)
+                }
+                config::PowerPreferencesType::WebRTC => {
+                    (self.web_rtc_power_preferences)(power_source_type)
+                }
+                config::PowerPreferencesType::Fullscreen => {
+                    (self.fullscreen_power_preferences)(power_source_type)
+                }
+                config::PowerPreferencesType::VmBoot => {
+                    (self.vm_boot_power_preferences)(power_source_type)
+                }
+                config::PowerPreferencesType::BorealisGaming => {
+                    (self.borealis_gaming_power_preferences)(power_source_type)
+                }
+                config::PowerPreferencesType::ArcvmGaming => {
+                    (self.arcvm_gaming_power_preferences)(power_source_type)
+                }
+            }
+        }
+
+        fn read_thermal_clamp(&self) -> Result<Option<config::ThermalClamp>> {
+            (self.thermal_clamp)()
+        }
+
+        fn read_power_preferences_hold(&self) -> Result<Option<Duration>> {
+            Ok(None)
+        }
+    }
+
+    struct FakePowerSourceProvider {
+        power_source: config::PowerSourceType,
+    }
+
+    impl PowerSourceProvider for FakePowerSourceProvider {
+        fn get_power_source(&self) -> Result<config::PowerSourceType> {
+            Ok(self.power_source)
+        }
+    }
+
+    fn write_global_powersave_bias(root: &Path, value: u32) -> Result<()> {
+        let ondemand_path = root.join("sys/devices/system/cpu/cpufreq/ondemand");
+        fs::create_dir_all(&ondemand_path)?;
+
+        std::fs::write(
+            ondemand_path.join("powersave_bias"),
+            value.to_string() + "\n",
+        )?;
+
+        Ok(())
+    }
+
+    fn read_global_powersave_bias(root: &Path) -> Result<String> {
+        let powersave_bias_path = root
+            .join("sys/devices/system/cpu/cpufreq/ondemand")
+            .join("powersave_bias");
+
+        let mut powersave_bias = std::fs::read_to_string(powersave_bias_path)?;
+        if powersave_bias.ends_with('\n') {
+            powersave_bias.pop();
+        }
+
+        Ok(powersave_bias)
+    }
+
+    fn write_global_sampling_rate(root: &Path, value: u32) -> Result<()> {
+        let ondemand_path = root.join("sys/devices/system/cpu/cpufreq/ondemand");
+        fs::create_dir_all(&ondemand_path)?;
+
+        std::fs::write(ondemand_path.join("sampling_rate"), value.to_string())?;
+
+        Ok(())
+    }
+
+    fn read_global_sampling_rate(root: &Path) -> Result<String> {
+        let sampling_rate_path = root
+            .join("sys/devices/system/cpu/cpufreq/ondemand")
+            .join("sampling_rate");
+
+        let mut sampling_rate = std::fs::read_to_string(sampling_rate_path)?;
+        if sampling_rate.ends_with('\n') {
+            sampling_rate.pop();
+        }
+
+        Ok(sampling_rate)
+    }
+
+    // In the following per policy access functions, there are 2 cpufreq policies: policy0 and
+    // policy1.
+
+    const TEST_CPUFREQ_POLICIES: &[&str] = &[
+        "sys/devices/system/cpu/cpufreq/policy0",
+        "sys/devices/system/cpu/cpufreq/policy1",
+    ];
+    const SCALING_GOVERNOR_FILENAME: &str = "scaling_governor";
+    const ONDEMAND_DIRECTORY: &str = "ondemand";
+    const POWERSAVE_BIAS_FILENAME: &str = "powersave_bias";
+    const SAMPLING_RATE_FILENAME: &str = "sampling_rate";
+
+    // Instead of returning an error, crash/assert immediately in a test utility function makes it
+    // easier to debug an unittest.
+    fn write_per_policy_scaling_governor(root: &Path, governor: config::Governor) {
+        for policy in TEST_CPUFREQ_POLICIES {
+            let policy_path = root.join(policy);
+            fs::create_dir_all(&policy_path).unwrap();
+            std::fs::write(
+                policy_path.join(SCALING_GOVERNOR_FILENAME),
+                governor.to_name().to_string() + "\n",
+            )
+            .unwrap();
+        }
+    }
+
+    fn check_per_policy_scaling_governor(root: &Path, expected: config::Governor) {
+        for policy in TEST_CPUFREQ_POLICIES {
+            let governor_path = root.join(policy).join(SCALING_GOVERNOR_FILENAME);
+            let scaling_governor = std::fs::read_to_string(governor_path).unwrap();
+            assert_eq!(scaling_governor.trim_end_matches('\n'), expected.to_name());
+        }
+    }
+
+    fn write_per_policy_powersave_bias(root: &Path, value: u32) {
+        for policy in TEST_CPUFREQ_POLICIES {
+            let ondemand_path = root.join(policy).join(ONDEMAND_DIRECTORY);
+            println!("ondemand_path: {}", ondemand_path.display());
+            fs::create_dir_all(&ondemand_path).unwrap();
+            std::fs::write(
+                ondemand_path.join(POWERSAVE_BIAS_FILENAME),
+                value.to_string() + "\n",
+            )
+            .unwrap();
+        }
+    }
+
+    fn check_per_policy_powersave_bias(root: &Path, expected: u32) {
+        for policy in TEST_CPUFREQ_POLICIES {
+            let powersave_bias_path = root
+                .join(policy)
+                .join(ONDEMAND_DIRECTORY)
+                .join(POWERSAVE_BIAS_FILENAME);
+            let powersave_bias = std::fs::read_to_string(powersave_bias_path).unwrap();
+            assert_eq!(powersave_bias.trim_end_matches('\n'), expected.to_string());
+        }
+    }
+
+    fn write_per_policy_sampling_rate(root: &Path, value: u32) {
+        for policy in TEST_CPUFREQ_POLICIES {
+            let ondemand_path = root.join(policy).join(ONDEMAND_DIRECTORY);
+            fs::create_dir_all(&ondemand_path).unwrap();
+            std::fs::write(
+                ondemand_path.join(SAMPLING_RATE_FILENAME),
+                value.to_string(),
+            )
+            .unwrap();
+        }
+    }
+
+    fn check_per_policy_sampling_rate(root: &Path, expected: u32) {
+        for policy in TEST_CPUFREQ_POLICIES {
+            let sampling_rate_path = root
+                .join(policy)
+                .join(ONDEMAND_DIRECTORY)
+                .join(SAMPLING_RATE_FILENAME);
+            let sampling_rate = std::fs::read_to_string(sampling_rate_path).unwrap();
+            assert_eq!(sampling_rate, expected.to_string());
+        }
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_wrong_governor() -> Result<()> {
+        let root = tempdir()?;
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: 200,
+                        sampling_rate: None,
+                    }),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        // We shouldn't have written anything.
+        let powersave_bias = read_global_powersave_bias(root.path());
+        assert!(powersave_bias.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_none() -> Result<()> {
+        let root = tempdir()?;
+
+        write_global_powersave_bias(root.path(), 0)?;
+        write_global_sampling_rate(root.path(), 2000)?;
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| Ok(None),
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        let powersave_bias = read_global_powersave_bias(root.path())?;
+        assert_eq!(powersave_bias, "0");
+
+        let sampling_rate = read_global_sampling_rate(root.path())?;
+        assert_eq!(sampling_rate, "2000");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_default_ac() -> Result<()> {
+        let root = tempdir()?;
+
+        write_global_powersave_bias(root.path(), 0)?;
+        write_global_sampling_rate(root.path(), 2000)?;
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |power_source| {
+                assert_eq!(power_source, config::PowerSourceType::AC);
+
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: 200,
+                        sampling_rate: Some(16000),
+                    }),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        let powersave_bias = read_global_powersave_bias(root.path())?;
+        assert_eq!(powersave_bias, "200");
+
+        let sampling_rate = read_global_sampling_rate(root.path())?;
+        assert_eq!(sampling_rate, "16000");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_default_dc() -> Result<()> {
+        let root = tempdir()?;
+
+        write_global_powersave_bias(root.path(), 0)?;
+        write_global_sampling_rate(root.path(), 2000)?;
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::DC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |power_source| {
+                assert_eq!(power_source, config::PowerSourceType::DC);
+
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: 200,
+                        sampling_rate: None,
+                    }),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        let powersave_bias = read_global_powersave_bias(root.path())?;
+        assert_eq!(powersave_bias, "200");
+
+        let sampling_rate = read_global_sampling_rate(root.path())?;
+        assert_eq!(sampling_rate, "2000");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_default_rtc_active() -> Result<()> {
+        let root = tempdir()?;
+
+        write_global_powersave_bias(root.path(), 0)?;
+        write_global_sampling_rate(root.path(), 2000)?;
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: 200,
+                        sampling_rate: Some(4000),
+                    }),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            web_rtc_power_preferences: |_| Ok(None),
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Active,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        let powersave_bias = read_global_powersave_bias(root.path())?;
+        assert_eq!(powersave_bias, "200");
+
+        let sampling_rate = read_global_sampling_rate(root.path())?;
+        assert_eq!(sampling_rate, "4000");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_rtc_active() -> Result<()> {
+        let root = tempdir()?;
+
+        write_global_powersave_bias(root.path(), 0)?;
+        write_global_sampling_rate(root.path(), 2000)?;
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            web_rtc_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: 200,
+                        sampling_rate: Some(16000),
+                    }),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Active,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        let powersave_bias = read_global_powersave_bias(root.path())?;
+        assert_eq!(powersave_bias, "200");
+
+        let sampling_rate = read_global_sampling_rate(root.path())?;
+        assert_eq!(sampling_rate, "16000");
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests default battery saver mode
+    fn test_power_update_power_preferences_battery_saver_active() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            arcvm_gaming_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Schedutil),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        let tests = [
+            (
+                BatterySaverMode::Active,
+                "balance_power",
+                config::Governor::Conservative,
+            ),
+            (
+                BatterySaverMode::Inactive,
+                "balance_performance",
+                config::Governor::Schedutil,
+            ),
+        ];
+
+        // Test device without EPP path
+        write_per_policy_scaling_governor(root, config::Governor::Performance);
+        for test in tests {
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                common::GameMode::Arc,
+                common::VmBootMode::Inactive,
+                test.0,
+            )?;
+
+            check_per_policy_scaling_governor(root, test.2);
+        }
+
+        // Test device with EPP path
+        write_mock_epp(root, "balance_performance")?;
+        for test in tests {
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                common::GameMode::Arc,
+                common::VmBootMode::Inactive,
+                test.0,
+            )?;
+
+            let epp = read_mock_epp(root)?;
+            assert_eq!(epp, test.1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests the various EPP permutations
+    fn test_power_update_power_preferences_epp() -> Result<()> {
+        let root = tempdir()?;
+
+        write_mock_epp(root.path(), "balance_performance")?;
+
+        let tests = [
+            (
+                FakePowerSourceProvider {
+                    power_source: config::PowerSourceType::DC,
+                },
+                RTCAudioActive::Active,
+                FullscreenVideo::Inactive,
+                "balance_power",
+            ),
+            (
+                FakePowerSourceProvider {
+                    power_source: config::PowerSourceType::DC,
+                },
+                RTCAudioActive::Inactive,
+                FullscreenVideo::Active,
+                "balance_power",
+            ),
+            (
+                FakePowerSourceProvider {
+                    power_source: config::PowerSourceType::DC,
+                },
+                RTCAudioActive::Active,
+                FullscreenVideo::Active,
+                "balance_power",
+            ),
+            (
+                FakePowerSourceProvider {
+                    power_source: config::PowerSourceType::DC,
+                },
+                RTCAudioActive::Inactive,
+                FullscreenVideo::Inactive,
+                "balance_performance",
+            ),
+            (
+                FakePowerSourceProvider {
+                    power_source: config::PowerSourceType::AC,
+                },
+                RTCAudioActive::Inactive,
+                FullscreenVideo::Active,
+                "balance_performance",
+            ),
+            (
+                FakePowerSourceProvider {
+                    power_source: config::PowerSourceType::AC,
+                },
+                RTCAudioActive::Active,
+                FullscreenVideo::Active,
+                "balance_performance",
+            ),
+            (
+                FakePowerSourceProvider {
+                    power_source: config::PowerSourceType::AC,
+                },
+                RTCAudioActive::Active,
+                FullscreenVideo::Inactive,
+                "balance_performance",
+            ),
+        ];
+
+        for test in tests {
+            // Let's assume we have no config
+            let config_provider = FakeConfigProvider {
+                default_power_preferences: |_| Ok(None),
+                web_rtc_power_preferences: |_| Ok(None),
+                fullscreen_power_preferences: |_| Ok(None),
+                ..Default::default()
+            };
+
+            let manager = DirectoryPowerPreferencesManager::new(
+                root.path().to_path_buf(),
+                config_provider,
+                test.0,
+            );
+
+            manager.update_power_preferences(
+                test.1,
+                test.2,
+                common::GameMode::Off,
+                common::VmBootMode::Inactive,
+                common::BatterySaverMode::Inactive,
+            )?;
+
+            let epp = read_mock_epp(root.path())?;
+
+            assert_eq!(epp, test.3);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_epp_game_mode() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        setup_mock_cpu_dev_dirs(root)?;
+        write_mock_epp(root, "balance_performance")?;
+
+        use config::PowerSourceType::{AC, DC};
+        let tests = [
+            (
+                AC,
+                GameMode::Borealis,
+                RTCAudioActive::Inactive,
+                "performance",
+            ),
+            (
+                AC,
+                GameMode::Borealis,
+                RTCAudioActive::Active,
+                "performance",
+            ),
+            (
+                DC,
+                GameMode::Borealis,
+                RTCAudioActive::Inactive,
+                "balance_performance",
+            ),
+            (
+                DC,
+                GameMode::Borealis,
+                RTCAudioActive::Active,
+                "balance_power",
+            ),
+            (
+                AC,
+                GameMode::Arc,
+                RTCAudioActive::Inactive,
+                "balance_performance",
+            ),
+            (
+                AC,
+                GameMode::Off,
+                RTCAudioActive::Inactive,
+                "balance_performance",
+            ),
+        ];
+
+        for (power_source, game, rtc, expected) in tests {
+            let config_provider = FakeConfigProvider {
+                default_power_preferences: |_| Ok(None),
+                web_rtc_power_preferences: |_| Ok(None),
+                borealis_gaming_power_preferences: |_| Ok(None),
+                arcvm_gaming_power_preferences: |_| Ok(None),
+                ..Default::default()
+            };
+            let manager = DirectoryPowerPreferencesManager::new(
+                root.to_path_buf(),
+                config_provider,
+                FakePowerSourceProvider { power_source },
+            );
+
+            manager.update_power_preferences(
+                rtc,
+                common::FullscreenVideo::Inactive,
+                game,
+                common::VmBootMode::Inactive,
+                common::BatterySaverMode::Inactive,
+            )?;
+
+            // Every policy gets the same EPP.
+            for i in 0..MOCK_NUM_CPU {
+                let epp_path = root
+                    .join(DEVICE_CPUFREQ_PATH)
+                    .join(format!("policy{i}"))
+                    .join("energy_performance_preference");
+                assert_eq!(
+                    fs::read_to_string(epp_path)?,
+                    expected,
+                    "{:?} {:?} {:?}",
+                    power_source,
+                    game,
+                    rtc
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_epp_from_config() -> Result<()> {
+        let root = tempdir()?;
+
+        write_mock_epp(root.path(), "balance_performance")?;
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: None,
+                    epp: Some(config::EnergyPerformancePreference::Power),
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        // Not replaced by the default balance_performance.
+        assert_eq!(read_mock_epp(root.path())?, "power");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_fullscreen_active() -> Result<()> {
+        let root = tempdir()?;
+
+        write_global_powersave_bias(root.path(), 0)?;
+        write_global_sampling_rate(root.path(), 2000)?;
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            fullscreen_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: 200,
+                        sampling_rate: Some(16000),
+                    }),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Active,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        let powersave_bias = read_global_powersave_bias(root.path())?;
+        assert_eq!(powersave_bias, "200");
+
+        let sampling_rate = read_global_sampling_rate(root.path())?;
+        assert_eq!(sampling_rate, "16000");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_borealis_gaming_active() -> Result<()> {
+        let root = tempdir()?;
+
+        write_global_powersave_bias(root.path(), 0)?;
+        write_global_sampling_rate(root.path(), 2000)?;
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            borealis_gaming_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: 200,
+                        sampling_rate: Some(16000),
+                    }),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Borealis,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        let powersave_bias = read_global_powersave_bias(root.path())?;
+        assert_eq!(powersave_bias, "200");
+
+        let sampling_rate = read_global_sampling_rate(root.path())?;
+        assert_eq!(sampling_rate, "16000");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_arcvm_gaming_active() -> Result<()> {
+        let root = tempdir()?;
+
+        write_global_powersave_bias(root.path(), 0)?;
+        write_global_sampling_rate(root.path(), 2000)?;
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            arcvm_gaming_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: 200,
+                        sampling_rate: Some(16000),
+                    }),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.path().to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Arc,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        let powersave_bias = read_global_powersave_bias(root.path())?;
+        assert_eq!(powersave_bias, "200");
+
+        let sampling_rate = read_global_sampling_rate(root.path())?;
+        assert_eq!(sampling_rate, "16000");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_policy_ondemand_governor() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        const INIT_POWERSAVE_BIAS: u32 = 0;
+        const INIT_SAMPLING_RATE: u32 = 2000;
+        const CONFIG_POWERSAVE_BIAS: u32 = 200;
+        const CONFIG_SAMPLING_RATE: u32 = 16000;
+
+        let ondemand = config::Governor::Ondemand {
+            powersave_bias: INIT_POWERSAVE_BIAS,
+            sampling_rate: Some(INIT_SAMPLING_RATE),
+        };
+        write_per_policy_scaling_governor(root, ondemand);
+        write_per_policy_powersave_bias(root, INIT_POWERSAVE_BIAS);
+        write_per_policy_sampling_rate(root, INIT_SAMPLING_RATE);
+
+        let power_source_provider = FakePowerSourceProvider {
+            power_source: config::PowerSourceType::AC,
+        };
+
+        let config_provider = FakeConfigProvider {
+            arcvm_gaming_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: CONFIG_POWERSAVE_BIAS,
+                        sampling_rate: Some(CONFIG_SAMPLING_RATE),
+                    }),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            config_provider,
+            power_source_provider,
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Arc,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+        check_per_policy_scaling_governor(root, ondemand);
+        check_per_policy_powersave_bias(root, CONFIG_POWERSAVE_BIAS);
+        check_per_policy_sampling_rate(root, CONFIG_SAMPLING_RATE);
+        Ok(())
+    }
+
+    struct ArcvmGamingConfigProvider {
+        arcvm_gaming_power_preferences: config::PowerPreferences,
+    }
+
+    impl config::ConfigProvider for ArcvmGamingConfigProvider {
+        fn read_power_preferences(
+            &self,
+            _power_source_type: config::PowerSourceType,
+            power_preference_type: config::PowerPreferencesType,
+        ) -> Result<Option<config::PowerPreferences>> {
+            match power_preference_type {
+                config::PowerPreferencesType::ArcvmGaming => {
+                    Ok(Some(self.arcvm_gaming_power_preferences))
+                }
+                _ => bail!("Unexpected power preference type"),
+            }
+        }
+
+        fn read_thermal_clamp(&self) -> Result<Option<config::ThermalClamp>> {
+            Ok(None)
+        }
+
+        fn read_power_preferences_hold(&self) -> Result<Option<Duration>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_scaling_governors() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        const INIT_POWERSAVE_BIAS: u32 = 0;
+        const INIT_SAMPLING_RATE: u32 = 2000;
+
+        let ondemand = config::Governor::Ondemand {
+            powersave_bias: INIT_POWERSAVE_BIAS,
+            sampling_rate: Some(INIT_SAMPLING_RATE),
+        };
+        write_per_policy_scaling_governor(root, ondemand);
+
+        let governors = [
+            config::Governor::Conservative,
+            config::Governor::Performance,
+            config::Governor::Powersave,
+            config::Governor::Schedutil,
+            config::Governor::Userspace,
+        ];
+
+        for governor in governors {
+            let power_source_provider = FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            };
+            // The governor is a variable that we cannot use FakeConfigProvider.
+            // Got the following error when using FakeConfigProvider:
+            // closures can only be coerced to `fn` types if they do not capture any variables
+            let config_provider = ArcvmGamingConfigProvider {
+                arcvm_gaming_power_preferences: config::PowerPreferences {
+                    governor: Some(governor),
+                    epp: None,
+                    cpu_perf_pct: None,
+                    uclamp: None,
+                },
+            };
+            let manager = DirectoryPowerPreferencesManager::new(
+                root.to_path_buf(),
+                config_provider,
+                power_source_provider,
+            );
+
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                common::GameMode::Arc,
+                common::VmBootMode::Inactive,
+                common::BatterySaverMode::Inactive,
+            )?;
+
+            check_per_policy_scaling_governor(root, governor);
+        }
+
+        Ok(())
+    }
+
+    fn write_per_policy_cpufreq(root: &Path, driver: &str, max_freq: u64) {
+        for policy in TEST_CPUFREQ_POLICIES {
+            let policy_path = root.join(policy);
+            fs::create_dir_all(&policy_path).unwrap();
+            std::fs::write(
+                policy_path.join("scaling_driver"),
+                driver.to_string() + "\n",
+            )
+            .unwrap();
+            std::fs::write(policy_path.join("cpuinfo_max_freq"), max_freq.to_string()).unwrap();
+            std::fs::write(policy_path.join("scaling_max_freq"), max_freq.to_string()).unwrap();
+            std::fs::write(policy_path.join("scaling_min_freq"), "0").unwrap();
+        }
+    }
+
+    fn check_per_policy_scaling_freq(root: &Path, expected_min: u64, expected_max: u64) {
+        for policy in TEST_CPUFREQ_POLICIES {
+            let policy_path = root.join(policy);
+            let min_freq = std::fs::read_to_string(policy_path.join("scaling_min_freq")).unwrap();
+            let max_freq = std::fs::read_to_string(policy_path.join("scaling_max_freq")).unwrap();
+            assert_eq!(min_freq, expected_min.to_string());
+            assert_eq!(max_freq, expected_max.to_string());
+        }
+    }
+
+    fn new_cpu_perf_pct_manager(
+        root: &Path,
+    ) -> DirectoryPowerPreferencesManager<FakeConfigProvider, FakePowerSourceProvider> {
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: None,
+                    epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 20, max: 80 }),
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+
+        DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            config_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        )
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_intel_pstate_perf_pct() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        write_per_policy_cpufreq(root, "intel_pstate", 4000000);
+        let intel_pstate_path = root.join(INTEL_PSTATE_PATH);
+        fs::create_dir_all(&intel_pstate_path)?;
+        fs::write(intel_pstate_path.join("min_perf_pct"), "10")?;
+        fs::write(intel_pstate_path.join("max_perf_pct"), "100")?;
+
+        let manager = new_cpu_perf_pct_manager(root);
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        assert_eq!(
+            fs::read_to_string(intel_pstate_path.join("min_perf_pct"))?,
+            "20"
+        );
+        assert_eq!(
+            fs::read_to_string(intel_pstate_path.join("max_perf_pct"))?,
+            "80"
+        );
+        // The per-policy frequencies are left alone.
+        check_per_policy_scaling_freq(root, 0, 4000000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_per_policy_perf_pct() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        write_per_policy_cpufreq(root, "acpi-cpufreq", 4000000);
+
+        let manager = new_cpu_perf_pct_manager(root);
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        check_per_policy_scaling_freq(root, 800000, 3200000);
+        assert!(!root.join(INTEL_PSTATE_PATH).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_hotplugged_policy() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        write_per_policy_cpufreq(root, "acpi-cpufreq", 4000000);
+        // policy1 is offline when the profile is applied.
+        fs::remove_dir_all(root.join(TEST_CPUFREQ_POLICIES[1]))?;
+
+        let manager = new_cpu_perf_pct_manager(root);
+        let update = || {
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                common::GameMode::Off,
+                common::VmBootMode::Inactive,
+                common::BatterySaverMode::Inactive,
+            )
+        };
+        update()?;
+
+        // policy1 comes online with the default limits and the active profile is re-applied.
+        write_per_policy_cpufreq(root, "acpi-cpufreq", 4000000);
+        update()?;
+
+        check_per_policy_scaling_freq(root, 800000, 3200000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_arm_cpufreq() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        setup_mock_arm_cpufreq_files(root)?;
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    // Not built on the mock board.
+                    governor: Some(config::Governor::Ondemand {
+                        powersave_bias: 200,
+                        sampling_rate: None,
+                    }),
+                    epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 20, max: 80 }),
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            config_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        let little = root.join(DEVICE_CPUFREQ_PATH).join("policy0");
+        let big = root.join(DEVICE_CPUFREQ_PATH).join("policy6");
+        for policy in [&little, &big] {
+            assert_eq!(
+                fs::read_to_string(policy.join("scaling_governor"))?,
+                "schedutil\n"
+            );
+            assert!(!policy.join("ondemand").exists());
+        }
+        // 20% and 80% of the 2200000 of the big cores, which policy6 gets from its available
+        // frequencies, snapped to the available frequencies.
+        assert_eq!(fs::read_to_string(big.join("scaling_min_freq"))?, "500000");
+        assert_eq!(fs::read_to_string(big.join("scaling_max_freq"))?, "1700000");
+        assert_eq!(
+            fs::read_to_string(little.join("scaling_min_freq"))?,
+            "500000"
+        );
+        assert_eq!(
+            fs::read_to_string(little.join("scaling_max_freq"))?,
+            "2000000"
+        );
+
+        // A policy without any max frequency is left alone.
+        fs::remove_file(big.join("scaling_available_frequencies"))?;
+        fs::write(big.join("scaling_max_freq"), "2800000")?;
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+        assert_eq!(fs::read_to_string(big.join("scaling_max_freq"))?, "2800000");
 
         Ok(())
     }
 
-    fn read_global_sampling_rate(root: &Path) -> Result<String> {
-        let sampling_rate_path = root
-            .join("sys/devices/system/cpu/cpufreq/ondemand")
-            .join("sampling_rate");
+    #[test]
+    fn test_scaling_governor_available() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
 
-        let mut sampling_rate = std::fs::read_to_string(sampling_rate_path)?;
-        if sampling_rate.ends_with('\n') {
-            sampling_rate.pop();
+        setup_mock_arm_cpufreq_files(root)?;
+
+        let config_provider = ArcvmGamingConfigProvider {
+            arcvm_gaming_power_preferences: config::PowerPreferences {
+                governor: Some(config::Governor::Performance),
+                epp: None,
+                cpu_perf_pct: None,
+                uclamp: None,
+            },
+        };
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            config_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
+
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Arc,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+
+        for policy in ["policy0", "policy6"] {
+            let governor_path = root
+                .join(DEVICE_CPUFREQ_PATH)
+                .join(policy)
+                .join("scaling_governor");
+            assert_eq!(fs::read_to_string(governor_path)?, "performance");
         }
 
-        Ok(sampling_rate)
+        Ok(())
     }
 
-    // In the following per policy access functions, there are 2 cpufreq policies: policy0 and
-    // policy1.
+    #[test]
+    fn test_power_update_power_preferences_governor_game_mode() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
 
-    const TEST_CPUFREQ_POLICIES: &[&str] = &[
-        "sys/devices/system/cpu/cpufreq/policy0",
-        "sys/devices/system/cpu/cpufreq/policy1",
-    ];
-    const SCALING_GOVERNOR_FILENAME: &str = "scaling_governor";
-    const ONDEMAND_DIRECTORY: &str = "ondemand";
-    const POWERSAVE_BIAS_FILENAME: &str = "powersave_bias";
-    const SAMPLING_RATE_FILENAME: &str = "sampling_rate";
+        setup_mock_cpu_dev_dirs(root)?;
+        setup_mock_cpu_files(root)?;
+        write_mock_cpu_governors(root, "schedutil", "performance schedutil\n")?;
 
-    // Instead of returning an error, crash/assert immediately in a test utility function makes it
-    // easier to debug an unittest.
-    fn write_per_policy_scaling_governor(root: &Path, governor: config::Governor) {
-        for policy in TEST_CPUFREQ_POLICIES {
-            let policy_path = root.join(policy);
-            fs::create_dir_all(&policy_path).unwrap();
-            std::fs::write(
-                policy_path.join(SCALING_GOVERNOR_FILENAME),
-                governor.to_name().to_string() + "\n",
-            )
-            .unwrap();
+        fn governor_preferences(
+            governor: config::Governor,
+        ) -> Result<Option<config::PowerPreferences>> {
+            Ok(Some(config::PowerPreferences {
+                governor: Some(governor),
+                epp: None,
+                cpu_perf_pct: None,
+                uclamp: None,
+            }))
+        }
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| governor_preferences(config::Governor::Schedutil),
+            borealis_gaming_power_preferences: |_| {
+                governor_preferences(config::Governor::Performance)
+            },
+            // Not available on the mock CPU.
+            arcvm_gaming_power_preferences: |_| {
+                governor_preferences(config::Governor::Conservative)
+            },
+            ..Default::default()
+        };
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            config_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
+
+        // The unavailable governor keeps the one of the previous game mode.
+        let tests = [
+            (GameMode::Borealis, "performance"),
+            (GameMode::Off, "schedutil"),
+            (GameMode::Borealis, "performance"),
+            (GameMode::Arc, "performance"),
+            (GameMode::Off, "schedutil"),
+            (GameMode::Arc, "schedutil"),
+        ];
+        for (game, expected) in tests {
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                game,
+                common::VmBootMode::Inactive,
+                common::BatterySaverMode::Inactive,
+            )?;
+            for i in 0..MOCK_NUM_CPU {
+                let governor_path = root
+                    .join(DEVICE_CPUFREQ_PATH)
+                    .join(format!("policy{i}"))
+                    .join("scaling_governor");
+                assert_eq!(
+                    fs::read_to_string(governor_path)?,
+                    expected,
+                    "{:?} policy{i}",
+                    game
+                );
+            }
         }
+
+        Ok(())
     }
 
-    fn check_per_policy_scaling_governor(root: &Path, expected: config::Governor) {
-        for policy in TEST_CPUFREQ_POLICIES {
-            let governor_path = root.join(policy).join(SCALING_GOVERNOR_FILENAME);
-            let scaling_governor = std::fs::read_to_string(governor_path).unwrap();
-            assert_eq!(scaling_governor.trim_end_matches('\n'), expected.to_name());
+    #[test]
+    fn test_power_update_power_preferences_game_mode_variants() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        setup_mock_cpu_dev_dirs(root)?;
+        setup_mock_cpu_files(root)?;
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: None,
+                    epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 0, max: 100 }),
+                    uclamp: None,
+                }))
+            },
+            borealis_gaming_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: None,
+                    epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 10, max: 90 }),
+                    uclamp: None,
+                }))
+            },
+            arcvm_gaming_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: None,
+                    epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 10, max: 70 }),
+                    uclamp: None,
+                }))
+            },
+            ..Default::default()
+        };
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            config_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
+
+        // Each game mode source gets its own preferences, game mode off the default ones.
+        let tests = [
+            (GameMode::Borealis, 3690000),
+            (GameMode::Arc, 2870000),
+            (GameMode::Off, 4100000),
+            (GameMode::Borealis, 3690000),
+        ];
+        for (game, expected_max) in tests {
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                game,
+                common::VmBootMode::Inactive,
+                common::BatterySaverMode::Inactive,
+            )?;
+            assert_eq!(get_cpu0_freq_max(root), expected_max, "{:?}", game);
         }
+
+        Ok(())
     }
 
-    fn write_per_policy_powersave_bias(root: &Path, value: u32) {
-        for policy in TEST_CPUFREQ_POLICIES {
-            let ondemand_path = root.join(policy).join(ONDEMAND_DIRECTORY);
-            println!("ondemand_path: {}", ondemand_path.display());
-            fs::create_dir_all(&ondemand_path).unwrap();
-            std::fs::write(
-                ondemand_path.join(POWERSAVE_BIAS_FILENAME),
-                value.to_string() + "\n",
-            )
-            .unwrap();
+    #[test]
+    fn test_power_update_power_preferences_thermal_clamp() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        setup_mock_cpu_dev_dirs(root)?;
+        setup_mock_cpu_files(root)?;
+        write_mock_thermal_zone(root, 0, "45000")?;
+        // A sensor that fails to read doesn't keep the others from being used.
+        write_mock_thermal_zone(root, 1, "")?;
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: None,
+                    epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 0, max: 100 }),
+                    uclamp: None,
+                }))
+            },
+            borealis_gaming_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: None,
+                    epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 10, max: 90 }),
+                    uclamp: None,
+                }))
+            },
+            thermal_clamp: || Ok(Some(config::ThermalClamp { max_perf_pct: 50 })),
+            ..Default::default()
+        };
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            config_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
+
+        // The clamp wins over what game mode requests while a zone is past its hot trip point,
+        // goes down to the min frequency past the critical one, and is lifted once it cooled down.
+        let tests = [
+            ("45000", GameMode::Borealis, 3690000),
+            ("95000", GameMode::Borealis, 2050000),
+            ("95000", GameMode::Off, 2050000),
+            ("89000", GameMode::Borealis, 3690000),
+            ("110000", GameMode::Borealis, 400000),
+            ("95000", GameMode::Borealis, 2050000),
+            ("60000", GameMode::Off, 4100000),
+        ];
+        for (temp, game, expected_max) in tests {
+            write_mock_thermal_zone(root, 2, temp)?;
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                game,
+                common::VmBootMode::Inactive,
+                common::BatterySaverMode::Inactive,
+            )?;
+            assert_eq!(get_cpu0_freq_max(root), expected_max, "{} {:?}", temp, game);
+            assert!(!manager.is_thermal_clamp_outdated()?);
         }
+
+        // A zone getting hot or cooling down between the updates is noticed.
+        write_mock_thermal_zone(root, 2, "95000")?;
+        assert!(manager.is_thermal_clamp_outdated()?);
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+        assert!(!manager.is_thermal_clamp_outdated()?);
+        write_mock_thermal_zone(root, 2, "60000")?;
+        assert!(manager.is_thermal_clamp_outdated()?);
+
+        Ok(())
     }
 
-    fn check_per_policy_powersave_bias(root: &Path, expected: u32) {
-        for policy in TEST_CPUFREQ_POLICIES {
-            let powersave_bias_path = root
-                .join(policy)
-                .join(ONDEMAND_DIRECTORY)
-                .join(POWERSAVE_BIAS_FILENAME);
-            let powersave_bias = std::fs::read_to_string(powersave_bias_path).unwrap();
-            assert_eq!(powersave_bias.trim_end_matches('\n'), expected.to_string());
+    #[test]
+    fn test_power_thermal_clamp_inhibits_charging() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        setup_mock_cpu_dev_dirs(root)?;
+        setup_mock_cpu_files(root)?;
+        let battery_path = root.join(POWER_SUPPLY_PATH).join("BAT0");
+        fs::create_dir_all(&battery_path)?;
+        let charge_behavior_path = battery_path.join(POWER_SUPPLY_CHARGE_BEHAVIOUR);
+        fs::write(&charge_behavior_path, "[auto] inhibit-charge\n")?;
+
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: None,
+                    epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 0, max: 100 }),
+                    uclamp: None,
+                }))
+            },
+            thermal_clamp: || Ok(Some(config::ThermalClamp { max_perf_pct: 50 })),
+            ..Default::default()
+        };
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            config_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
+
+        // Charging stays inhibited while the zone is hot, and the previous behavior comes back
+        // once it cooled down.
+        let tests = [
+            ("45000", "[auto] inhibit-charge\n"),
+            ("95000", "inhibit-charge"),
+            ("110000", "auto [inhibit-charge]\n"),
+            ("60000", "auto"),
+        ];
+        for (temp, expected_behavior) in tests {
+            write_mock_thermal_zone(root, 0, temp)?;
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                GameMode::Off,
+                common::VmBootMode::Inactive,
+                common::BatterySaverMode::Inactive,
+            )?;
+            // Mirror the kernel, which marks the selected behavior.
+            let behavior = fs::read_to_string(&charge_behavior_path)?;
+            assert_eq!(behavior, expected_behavior, "{}", temp);
+            let marked = match behavior.trim() {
+                "inhibit-charge" => "auto [inhibit-charge]\n",
+                "auto" => "[auto] inhibit-charge\n",
+                _ => continue,
+            };
+            fs::write(&charge_behavior_path, marked)?;
         }
+
+        // Failing to inhibit charging doesn't keep the CPU from being clamped.
+        fs::write(&charge_behavior_path, "[auto]\n")?;
+        write_mock_thermal_zone(root, 0, "95000")?;
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+        assert_eq!(get_cpu0_freq_max(root), 2050000);
+        assert_eq!(fs::read_to_string(&charge_behavior_path)?, "[auto]\n");
+
+        Ok(())
     }
 
-    fn write_per_policy_sampling_rate(root: &Path, value: u32) {
-        for policy in TEST_CPUFREQ_POLICIES {
-            let ondemand_path = root.join(policy).join(ONDEMAND_DIRECTORY);
-            fs::create_dir_all(&ondemand_path).unwrap();
-            std::fs::write(
-                ondemand_path.join(SAMPLING_RATE_FILENAME),
-                value.to_string(),
+    #[test]
+    fn test_power_update_power_preferences_gpu_boost() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        setup_mock_intel_gpu_dev_dirs(root);
+        setup_mock_intel_gpu_files(root);
+        set_intel_gpu_boost(root, 600);
+        // A card without a max doesn't keep the other cards from being boosted.
+        setup_mock_intel_gpu_card(root, "card1");
+        set_intel_gpu_card_boost(root, "card1", 800);
+        setup_mock_intel_gpu_card(root, "card2");
+        fs::remove_file(root.join(DRM_CLASS_PATH).join("card2/gt_max_freq_mhz"))?;
+
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
+            FakeConfigProvider {
+                default_power_preferences: |_| Ok(None),
+                borealis_gaming_power_preferences: |_| Ok(None),
+                arcvm_gaming_power_preferences: |_| Ok(None),
+                web_rtc_power_preferences: |_| Ok(None),
+                ..Default::default()
+            },
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
+        let update = |game, rtc| {
+            manager.update_power_preferences(
+                rtc,
+                common::FullscreenVideo::Inactive,
+                game,
+                common::VmBootMode::Inactive,
+                common::BatterySaverMode::Inactive,
             )
-            .unwrap();
-        }
-    }
+        };
 
-    fn check_per_policy_sampling_rate(root: &Path, expected: u32) {
-        for policy in TEST_CPUFREQ_POLICIES {
-            let sampling_rate_path = root
-                .join(policy)
-                .join(ONDEMAND_DIRECTORY)
-                .join(SAMPLING_RATE_FILENAME);
-            let sampling_rate = std::fs::read_to_string(sampling_rate_path).unwrap();
-            assert_eq!(sampling_rate, expected.to_string());
+        // A game raises the boost to the max of 1000 and game mode off restores it.
+        let tests = [
+            (GameMode::Off, 600),
+            (GameMode::Borealis, 1000),
+            (GameMode::Arc, 1000),
+            (GameMode::Off, 600),
+            (GameMode::Arc, 1000),
+            (GameMode::Off, 600),
+        ];
+        for (game, expected_boost) in tests {
+            update(game, RTCAudioActive::Inactive)?;
+            assert_eq!(get_intel_gpu_boost(root), expected_boost, "{:?}", game);
+            let expected_card1_boost = if game == GameMode::Off { 800 } else { 1000 };
+            assert_eq!(
+                get_intel_gpu_card_boost(root, "card1"),
+                expected_card1_boost,
+                "{:?}",
+                game
+            );
         }
-    }
 
-    fn write_epp(root: &Path, value: &str) -> Result<()> {
-        let policy_path = root.join("sys/devices/system/cpu/cpufreq/policy0");
-        fs::create_dir_all(&policy_path)?;
+        // The restored boost is clamped to a max lowered during the game.
+        update(GameMode::Borealis, RTCAudioActive::Inactive)?;
+        set_intel_gpu_max(root, 500);
+        update(GameMode::Off, RTCAudioActive::Inactive)?;
+        assert_eq!(get_intel_gpu_boost(root), 500);
 
-        std::fs::write(policy_path.join("energy_performance_preference"), value)?;
+        // RTC audio keeps its own boost.
+        set_intel_gpu_max(root, 1000);
+        set_intel_gpu_boost(root, 200);
+        update(GameMode::Borealis, RTCAudioActive::Active)?;
+        assert_eq!(get_intel_gpu_boost(root), 200);
 
         Ok(())
     }
 
-    fn read_epp(root: &Path) -> Result<String> {
-        let epp_path = root
-            .join("sys/devices/system/cpu/cpufreq/policy0/")
-            .join("energy_performance_preference");
-
-        let epp = std::fs::read_to_string(epp_path)?;
-
-        Ok(epp)
-    }
-
     #[test]
-    fn test_power_update_power_preferences_wrong_governor() -> Result<()> {
-        let root = tempdir()?;
+    fn test_power_update_power_preferences_offline_cpu() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
-        };
+        setup_mock_cpu_dev_dirs(root)?;
+        setup_mock_cpu_files(root)?;
+        // Policies 2 and 3 are gone and the CPUs of policy5 are offline.
+        remove_mock_cpu_policy(root, 2)?;
+        remove_mock_cpu_policy(root, 3)?;
+        write_mock_cpu_online(root, 5, false)?;
+        write_mock_cpu_online(root, 6, true)?;
 
         let config_provider = FakeConfigProvider {
             default_power_preferences: |_| {
                 Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Ondemand {
-                        powersave_bias: 200,
-                        sampling_rate: None,
-                    }),
+                    governor: None,
                     epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 0, max: 60 }),
+                    uclamp: None,
                 }))
             },
             ..Default::default()
         };
-
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.path().to_path_buf(),
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
             config_provider,
-            power_source_provider,
-        };
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
 
         manager.update_power_preferences(
             common::RTCAudioActive::Inactive,
@@ -780,34 +4137,69 @@ mod tests {
             common::BatterySaverMode::Inactive,
         )?;
 
-        // We shouldn't have written anything.
-        let powersave_bias = read_global_powersave_bias(root.path());
-        assert!(powersave_bias.is_err());
+        for i in 0..MOCK_NUM_CPU {
+            let max_path = root
+                .join(DEVICE_CPUFREQ_PATH)
+                .join(format!("policy{i}"))
+                .join("scaling_max_freq");
+            match i {
+                2 | 3 => assert!(!max_path.exists()),
+                // The offline policy keeps its limits.
+                5 => assert_eq!(fs::read_to_string(max_path)?, "4100000"),
+                _ => assert_eq!(fs::read_to_string(max_path)?, "2460000", "policy{i}"),
+            }
+        }
+
+        // Once the CPU is back online its policy gets the limits too.
+        write_mock_cpu_online(root, 5, true)?;
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Inactive,
+        )?;
+        let max_path = root
+            .join(DEVICE_CPUFREQ_PATH)
+            .join("policy5/scaling_max_freq");
+        assert_eq!(fs::read_to_string(max_path)?, "2460000");
 
         Ok(())
     }
 
     #[test]
-    fn test_power_update_power_preferences_none() -> Result<()> {
-        let root = tempdir()?;
+    fn test_core_type_classify() {
+        assert_eq!(CoreType::classify(4100000, 4100000), CoreType::Performance);
+        assert_eq!(CoreType::classify(2000000, 4100000), CoreType::Efficiency);
+    }
 
-        write_global_powersave_bias(root.path(), 0)?;
-        write_global_sampling_rate(root.path(), 2000)?;
+    #[test]
+    fn test_power_update_power_preferences_hybrid_cpu() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
-        };
+        setup_mock_cpu_dev_dirs(root)?;
+        setup_mock_cpu_files(root)?;
+        write_mock_hybrid_cpus(root, 4, 4100000, 2000000)?;
 
         let config_provider = FakeConfigProvider {
-            default_power_preferences: |_| Ok(None),
+            default_power_preferences: |_| {
+                Ok(Some(config::PowerPreferences {
+                    governor: None,
+                    epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 10, max: 60 }),
+                    uclamp: None,
+                }))
+            },
             ..Default::default()
         };
-
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.path().to_path_buf(),
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
             config_provider,
-            power_source_provider,
-        };
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
 
         manager.update_power_preferences(
             common::RTCAudioActive::Inactive,
@@ -817,641 +4209,1123 @@ mod tests {
             common::BatterySaverMode::Inactive,
         )?;
 
-        let powersave_bias = read_global_powersave_bias(root.path())?;
-        assert_eq!(powersave_bias, "0");
-
-        let sampling_rate = read_global_sampling_rate(root.path())?;
-        assert_eq!(sampling_rate, "2000");
+        // 60% of the P-core max is above the E-core max, only the P-cores are capped. The min is
+        // 10% of the P-core max on both.
+        for i in 0..MOCK_NUM_CPU {
+            let policy_path = root.join(DEVICE_CPUFREQ_PATH).join(format!("policy{i}"));
+            let expected_max = if i < 4 { "2460000" } else { "2000000" };
+            assert_eq!(
+                fs::read_to_string(policy_path.join("scaling_max_freq"))?,
+                expected_max,
+                "policy{i}"
+            );
+            assert_eq!(
+                fs::read_to_string(policy_path.join("scaling_min_freq"))?,
+                "410000",
+                "policy{i}"
+            );
+        }
 
         Ok(())
     }
 
     #[test]
-    fn test_power_update_power_preferences_default_ac() -> Result<()> {
-        let root = tempdir()?;
-
-        write_global_powersave_bias(root.path(), 0)?;
-        write_global_sampling_rate(root.path(), 2000)?;
+    fn test_power_update_power_preferences_battery_saver_precedence() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
-        };
+        setup_mock_cpu_dev_dirs(root)?;
+        setup_mock_cpu_files(root)?;
 
         let config_provider = FakeConfigProvider {
-            default_power_preferences: |power_source| {
-                assert_eq!(power_source, config::PowerSourceType::AC);
-
+            default_power_preferences: |_| Ok(None),
+            borealis_gaming_power_preferences: |_| {
                 Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Ondemand {
-                        powersave_bias: 200,
-                        sampling_rate: Some(16000),
-                    }),
+                    governor: None,
                     epp: None,
+                    cpu_perf_pct: Some(config::CpuPerfPercent { min: 10, max: 90 }),
+                    uclamp: None,
                 }))
             },
+            arcvm_gaming_power_preferences: |_| Ok(None),
             ..Default::default()
         };
-
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.path().to_path_buf(),
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
             config_provider,
-            power_source_provider,
-        };
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
 
-        manager.update_power_preferences(
-            common::RTCAudioActive::Inactive,
-            common::FullscreenVideo::Inactive,
-            common::GameMode::Off,
-            common::VmBootMode::Inactive,
-            common::BatterySaverMode::Inactive,
-        )?;
+        // The mock max frequency is 4100000. Battery saver caps it to 60% in every game mode,
+        // each case starting from the state the previous one left.
+        let tests = [
+            (GameMode::Off, BatterySaverMode::Inactive, 4100000),
+            (GameMode::Off, BatterySaverMode::Active, 2460000),
+            // Lifted again by battery saver, the default preferences have no range.
+            (GameMode::Off, BatterySaverMode::Inactive, 4100000),
+            (GameMode::Borealis, BatterySaverMode::Inactive, 3690000),
+            (GameMode::Borealis, BatterySaverMode::Active, 2460000),
+            // Replaced by the gaming range.
+            (GameMode::Borealis, BatterySaverMode::Inactive, 3690000),
+            (GameMode::Arc, BatterySaverMode::Active, 2460000),
+            (GameMode::Arc, BatterySaverMode::Inactive, 4100000),
+        ];
 
-        let powersave_bias = read_global_powersave_bias(root.path())?;
-        assert_eq!(powersave_bias, "200");
+        for (game, batterysaver, expected_max) in tests {
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                game,
+                common::VmBootMode::Inactive,
+                batterysaver,
+            )?;
 
-        let sampling_rate = read_global_sampling_rate(root.path())?;
-        assert_eq!(sampling_rate, "16000");
+            assert_eq!(
+                get_cpu0_freq_max(root),
+                expected_max,
+                "{:?} {:?}",
+                game,
+                batterysaver
+            );
+        }
 
         Ok(())
     }
 
-    #[test]
-    fn test_power_update_power_preferences_default_dc() -> Result<()> {
-        let root = tempdir()?;
+    struct FakeClock {
+        now: std::rc::Rc<std::cell::Cell<Instant>>,
+    }
 
-        write_global_powersave_bias(root.path(), 0)?;
-        write_global_sampling_rate(root.path(), 2000)?;
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::DC,
-        };
+        fn sleep(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+        }
+    }
 
-        let config_provider = FakeConfigProvider {
-            default_power_preferences: |power_source| {
-                assert_eq!(power_source, config::PowerSourceType::DC);
+    #[derive(Default)]
+    struct RecordingPowerPreferencesManager {
+        updates: std::cell::RefCell<Vec<PowerActivities>>,
+        render_boost: std::cell::Cell<bool>,
+    }
 
-                Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Ondemand {
-                        powersave_bias: 200,
-                        sampling_rate: None,
-                    }),
-                    epp: None,
-                }))
-            },
+    impl PowerPreferencesManager for RecordingPowerPreferencesManager {
+        fn update_power_preferences(
+            &self,
+            rtc: RTCAudioActive,
+            fullscreen: FullscreenVideo,
+            game: GameMode,
+            vmboot: VmBootMode,
+            batterysaver: BatterySaverMode,
+        ) -> Result<()> {
+            self.updates.borrow_mut().push(PowerActivities {
+                rtc,
+                fullscreen,
+                game,
+                vmboot,
+                batterysaver,
+            });
+            Ok(())
+        }
+
+        fn is_thermal_clamp_outdated(&self) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn set_render_boost(&self, active: bool) -> Result<()> {
+            self.render_boost.set(active);
+            Ok(())
+        }
+    }
+
+    // The max CPU frequency tells which power preferences are applied: 4000000 by default,
+    // 2000000 for RTC audio, 2800000 for fullscreen video and 3600000 for Borealis gaming.
+    fn new_debouncer_test_manager(
+        root: &Path,
+    ) -> DirectoryPowerPreferencesManager<FakeConfigProvider, FakePowerSourceProvider> {
+        write_per_policy_cpufreq(root, "acpi-cpufreq", 4000000);
+
+        fn cpu_perf_pct(max: u32) -> Result<Option<config::PowerPreferences>> {
+            Ok(Some(config::PowerPreferences {
+                governor: None,
+                epp: None,
+                cpu_perf_pct: Some(config::CpuPerfPercent { min: 0, max }),
+                uclamp: None,
+            }))
+        }
+        let config_provider = FakeConfigProvider {
+            default_power_preferences: |_| cpu_perf_pct(100),
+            web_rtc_power_preferences: |_| cpu_perf_pct(50),
+            fullscreen_power_preferences: |_| cpu_perf_pct(70),
+            borealis_gaming_power_preferences: |_| cpu_perf_pct(90),
             ..Default::default()
         };
 
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.path().to_path_buf(),
+        DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
             config_provider,
-            power_source_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        )
+    }
+
+    #[test]
+    fn test_power_preferences_debouncer() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        let manager = new_debouncer_test_manager(root);
+
+        let now = std::rc::Rc::new(std::cell::Cell::new(Instant::now()));
+        let clock = FakeClock { now: now.clone() };
+        let mut debouncer = PowerPreferencesDebouncer::new(clock, Duration::from_secs(2));
+
+        let idle = PowerActivities {
+            rtc: RTCAudioActive::Inactive,
+            fullscreen: FullscreenVideo::Inactive,
+            game: GameMode::Off,
+            vmboot: VmBootMode::Inactive,
+            batterysaver: BatterySaverMode::Inactive,
+        };
+        let rtc = PowerActivities {
+            rtc: RTCAudioActive::Active,
+            ..idle
+        };
+        let fullscreen = PowerActivities {
+            fullscreen: FullscreenVideo::Active,
+            ..idle
         };
 
-        manager.update_power_preferences(
-            common::RTCAudioActive::Inactive,
-            common::FullscreenVideo::Inactive,
-            common::GameMode::Off,
-            common::VmBootMode::Inactive,
-            common::BatterySaverMode::Inactive,
-        )?;
+        // The first update is applied right away.
+        assert_eq!(debouncer.update(&manager, idle)?, None);
+        assert_eq!(get_cpu0_freq_max(root), 4000000);
+
+        // Toggling RTC audio every 500ms doesn't apply anything.
+        for _ in 0..5 {
+            assert_eq!(
+                debouncer.update(&manager, rtc)?,
+                Some(Duration::from_secs(2))
+            );
+            now.set(now.get() + Duration::from_millis(500));
+            assert_eq!(debouncer.update(&manager, idle)?, None);
+            now.set(now.get() + Duration::from_millis(500));
+        }
+        assert_eq!(get_cpu0_freq_max(root), 4000000);
+
+        // A change is applied once it held for 2s, even if the power preferences are applied in
+        // between for another reason, e.g. the thermal poll.
+        debouncer.update(&manager, rtc)?;
+        now.set(now.get() + Duration::from_millis(1000));
+        debouncer.apply(&manager, rtc)?;
+        assert_eq!(get_cpu0_freq_max(root), 4000000);
+        now.set(now.get() + Duration::from_millis(500));
+        assert_eq!(
+            debouncer.update(&manager, rtc)?,
+            Some(Duration::from_millis(500))
+        );
+        now.set(now.get() + Duration::from_millis(500));
+        assert_eq!(debouncer.update(&manager, rtc)?, None);
+        assert_eq!(get_cpu0_freq_max(root), 2000000);
 
-        let powersave_bias = read_global_powersave_bias(root.path())?;
-        assert_eq!(powersave_bias, "200");
+        // Changing to another debounced state restarts the hold.
+        debouncer.update(&manager, idle)?;
+        now.set(now.get() + Duration::from_millis(1500));
+        assert_eq!(
+            debouncer.update(&manager, fullscreen)?,
+            Some(Duration::from_secs(2))
+        );
+        now.set(now.get() + Duration::from_secs(2));
+        assert_eq!(get_cpu0_freq_max(root), 2000000);
+        assert_eq!(debouncer.update(&manager, fullscreen)?, None);
+        assert_eq!(get_cpu0_freq_max(root), 2800000);
 
-        let sampling_rate = read_global_sampling_rate(root.path())?;
-        assert_eq!(sampling_rate, "2000");
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_preferences_debouncer_immediate() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        let manager = new_debouncer_test_manager(root);
+
+        let now = std::rc::Rc::new(std::cell::Cell::new(Instant::now()));
+        let clock = FakeClock { now: now.clone() };
+        let mut debouncer = PowerPreferencesDebouncer::new(clock, Duration::from_secs(2));
+
+        let idle = PowerActivities {
+            rtc: RTCAudioActive::Inactive,
+            fullscreen: FullscreenVideo::Inactive,
+            game: GameMode::Off,
+            vmboot: VmBootMode::Inactive,
+            batterysaver: BatterySaverMode::Inactive,
+        };
+        debouncer.update(&manager, idle)?;
+
+        // Game mode takes effect immediately, along with the pending RTC audio change.
+        let rtc = PowerActivities {
+            rtc: RTCAudioActive::Active,
+            ..idle
+        };
+        assert!(debouncer.update(&manager, rtc)?.is_some());
+        assert_eq!(get_cpu0_freq_max(root), 4000000);
+        let game = PowerActivities {
+            game: GameMode::Borealis,
+            ..rtc
+        };
+        assert_eq!(debouncer.update(&manager, game)?, None);
+        assert_eq!(get_cpu0_freq_max(root), 3600000);
+        assert_eq!(debouncer.update(&manager, rtc)?, None);
+        assert_eq!(get_cpu0_freq_max(root), 2000000);
+
+        // So does battery saver.
+        let battery_saver = PowerActivities {
+            batterysaver: BatterySaverMode::Active,
+            ..rtc
+        };
+        assert_eq!(debouncer.update(&manager, battery_saver)?, None);
+        assert_eq!(get_cpu0_freq_max(root), 2400000);
+
+        // Applying right away doesn't hold back a change that isn't pending.
+        let fullscreen = PowerActivities {
+            fullscreen: FullscreenVideo::Active,
+            ..idle
+        };
+        debouncer.apply(&manager, fullscreen)?;
+        assert_eq!(get_cpu0_freq_max(root), 2800000);
 
         Ok(())
     }
 
     #[test]
-    fn test_power_update_power_preferences_default_rtc_active() -> Result<()> {
-        let root = tempdir()?;
+    fn test_power_preferences_debouncer_observer() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        let manager = new_debouncer_test_manager(root);
+
+        let now = std::rc::Rc::new(std::cell::Cell::new(Instant::now()));
+        let clock = FakeClock { now: now.clone() };
+        let mut debouncer = PowerPreferencesDebouncer::new(clock, Duration::from_secs(2));
+        let transitions = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let observed = transitions.clone();
+        debouncer.set_observer(Box::new(move |previous, new| {
+            observed.lock().unwrap().push((previous, new))
+        }));
+
+        let idle = PowerActivities {
+            rtc: RTCAudioActive::Inactive,
+            fullscreen: FullscreenVideo::Inactive,
+            game: GameMode::Off,
+            vmboot: VmBootMode::Inactive,
+            batterysaver: BatterySaverMode::Inactive,
+        };
+        let game = PowerActivities {
+            game: GameMode::Borealis,
+            ..idle
+        };
+        let rtc = PowerActivities {
+            rtc: RTCAudioActive::Active,
+            ..idle
+        };
 
-        write_global_powersave_bias(root.path(), 0)?;
-        write_global_sampling_rate(root.path(), 2000)?;
+        debouncer.update(&manager, idle)?;
+        debouncer.apply(&manager, idle)?;
+        debouncer.update(&manager, game)?;
+        debouncer.apply(&manager, game)?;
+        assert_eq!(get_cpu0_freq_max(root), 3600000);
+        debouncer.update(&manager, idle)?;
+        // Held back, applied right away for another reason while held back, and reverted before
+        // it was applied.
+        debouncer.update(&manager, rtc)?;
+        debouncer.apply(&manager, rtc)?;
+        debouncer.update(&manager, idle)?;
+        assert_eq!(get_cpu0_freq_max(root), 4000000);
+
+        assert_eq!(*transitions.lock().unwrap(), [(idle, game), (game, idle)]);
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_boost() -> Result<()> {
+        let start = Instant::now();
+        let now = std::rc::Rc::new(std::cell::Cell::new(start));
+        let mut boost = RenderBoost::new(FakeClock { now: now.clone() });
+        let manager = RecordingPowerPreferencesManager::default();
+
+        // 6 frames at 60 Hz, the power preferences apply the boost.
+        assert_eq!(boost.request(&manager, 6)?, Duration::from_millis(100));
+        assert!(boost.deadline.is_some());
+        assert!(manager.render_boost.get());
+        assert_eq!(manager.updates.borrow().len(), 1);
+
+        // An overlapping request extends the boost.
+        now.set(start + Duration::from_millis(50));
+        assert_eq!(boost.request(&manager, 6)?, Duration::from_millis(100));
+        assert_eq!(manager.updates.borrow().len(), 1);
+
+        now.set(start + Duration::from_millis(120));
+        assert_eq!(boost.update(&manager)?, Some(Duration::from_millis(30)));
+        assert!(manager.render_boost.get());
+
+        // The power preferences are applied again without the boost on release.
+        now.set(start + Duration::from_millis(150));
+        assert_eq!(boost.update(&manager)?, None);
+        assert!(boost.deadline.is_none());
+        assert!(!manager.render_boost.get());
+        assert_eq!(manager.updates.borrow().len(), 2);
+
+        // The boost duration follows the refresh rate, and is bounded.
+        boost.set_refresh_rate(120)?;
+        assert_eq!(boost.request(&manager, 12)?, Duration::from_millis(100));
+        now.set(start + Duration::from_millis(250));
+        assert_eq!(boost.update(&manager)?, None);
+        assert!(boost
+            .request(&manager, RENDER_BOOST_MAX_FRAMES + 1)
+            .is_err());
+        assert!(boost.deadline.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_render_boost() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        write_per_policy_cpufreq(root, "acpi-cpufreq", 4000000);
+
+        let manager = new_cpu_perf_pct_manager(root);
+        let update = |batterysaver| {
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                GameMode::Off,
+                common::VmBootMode::Inactive,
+                batterysaver,
+            )
         };
 
+        update(BatterySaverMode::Inactive)?;
+        check_per_policy_scaling_freq(root, 800000, 3200000);
+
+        // The boost survives the power preferences being updated for other reasons.
+        manager.set_render_boost(true)?;
+        update(BatterySaverMode::Inactive)?;
+        check_per_policy_scaling_freq(root, 2800000, 4000000);
+        update(BatterySaverMode::Inactive)?;
+        check_per_policy_scaling_freq(root, 2800000, 4000000);
+
+        // Battery saver keeps its cap, which bounds the boost.
+        update(BatterySaverMode::Active)?;
+        check_per_policy_scaling_freq(root, 2400000, 2400000);
+        update(BatterySaverMode::Inactive)?;
+        check_per_policy_scaling_freq(root, 2800000, 4000000);
+
+        // Releasing the boost puts the range of the power preferences back.
+        manager.set_render_boost(false)?;
+        update(BatterySaverMode::Inactive)?;
+        check_per_policy_scaling_freq(root, 800000, 3200000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_update_power_preferences_render_boost_no_cpu_perf_pct() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        write_per_policy_cpufreq(root, "acpi-cpufreq", 4000000);
+        for policy in TEST_CPUFREQ_POLICIES {
+            fs::write(root.join(policy).join("cpuinfo_min_freq"), "400000")?;
+        }
+
         let config_provider = FakeConfigProvider {
-            default_power_preferences: |_| {
-                Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Ondemand {
-                        powersave_bias: 200,
-                        sampling_rate: Some(4000),
-                    }),
-                    epp: None,
-                }))
-            },
-            web_rtc_power_preferences: |_| Ok(None),
+            default_power_preferences: |_| Ok(None),
             ..Default::default()
         };
-
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.path().to_path_buf(),
+        let manager = DirectoryPowerPreferencesManager::new(
+            root.to_path_buf(),
             config_provider,
-            power_source_provider,
+            FakePowerSourceProvider {
+                power_source: config::PowerSourceType::AC,
+            },
+        );
+        let update = || {
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                GameMode::Off,
+                common::VmBootMode::Inactive,
+                BatterySaverMode::Inactive,
+            )
         };
 
-        manager.update_power_preferences(
-            common::RTCAudioActive::Active,
-            common::FullscreenVideo::Inactive,
-            common::GameMode::Off,
-            common::VmBootMode::Inactive,
-            common::BatterySaverMode::Inactive,
-        )?;
-
-        let powersave_bias = read_global_powersave_bias(root.path())?;
-        assert_eq!(powersave_bias, "200");
+        manager.set_render_boost(true)?;
+        update()?;
+        check_per_policy_scaling_freq(root, 2800000, 4000000);
 
-        let sampling_rate = read_global_sampling_rate(root.path())?;
-        assert_eq!(sampling_rate, "4000");
+        // Without a range of the power preferences to put back, the min goes back down.
+        manager.set_render_boost(false)?;
+        update()?;
+        check_per_policy_scaling_freq(root, 400000, 4000000);
 
         Ok(())
     }
 
     #[test]
-    fn test_power_update_power_preferences_rtc_active() -> Result<()> {
-        let root = tempdir()?;
+    fn test_parse_charge_behaviors() -> Result<()> {
+        assert_eq!(
+            parse_charge_behaviors("[auto] inhibit-charge force-discharge\n")?,
+            (
+                ChargeBehavior::Auto,
+                vec![
+                    ChargeBehavior::Auto,
+                    ChargeBehavior::InhibitCharge,
+                    ChargeBehavior::ForceDischarge
+                ]
+            )
+        );
+        // Unknown behaviors are skipped.
+        assert_eq!(
+            parse_charge_behaviors("auto [inhibit-charge] inhibit-charge-awake\n")?,
+            (
+                ChargeBehavior::InhibitCharge,
+                vec![ChargeBehavior::Auto, ChargeBehavior::InhibitCharge]
+            )
+        );
+        assert!(parse_charge_behaviors("auto inhibit-charge\n").is_err());
 
-        write_global_powersave_bias(root.path(), 0)?;
-        write_global_sampling_rate(root.path(), 2000)?;
+        Ok(())
+    }
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
-        };
+    #[test]
+    fn test_charge_behavior() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
 
-        let config_provider = FakeConfigProvider {
-            web_rtc_power_preferences: |_| {
-                Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Ondemand {
-                        powersave_bias: 200,
-                        sampling_rate: Some(16000),
-                    }),
-                    epp: None,
-                }))
-            },
-            ..Default::default()
-        };
+        // Missing node is a no-op.
+        assert_eq!(get_charge_behavior(root)?, None);
+        set_charge_behavior(root, ChargeBehavior::InhibitCharge)?;
 
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.path().to_path_buf(),
-            config_provider,
-            power_source_provider,
-        };
+        let battery_path = root.join(POWER_SUPPLY_PATH).join("BAT0");
+        fs::create_dir_all(&battery_path)?;
+        let charge_behavior_path = battery_path.join(POWER_SUPPLY_CHARGE_BEHAVIOUR);
+        fs::write(&charge_behavior_path, "[auto] inhibit-charge\n")?;
 
-        manager.update_power_preferences(
-            common::RTCAudioActive::Active,
-            common::FullscreenVideo::Inactive,
-            common::GameMode::Off,
-            common::VmBootMode::Inactive,
-            common::BatterySaverMode::Inactive,
-        )?;
+        assert_eq!(get_charge_behavior(root)?, Some(ChargeBehavior::Auto));
 
-        let powersave_bias = read_global_powersave_bias(root.path())?;
-        assert_eq!(powersave_bias, "200");
+        set_charge_behavior(root, ChargeBehavior::InhibitCharge)?;
+        assert_eq!(fs::read_to_string(&charge_behavior_path)?, "inhibit-charge");
 
-        let sampling_rate = read_global_sampling_rate(root.path())?;
-        assert_eq!(sampling_rate, "16000");
+        // Behaviors not offered by the platform are rejected.
+        fs::write(&charge_behavior_path, "auto [inhibit-charge]\n")?;
+        assert_eq!(
+            get_charge_behavior(root)?,
+            Some(ChargeBehavior::InhibitCharge)
+        );
+        assert!(set_charge_behavior(root, ChargeBehavior::ForceDischarge).is_err());
+        assert_eq!(
+            fs::read_to_string(&charge_behavior_path)?,
+            "auto [inhibit-charge]\n"
+        );
 
         Ok(())
     }
 
     #[test]
-    /// Tests default battery saver mode
-    fn test_power_update_power_preferences_battery_saver_active() -> Result<()> {
+    fn test_frequency_residency() -> Result<()> {
         let temp_dir = tempdir()?;
         let root = temp_dir.path();
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
-        };
+        // No cpufreq stats.
+        assert_eq!(frequency_residency(root, "caller", 0)?, None);
 
-        let config_provider = FakeConfigProvider {
-            arcvm_gaming_power_preferences: |_| {
-                Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Schedutil),
-                    epp: None,
-                }))
-            },
-            ..Default::default()
-        };
+        let stats_path = root.join("sys/devices/system/cpu/cpufreq/policy0/stats");
+        fs::create_dir_all(&stats_path)?;
+        let time_in_state_path = stats_path.join("time_in_state");
+        fs::write(&time_in_state_path, "400000 100\n1200000 50\n2400000 0\n")?;
 
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.to_path_buf(),
-            config_provider,
-            power_source_provider,
-        };
+        // The first snapshot counts since boot.
+        assert_eq!(
+            frequency_residency(root, "caller", 0)?,
+            Some(vec![
+                (400000, Duration::from_millis(1000)),
+                (1200000, Duration::from_millis(500)),
+                (2400000, Duration::ZERO),
+            ])
+        );
 
-        let tests = [
-            (
-                BatterySaverMode::Active,
-                "balance_power",
-                config::Governor::Conservative,
-            ),
-            (
-                BatterySaverMode::Inactive,
-                "balance_performance",
-                config::Governor::Schedutil,
-            ),
-        ];
+        fs::write(&time_in_state_path, "400000 130\n1200000 50\n2400000 20\n")?;
+        assert_eq!(
+            frequency_residency(root, "caller", 0)?,
+            Some(vec![
+                (400000, Duration::from_millis(300)),
+                (1200000, Duration::ZERO),
+                (2400000, Duration::from_millis(200)),
+            ])
+        );
 
-        // Test device without EPP path
-        write_per_policy_scaling_governor(root, config::Governor::Performance);
-        for test in tests {
-            manager.update_power_preferences(
-                common::RTCAudioActive::Inactive,
-                common::FullscreenVideo::Inactive,
-                common::GameMode::Arc,
-                common::VmBootMode::Inactive,
-                test.0,
-            )?;
+        // Another caller doesn't move the baseline of the first one.
+        fs::write(&time_in_state_path, "400000 140\n1200000 60\n2400000 20\n")?;
+        assert_eq!(
+            frequency_residency(root, "other caller", 0)?,
+            Some(vec![
+                (400000, Duration::from_millis(1400)),
+                (1200000, Duration::from_millis(600)),
+                (2400000, Duration::from_millis(200)),
+            ])
+        );
+        assert_eq!(
+            frequency_residency(root, "caller", 0)?,
+            Some(vec![
+                (400000, Duration::from_millis(100)),
+                (1200000, Duration::from_millis(100)),
+                (2400000, Duration::ZERO),
+            ])
+        );
 
-            check_per_policy_scaling_governor(root, test.2);
+        // The least recent caller is forgotten once too many callers ask.
+        for index in 0..=MAX_RESIDENCY_CALLERS {
+            frequency_residency(root, &format!("caller {}", index), 0)?;
         }
+        assert_eq!(
+            frequency_residency(root, "caller", 0)?,
+            Some(vec![
+                (400000, Duration::from_millis(1400)),
+                (1200000, Duration::from_millis(600)),
+                (2400000, Duration::from_millis(200)),
+            ])
+        );
 
-        // Test device with EPP path
-        write_epp(root, "balance_performance")?;
-        for test in tests {
-            manager.update_power_preferences(
-                common::RTCAudioActive::Inactive,
-                common::FullscreenVideo::Inactive,
-                common::GameMode::Arc,
-                common::VmBootMode::Inactive,
-                test.0,
-            )?;
-
-            let epp = read_epp(root)?;
-            assert_eq!(epp, test.1);
-        }
+        fs::write(&time_in_state_path, "400000 oops\n")?;
+        assert!(frequency_residency(root, "caller", 0).is_err());
 
         Ok(())
     }
 
     #[test]
-    /// Tests the various EPP permutations
-    fn test_power_update_power_preferences_epp() -> Result<()> {
-        let root = tempdir()?;
+    fn test_set_uclamp() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
 
-        write_epp(root.path(), "balance_performance")?;
+        // No uclamp support.
+        set_uclamp(root, 10, 90)?;
 
-        let tests = [
-            (
-                FakePowerSourceProvider {
-                    power_source: config::PowerSourceType::DC,
-                },
-                RTCAudioActive::Active,
-                FullscreenVideo::Inactive,
-                "balance_power",
-            ),
-            (
-                FakePowerSourceProvider {
-                    power_source: config::PowerSourceType::DC,
-                },
-                RTCAudioActive::Inactive,
-                FullscreenVideo::Active,
-                "balance_power",
-            ),
-            (
-                FakePowerSourceProvider {
-                    power_source: config::PowerSourceType::DC,
-                },
-                RTCAudioActive::Active,
-                FullscreenVideo::Active,
-                "balance_power",
-            ),
-            (
-                FakePowerSourceProvider {
-                    power_source: config::PowerSourceType::DC,
-                },
-                RTCAudioActive::Inactive,
-                FullscreenVideo::Inactive,
-                "balance_performance",
-            ),
-            (
-                FakePowerSourceProvider {
-                    power_source: config::PowerSourceType::AC,
-                },
-                RTCAudioActive::Inactive,
-                FullscreenVideo::Active,
-                "balance_performance",
-            ),
-            (
-                FakePowerSourceProvider {
-                    power_source: config::PowerSourceType::AC,
-                },
-                RTCAudioActive::Active,
-                FullscreenVideo::Active,
-                "balance_performance",
-            ),
-            (
-                FakePowerSourceProvider {
-                    power_source: config::PowerSourceType::AC,
-                },
-                RTCAudioActive::Active,
-                FullscreenVideo::Inactive,
-                "balance_performance",
-            ),
-        ];
+        let min_path = root.join(UCLAMP_MIN_PATH);
+        let max_path = root.join(UCLAMP_MAX_PATH);
+        fs::create_dir_all(min_path.parent().unwrap())?;
+        fs::write(&min_path, "1024\n")?;
+        fs::write(&max_path, "1024\n")?;
 
-        for test in tests {
-            // Let's assume we have no config
-            let config_provider = FakeConfigProvider {
-                default_power_preferences: |_| Ok(None),
-                web_rtc_power_preferences: |_| Ok(None),
-                fullscreen_power_preferences: |_| Ok(None),
-                ..Default::default()
-            };
+        // The new max is below the current min.
+        set_uclamp(root, 25, 50)?;
+        assert_eq!(fs::read_to_string(&min_path)?, "256");
+        assert_eq!(fs::read_to_string(&max_path)?, "512");
 
-            let manager = DirectoryPowerPreferencesManager {
-                root: root.path().to_path_buf(),
-                config_provider,
-                power_source_provider: test.0,
-            };
+        set_uclamp(root, 0, 100)?;
+        assert_eq!(fs::read_to_string(&min_path)?, "0");
+        assert_eq!(fs::read_to_string(&max_path)?, "1024");
 
-            manager.update_power_preferences(
-                test.1,
-                test.2,
-                common::GameMode::Off,
-                common::VmBootMode::Inactive,
-                common::BatterySaverMode::Inactive,
-            )?;
+        assert!(set_uclamp(root, 0, 101).is_err());
+        assert!(set_uclamp(root, 60, 40).is_err());
+        assert_eq!(fs::read_to_string(&min_path)?, "0");
+        assert_eq!(fs::read_to_string(&max_path)?, "1024");
 
-            let epp = read_epp(root.path())?;
+        Ok(())
+    }
 
-            assert_eq!(epp, test.3);
+    fn write_mock_rapl_domain(path: &Path, name: &str, limits: &[(&str, u64)]) -> Result<()> {
+        fs::create_dir_all(path)?;
+        fs::write(path.join("name"), format!("{}\n", name))?;
+        fs::write(path.join("enabled"), "1\n")?;
+        for (n, (constraint, limit)) in limits.iter().enumerate() {
+            fs::write(
+                path.join(format!("constraint_{}_name", n)),
+                format!("{}\n", constraint),
+            )?;
+            fs::write(
+                path.join(format!("constraint_{}_power_limit_uw", n)),
+                format!("{}\n", limit),
+            )?;
         }
 
         Ok(())
     }
 
     #[test]
-    fn test_power_update_power_preferences_fullscreen_active() -> Result<()> {
-        let root = tempdir()?;
+    fn test_enumerate_rapl_domains() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        let powercap_path = root.join(POWERCAP_PATH);
 
-        write_global_powersave_bias(root.path(), 0)?;
-        write_global_sampling_rate(root.path(), 2000)?;
+        assert_eq!(enumerate_rapl_domains(root)?, vec![]);
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
-        };
+        let package_path = powercap_path.join("intel-rapl:0");
+        write_mock_rapl_domain(
+            &package_path,
+            "package-0",
+            &[("long_term", 15000000), ("short_term", 25000000)],
+        )?;
+        // A subdomain nested in its package.
+        let core_path = package_path.join("intel-rapl:0:0");
+        write_mock_rapl_domain(&core_path, "core", &[("long_term", 0)])?;
+        // A subdomain at the top level.
+        let dram_path = powercap_path.join("intel-rapl:0:2");
+        write_mock_rapl_domain(&dram_path, "dram", &[("long_term", 5000000)])?;
+        fs::write(dram_path.join("enabled"), "0\n")?;
+        fs::write(package_path.join("energy_uj"), "123456789\n")?;
+        fs::write(core_path.join("energy_uj"), "23456789\n")?;
+        // Not a RAPL domain.
+        fs::create_dir_all(powercap_path.join("intel-rapl-mmio:0"))?;
+
+        let domains = enumerate_rapl_domains(root)?;
+        assert_eq!(
+            domains,
+            vec![
+                RaplDomain {
+                    path: package_path,
+                    package: 0,
+                    subdomain: None,
+                    name: "package-0".to_string(),
+                    enabled: true,
+                    constraints: vec![
+                        RaplConstraint {
+                            name: "long_term".to_string(),
+                            power_limit_uw: 15000000,
+                        },
+                        RaplConstraint {
+                            name: "short_term".to_string(),
+                            power_limit_uw: 25000000,
+                        },
+                    ],
+                    energy_uj: Some(123456789),
+                },
+                RaplDomain {
+                    path: core_path,
+                    package: 0,
+                    subdomain: Some(0),
+                    name: "core".to_string(),
+                    enabled: true,
+                    constraints: vec![RaplConstraint {
+                        name: "long_term".to_string(),
+                        power_limit_uw: 0,
+                    }],
+                    energy_uj: Some(23456789),
+                },
+                RaplDomain {
+                    path: dram_path,
+                    package: 0,
+                    subdomain: Some(2),
+                    name: "dram".to_string(),
+                    enabled: false,
+                    constraints: vec![RaplConstraint {
+                        name: "long_term".to_string(),
+                        power_limit_uw: 5000000,
+                    }],
+                    energy_uj: None,
+                },
+            ]
+        );
 
-        let config_provider = FakeConfigProvider {
-            fullscreen_power_preferences: |_| {
-                Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Ondemand {
-                        powersave_bias: 200,
-                        sampling_rate: Some(16000),
-                    }),
-                    epp: None,
-                }))
-            },
-            ..Default::default()
-        };
+        Ok(())
+    }
+
+    #[test]
+    fn test_enumerate_rapl_domains_amd() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("proc"))?;
+        write_mock_amd_cpuinfo(root, 4);
+
+        // amd_energy reports the sockets, and the cores which are not RAPL domains.
+        let k10temp_path = root.join(HWMON_PATH).join("hwmon0");
+        fs::create_dir_all(&k10temp_path)?;
+        fs::write(k10temp_path.join("name"), "k10temp\n")?;
+        let amd_energy_path = root.join(HWMON_PATH).join("hwmon1");
+        fs::create_dir_all(&amd_energy_path)?;
+        fs::write(amd_energy_path.join("name"), "amd_energy\n")?;
+        for (n, label, energy) in [
+            (1, "Ecore000", 1000),
+            (2, "Ecore001", 2000),
+            (3, "Esocket0", 9000),
+        ] {
+            fs::write(
+                amd_energy_path.join(format!("energy{}_label", n)),
+                format!("{}\n", label),
+            )?;
+            fs::write(
+                amd_energy_path.join(format!("energy{}_input", n)),
+                format!("{}\n", energy),
+            )?;
+        }
 
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.path().to_path_buf(),
-            config_provider,
-            power_source_provider,
+        let amd_energy_socket = RaplDomain {
+            path: amd_energy_path,
+            package: 0,
+            subdomain: None,
+            name: "package-0".to_string(),
+            enabled: true,
+            constraints: vec![],
+            energy_uj: Some(9000),
         };
+        assert_eq!(
+            enumerate_rapl_domains(root)?,
+            vec![amd_energy_socket.clone()]
+        );
 
-        manager.update_power_preferences(
-            common::RTCAudioActive::Inactive,
-            common::FullscreenVideo::Active,
-            common::GameMode::Off,
-            common::VmBootMode::Inactive,
-            common::BatterySaverMode::Inactive,
-        )?;
-
-        let powersave_bias = read_global_powersave_bias(root.path())?;
-        assert_eq!(powersave_bias, "200");
+        // The RAPL powercap nodes are used when the kernel exposes them.
+        let package_path = root.join(POWERCAP_PATH).join("intel-rapl:0");
+        write_mock_rapl_domain(&package_path, "package-0", &[("long_term", 15000000)])?;
+        fs::write(package_path.join("energy_uj"), "8000\n")?;
+        assert_eq!(
+            enumerate_rapl_domains(root)?,
+            vec![RaplDomain {
+                path: package_path,
+                package: 0,
+                subdomain: None,
+                name: "package-0".to_string(),
+                enabled: true,
+                constraints: vec![RaplConstraint {
+                    name: "long_term".to_string(),
+                    power_limit_uw: 15000000,
+                }],
+                energy_uj: Some(8000),
+            }]
+        );
 
-        let sampling_rate = read_global_sampling_rate(root.path())?;
-        assert_eq!(sampling_rate, "16000");
+        // amd_energy is only used on AMD CPUs.
+        fs::remove_dir_all(root.join(POWERCAP_PATH))?;
+        write_mock_intel_cpuinfo(root, 4);
+        assert_eq!(enumerate_rapl_domains(root)?, vec![]);
 
         Ok(())
     }
 
     #[test]
-    fn test_power_update_power_preferences_borealis_gaming_active() -> Result<()> {
-        let root = tempdir()?;
+    fn test_cpu_vendor() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
 
-        write_global_powersave_bias(root.path(), 0)?;
-        write_global_sampling_rate(root.path(), 2000)?;
+        assert_eq!(cpu_vendor(root)?, CpuVendor::Other);
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
-        };
+        fs::create_dir_all(root.join("proc"))?;
+        for num_cores in [1, 8] {
+            write_mock_amd_cpuinfo(root, num_cores);
+            assert_eq!(cpu_vendor(root)?, CpuVendor::Amd, "{} cores", num_cores);
+            write_mock_intel_cpuinfo(root, num_cores);
+            assert_eq!(cpu_vendor(root)?, CpuVendor::Intel, "{} cores", num_cores);
+        }
+        write_mock_cpuinfo(root, "HygonGenuine", "Hygon C86 3250 8-core Processor");
+        assert_eq!(cpu_vendor(root)?, CpuVendor::Other);
 
-        let config_provider = FakeConfigProvider {
-            borealis_gaming_power_preferences: |_| {
-                Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Ondemand {
-                        powersave_bias: 200,
-                        sampling_rate: Some(16000),
-                    }),
-                    epp: None,
-                }))
-            },
-            ..Default::default()
-        };
+        Ok(())
+    }
 
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.path().to_path_buf(),
-            config_provider,
-            power_source_provider,
-        };
+    #[test]
+    fn test_cpus_to_affinity_mask() {
+        assert_eq!(cpus_to_affinity_mask(&[0, 1]), "3");
+        assert_eq!(cpus_to_affinity_mask(&[4, 5, 6, 7]), "f0");
+        assert_eq!(cpus_to_affinity_mask(&[0, 33]), "2,00000001");
+        assert_eq!(parse_cpu_list("0-2,5\n").unwrap(), vec![0, 1, 2, 5]);
+    }
 
-        manager.update_power_preferences(
-            common::RTCAudioActive::Inactive,
-            common::FullscreenVideo::Inactive,
-            common::GameMode::Borealis,
-            common::VmBootMode::Inactive,
-            common::BatterySaverMode::Inactive,
+    #[test]
+    fn test_set_irq_affinity_for_gaming() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+
+        test_write_ui_use_flags(root, "big_little");
+        for (cpu, capacity) in [(0, 512), (1, 512), (2, 1024), (3, 1024)] {
+            let cpu_path = root.join(format!("sys/bus/cpu/devices/cpu{}", cpu));
+            fs::create_dir_all(&cpu_path)?;
+            fs::write(cpu_path.join("cpu_capacity"), capacity.to_string())?;
+        }
+
+        fs::create_dir_all(root.join("proc"))?;
+        fs::write(root.join(PROC_UPTIME_PATH), "100.00 350.00\n")?;
+        fs::write(
+            root.join(PROC_INTERRUPTS_PATH),
+            r#"           CPU0       CPU1       CPU2       CPU3
+  9:          0          0          0          0   IO-APIC   9-fasteoi   acpi
+ 24:       2000       3000      20000       5000   PCI-MSI 327680-edge      xhci_hcd
+ 25:     100000          0          0          0   PCI-MSI 1048576-edge      nvme0q0
+ 26:          0      20000          0          0   PCI-MSI 1572864-edge      iwlwifi
+NMI:     900000     900000     900000     900000   Non-maskable interrupts
+"#,
         )?;
+        let irq_path = root.join(PROC_IRQ_PATH);
+        for irq in [9, 24, 25] {
+            fs::create_dir_all(irq_path.join(irq.to_string()))?;
+            fs::write(irq_path.join(format!("{}/smp_affinity", irq)), "f\n")?;
+        }
+        // IRQ 26 is managed, its affinity can't be read nor written.
+        fs::create_dir_all(irq_path.join("26/smp_affinity"))?;
+        let read_mask = |irq: u32| {
+            fs::read_to_string(irq_path.join(format!("{}/smp_affinity", irq)))
+                .unwrap()
+                .trim_end()
+                .to_string()
+        };
 
-        let powersave_bias = read_global_powersave_bias(root.path())?;
-        assert_eq!(powersave_bias, "200");
+        set_irq_affinity_for_gaming(root, true)?;
+        // Only the high-rate IRQs are steered to the little cores.
+        assert_eq!(read_mask(9), "f");
+        assert_eq!(read_mask(24), "3");
+        assert_eq!(read_mask(25), "3");
 
-        let sampling_rate = read_global_sampling_rate(root.path())?;
-        assert_eq!(sampling_rate, "16000");
+        // Enabling again keeps the original masks.
+        set_irq_affinity_for_gaming(root, true)?;
+        set_irq_affinity_for_gaming(root, false)?;
+        assert_eq!(read_mask(9), "f");
+        assert_eq!(read_mask(24), "f");
+        assert_eq!(read_mask(25), "f");
 
         Ok(())
     }
 
-    #[test]
-    fn test_power_update_power_preferences_arcvm_gaming_active() -> Result<()> {
-        let root = tempdir()?;
+    // Reads back the min and max limits every time it sleeps.
+    struct RecordingClock {
+        now: std::cell::Cell<Instant>,
+        min_path: PathBuf,
+        max_path: PathBuf,
+        steps: std::cell::RefCell<Vec<(Instant, u64, u64)>>,
+    }
 
-        write_global_powersave_bias(root.path(), 0)?;
-        write_global_sampling_rate(root.path(), 2000)?;
+    impl RecordingClock {
+        fn new(start: Instant, min_path: PathBuf, max_path: PathBuf) -> Self {
+            RecordingClock {
+                now: std::cell::Cell::new(start),
+                min_path,
+                max_path,
+                steps: std::cell::RefCell::new(Vec::new()),
+            }
+        }
+    }
 
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
-        };
+    impl Clock for RecordingClock {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
 
-        let config_provider = FakeConfigProvider {
-            arcvm_gaming_power_preferences: |_| {
-                Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Ondemand {
-                        powersave_bias: 200,
-                        sampling_rate: Some(16000),
-                    }),
-                    epp: None,
-                }))
-            },
-            ..Default::default()
-        };
+        fn sleep(&self, duration: Duration) {
+            let read = |path: &Path| common::read_file_to_u64(path).unwrap();
+            self.steps.borrow_mut().push((
+                self.now.get(),
+                read(&self.min_path),
+                read(&self.max_path),
+            ));
+            self.now.set(self.now.get() + duration);
+        }
+    }
 
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.path().to_path_buf(),
-            config_provider,
-            power_source_provider,
-        };
+    #[test]
+    fn test_apply_profile_ramped() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        write_per_policy_cpufreq(root, "acpi-cpufreq", 4000000);
+
+        let start = Instant::now();
+        let policy_path = root.join(TEST_CPUFREQ_POLICIES[0]);
+        let clock = RecordingClock::new(
+            start,
+            policy_path.join("scaling_min_freq"),
+            policy_path.join("scaling_max_freq"),
+        );
 
-        manager.update_power_preferences(
-            common::RTCAudioActive::Inactive,
-            common::FullscreenVideo::Inactive,
-            common::GameMode::Arc,
-            common::VmBootMode::Inactive,
-            common::BatterySaverMode::Inactive,
+        let manager = new_cpu_perf_pct_manager(root);
+        manager.apply_profile_ramped(
+            &clock,
+            config::CpuPerfPercent { min: 20, max: 60 },
+            Duration::from_millis(400),
+            "ramped profile",
         )?;
 
-        let powersave_bias = read_global_powersave_bias(root.path())?;
-        assert_eq!(powersave_bias, "200");
+        let ms = Duration::from_millis;
+        assert_eq!(
+            *clock.steps.borrow(),
+            vec![
+                (start, 0, 4000000),
+                (start + ms(100), 200000, 3600000),
+                (start + ms(200), 400000, 3200000),
+                (start + ms(300), 600000, 2800000),
+            ]
+        );
+        // The target is reached exactly at the end of the ramp.
+        assert_eq!(clock.now(), start + ms(400));
+        check_per_policy_scaling_freq(root, 800000, 2400000);
 
-        let sampling_rate = read_global_sampling_rate(root.path())?;
-        assert_eq!(sampling_rate, "16000");
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_profile_ramped_intel_pstate() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        write_per_policy_cpufreq(root, "intel_pstate", 4000000);
+        let intel_pstate_path = root.join(INTEL_PSTATE_PATH);
+        fs::create_dir_all(&intel_pstate_path)?;
+        fs::write(intel_pstate_path.join("min_perf_pct"), "20")?;
+        fs::write(intel_pstate_path.join("max_perf_pct"), "80")?;
+
+        let start = Instant::now();
+        let clock = RecordingClock::new(
+            start,
+            intel_pstate_path.join("min_perf_pct"),
+            intel_pstate_path.join("max_perf_pct"),
+        );
+
+        let manager = new_cpu_perf_pct_manager(root);
+        manager.apply_profile_ramped(
+            &clock,
+            BATTERY_SAVER_CPU_PERF_PCT,
+            Duration::from_millis(400),
+            "ramped profile",
+        )?;
+
+        let ms = Duration::from_millis;
+        assert_eq!(
+            *clock.steps.borrow(),
+            vec![
+                (start, 20, 80),
+                (start + ms(100), 15, 75),
+                (start + ms(200), 10, 70),
+                (start + ms(300), 5, 65),
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(intel_pstate_path.join("min_perf_pct"))?,
+            "0"
+        );
+        assert_eq!(
+            fs::read_to_string(intel_pstate_path.join("max_perf_pct"))?,
+            "60"
+        );
+        // The per-policy frequencies are left alone.
+        check_per_policy_scaling_freq(root, 0, 4000000);
 
         Ok(())
     }
 
     #[test]
-    fn test_per_policy_ondemand_governor() -> Result<()> {
+    fn test_power_update_power_preferences_battery_saver_ramp() -> Result<()> {
         let temp_dir = tempdir()?;
         let root = temp_dir.path();
 
-        const INIT_POWERSAVE_BIAS: u32 = 0;
-        const INIT_SAMPLING_RATE: u32 = 2000;
-        const CONFIG_POWERSAVE_BIAS: u32 = 200;
-        const CONFIG_SAMPLING_RATE: u32 = 16000;
+        write_per_policy_cpufreq(root, "acpi-cpufreq", 4000000);
 
-        let ondemand = config::Governor::Ondemand {
-            powersave_bias: INIT_POWERSAVE_BIAS,
-            sampling_rate: Some(INIT_SAMPLING_RATE),
+        let manager = new_cpu_perf_pct_manager(root);
+        let update = |batterysaver| {
+            manager.update_power_preferences(
+                common::RTCAudioActive::Inactive,
+                common::FullscreenVideo::Inactive,
+                common::GameMode::Off,
+                common::VmBootMode::Inactive,
+                batterysaver,
+            )
         };
-        write_per_policy_scaling_governor(root, ondemand);
-        write_per_policy_powersave_bias(root, INIT_POWERSAVE_BIAS);
-        write_per_policy_sampling_rate(root, INIT_SAMPLING_RATE);
-
-        let power_source_provider = FakePowerSourceProvider {
-            power_source: config::PowerSourceType::AC,
+        let last_reason = || -> Result<String> {
+            let max_freq_path = root.join(TEST_CPUFREQ_POLICIES[0]).join("scaling_max_freq");
+            Ok(sysfs_audit::get_records()?
+                .into_iter()
+                .rev()
+                .find(|record| record.path == max_freq_path)
+                .unwrap()
+                .reason)
         };
 
-        let config_provider = FakeConfigProvider {
-            arcvm_gaming_power_preferences: |_| {
-                Ok(Some(config::PowerPreferences {
-                    governor: Some(config::Governor::Ondemand {
-                        powersave_bias: CONFIG_POWERSAVE_BIAS,
-                        sampling_rate: Some(CONFIG_SAMPLING_RATE),
-                    }),
-                    epp: None,
-                }))
-            },
-            ..Default::default()
-        };
+        update(common::BatterySaverMode::Inactive)?;
+        check_per_policy_scaling_freq(root, 800000, 3200000);
 
-        let manager = DirectoryPowerPreferencesManager {
-            root: root.to_path_buf(),
-            config_provider,
-            power_source_provider,
-        };
+        // Entering battery saver ramps the range down, staying in it and leaving it step.
+        update(common::BatterySaverMode::Active)?;
+        check_per_policy_scaling_freq(root, 0, 2400000);
+        assert_eq!(last_reason()?, "cpu perf pct, entering battery saver");
+        update(common::BatterySaverMode::Active)?;
+        check_per_policy_scaling_freq(root, 0, 2400000);
+        assert_eq!(last_reason()?, "cpu perf pct");
+        update(common::BatterySaverMode::Inactive)?;
+        check_per_policy_scaling_freq(root, 800000, 3200000);
+        assert_eq!(last_reason()?, "cpu perf pct");
 
-        manager.update_power_preferences(
-            common::RTCAudioActive::Inactive,
-            common::FullscreenVideo::Inactive,
-            common::GameMode::Arc,
-            common::VmBootMode::Inactive,
-            common::BatterySaverMode::Inactive,
-        )?;
-        check_per_policy_scaling_governor(root, ondemand);
-        check_per_policy_powersave_bias(root, CONFIG_POWERSAVE_BIAS);
-        check_per_policy_sampling_rate(root, CONFIG_SAMPLING_RATE);
         Ok(())
     }
 
-    struct ArcvmGamingConfigProvider {
-        arcvm_gaming_power_preferences: config::PowerPreferences,
-    }
-
-    impl config::ConfigProvider for ArcvmGamingConfigProvider {
-        fn read_power_preferences(
-            &self,
-            _power_source_type: config::PowerSourceType,
-            power_preference_type: config::PowerPreferencesType,
-        ) -> Result<Option<config::PowerPreferences>> {
-            match power_preference_type {
-                config::PowerPreferencesType::ArcvmGaming => {
-                    Ok(Some(self.arcvm_gaming_power_preferences))
-                }
-                _ => bail!("Unexpected power preference type"),
-            }
-        }
+    fn write_power_limit_count(root: &Path, count: u64) {
+        let throttle_path = root.join("sys/devices/system/cpu/cpu0/thermal_throttle");
+        fs::create_dir_all(&throttle_path).unwrap();
+        fs::write(
+            throttle_path.join("package_power_limit_count"),
+            count.to_string(),
+        )
+        .unwrap();
     }
 
     #[test]
-    fn test_scaling_governors() -> Result<()> {
+    fn test_power_update_power_preferences_firmware_power_limit() -> Result<()> {
         let temp_dir = tempdir()?;
         let root = temp_dir.path();
 
-        const INIT_POWERSAVE_BIAS: u32 = 0;
-        const INIT_SAMPLING_RATE: u32 = 2000;
-
-        let ondemand = config::Governor::Ondemand {
-            powersave_bias: INIT_POWERSAVE_BIAS,
-            sampling_rate: Some(INIT_SAMPLING_RATE),
-        };
-        write_per_policy_scaling_governor(root, ondemand);
-
-        let governors = [
-            config::Governor::Conservative,
-            config::Governor::Performance,
-            config::Governor::Powersave,
-            config::Governor::Schedutil,
-            config::Governor::Userspace,
-        ];
-
-        for governor in governors {
-            let power_source_provider = FakePowerSourceProvider {
-                power_source: config::PowerSourceType::AC,
-            };
-            // The governor is a variable that we cannot use FakeConfigProvider.
-            // Got the following error when using FakeConfigProvider:
-            // closures can only be coerced to `fn` types if they do not capture any variables
-            let config_provider = ArcvmGamingConfigProvider {
-                arcvm_gaming_power_preferences: config::PowerPreferences {
-                    governor: Some(governor),
-                    epp: None,
-                },
-            };
-            let manager = DirectoryPowerPreferencesManager {
-                root: root.to_path_buf(),
-                config_provider,
-                power_source_provider,
-            };
+        write_per_policy_cpufreq(root, "acpi-cpufreq", 4000000);
+        write_power_limit_count(root, 3);
 
+        let manager = new_cpu_perf_pct_manager(root);
+        let update = || {
             manager.update_power_preferences(
                 common::RTCAudioActive::Inactive,
                 common::FullscreenVideo::Inactive,
-                common::GameMode::Arc,
+                common::GameMode::Off,
                 common::VmBootMode::Inactive,
                 common::BatterySaverMode::Inactive,
-            )?;
+            )
+        };
 
-            check_per_policy_scaling_governor(root, governor);
-        }
+        // Power limit notifications from before the first update don't count.
+        update()?;
+        check_per_policy_scaling_freq(root, 800000, 3200000);
+
+        // The firmware started throttling, the max is not capped any more.
+        write_power_limit_count(root, 5);
+        update()?;
+        check_per_policy_scaling_freq(root, 800000, 4000000);
+        let max_freq_path = root.join(TEST_CPUFREQ_POLICIES[0]).join("scaling_max_freq");
+        let last_write = sysfs_audit::get_records()?
+            .into_iter()
+            .rev()
+            .find(|record| record.path == max_freq_path)
+            .unwrap();
+        assert_eq!(
+            last_write.reason,
+            "cpu perf pct, firmware power limit active"
+        );
+
+        // The firmware stopped throttling.
+        update()?;
+        check_per_policy_scaling_freq(root, 800000, 3200000);
+
+        // Battery saver keeps capping the max while the firmware throttles.
+        write_power_limit_count(root, 7);
+        manager.update_power_preferences(
+            common::RTCAudioActive::Inactive,
+            common::FullscreenVideo::Inactive,
+            common::GameMode::Off,
+            common::VmBootMode::Inactive,
+            common::BatterySaverMode::Active,
+        )?;
+        check_per_policy_scaling_freq(root, 0, 2400000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_firmware_power_limit_active_without_counters() -> Result<()> {
+        let temp_dir = tempdir()?;
+        assert!(!firmware_power_limit_active(
+            temp_dir.path(),
+            &mut HashMap::new()
+        )?);
 
         Ok(())
     }
@@ -0,0 +1,163 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Keeps a record of the sysfs nodes resourced wrote, for security review and debugging.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use log::info;
+use once_cell::sync::Lazy;
+
+// Number of writes kept in the audit log, the oldest writes are dropped first.
+const AUDIT_LOG_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub path: PathBuf,
+    /// The value of the node before the write, None if it couldn't be read.
+    pub old_value: Option<String>,
+    pub new_value: String,
+    /// Why resourced wrote the node.
+    pub reason: String,
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} ({})",
+            self.path.display(),
+            self.old_value.as_deref().unwrap_or("<unknown>"),
+            self.new_value,
+            self.reason
+        )
+    }
+}
+
+pub struct AuditLog {
+    records: VecDeque<AuditRecord>,
+    capacity: usize,
+    log_to_syslog: bool,
+}
+
+impl AuditLog {
+    fn new(capacity: usize) -> AuditLog {
+        AuditLog {
+            records: VecDeque::with_capacity(capacity),
+            capacity,
+            log_to_syslog: false,
+        }
+    }
+
+    /// Writes `value` to the sysfs node at `path` and records the write. Failed writes are not
+    /// recorded.
+    pub fn write(&mut self, path: &Path, value: &str, reason: &str) -> std::io::Result<()> {
+        let old_value = read_to_string(path)
+            .ok()
+            .map(|value| value.trim_end().to_string());
+
+        std::fs::write(path, value)?;
+
+        let record = AuditRecord {
+            path: path.to_path_buf(),
+            old_value,
+            new_value: value.trim_end().to_string(),
+            reason: reason.to_string(),
+        };
+        if self.log_to_syslog {
+            info!("sysfs write {}", record);
+        }
+
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+
+        Ok(())
+    }
+
+    /// Returns the recorded writes, oldest first.
+    pub fn records(&self) -> impl Iterator<Item = &AuditRecord> {
+        self.records.iter()
+    }
+}
+
+static AUDIT_LOG: Lazy<Mutex<AuditLog>> =
+    Lazy::new(|| Mutex::new(AuditLog::new(AUDIT_LOG_CAPACITY)));
+
+/// Writes `value` to the sysfs node at `path` and records the write in the audit log. A failed
+/// write keeps the std::io::Error as the source of the error.
+pub fn write(path: &Path, value: impl AsRef<str>, reason: &str) -> Result<()> {
+    match AUDIT_LOG.lock() {
+        Ok(mut audit_log) => Ok(audit_log.write(path, value.as_ref(), reason)?),
+        Err(_) => bail!("Lock failed"),
+    }
+}
+
+/// Returns the recorded writes, oldest first.
+pub fn get_records() -> Result<Vec<AuditRecord>> {
+    match AUDIT_LOG.lock() {
+        Ok(audit_log) => Ok(audit_log.records().cloned().collect()),
+        Err(_) => bail!("Lock failed"),
+    }
+}
+
+/// Sets whether the writes are also logged to syslog.
+pub fn set_log_to_syslog(enabled: bool) -> Result<()> {
+    match AUDIT_LOG.lock() {
+        Ok(mut audit_log) => audit_log.log_to_syslog = enabled,
+        Err(_) => bail!("Lock failed"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_audit_log_records_writes() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        let governor = root.path().join("scaling_governor");
+        let epp = root.path().join("energy_performance_preference");
+        std::fs::write(&governor, "ondemand\n")?;
+
+        let mut audit_log = AuditLog::new(2);
+        audit_log.write(&governor, "schedutil", "power preferences")?;
+        audit_log.write(&epp, "balance_power", "power preferences")?;
+        audit_log.write(&governor, "performance", "game mode")?;
+        // Failed writes are not recorded.
+        assert!(audit_log
+            .write(&root.path().join("missing/node"), "1", "test")
+            .is_err());
+
+        // The oldest write was dropped.
+        assert_eq!(
+            audit_log.records().collect::<Vec<_>>(),
+            vec![
+                &AuditRecord {
+                    path: epp,
+                    old_value: None,
+                    new_value: "balance_power".to_string(),
+                    reason: "power preferences".to_string(),
+                },
+                &AuditRecord {
+                    path: governor.clone(),
+                    old_value: Some("schedutil".to_string()),
+                    new_value: "performance".to_string(),
+                    reason: "game mode".to_string(),
+                },
+            ]
+        );
+        assert_eq!(std::fs::read_to_string(&governor)?, "performance");
+
+        Ok(())
+    }
+}
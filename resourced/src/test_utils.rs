@@ -13,7 +13,7 @@ pub(crate) mod tests {
     use crate::common::{BatterySaverMode, FullscreenVideo, GameMode, RTCAudioActive, VmBootMode};
     use crate::power;
 
-    const MOCK_NUM_CPU: i32 = 16;
+    pub const MOCK_NUM_CPU: i32 = 16;
 
     pub const CPUINFO_PATH: &str = "proc/cpuinfo";
     /// Base path for power_limit relative to rootdir.
@@ -22,12 +22,21 @@ pub(crate) mod tests {
     /// Base path for cpufreq relative to rootdir.
     pub const DEVICE_CPUFREQ_PATH: &str = "sys/devices/system/cpu/cpufreq";
 
+    // Class path of the DRM devices, where the GPU cards are.
+    pub const DRM_CLASS_PATH: &str = "sys/class/drm";
+
     // Device path for GPU card.
     pub const GPU0_DEVICE_PATH: &str = "sys/class/drm/card0";
 
     // Device path for GPU RPS path
     pub const GPU0_RPS_DEVICE_PATH: &str = "sys/class/drm/card0/gt/gt0";
 
+    // Device path for the PCI device of the GPU card, where AMD exposes its controls.
+    pub const GPU0_PCI_DEVICE_PATH: &str = "sys/class/drm/card0/device";
+
+    // Path of the kernel drivers the GPU driver symlink points to.
+    const PCI_DRIVERS_PATH: &str = "sys/bus/pci/drivers";
+
     pub struct MockPowerPreferencesManager {}
     impl power::PowerPreferencesManager for MockPowerPreferencesManager {
         fn update_power_preferences(
@@ -40,6 +49,14 @@ pub(crate) mod tests {
         ) -> Result<()> {
             Ok(())
         }
+
+        fn is_thermal_clamp_outdated(&self) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn set_render_boost(&self, _active: bool) -> Result<()> {
+            Ok(())
+        }
     }
 
     pub fn test_create_parent_dir(path: &Path) {
@@ -77,6 +94,17 @@ pub(crate) mod tests {
         Ok(())
     }
 
+    pub fn write_mock_energy(root: &Path, energy_uj: u64, max_energy_range_uj: u64) -> Result<()> {
+        let power_limit_path = root.join(DEVICE_POWER_LIMIT_PATH);
+        std::fs::write(power_limit_path.join("energy_uj"), energy_uj.to_string())?;
+        std::fs::write(
+            power_limit_path.join("max_energy_range_uj"),
+            max_energy_range_uj.to_string(),
+        )?;
+
+        Ok(())
+    }
+
     pub fn write_mock_cpu(
         root: &Path,
         cpu_num: i32,
@@ -104,6 +132,64 @@ pub(crate) mod tests {
         Ok(())
     }
 
+    /// Removes a mock policy, leaving a gap in the policy numbering.
+    pub fn remove_mock_cpu_policy(root: &Path, policy: i32) -> Result<()> {
+        fs::remove_dir_all(
+            root.join(DEVICE_CPUFREQ_PATH)
+                .join(format!("policy{policy}")),
+        )?;
+        Ok(())
+    }
+
+    /// Writes the online attribute of a CPU, which also makes it the only CPU of its policy.
+    pub fn write_mock_cpu_online(root: &Path, cpu: i32, online: bool) -> Result<()> {
+        let cpu_path = root.join(format!("sys/devices/system/cpu/cpu{cpu}"));
+        fs::create_dir_all(&cpu_path)?;
+        fs::write(cpu_path.join("online"), if online { "1" } else { "0" })?;
+        fs::write(
+            root.join(DEVICE_CPUFREQ_PATH)
+                .join(format!("policy{cpu}"))
+                .join("related_cpus"),
+            format!("{cpu}\n"),
+        )?;
+        Ok(())
+    }
+
+    /// Writes the current governor and the available governors of every mock policy.
+    pub fn write_mock_cpu_governors(root: &Path, governor: &str, available: &str) -> Result<()> {
+        for i in 0..MOCK_NUM_CPU {
+            let policy_path = root.join(DEVICE_CPUFREQ_PATH).join(format!("policy{i}"));
+            std::fs::write(policy_path.join("scaling_governor"), governor)?;
+            std::fs::write(policy_path.join("scaling_available_governors"), available)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the baseline frequencies of a hybrid CPU to the mock policies: `p_cores` P-cores
+    /// at the first policies, E-cores at the rest. The min is the same on both.
+    pub fn write_mock_hybrid_cpus(
+        root: &Path,
+        p_cores: i32,
+        p_core_max: u64,
+        e_core_max: u64,
+    ) -> Result<()> {
+        for i in 0..MOCK_NUM_CPU {
+            let max = if i < p_cores { p_core_max } else { e_core_max };
+            write_mock_cpu(root, i, max, max, 400000, 400000)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the temperature in millidegree Celsius of a thermal zone.
+    pub fn write_mock_thermal_zone(root: &Path, zone: i32, temp: &str) -> Result<()> {
+        let zone_path = root.join(format!("sys/class/thermal/thermal_zone{zone}"));
+        fs::create_dir_all(&zone_path)?;
+        fs::write(zone_path.join("temp"), temp)?;
+        Ok(())
+    }
+
     pub fn setup_mock_cpu_dev_dirs(root: &Path) -> anyhow::Result<()> {
         fs::create_dir_all(root.join(DEVICE_POWER_LIMIT_PATH))?;
         for i in 0..MOCK_NUM_CPU {
@@ -129,14 +215,41 @@ pub(crate) mod tests {
         str::from_utf8(&read_val).unwrap().parse::<i32>().unwrap()
     }
 
+    /// Writes the energy_performance_preference of policy0 and of every other mock policy.
+    pub fn write_mock_epp(root: &Path, value: &str) -> Result<()> {
+        let cpufreq_path = root.join(DEVICE_CPUFREQ_PATH);
+        fs::create_dir_all(cpufreq_path.join("policy0"))?;
+
+        for entry in fs::read_dir(&cpufreq_path)? {
+            let policy_path = entry?.path();
+            if policy_path.is_dir() {
+                std::fs::write(policy_path.join("energy_performance_preference"), value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read_mock_epp(root: &Path) -> Result<String> {
+        let epp_path = root
+            .join(DEVICE_CPUFREQ_PATH)
+            .join("policy0")
+            .join("energy_performance_preference");
+
+        Ok(std::fs::read_to_string(epp_path)?)
+    }
+
     pub fn setup_mock_cpu_files(root: &Path) -> Result<()> {
-        let pl_files: Vec<&str> = vec![
-            "constraint_0_power_limit_uw",
-            "constraint_0_max_power_uw",
-            "constraint_1_power_limit_uw",
-            "constraint_1_max_power_uw",
-            "energy_uj",
-            "max_energy_range_uj",
+        let pl_files: Vec<(&str, &str)> = vec![
+            ("name", "package-0"),
+            ("constraint_0_name", "long_term"),
+            ("constraint_0_power_limit_uw", "0"),
+            ("constraint_0_max_power_uw", "0"),
+            ("constraint_1_name", "short_term"),
+            ("constraint_1_power_limit_uw", "0"),
+            ("constraint_1_max_power_uw", "0"),
+            ("energy_uj", "0"),
+            ("max_energy_range_uj", "0"),
         ];
 
         let cpufreq_files: Vec<(&str, &str)> = vec![
@@ -149,9 +262,8 @@ pub(crate) mod tests {
 
         for pl_file in &pl_files {
             std::fs::write(
-                root.join(DEVICE_POWER_LIMIT_PATH)
-                    .join(PathBuf::from(pl_file)),
-                "0",
+                root.join(DEVICE_POWER_LIMIT_PATH).join(pl_file.0),
+                pl_file.1,
             )?;
         }
 
@@ -166,6 +278,43 @@ pub(crate) mod tests {
         Ok(())
     }
 
+    /// Sets up the cpufreq policies of an ARM big.LITTLE board: policy0 for cpus 0-5 and policy6
+    /// for cpus 6-7. Unlike on x86, only some governors are built, there is no
+    /// energy_performance_preference, and policy6 has no cpuinfo_max_freq.
+    pub fn setup_mock_arm_cpufreq_files(root: &Path) -> Result<()> {
+        let policies: Vec<(&str, &str, Option<&str>)> = vec![
+            ("policy0", "500000 1000000 1500000 2000000", Some("2000000")),
+            ("policy6", "500000 1100000 1700000 2200000", None),
+        ];
+
+        for (policy, available_freqs, cpuinfo_max_freq) in &policies {
+            let policy_path = root.join(DEVICE_CPUFREQ_PATH).join(policy);
+            fs::create_dir_all(&policy_path)?;
+
+            let max_freq = available_freqs.split_whitespace().last().unwrap();
+            let min_freq = available_freqs.split_whitespace().next().unwrap();
+            let cpufreq_files: Vec<(&str, &str)> = vec![
+                ("scaling_driver", "mediatek-cpufreq-hw\n"),
+                ("scaling_governor", "schedutil\n"),
+                (
+                    "scaling_available_governors",
+                    "userspace performance schedutil\n",
+                ),
+                ("scaling_available_frequencies", available_freqs),
+                ("scaling_max_freq", max_freq),
+                ("scaling_min_freq", min_freq),
+            ];
+            for cpufreq_file in &cpufreq_files {
+                std::fs::write(policy_path.join(cpufreq_file.0), cpufreq_file.1)?;
+            }
+            if let Some(cpuinfo_max_freq) = cpuinfo_max_freq {
+                std::fs::write(policy_path.join("cpuinfo_max_freq"), cpuinfo_max_freq)?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn construct_poc_cpuinfo_snippet(vendor: &str, model_name: &str) -> String {
         format!(
             r#"
@@ -193,25 +342,180 @@ model name      : {model_name}"#
         .unwrap();
     }
 
-    pub fn setup_mock_intel_gpu_dev_dirs(root: &Path) {
-        fs::create_dir_all(root.join(CPUINFO_PATH).parent().unwrap()).unwrap();
-        fs::create_dir_all(root.join(GPU0_RPS_DEVICE_PATH)).unwrap();
+    // Constructs the /proc/cpuinfo of a single-socket CPU with `num_cores` cores and no SMT.
+    fn construct_cpuinfo(
+        num_cores: u32,
+        vendor: &str,
+        family: u32,
+        model: u32,
+        model_name: &str,
+        stepping: u32,
+        flags: &str,
+    ) -> String {
+        (0..num_cores)
+            .map(|core| {
+                format!(
+                    "processor\t: {core}\n\
+                     vendor_id\t: {vendor}\n\
+                     cpu family\t: {family}\n\
+                     model\t\t: {model}\n\
+                     model name\t: {model_name}\n\
+                     stepping\t: {stepping}\n\
+                     physical id\t: 0\n\
+                     siblings\t: {num_cores}\n\
+                     core id\t\t: {core}\n\
+                     cpu cores\t: {num_cores}\n\
+                     flags\t\t: {flags}\n"
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
     }
 
-    pub fn setup_mock_intel_gpu_files(root: &Path) {
+    /// Constructs the /proc/cpuinfo of an AMD Ryzen (Zen 2) CPU with `num_cores` cores. The cpb
+    /// flag tells that the cores can boost.
+    pub fn construct_amd_cpuinfo(num_cores: u32) -> String {
+        construct_cpuinfo(
+            num_cores,
+            "AuthenticAMD",
+            23,
+            160,
+            "AMD Ryzen 5 7520C with Radeon Graphics",
+            0,
+            "fpu vme de pse tsc msr pae mce cx8 apic sep mtrr pge mca cmov pat pse36 clflush mmx \
+             fxsr sse sse2 ht syscall nx mmxext fxsr_opt pdpe1gb rdtscp lm constant_tsc rep_good \
+             nopl nonstop_tsc cpuid extd_apicid aperfmperf pni pclmulqdq monitor ssse3 fma cx16 \
+             sse4_1 sse4_2 movbe popcnt aes xsave avx f16c rdrand lahf_lm cmp_legacy svm \
+             extapic cr8_legacy abm sse4a misalignsse 3dnowprefetch osvw topoext perfctr_core \
+             perfctr_nb bpext perfctr_llc mwaitx cpb hw_pstate ssbd ibpb stibp vmmcall fsgsbase \
+             bmi1 avx2 smep bmi2 rdseed adx smap clflushopt clwb sha_ni xsaveopt xsavec xgetbv1 \
+             clzero irperf xsaveerptr rdpru wbnoinvd arat npt lbrv svm_lock nrip_save tsc_scale \
+             vmcb_clean flushbyasid decodeassists pausefilter pfthreshold avic v_vmsave_vmload \
+             vgif umip rdpid overflow_recov succor smca",
+        )
+    }
+
+    /// Constructs the /proc/cpuinfo of an Intel Core (Comet Lake) CPU with `num_cores` cores.
+    /// Intel has no cpb flag, ida tells that the cores can boost.
+    pub fn construct_intel_cpuinfo(num_cores: u32) -> String {
+        construct_cpuinfo(
+            num_cores,
+            "GenuineIntel",
+            6,
+            142,
+            "Intel(R) Core(TM) i3-10110U CPU @ 2.10GHz",
+            12,
+            "fpu vme de pse tsc msr pae mce cx8 apic sep mtrr pge mca cmov pat pse36 clflush dts \
+             acpi mmx fxsr sse sse2 ss ht tm pbe syscall nx pdpe1gb rdtscp lm constant_tsc art \
+             arch_perfmon pebs bts rep_good nopl xtopology nonstop_tsc cpuid aperfmperf pni \
+             pclmulqdq dtes64 monitor ds_cpl vmx est tm2 ssse3 sdbg fma cx16 xtpr pdcm pcid \
+             sse4_1 sse4_2 x2apic movbe popcnt tsc_deadline_timer aes xsave avx f16c rdrand \
+             lahf_lm abm 3dnowprefetch cpuid_fault epb invpcid_single ssbd ibrs ibpb stibp \
+             ibrs_enhanced tpr_shadow vnmi flexpriority ept vpid ept_ad fsgsbase tsc_adjust bmi1 \
+             avx2 smep bmi2 erms invpcid mpx rdseed adx smap clflushopt intel_pt xsaveopt xsavec \
+             xgetbv1 xsaves dtherm ida arat pln pts hwp hwp_notify hwp_act_window hwp_epp \
+             md_clear flush_l1d arch_capabilities",
+        )
+    }
+
+    pub fn write_mock_amd_cpuinfo(root: &Path, num_cores: u32) {
+        fs::write(root.join(CPUINFO_PATH), construct_amd_cpuinfo(num_cores)).unwrap();
+    }
+
+    pub fn write_mock_intel_cpuinfo(root: &Path, num_cores: u32) {
+        fs::write(root.join(CPUINFO_PATH), construct_intel_cpuinfo(num_cores)).unwrap();
+    }
+
+    fn setup_mock_gpu_driver(root: &Path, card: &str, driver: &str) {
+        let driver_dir = root.join(PCI_DRIVERS_PATH).join(driver);
+        fs::create_dir_all(&driver_dir).unwrap();
+        let device_path = root.join(DRM_CLASS_PATH).join(card).join("device");
+        fs::create_dir_all(&device_path).unwrap();
+        std::os::unix::fs::symlink(driver_dir, device_path.join("driver")).unwrap();
+    }
+
+    fn write_mock_intel_gpu_card_files(root: &Path, card: &str) {
         let gpu_files = vec![
             ("gt_min_freq_mhz", 200),
             ("gt_max_freq_mhz", 1000),
             ("gt_boost_freq_mhz", 1000),
+            ("gt_RP0_freq_mhz", 1100),
         ];
 
         for (gpu_file, default_freq) in &gpu_files {
             fs::write(
-                root.join(GPU0_DEVICE_PATH).join(PathBuf::from(gpu_file)),
+                root.join(DRM_CLASS_PATH).join(card).join(gpu_file),
                 default_freq.to_string(),
             )
             .unwrap();
         }
+    }
+
+    fn write_mock_amd_gpu_card_files(root: &Path, card: &str) {
+        let gpu_files = vec![
+            ("power_dpm_force_performance_level", "auto"),
+            ("pp_dpm_sclk", "0: 200Mhz \n1: 700Mhz *\n2: 1400Mhz \n"),
+            ("pp_od_clk_voltage", ""),
+        ];
+
+        for (gpu_file, default_val) in &gpu_files {
+            fs::write(
+                root.join(DRM_CLASS_PATH)
+                    .join(card)
+                    .join("device")
+                    .join(gpu_file),
+                default_val,
+            )
+            .unwrap();
+        }
+    }
+
+    /// Sets up an extra Intel GPU card, e.g. "card1", with the same files as card0.
+    pub fn setup_mock_intel_gpu_card(root: &Path, card: &str) {
+        setup_mock_gpu_driver(root, card, "i915");
+        write_mock_intel_gpu_card_files(root, card);
+    }
+
+    /// Sets up an extra AMD GPU card, e.g. "card1", with the same files as card0.
+    pub fn setup_mock_amd_gpu_card(root: &Path, card: &str) {
+        setup_mock_gpu_driver(root, card, "amdgpu");
+        write_mock_amd_gpu_card_files(root, card);
+    }
+
+    pub fn get_intel_gpu_card_boost(root: &Path, card: &str) -> i32 {
+        let gpu_boost_path = root
+            .join(DRM_CLASS_PATH)
+            .join(card)
+            .join("gt_boost_freq_mhz");
+        let read_val = std::fs::read(gpu_boost_path).unwrap();
+        str::from_utf8(&read_val).unwrap().parse::<i32>().unwrap()
+    }
+
+    pub fn set_intel_gpu_card_boost(root: &Path, card: &str, val: u32) {
+        let gpu_boost_path = root
+            .join(DRM_CLASS_PATH)
+            .join(card)
+            .join("gt_boost_freq_mhz");
+        std::fs::write(gpu_boost_path, val.to_string()).unwrap();
+    }
+
+    pub fn get_amd_gpu_card_mode(root: &Path, card: &str) -> String {
+        let gpu_mode_path = root
+            .join(DRM_CLASS_PATH)
+            .join(card)
+            .join("device")
+            .join("power_dpm_force_performance_level");
+        std::fs::read_to_string(gpu_mode_path).unwrap()
+    }
+
+    pub fn setup_mock_intel_gpu_dev_dirs(root: &Path) {
+        fs::create_dir_all(root.join(CPUINFO_PATH).parent().unwrap()).unwrap();
+        fs::create_dir_all(root.join(GPU0_RPS_DEVICE_PATH)).unwrap();
+        setup_mock_gpu_driver(root, "card0", "i915");
+    }
+
+    pub fn setup_mock_intel_gpu_files(root: &Path) {
+        write_mock_intel_gpu_card_files(root, "card0");
 
         let rps_files = vec![("rps_up_threshold_pct", 85), ("rps_down_threshold_pct", 95)];
 
@@ -251,4 +555,25 @@ model name      : {model_name}"#
         let gpu_boot_path = root.join(GPU0_DEVICE_PATH).join("gt_boost_freq_mhz");
         std::fs::write(gpu_boot_path, val.to_string()).unwrap();
     }
+
+    pub fn setup_mock_amd_gpu_dev_dirs(root: &Path) {
+        fs::create_dir_all(root.join(CPUINFO_PATH).parent().unwrap()).unwrap();
+        setup_mock_gpu_driver(root, "card0", "amdgpu");
+    }
+
+    pub fn setup_mock_amd_gpu_files(root: &Path) {
+        write_mock_amd_gpu_card_files(root, "card0");
+    }
+
+    pub fn get_amd_gpu_mode(root: &Path) -> String {
+        let gpu_mode_path = root
+            .join(GPU0_PCI_DEVICE_PATH)
+            .join("power_dpm_force_performance_level");
+        std::fs::read_to_string(gpu_mode_path).unwrap()
+    }
+
+    pub fn get_amd_gpu_sclk(root: &Path) -> String {
+        let gpu_sclk_path = root.join(GPU0_PCI_DEVICE_PATH).join("pp_dpm_sclk");
+        std::fs::read_to_string(gpu_sclk_path).unwrap()
+    }
 }
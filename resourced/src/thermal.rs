@@ -0,0 +1,295 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use glob::glob;
+
+/// Base path for thermal zones relative to rootdir.
+const THERMAL_ZONE_PATH: &str = "sys/class/thermal";
+
+/// Throttling starts at this temperature (in millidegree Celsius) on zones that don't declare a
+/// "hot" trip point.
+const DEFAULT_HOT_TEMP: i64 = 90000;
+
+/// Maximum throttling is applied at this temperature (in millidegree Celsius) on zones that don't
+/// declare a "critical" trip point.
+const DEFAULT_CRITICAL_TEMP: i64 = 105000;
+
+/// See the `thermal_trip_type` enum in the linux kernel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TripPointType {
+    Active,
+    Passive,
+    Hot,
+    Critical,
+}
+
+impl FromStr for TripPointType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_end() {
+            "active" => Ok(TripPointType::Active),
+            "passive" => Ok(TripPointType::Passive),
+            "hot" => Ok(TripPointType::Hot),
+            "critical" => Ok(TripPointType::Critical),
+            s => bail!("Unknown trip point type: '{}'", s),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TripPoint {
+    pub trip_type: TripPointType,
+    /// Temperature in millidegree Celsius.
+    pub temp: i64,
+}
+
+/// The temperatures (in millidegree Celsius) at which throttling starts and at which maximum
+/// throttling is applied.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ThrottleThresholds {
+    pub hot: i64,
+    pub critical: i64,
+}
+
+/// Ordered from the least to the most throttling.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThrottleBand {
+    // Below the hot threshold.
+    None,
+    // Between the hot and the critical threshold.
+    Hot,
+    // At or above the critical threshold.
+    Critical,
+}
+
+impl ThrottleThresholds {
+    /// Returns the throttling band that `temp` (in millidegree Celsius) falls into.
+    pub fn band(&self, temp: i64) -> ThrottleBand {
+        if temp >= self.critical {
+            ThrottleBand::Critical
+        } else if temp >= self.hot {
+            ThrottleBand::Hot
+        } else {
+            ThrottleBand::None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ThermalZone {
+    pub path: PathBuf,
+    pub trip_points: Vec<TripPoint>,
+}
+
+impl ThermalZone {
+    /// Reads the trip points declared in `trip_point_N_temp` and `trip_point_N_type` of the zone.
+    pub fn new(path: PathBuf) -> Result<ThermalZone> {
+        let mut trip_points = Vec::new();
+
+        for n in 0.. {
+            let temp_path = path.join(format!("trip_point_{}_temp", n));
+            let type_path = path.join(format!("trip_point_{}_type", n));
+
+            if !temp_path.exists() || !type_path.exists() {
+                break;
+            }
+
+            let temp = std::fs::read_to_string(&temp_path)
+                .with_context(|| format!("Failed to read {}", temp_path.display()))?
+                .trim_end()
+                .parse::<i64>()
+                .with_context(|| format!("Failed to parse {}", temp_path.display()))?;
+            let trip_type = TripPointType::from_str(
+                &std::fs::read_to_string(&type_path)
+                    .with_context(|| format!("Failed to read {}", type_path.display()))?,
+            )?;
+
+            trip_points.push(TripPoint { trip_type, temp });
+        }
+
+        Ok(ThermalZone { path, trip_points })
+    }
+
+    /// Returns the throttling thresholds derived from the lowest "hot" and "critical" trip points
+    /// of the zone. Missing trip points fall back to the defaults.
+    pub fn throttle_thresholds(&self) -> ThrottleThresholds {
+        let lowest_trip = |trip_type| {
+            self.trip_points
+                .iter()
+                .filter(|trip| trip.trip_type == trip_type)
+                .map(|trip| trip.temp)
+                .min()
+        };
+
+        let critical = lowest_trip(TripPointType::Critical).unwrap_or(DEFAULT_CRITICAL_TEMP);
+        // Throttling must start before the critical trip is reached.
+        let hot = lowest_trip(TripPointType::Hot)
+            .unwrap_or(DEFAULT_HOT_TEMP)
+            .min(critical);
+
+        ThrottleThresholds { hot, critical }
+    }
+
+    /// Returns the current temperature of the zone in millidegree Celsius.
+    pub fn get_temp(&self) -> Result<i64> {
+        let temp_path = self.path.join("temp");
+        std::fs::read_to_string(&temp_path)
+            .with_context(|| format!("Failed to read {}", temp_path.display()))?
+            .trim_end()
+            .parse::<i64>()
+            .with_context(|| format!("Failed to parse {}", temp_path.display()))
+    }
+}
+
+/// Returns all the thermal zones in sysfs with their trip points.
+pub fn get_thermal_zones(root: &Path) -> Result<Vec<ThermalZone>> {
+    let pattern = root
+        .join(THERMAL_ZONE_PATH)
+        .join("thermal_zone*")
+        .to_str()
+        .context("Cannot convert thermal zone path to string")?
+        .to_owned();
+
+    glob(&pattern)?
+        .map(|zone_path| ThermalZone::new(zone_path?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn write_mock_zone(root: &Path, zone: u32, temp: i64, trip_points: &[(&str, i64)]) -> PathBuf {
+        let zone_path = root
+            .join(THERMAL_ZONE_PATH)
+            .join(format!("thermal_zone{}", zone));
+        std::fs::create_dir_all(&zone_path).unwrap();
+        std::fs::write(zone_path.join("temp"), format!("{}\n", temp)).unwrap();
+
+        for (n, (trip_type, trip_temp)) in trip_points.iter().enumerate() {
+            std::fs::write(
+                zone_path.join(format!("trip_point_{}_type", n)),
+                format!("{}\n", trip_type),
+            )
+            .unwrap();
+            std::fs::write(
+                zone_path.join(format!("trip_point_{}_temp", n)),
+                format!("{}\n", trip_temp),
+            )
+            .unwrap();
+        }
+
+        zone_path
+    }
+
+    #[test]
+    fn test_parse_trip_point_type() -> Result<()> {
+        assert_eq!(TripPointType::from_str("active\n")?, TripPointType::Active);
+        assert_eq!(
+            TripPointType::from_str("passive\n")?,
+            TripPointType::Passive
+        );
+        assert_eq!(TripPointType::from_str("hot\n")?, TripPointType::Hot);
+        assert_eq!(
+            TripPointType::from_str("critical\n")?,
+            TripPointType::Critical
+        );
+        assert!(TripPointType::from_str("").is_err());
+        assert!(TripPointType::from_str("warm").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_thermal_zone_trip_points() -> Result<()> {
+        let root = tempdir()?;
+        let zone_path = write_mock_zone(
+            root.path(),
+            0,
+            45000,
+            &[
+                ("active", 60000),
+                ("passive", 80000),
+                ("hot", 95000),
+                ("critical", 110000),
+            ],
+        );
+
+        let zone = ThermalZone::new(zone_path)?;
+        assert_eq!(
+            zone.trip_points,
+            vec![
+                TripPoint {
+                    trip_type: TripPointType::Active,
+                    temp: 60000
+                },
+                TripPoint {
+                    trip_type: TripPointType::Passive,
+                    temp: 80000
+                },
+                TripPoint {
+                    trip_type: TripPointType::Hot,
+                    temp: 95000
+                },
+                TripPoint {
+                    trip_type: TripPointType::Critical,
+                    temp: 110000
+                },
+            ]
+        );
+
+        let thresholds = zone.throttle_thresholds();
+        assert_eq!(
+            thresholds,
+            ThrottleThresholds {
+                hot: 95000,
+                critical: 110000
+            }
+        );
+        assert_eq!(thresholds.band(zone.get_temp()?), ThrottleBand::None);
+        assert_eq!(thresholds.band(94999), ThrottleBand::None);
+        assert_eq!(thresholds.band(95000), ThrottleBand::Hot);
+        assert_eq!(thresholds.band(110000), ThrottleBand::Critical);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_thermal_zone_default_thresholds() -> Result<()> {
+        let root = tempdir()?;
+        write_mock_zone(root.path(), 0, 45000, &[]);
+        write_mock_zone(root.path(), 1, 45000, &[("critical", 85000)]);
+
+        let zones = get_thermal_zones(root.path())?;
+        assert_eq!(zones.len(), 2);
+
+        // No trip points at all.
+        assert!(zones[0].trip_points.is_empty());
+        assert_eq!(
+            zones[0].throttle_thresholds(),
+            ThrottleThresholds {
+                hot: DEFAULT_HOT_TEMP,
+                critical: DEFAULT_CRITICAL_TEMP
+            }
+        );
+
+        // The default hot threshold is capped by the declared critical trip.
+        assert_eq!(
+            zones[1].throttle_thresholds(),
+            ThrottleThresholds {
+                hot: 85000,
+                critical: 85000
+            }
+        );
+
+        Ok(())
+    }
+}